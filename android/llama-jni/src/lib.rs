@@ -53,35 +53,18 @@ pub fn generate(model_path: &Path, prompt: &str, max_new_tokens: i32) -> Result<
         .with_context(|| format!("failed to tokenize prompt: {prompt:?}"))?;
     anyhow::ensure!(!tokens.is_empty(), "prompt tokenized to zero tokens");
 
-    // Prefill the prompt; only the last token needs logits.
-    let mut batch = LlamaBatch::new(512, 1);
-    let last = tokens.len() as i32 - 1;
-    for (i, token) in (0_i32..).zip(tokens.iter().copied()) {
-        batch.add(token, i, &[0], i == last)?;
-    }
-    ctx.decode(&mut batch).context("prompt decode failed")?;
-
-    // Greedy decode loop. Accumulate raw bytes and lossily decode once at the
-    // end so multi-byte tokens split across steps still render.
-    let mut sampler = LlamaSampler::chain_simple([LlamaSampler::greedy()]);
-    let mut out = Vec::<u8>::new();
-    let mut n_cur = batch.n_tokens();
-
-    for _ in 0..max_new_tokens {
-        let token = sampler.sample(&ctx, batch.n_tokens() - 1);
-        sampler.accept(token);
-        if model.is_eog_token(token) {
-            break;
-        }
-        out.extend_from_slice(&model.token_to_bytes(token, Special::Tokenize)?);
-
-        batch.clear();
-        batch.add(token, n_cur, &[0], true)?;
-        n_cur += 1;
-        ctx.decode(&mut batch).context("token decode failed")?;
-    }
-
-    Ok(String::from_utf8_lossy(&out).into_owned())
+    // Prefill the prompt, then greedily decode until EOG or the token budget.
+    // The generator detokenizes incrementally, so multi-byte characters split
+    // across tokens still render.
+    let config =
+        GeneratorConfig::new().with_max_tokens(Some(usize::try_from(max_new_tokens).unwrap_or(0)));
+    let sampler = LlamaSampler::chain_simple([LlamaSampler::greedy()]);
+    let (text, _reason) = Generator::new(&mut ctx, sampler, &tokens, config)
+        .context("prompt decode failed")?
+        .collect_text()
+        .context("token decode failed")?;
+
+    Ok(text)
 }
 
 /// JNI bridge for `com.example.llama.LlamaBridge.generate(...)`.
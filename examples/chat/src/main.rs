@@ -1,7 +1,8 @@
 //! Interactive multi-turn chat using the model's built-in chat template.
 //!
-//! Uses [`LlamaModel::apply_chat_template`] so formatting matches the GGUF
-//! metadata (Llama, Qwen, Mistral, …) instead of raw user text.
+//! Uses [`ChatSession`], which formats turns with the GGUF chat template
+//! (Llama, Qwen, Mistral, …) and keeps earlier turns in the KV cache, so each
+//! turn only decodes the new message.
 //!
 //! ```console
 //! cargo run -p chat -- local path/to/model.gguf
@@ -26,27 +27,26 @@
     clippy::cast_sign_loss
 )]
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
 use colored::Colorize;
 use hf_hub::{split_id, HFClientSync};
 use llama_cpp_4::prelude::*;
 // use llama_cpp_sys_4::LLAMA_DEFAULT_SEED;
 use std::ffi::CString;
+use std::io::Write;
 use std::num::NonZeroU32;
 use std::path::PathBuf;
 use std::pin::pin;
 use std::str::FromStr;
 
-const BATCH_SIZE: usize = 512;
-
 #[derive(clap::Parser, Debug, Clone)]
 struct Args {
     /// The path to the model
     #[command(subcommand)]
     model: Model,
-    /// set the length of the prompt + output in tokens
-    #[arg(long, default_value_t = 32)]
+    /// maximum number of tokens generated per reply
+    #[arg(long, default_value_t = 256)]
     n_len: i32,
     /// override some parameters of the model
     #[arg(short = 'o', value_parser = parse_key_val)]
@@ -183,85 +183,10 @@ fn main() -> Result<()> {
         .new_context(&backend, ctx_params)
         .with_context(|| "unable to create the llama_context")?;
 
-    #[allow(unused)]
-    let mut sampler = LlamaSampler::chain_simple([LlamaSampler::common(), LlamaSampler::greedy()]);
-
-    let mut messages: Vec<LlamaChatMessage> = Vec::new();
-
-    let mut generate = |prompt: &str| -> Result<String> {
-        let mut output: String = String::new();
-
-        ctx.clear_kv_cache();
-
-        let tokens_list = model
-            .str_to_token(prompt, AddBos::Always)
-            .with_context(|| "failed to tokenize prompt".to_string())?;
-
-        let n_cxt = ctx.n_ctx() as i32;
-        let n_kv_req = tokens_list.len() as i32 + (n_len - tokens_list.len() as i32);
-
-        // make sure the KV cache is big enough to hold all the prompt and generated tokens
-        if n_kv_req > n_cxt {
-            bail!(
-                "n_kv_req > n_ctx, the required kv cache size is not big enough either reduce n_len or increase n_ctx"
-            )
-        }
-
-        if tokens_list.len() >= usize::try_from(n_len)? {
-            bail!("the prompt is too long, it has more tokens than n_len")
-        }
-
-        // we use this object to submit token data for decoding
-        let mut batch = LlamaBatch::new(BATCH_SIZE, 1);
-
-        let last_index: i32 = (tokens_list.len() - 1) as i32;
-        for (i, token) in (0_i32..).zip(tokens_list) {
-            // llama_decode will output logits only for the last token of the prompt
-            let is_last = i == last_index;
-            batch.add(token, i, &[0], is_last)?;
-        }
-
-        ctx.decode(&mut batch)
-            .with_context(|| "llama_decode() failed")?;
-
-        // main loop
-
-        let mut n_cur = batch.n_tokens();
-
-        // The `Decoder`
-        let mut decoder = encoding_rs::UTF_8.new_decoder();
-
-        while n_cur <= n_len {
-            // sample the next token
-            {
-                let new_token_id = sampler.sample(&ctx, batch.n_tokens() - 1);
-
-                // is it an end of stream?
-                if model.is_eog_token(new_token_id) {
-                    eprintln!();
-                    break;
-                }
-
-                let output_bytes = model.token_to_bytes(new_token_id, Special::Tokenize)?;
-                // use `Decoder.decode_to_string()` to avoid the intermediate buffer
-                let mut output_string = String::with_capacity(32);
-                let _decode_result =
-                    decoder.decode_to_string(&output_bytes, &mut output_string, false);
-                // print!("{output_string}");
-                // std::io::stdout().flush()?;
-                output.push_str(output_string.as_str());
-
-                batch.clear();
-                batch.add(new_token_id, n_cur, &[0], true)?;
-            }
-
-            n_cur += 1;
-
-            ctx.decode(&mut batch).with_context(|| "failed to eval")?;
-        }
-
-        Ok(output)
-    };
+    let mut session = ChatSession::new(&mut ctx);
+    let config = GeneratorConfig::new()
+        .with_max_tokens(usize::try_from(n_len).ok())
+        .with_special(Special::Tokenize);
 
     loop {
         let mut line = String::new();
@@ -272,24 +197,25 @@ fn main() -> Result<()> {
         if user_text.is_empty() {
             continue;
         }
-
-        let user_msg = LlamaChatMessage::new("user".into(), user_text.into())
+        session
+            .push_message("user", user_text)
             .context("invalid user message")?;
 
-        let mut turn_messages = messages.clone();
-        turn_messages.push(user_msg.clone());
-
-        let prompt = model
-            .apply_chat_template(None, &turn_messages, true)
-            .context("failed to apply chat template")?;
-
-        let response = generate(&prompt)?;
+        // Only the new turn is decoded; earlier turns stay in the KV cache.
+        let sampler = LlamaSampler::chain_simple([LlamaSampler::common(), LlamaSampler::greedy()]);
         println!("\n{}", "assistant".red());
-        println!("{}", response.white());
-
-        let asst_msg = LlamaChatMessage::new("assistant".into(), response)
-            .context("invalid assistant message")?;
-        messages.push(user_msg);
-        messages.push(asst_msg);
+        let reply = session
+            .respond(sampler, config.clone(), |piece| {
+                print!("{}", piece.text.white());
+                let _ = std::io::stdout().flush();
+            })
+            .context("failed to generate a reply")?;
+        println!();
+        if reply.n_evicted > 0 {
+            eprintln!(
+                "(evicted {} old messages to fit the context)",
+                reply.n_evicted
+            );
+        }
     }
 }
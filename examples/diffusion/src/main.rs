@@ -0,0 +1,215 @@
+//! # Diffusion
+//!
+//! Generate text with a diffusion language model such as Dream or LLaDA by
+//! iterative unmasking. This is the Rust equivalent of llama.cpp's
+//! `llama-diffusion-cli`.
+//!
+//! ## Usage
+//!
+//! ```console
+//! # Dream: timestep schedule, logits shifted by one
+//! cargo run -p diffusion -- -m dream-v0-instruct-7b.gguf -p "Write a quicksort in Rust" --steps 256
+//!
+//! # LLaDA: block schedule
+//! cargo run -p diffusion -- -m llada-8b-instruct.gguf -p "What is a monad?" --block-length 32 --visual
+//! ```
+#![allow(clippy::cast_precision_loss)]
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, ValueEnum};
+use llama_cpp_4::prelude::*;
+use std::io::Write;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+
+#[derive(clap::Parser, Debug)]
+#[command(about = "Generate text with a diffusion language model")]
+struct Args {
+    /// Path to the GGUF model file
+    #[arg(short = 'm', long)]
+    model: PathBuf,
+
+    /// The prompt
+    #[arg(short = 'p', long, default_value = "Write a short poem about the sea.")]
+    prompt: String,
+
+    /// Use the prompt as is instead of applying the chat template
+    #[arg(long)]
+    raw: bool,
+
+    /// Number of tokens to generate
+    #[arg(short = 'n', long, default_value_t = 256)]
+    n_gen: usize,
+
+    /// Denoising steps
+    #[arg(long, default_value_t = 128)]
+    steps: u32,
+
+    /// How unmasked positions are chosen
+    #[arg(long, value_enum, default_value_t = Algorithm::Confidence)]
+    algorithm: Algorithm,
+
+    /// Temperature for choosing positions (0 = most confident first)
+    #[arg(long, default_value_t = 0.0)]
+    alg_temp: f32,
+
+    /// Final timestep of the timestep schedule
+    #[arg(long, default_value_t = 1e-3)]
+    eps: f32,
+
+    /// Fill the output in blocks of this many tokens (LLaDA)
+    #[arg(long)]
+    block_length: Option<usize>,
+
+    /// Sampling temperature (0 = greedy)
+    #[arg(long, default_value_t = 0.0)]
+    temp: f32,
+
+    /// Top-k sampling
+    #[arg(long)]
+    top_k: Option<i32>,
+
+    /// Top-p sampling
+    #[arg(long)]
+    top_p: Option<f32>,
+
+    /// Seed for sampling and remasking
+    #[arg(long, default_value_t = 0)]
+    seed: u32,
+
+    /// Redraw the canvas after every step
+    #[arg(long)]
+    visual: bool,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Algorithm {
+    Origin,
+    Entropy,
+    Margin,
+    Random,
+    Confidence,
+}
+
+impl From<Algorithm> for DiffusionAlgorithm {
+    fn from(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Origin => Self::Origin,
+            Algorithm::Entropy => Self::Entropy,
+            Algorithm::Margin => Self::Margin,
+            Algorithm::Random => Self::Random,
+            Algorithm::Confidence => Self::Confidence,
+        }
+    }
+}
+
+/// Renders the canvas, drawing masked positions as `_`.
+fn render(model: &LlamaModel, tokens: &[LlamaToken]) -> String {
+    let mask = model.mask();
+    tokens
+        .iter()
+        .map(|&token| {
+            if token == mask {
+                "_".to_owned()
+            } else {
+                model
+                    .token_to_str(token, Special::Plaintext)
+                    .unwrap_or_default()
+            }
+        })
+        .collect()
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let mut backend = LlamaBackend::init()?;
+    backend.void_logs();
+    let model = LlamaModel::load_from_file(&backend, &args.model, &LlamaModelParams::default())
+        .with_context(|| "failed to load model")?;
+    if !model.is_diffusion() {
+        bail!("{} is not a diffusion model", args.model.display());
+    }
+
+    let prompt = if args.raw {
+        args.prompt.clone()
+    } else {
+        let message = LlamaChatMessage::new("user".into(), args.prompt.clone())?;
+        model
+            .apply_chat_template(None, &[message], true)
+            .unwrap_or_else(|_| args.prompt.clone())
+    };
+    let tokens = model
+        .str_to_token(&prompt, AddBos::Always)
+        .with_context(|| "tokenization failed")?;
+
+    // The whole sequence is decoded at once with non-causal attention
+    let n_tokens = u32::try_from(tokens.len() + args.n_gen)?;
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(n_tokens))
+        .with_n_batch(n_tokens)
+        .with_n_ubatch(n_tokens);
+    let mut ctx = model
+        .new_context(&backend, ctx_params)
+        .with_context(|| "failed to create context")?;
+
+    let mut samplers = Vec::new();
+    if let Some(k) = args.top_k {
+        samplers.push(LlamaSampler::top_k(k));
+    }
+    if let Some(p) = args.top_p {
+        samplers.push(LlamaSampler::top_p(p, 1));
+    }
+    if args.temp > 0.0 {
+        samplers.push(LlamaSampler::temp(args.temp));
+        samplers.push(LlamaSampler::dist(args.seed));
+    } else {
+        samplers.push(LlamaSampler::greedy());
+    }
+    let mut sampler = LlamaSampler::chain_simple(samplers);
+
+    let config = DiffusionConfig::for_model(&model)
+        .with_n_gen(args.n_gen)
+        .with_steps(args.steps)
+        .with_algorithm(args.algorithm.into())
+        .with_alg_temp(args.alg_temp)
+        .with_eps(args.eps)
+        .with_block_length(args.block_length)
+        .with_seed(u64::from(args.seed));
+
+    eprintln!(
+        "prompt: {} tokens, generating {} tokens in {} steps",
+        tokens.len(),
+        args.n_gen,
+        args.steps
+    );
+    let t_start = ggml_time_us();
+    let output = diffusion_generate(&mut ctx, &mut sampler, &tokens, &config, |step| {
+        if args.visual {
+            // Clear the screen and redraw the canvas
+            print!("\x1b[2J\x1b[H");
+            println!("step {}/{}\n", step.step, step.n_steps);
+            println!("{}", render(&model, step.tokens));
+        } else {
+            eprint!(
+                "\rstep {}/{} ({} masked)   ",
+                step.step, step.n_steps, step.n_masked
+            );
+        }
+        std::io::stdout().flush().is_ok()
+    })
+    .with_context(|| "diffusion generation failed")?;
+    let elapsed = (ggml_time_us() - t_start) as f64 / 1_000_000.0;
+
+    if !args.visual {
+        eprintln!();
+        println!("{}", render(&model, &output.tokens));
+    }
+    eprintln!(
+        "\n{} steps in {elapsed:.2} s ({:.2} steps/s)",
+        output.n_steps,
+        f64::from(output.n_steps) / elapsed
+    );
+
+    Ok(())
+}
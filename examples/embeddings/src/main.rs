@@ -27,6 +27,9 @@ struct Args {
     /// Whether to normalise the produced embeddings
     #[clap(short)]
     normalise: bool,
+    /// Keep only the first N dimensions (Matryoshka models)
+    #[clap(long)]
+    dimensions: Option<usize>,
     /// Disable offloading layers to the GPU
     #[cfg(any(feature = "cuda", feature = "vulkan"))]
     #[clap(long)]
@@ -77,6 +80,7 @@ fn main() -> Result<()> {
         model,
         prompt,
         normalise,
+        dimensions,
         #[cfg(any(feature = "cuda", feature = "vulkan"))]
         disable_gpu,
     } = Args::parse();
@@ -109,10 +113,14 @@ fn main() -> Result<()> {
     let model = LlamaModel::load_from_file(&backend, model_path, &model_params)
         .with_context(|| "unable to load model")?;
 
+    // Split the prompt into lines; each line is embedded on its own sequence
+    let prompt_lines: Vec<&str> = prompt.lines().collect();
+
     // Initialize the context with batch parameters
     let ctx_params = LlamaContextParams::default()
         .with_n_batch(batch_size)
         .with_n_ubatch(batch_size)
+        .with_n_seq_max(prompt_lines.len().clamp(1, 64) as u32)
         .with_n_threads_batch(std::thread::available_parallelism()?.get().try_into()?)
         .with_embeddings(true);
 
@@ -120,11 +128,9 @@ fn main() -> Result<()> {
         .new_context(&backend, ctx_params)
         .with_context(|| "unable to create the llama_context")?;
 
-    // Split the prompt into lines for batching
-    let prompt_lines = prompt.lines();
-
     // Tokenize the prompt
     let tokens_lines_list = prompt_lines
+        .iter()
         .map(|line| model.str_to_token(line, AddBos::Always))
         .collect::<Result<Vec<_>, _>>()
         .with_context(|| format!("failed to tokenize {prompt}"))?;
@@ -160,41 +166,16 @@ fn main() -> Result<()> {
 
     std::io::stderr().flush()?; // Flush stderr buffer
 
-    // Create a batch object for token sequences
-    let mut batch = LlamaBatch::new(batch_size as usize, 1);
-
-    let mut max_seq_id_batch = 0;
-    let mut output = Vec::with_capacity(tokens_lines_list.len());
-
     let t_main_start = ggml_time_us(); // Measure the start time for processing
 
-    // Process the tokenized prompts in batches
-    for tokens in &tokens_lines_list {
-        // If the current batch exceeds the context size, decode the batch and reset
-        if (batch.n_tokens() as usize + tokens.len()) > batch_size as usize {
-            let _ = batch_decode(
-                &mut ctx,
-                &mut batch,
-                max_seq_id_batch,
-                &mut output,
-                normalise,
-            );
-            max_seq_id_batch = 0;
-        }
-
-        // Add tokens to the batch
-        batch.add_sequence(tokens, max_seq_id_batch, false)?;
-        max_seq_id_batch += 1;
-    }
-
-    // Handle the final batch if any tokens are left
-    batch_decode(
-        &mut ctx,
-        &mut batch,
-        max_seq_id_batch,
-        &mut output,
-        normalise,
-    )?;
+    // Tokenize, pack the lines into batches and decode them
+    let config = EmbedConfig::new()
+        .with_normalize(normalise)
+        .with_dimensions(dimensions);
+    let embeddings = embed(&mut ctx, &prompt_lines, &config).with_context(|| "embedding failed")?;
+    let output: Vec<&[f32]> = (0..embeddings.n_inputs())
+        .map(|i| embeddings.input(i))
+        .collect();
 
     let t_main_end = ggml_time_us(); // Measure the end time for processing
 
@@ -205,7 +186,6 @@ fn main() -> Result<()> {
     }
 
     // Calculate and display cosine similarity between embeddings if there are multiple prompt lines
-    let prompt_lines: Vec<&str> = prompt.lines().collect();
     if output.len() > 1 {
         println!("cosine similarity matrix:\n\n");
         prompt_lines
@@ -222,7 +202,7 @@ fn main() -> Result<()> {
             let i_embeddings = output.get(i).unwrap();
             for j in 0..output.len() {
                 let j_embeddings = output.get(j).unwrap();
-                let sim = common_embd_similarity_cos(i_embeddings, j_embeddings);
+                let sim = cosine_similarity(i_embeddings, j_embeddings);
                 print!("{sim}\t");
             }
             let prompt = prompt_lines.get(i).unwrap();
@@ -245,80 +225,3 @@ fn main() -> Result<()> {
 
     Ok(())
 }
-
-// Function to decode a batch and extract embeddings
-fn batch_decode(
-    ctx: &mut LlamaContext,     // Llama context to interact with the model
-    batch: &mut LlamaBatch,     // Batch of token sequences
-    _s_batch: i32,              // Unused batch sequence ID (could be for future use)
-    output: &mut Vec<Vec<f32>>, // Output vector to store embeddings
-    normalise: bool,            // Whether to normalize the embeddings
-) -> Result<()> {
-    let pooling_type = ctx.pooling_type(); // Get the pooling type (used to process embeddings)
-
-    // Clear the key-value cache and decode the batch
-    ctx.clear_kv_cache();
-    ctx.decode(batch).with_context(|| "llama_decode() failed")?;
-
-    // For each token in the batch, extract and process its embedding
-    for i in 0..batch.n_tokens() {
-        let embedding = match pooling_type {
-            llama_cpp_4::context::params::LlamaPoolingType::None => ctx.embeddings_ith(i), // Get the embedding without pooling
-            _ => ctx.embeddings_seq_ith(i), // Use sequence-based pooling for the embeddings
-        };
-
-        if let Ok(embedding) = embedding {
-            // If the embedding is valid
-            // Normalize or return the raw embedding based on user preference
-            let output_embeddings = if normalise {
-                normalize(embedding) // Normalize the embedding
-            } else {
-                embedding.to_vec() // Return the raw embedding
-            };
-
-            output.push(output_embeddings); // Add the processed embedding to the output vector
-        }
-    }
-
-    // Clear the batch after processing
-    batch.clear();
-
-    Ok(())
-}
-
-// Function to normalize a vector of floats (L2 normalization)
-fn normalize(input: &[f32]) -> Vec<f32> {
-    // Calculate the magnitude (L2 norm) of the input vector
-    let magnitude = input
-        .iter()
-        .fold(0.0, |acc, &val| val.mul_add(val, acc)) // Sum of squares
-        .sqrt(); // Square root to get the L2 norm
-
-    // Normalize each value in the input vector by dividing by the magnitude
-    input.iter().map(|&val| val / magnitude).collect()
-}
-
-// Function to compute the cosine similarity between two embeddings
-fn common_embd_similarity_cos(embd1: &[f32], embd2: &[f32]) -> f32 {
-    let mut sum = 0.0;
-    let mut sum1 = 0.0;
-    let mut sum2 = 0.0;
-
-    // Iterate through the vectors and compute dot products and squared magnitudes
-    for i in 0..embd1.len() {
-        sum += f64::from(embd1[i]) * f64::from(embd2[i]); // Dot product
-        sum1 += f64::from(embd1[i]) * f64::from(embd1[i]); // Squared magnitude of embd1
-        sum2 += f64::from(embd2[i]) * f64::from(embd2[i]); // Squared magnitude of embd2
-    }
-
-    // Handle the case where one or both vectors are zero vectors
-    if sum1 == 0.0 || sum2 == 0.0 {
-        if sum1 == 0.0 && sum2 == 0.0 {
-            return 1.0; // Two zero vectors are considered perfectly similar
-        }
-        return 0.0; // One of the vectors is a zero vector, so similarity is zero
-    }
-
-    // Calculate the cosine similarity
-    (sum / (f64::sqrt(sum1) * f64::sqrt(sum2))) as f32 // Return the similarity as a float
-}
@@ -26,8 +26,6 @@
     clippy::assigning_clones
 )]
 
-mod prefill;
-
 use std::num::NonZeroU32;
 use std::pin::pin;
 use std::time::Instant;
@@ -36,8 +34,6 @@ use anyhow::{anyhow, Context, Result};
 
 use llama_cpp_4::prelude::*;
 
-use prefill::IncrementalPrefill;
-
 const BATCH_SIZE: usize = 512;
 
 // ---------------------------------------------------------------------------
@@ -721,14 +717,13 @@ fn bench_dx() {
     println!("    IncrementalPrefill::len() → usize");
     println!("  Integration points:");
     println!("    - Uses existing LlamaContext::decode() and clear_kv_cache_seq()");
-    println!("    - No changes to llama-cpp-4 core — pure userspace pattern");
+    println!("    - llama_cpp_4::context::incremental::IncrementalPrefill, any seq id");
     println!("    - Works with any model/tokenizer/chat template");
     println!("  Error handling:");
     println!("    - KV cache trim errors propagated via Result");
     println!("    - Decode errors propagated via Result");
     println!("    - Graceful recovery: next TextChanged retries from last good state");
     println!("  Source files:");
-    println!("    - prefill.rs:    ~130 lines (shared module)");
     println!("    - line_editor.rs: ~200 lines (cursor-based editor)");
     println!("    - main.rs:       ~500 lines (interactive chat)");
     println!("    - bench.rs:      ~400 lines (this benchmark)");
@@ -44,7 +44,6 @@
 )]
 
 mod line_editor;
-mod prefill;
 
 use std::ffi::CString;
 use std::io::{self, Write};
@@ -67,7 +66,6 @@ use hf_hub::{split_id, HFClientSync};
 use llama_cpp_4::prelude::*;
 
 use line_editor::LineEditor;
-use prefill::IncrementalPrefill;
 
 // ---------------------------------------------------------------------------
 // CLI
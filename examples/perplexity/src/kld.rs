@@ -0,0 +1,413 @@
+//! Logits dump format and KL-divergence statistics.
+//!
+//! The base model's run writes every scored row as log-probabilities, and a
+//! later run of a quantized model compares its own rows against them, like
+//! `llama-perplexity --kl-divergence-base` / `--kl-divergence`.
+//!
+//! ## File format
+//!
+//! All integers and floats are little-endian.
+//!
+//! | Field | Type |
+//! |---|---|
+//! | magic | `b"LLAMALOG"` |
+//! | version | `u32` (currently 1) |
+//! | `n_ctx`, `n_vocab`, `n_chunk`, `n_tokens` | `u32` each |
+//! | tokens | `n_tokens` × `i32` |
+//! | rows | `n_chunk` × (`n_ctx` - 1 - `n_ctx` / 2) rows |
+//!
+//! A row is the maximum log-probability as `f32` followed by `n_vocab` `u16`
+//! values that map linearly onto `max - LOGPROB_RANGE ..= max`. Less likely
+//! tokens are clamped to the bottom of the range; each is at most e^-16 as
+//! likely as the top token, so the clamping changes the KL-divergence little.
+
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use anyhow::{bail, Result};
+
+const MAGIC: &[u8; 8] = b"LLAMALOG";
+const VERSION: u32 = 1;
+
+/// Width of the stored log-probability range, in nats.
+pub const LOGPROB_RANGE: f32 = 16.0;
+
+const LEVELS: f32 = u16::MAX as f32;
+
+/// Dimensions and tokens of a logits file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogitsHeader {
+    /// Context size the rows were computed with.
+    pub n_ctx: u32,
+    /// Vocabulary size of the base model.
+    pub n_vocab: u32,
+    /// Number of chunks stored.
+    pub n_chunk: u32,
+    /// The tokenized dataset.
+    pub tokens: Vec<i32>,
+}
+
+/// Writes a logits file.
+#[derive(Debug)]
+pub struct LogitsWriter<W: Write> {
+    out: BufWriter<W>,
+    n_vocab: usize,
+    logprobs: Vec<f32>,
+}
+
+impl<W: Write> LogitsWriter<W> {
+    /// Writes the header.
+    pub fn new(out: W, header: &LogitsHeader) -> io::Result<Self> {
+        let mut out = BufWriter::new(out);
+        out.write_all(MAGIC)?;
+        let n_tokens = u32::try_from(header.tokens.len())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        for value in [
+            VERSION,
+            header.n_ctx,
+            header.n_vocab,
+            header.n_chunk,
+            n_tokens,
+        ] {
+            out.write_all(&value.to_le_bytes())?;
+        }
+        for token in &header.tokens {
+            out.write_all(&token.to_le_bytes())?;
+        }
+        Ok(Self {
+            out,
+            n_vocab: header.n_vocab as usize,
+            logprobs: Vec::new(),
+        })
+    }
+
+    /// Appends the row for one position, given the raw logits.
+    pub fn write_row(&mut self, logits: &[f32]) -> io::Result<()> {
+        if logits.len() != self.n_vocab {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("expected {} logits, got {}", self.n_vocab, logits.len()),
+            ));
+        }
+        log_softmax(logits, &mut self.logprobs);
+        let max = self
+            .logprobs
+            .iter()
+            .copied()
+            .fold(f32::NEG_INFINITY, f32::max);
+        let min = max - LOGPROB_RANGE;
+        self.out.write_all(&max.to_le_bytes())?;
+        for &logprob in &self.logprobs {
+            let level = ((logprob - min) / LOGPROB_RANGE * LEVELS)
+                .round()
+                .clamp(0.0, LEVELS) as u16;
+            self.out.write_all(&level.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Flushes buffered rows.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Reads a logits file.
+#[derive(Debug)]
+pub struct LogitsReader<R: Read> {
+    input: BufReader<R>,
+    header: LogitsHeader,
+    row: Vec<u8>,
+}
+
+impl<R: Read> LogitsReader<R> {
+    /// Reads and validates the header.
+    pub fn new(input: R) -> Result<Self> {
+        let mut input = BufReader::new(input);
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("not a logits file (bad magic)");
+        }
+        let version = read_u32(&mut input)?;
+        if version != VERSION {
+            bail!("unsupported logits file version {version}");
+        }
+        let n_ctx = read_u32(&mut input)?;
+        let n_vocab = read_u32(&mut input)?;
+        let n_chunk = read_u32(&mut input)?;
+        let n_tokens = read_u32(&mut input)? as usize;
+        let mut bytes = vec![0; n_tokens * 4];
+        input.read_exact(&mut bytes)?;
+        let tokens = bytes
+            .chunks_exact(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        Ok(Self {
+            input,
+            header: LogitsHeader {
+                n_ctx,
+                n_vocab,
+                n_chunk,
+                tokens,
+            },
+            row: vec![0; n_vocab as usize * 2],
+        })
+    }
+
+    /// The header of the file.
+    pub fn header(&self) -> &LogitsHeader {
+        &self.header
+    }
+
+    /// Reads the next row as log-probabilities into `logprobs`.
+    pub fn read_row(&mut self, logprobs: &mut Vec<f32>) -> Result<()> {
+        let max = read_f32(&mut self.input)?;
+        self.input.read_exact(&mut self.row)?;
+        let min = max - LOGPROB_RANGE;
+        logprobs.clear();
+        logprobs.extend(
+            self.row.chunks_exact(2).map(|b| {
+                min + f32::from(u16::from_le_bytes([b[0], b[1]])) / LEVELS * LOGPROB_RANGE
+            }),
+        );
+        Ok(())
+    }
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_f32(input: &mut impl Read) -> io::Result<f32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+/// Log-softmax of `logits` into `out`, normalized in `f64`.
+pub fn log_softmax(logits: &[f32], out: &mut Vec<f32>) {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum_exp: f64 = logits.iter().map(|&l| f64::from(l - max).exp()).sum();
+    let log_sum_exp = sum_exp.ln() as f32;
+    out.clear();
+    out.extend(logits.iter().map(|&l| l - max - log_sum_exp));
+}
+
+fn argmax(values: &[f32]) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(0, |(i, _)| i)
+}
+
+/// Running comparison of a model against the base log-probabilities.
+#[derive(Debug, Default)]
+pub struct KldStats {
+    kld: Vec<f64>,
+    delta_p: Vec<f64>,
+    base_nll: f64,
+    nll: f64,
+    nll_ratio_sq: f64,
+    same_top: usize,
+    logprobs: Vec<f32>,
+}
+
+impl KldStats {
+    /// Adds one position: the base log-probabilities, this model's logits,
+    /// and the index of the token that actually followed.
+    pub fn add(&mut self, base: &[f32], logits: &[f32], target: usize) {
+        log_softmax(logits, &mut self.logprobs);
+        let kld: f64 = base
+            .iter()
+            .zip(&self.logprobs)
+            .map(|(&p, &q)| f64::from(p).exp() * f64::from(p - q))
+            .sum();
+        self.kld.push(kld.max(0.0));
+
+        let base_logprob = f64::from(base[target]);
+        let logprob = f64::from(self.logprobs[target]);
+        self.base_nll -= base_logprob;
+        self.nll -= logprob;
+        self.nll_ratio_sq += (base_logprob - logprob).powi(2);
+        self.delta_p.push(logprob.exp() - base_logprob.exp());
+
+        if argmax(base) == argmax(&self.logprobs) {
+            self.same_top += 1;
+        }
+    }
+
+    /// Number of positions compared.
+    pub fn count(&self) -> usize {
+        self.kld.len()
+    }
+
+    /// Mean KL-divergence so far.
+    pub fn mean_kld(&self) -> f64 {
+        mean(&self.kld)
+    }
+
+    /// Perplexity of the base model and of this model.
+    pub fn ppl(&self) -> (f64, f64) {
+        let n = self.count().max(1) as f64;
+        ((self.base_nll / n).exp(), (self.nll / n).exp())
+    }
+
+    /// Prints the summary, like the end of `llama-perplexity --kl-divergence`.
+    pub fn report(&self, out: &mut impl Write) -> io::Result<()> {
+        let n = self.count().max(1) as f64;
+        let (base_ppl, ppl) = self.ppl();
+        // ln(PPL(Q)/PPL(base)) is the mean of the per-token NLL differences.
+        let ln_ratio = (self.nll - self.base_nll) / n;
+        let ln_ratio_err = ((self.nll_ratio_sq / n - ln_ratio * ln_ratio) / n)
+            .max(0.0)
+            .sqrt();
+
+        writeln!(out, "====== Perplexity statistics ======")?;
+        writeln!(out, "Mean PPL(Q)            : {ppl:10.6}")?;
+        writeln!(out, "Mean PPL(base)         : {base_ppl:10.6}")?;
+        writeln!(
+            out,
+            "Mean ln(PPL(Q)/PPL(base)) : {ln_ratio:10.6} ± {ln_ratio_err:.6}"
+        )?;
+        writeln!(out, "Mean PPL(Q)/PPL(base)  : {:10.6}", ln_ratio.exp())?;
+        writeln!(out, "Mean PPL(Q)-PPL(base)  : {:10.6}", ppl - base_ppl)?;
+        writeln!(out)?;
+
+        let mut kld = self.kld.clone();
+        kld.sort_by(f64::total_cmp);
+        writeln!(out, "====== KL divergence statistics ======")?;
+        writeln!(
+            out,
+            "Mean    KLD: {:10.6} ± {:.6}",
+            self.mean_kld(),
+            std_error(&self.kld)
+        )?;
+        for (label, q) in [
+            ("Maximum", 1.0),
+            ("99.9%  ", 0.999),
+            ("99.0%  ", 0.99),
+            ("95.0%  ", 0.95),
+            ("90.0%  ", 0.90),
+            ("Median ", 0.5),
+            ("10.0%  ", 0.10),
+            ("Minimum", 0.0),
+        ] {
+            writeln!(out, "{label} KLD: {:10.6}", percentile(&kld, q))?;
+        }
+        writeln!(out)?;
+
+        let mut delta_p = self.delta_p.clone();
+        delta_p.sort_by(f64::total_cmp);
+        let rms = (self.delta_p.iter().map(|d| d * d).sum::<f64>() / n).sqrt();
+        let same_top = self.same_top as f64 / n;
+        writeln!(out, "====== Token probability statistics ======")?;
+        writeln!(
+            out,
+            "Mean    Δp: {:8.3} ± {:.3} %",
+            100.0 * mean(&self.delta_p),
+            100.0 * std_error(&self.delta_p)
+        )?;
+        writeln!(
+            out,
+            "Median  Δp: {:8.3} %",
+            100.0 * percentile(&delta_p, 0.5)
+        )?;
+        writeln!(out, "RMS     Δp: {:8.3} %", 100.0 * rms)?;
+        writeln!(
+            out,
+            "Same top p: {:8.3} ± {:.3} %",
+            100.0 * same_top,
+            100.0 * (same_top * (1.0 - same_top) / n).sqrt()
+        )?;
+        Ok(())
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len().max(1) as f64
+}
+
+/// Standard error of the mean.
+fn std_error(values: &[f64]) -> f64 {
+    if values.len() < 2 {
+        return 0.0;
+    }
+    let m = mean(values);
+    let var = values.iter().map(|v| (v - m).powi(2)).sum::<f64>() / (values.len() - 1) as f64;
+    (var / values.len() as f64).sqrt()
+}
+
+/// Nearest-rank percentile of sorted values.
+fn percentile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let index = (q * (sorted.len() - 1) as f64).round() as usize;
+    sorted[index.min(sorted.len() - 1)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_round_trip_within_quantization_error() {
+        let header = LogitsHeader {
+            n_ctx: 4,
+            n_vocab: 3,
+            n_chunk: 1,
+            tokens: vec![1, 2, 3, 4],
+        };
+        let logits = [2.0, 0.5, -30.0];
+        let mut bytes = Vec::new();
+        let mut writer = LogitsWriter::new(&mut bytes, &header).unwrap();
+        writer.write_row(&logits).unwrap();
+        writer.finish().unwrap();
+
+        let mut reader = LogitsReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.header(), &header);
+        let mut row = Vec::new();
+        reader.read_row(&mut row).unwrap();
+
+        let mut expected = Vec::new();
+        log_softmax(&logits, &mut expected);
+        for (got, want) in row.iter().zip(&expected).take(2) {
+            assert!((got - want).abs() < 1e-3, "{got} vs {want}");
+        }
+        // The unlikely token is clamped to the bottom of the range.
+        assert!((row[2] - (expected[0] - LOGPROB_RANGE)).abs() < 1e-3);
+        assert!(reader.read_row(&mut row).is_err());
+    }
+
+    #[test]
+    fn identical_models_agree() {
+        let logits = [1.0, 3.0, 0.0, -1.0];
+        let mut base = Vec::new();
+        log_softmax(&logits, &mut base);
+        let mut stats = KldStats::default();
+        stats.add(&base, &logits, 1);
+        stats.add(&base, &logits, 2);
+        assert_eq!(stats.count(), 2);
+        assert!(stats.mean_kld().abs() < 1e-9);
+        let (base_ppl, ppl) = stats.ppl();
+        assert!((base_ppl - ppl).abs() < 1e-9);
+        assert_eq!(stats.same_top, 2);
+    }
+
+    #[test]
+    fn different_models_diverge() {
+        let mut base = Vec::new();
+        log_softmax(&[0.0, 0.0], &mut base);
+        let mut stats = KldStats::default();
+        stats.add(&base, &[2.0_f32.ln(), 0.0], 0);
+        // KL(½,½ ‖ ⅔,⅓) = ½ ln(¾) + ½ ln(3/2)
+        let expected = 0.5 * 0.75_f64.ln() + 0.5 * 1.5_f64.ln();
+        assert!((stats.mean_kld() - expected).abs() < 1e-6);
+        let mut out = Vec::new();
+        stats.report(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("Mean    KLD"));
+    }
+}
@@ -15,6 +15,31 @@
 //! cargo run -p perplexity -- -m model.gguf -f wiki.test.raw
 //! cargo run -p perplexity -- -m model.gguf -f wiki.test.raw --chunks 10
 //! ```
+//!
+//! ## KL divergence
+//!
+//! To measure how much a quantization changes the output distribution, run the
+//! base model once and save its logits, then run the quantized model against
+//! the saved file. The second run reports the KL divergence from the base
+//! distribution, how often both models agree on the top token, and the change
+//! in perplexity. The tokens and context size are taken from the file.
+//!
+//! ```console
+//! cargo run -p perplexity -- -m f16.gguf -f wiki.test.raw --kl-divergence-base f16.logits
+//! cargo run -p perplexity -- -m q4_k_m.gguf --kl-divergence-base f16.logits --kl-divergence
+//! ```
+//!
+//! ## Multiple-choice benchmarks
+//!
+//! `--hellaswag`, `--winogrande` and `--multiple-choice` read a task file with
+//! `-f`, score every choice by log-likelihood and report the accuracy with a
+//! 95% confidence interval. See [`multiple_choice`] for the file formats.
+//!
+//! ```console
+//! cargo run -p perplexity -- -m model.gguf -f hellaswag_val_full.txt --hellaswag --tasks 400
+//! cargo run -p perplexity -- -m model.gguf -f winogrande-debiased-eval.csv --winogrande
+//! cargo run -p perplexity -- -m model.gguf -f mmlu_test.csv --multiple-choice
+//! ```
 #![allow(
     clippy::cast_possible_wrap,
     clippy::cast_possible_truncation,
@@ -22,23 +47,30 @@
     clippy::cast_sign_loss
 )]
 
+mod kld;
+mod multiple_choice;
+
 use anyhow::{bail, Context, Result};
 use clap::Parser;
+use kld::{KldStats, LogitsHeader, LogitsReader, LogitsWriter};
 use llama_cpp_4::prelude::*;
+use multiple_choice::{parse_tasks, TaskKind};
+use std::fs::File;
 use std::io::Write;
 use std::num::NonZeroU32;
 use std::path::PathBuf;
 
 #[derive(clap::Parser, Debug)]
 #[command(about = "Calculate perplexity of a model on a text dataset")]
+#[command(group(clap::ArgGroup::new("mode").args(["kl_divergence", "hellaswag", "winogrande", "multiple_choice"])))]
 struct Args {
     /// Path to the GGUF model file
     #[arg(short = 'm', long)]
     model: PathBuf,
 
     /// Path to the text file to evaluate
-    #[arg(short = 'f', long)]
-    file: PathBuf,
+    #[arg(short = 'f', long, required_unless_present = "kl_divergence")]
+    file: Option<PathBuf>,
 
     /// Context size (default: model's training context)
     #[arg(short = 'c', long)]
@@ -47,6 +79,49 @@ struct Args {
     /// Number of chunks to evaluate (-1 = all)
     #[arg(long, default_value_t = -1)]
     chunks: i32,
+
+    /// Logits file: written by a base run, read back with `--kl-divergence`
+    #[arg(long, value_name = "FILE")]
+    kl_divergence_base: Option<PathBuf>,
+
+    /// Compare against the logits in `--kl-divergence-base` instead of
+    /// writing them
+    #[arg(long, requires = "kl_divergence_base")]
+    kl_divergence: bool,
+
+    /// Evaluate the HellaSwag tasks in the input file
+    #[arg(long)]
+    hellaswag: bool,
+
+    /// Evaluate the Winogrande tasks in the input file
+    #[arg(long)]
+    winogrande: bool,
+
+    /// Evaluate the MMLU-style multiple-choice tasks in the input file
+    #[arg(long)]
+    multiple_choice: bool,
+
+    /// Number of tasks to evaluate (default: all)
+    #[arg(long)]
+    tasks: Option<usize>,
+
+    /// Parallel sequences used to score the choices of a task
+    #[arg(long, default_value_t = 4)]
+    parallel: u32,
+}
+
+impl Args {
+    fn task_kind(&self) -> Option<TaskKind> {
+        if self.hellaswag {
+            Some(TaskKind::Hellaswag)
+        } else if self.winogrande {
+            Some(TaskKind::Winogrande)
+        } else if self.multiple_choice {
+            Some(TaskKind::MultipleChoice)
+        } else {
+            None
+        }
+    }
 }
 
 /// Compute log-softmax for a single token given logits over the full vocabulary.
@@ -59,12 +134,19 @@ fn log_softmax(logits: &[f32], token: i32) -> (f64, f32) {
     (log_prob, prob)
 }
 
+#[allow(clippy::too_many_lines)]
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    // Read the input text
-    let text = std::fs::read_to_string(&args.file)
-        .with_context(|| format!("failed to read: {}", args.file.display()))?;
+    // In KL-divergence mode the tokens and context size come from the base run
+    let mut base = match (&args.kl_divergence_base, args.kl_divergence) {
+        (Some(path), true) => {
+            let file =
+                File::open(path).with_context(|| format!("failed to open: {}", path.display()))?;
+            Some(LogitsReader::new(file).with_context(|| "failed to read logits header")?)
+        }
+        _ => None,
+    };
 
     // Load model
     let backend = LlamaBackend::init()?;
@@ -74,13 +156,38 @@ fn main() -> Result<()> {
 
     eprintln!("Model: {model}");
 
-    let n_ctx = args.ctx_size.unwrap_or(model.n_ctx_train());
-    let _n_vocab = model.n_vocab();
+    let n_ctx = match &base {
+        Some(base) => {
+            let header = base.header();
+            if args.ctx_size.is_some_and(|c| c != header.n_ctx) {
+                bail!(
+                    "the base logits were computed with context size {}",
+                    header.n_ctx
+                );
+            }
+            if header.n_vocab != model.n_vocab() as u32 {
+                bail!(
+                    "vocabulary mismatch: base has {} tokens, model has {}",
+                    header.n_vocab,
+                    model.n_vocab()
+                );
+            }
+            header.n_ctx
+        }
+        None => args.ctx_size.unwrap_or(model.n_ctx_train()),
+    };
 
     // Set batch size to context size so we can process each chunk in one decode call
-    let ctx_params = LlamaContextParams::default()
+    let mut ctx_params = LlamaContextParams::default()
         .with_n_ctx(NonZeroU32::new(n_ctx))
         .with_n_batch(n_ctx);
+    let task_kind = args.task_kind();
+    if task_kind.is_some() {
+        // Choices are scored side by side, sharing the context's cells
+        ctx_params = ctx_params
+            .with_n_seq_max(args.parallel.max(1))
+            .with_kv_unified(true);
+    }
     let mut ctx = model
         .new_context(&backend, ctx_params)
         .with_context(|| "failed to create context")?;
@@ -91,22 +198,46 @@ fn main() -> Result<()> {
         AddBos::Never
     };
 
-    // Tokenize the entire text
-    eprintln!("Tokenizing input...");
-    let tokens = model
-        .str_to_token(&text, add_bos)
-        .with_context(|| "tokenization failed")?;
+    if let Some(kind) = task_kind {
+        let path = args.file.as_ref().context("no input file")?;
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read: {}", path.display()))?;
+        let mut tasks = parse_tasks(kind, &text)
+            .with_context(|| format!("failed to parse tasks: {}", path.display()))?;
+        if let Some(n) = args.tasks {
+            tasks.truncate(n);
+        }
+        multiple_choice::run(&mut ctx, &model, kind, &tasks, add_bos)?;
+        return Ok(());
+    }
 
-    let n_ctx = n_ctx as i32;
+    let tokens = if let Some(base) = &base {
+        let header = base.header();
+        if header.tokens.len() < (header.n_chunk * header.n_ctx) as usize {
+            bail!("the logits file holds fewer tokens than its chunks need");
+        }
+        header.tokens.iter().map(|&t| LlamaToken(t)).collect()
+    } else {
+        // Read and tokenize the entire text
+        let path = args.file.as_ref().context("no input file")?;
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read: {}", path.display()))?;
+        eprintln!("Tokenizing input...");
+        let tokens = model
+            .str_to_token(&text, add_bos)
+            .with_context(|| "tokenization failed")?;
+        if tokens.len() < 2 * n_ctx as usize {
+            bail!(
+                "Need at least {} tokens for context size {}, but input has only {} tokens",
+                2 * n_ctx,
+                n_ctx,
+                tokens.len()
+            );
+        }
+        tokens
+    };
 
-    if (tokens.len() as i32) < 2 * n_ctx {
-        bail!(
-            "Need at least {} tokens for context size {}, but input has only {} tokens",
-            2 * n_ctx,
-            n_ctx,
-            tokens.len()
-        );
-    }
+    let n_ctx = n_ctx as i32;
 
     let n_chunk_max = tokens.len() as i32 / n_ctx;
     let n_chunk = if args.chunks < 0 {
@@ -129,6 +260,27 @@ fn main() -> Result<()> {
     let mut count = 0_i64;
     let mut nll = 0.0_f64; // negative log-likelihood accumulator
 
+    // A base run saves the logits of every scored position
+    let mut dump = match (&args.kl_divergence_base, &base) {
+        (Some(path), None) => {
+            let file = File::create(path)
+                .with_context(|| format!("failed to create: {}", path.display()))?;
+            let header = LogitsHeader {
+                n_ctx: n_ctx as u32,
+                n_vocab: model.n_vocab() as u32,
+                n_chunk: n_chunk as u32,
+                tokens: tokens[..(n_chunk * n_ctx) as usize]
+                    .iter()
+                    .map(|t| t.0)
+                    .collect(),
+            };
+            Some(LogitsWriter::new(file, &header)?)
+        }
+        _ => None,
+    };
+    let mut kld_stats = KldStats::default();
+    let mut base_row = Vec::new();
+
     let mut batch = LlamaBatch::new(n_ctx as usize, 1);
 
     for i in 0..n_chunk {
@@ -163,13 +315,27 @@ fn main() -> Result<()> {
             let (log_prob, _prob) = log_softmax(logits, target_token);
             nll -= log_prob;
             count += 1;
+
+            if let Some(dump) = &mut dump {
+                dump.write_row(logits)
+                    .with_context(|| "failed to write logits")?;
+            }
+            if let Some(base) = &mut base {
+                base.read_row(&mut base_row)
+                    .with_context(|| "failed to read base logits")?;
+                kld_stats.add(&base_row, logits, target_token as usize);
+            }
         }
 
         let t_end = llama_cpp_4::ggml_time_us();
         let t_chunk = (t_end - t_start) as f64 / 1_000_000.0;
 
         let ppl = (nll / count as f64).exp();
-        eprint!("[{}]{:.4},", i + 1, ppl);
+        if base.is_some() {
+            eprint!("[{}]{:.4}/{:.6},", i + 1, ppl, kld_stats.mean_kld());
+        } else {
+            eprint!("[{}]{:.4},", i + 1, ppl);
+        }
         std::io::stderr().flush()?;
 
         // Print ETA after first chunk
@@ -200,5 +366,16 @@ fn main() -> Result<()> {
     println!();
     println!("Final perplexity: {ppl:.4} (avg NLL: {avg_nll:.6}, {count} tokens evaluated)");
 
+    if let Some(dump) = dump {
+        dump.finish().with_context(|| "failed to write logits")?;
+        if let Some(path) = &args.kl_divergence_base {
+            eprintln!("Saved base logits to {}", path.display());
+        }
+    }
+    if base.is_some() {
+        println!();
+        kld_stats.report(&mut std::io::stdout().lock())?;
+    }
+
     Ok(())
 }
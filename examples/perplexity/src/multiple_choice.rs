@@ -0,0 +1,403 @@
+//! Multiple-choice benchmarks scored by log-likelihood.
+//!
+//! Every task is a list of choices, each a context followed by a continuation,
+//! and exactly one choice is correct. All choices of a task are tokenized in
+//! full; the tokens they share are decoded once, and the rest of every choice
+//! is scored with [`LlamaContext::score_many`]. The choice with the highest
+//! mean log-probability per token wins, like llama.cpp's `--hellaswag`,
+//! `--winogrande` and `--multiple-choice` modes.
+//!
+//! ## Task files
+//!
+//! - HellaSwag: the plain-text format of llama.cpp (`hellaswag_val_full.txt`),
+//!   six lines per task: context, index of the correct ending, four endings.
+//! - Winogrande: the CSV with the columns `index,sentence,option1,option2,answer`.
+//!   The blank `_` in the sentence is filled with each option, and only the
+//!   text after the blank is scored.
+//! - Multiple choice: MMLU-style CSV, `question,choice,...,choice,answer`, with
+//!   the answer given as a letter (`A`, `B`, …) or a zero-based index. Each
+//!   choice is scored as the continuation of `question\nAnswer:`.
+
+use anyhow::{bail, Context, Result};
+use llama_cpp_4::prelude::*;
+
+/// The benchmark format of a task file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskKind {
+    /// HellaSwag sentence completion.
+    Hellaswag,
+    /// Winogrande pronoun resolution.
+    Winogrande,
+    /// MMLU-style question answering.
+    MultipleChoice,
+}
+
+impl TaskKind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Hellaswag => "HellaSwag",
+            Self::Winogrande => "Winogrande",
+            Self::MultipleChoice => "Multiple choice",
+        }
+    }
+}
+
+/// One choice: the text that sets it up and the text that is scored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Choice {
+    /// Text before the scored part.
+    pub context: String,
+    /// Text whose likelihood decides the choice.
+    pub continuation: String,
+}
+
+impl Choice {
+    fn new(context: impl Into<String>, continuation: impl Into<String>) -> Self {
+        Self {
+            context: context.into(),
+            continuation: continuation.into(),
+        }
+    }
+}
+
+/// A task: its choices and the index of the correct one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Task {
+    /// The candidate answers.
+    pub choices: Vec<Choice>,
+    /// Index of the correct choice.
+    pub answer: usize,
+}
+
+/// Parses a task file of the given kind.
+pub fn parse_tasks(kind: TaskKind, text: &str) -> Result<Vec<Task>> {
+    match kind {
+        TaskKind::Hellaswag => parse_hellaswag(text),
+        TaskKind::Winogrande => parse_winogrande(text),
+        TaskKind::MultipleChoice => parse_multiple_choice(text),
+    }
+}
+
+fn parse_hellaswag(text: &str) -> Result<Vec<Task>> {
+    let lines: Vec<&str> = text.lines().collect();
+    if lines.len() % 6 != 0 {
+        bail!("a HellaSwag file has six lines per task");
+    }
+    lines
+        .chunks_exact(6)
+        .enumerate()
+        .map(|(i, task)| {
+            let answer: usize = task[1]
+                .trim()
+                .parse()
+                .with_context(|| format!("task {i}: bad label {:?}", task[1]))?;
+            if answer >= 4 {
+                bail!("task {i}: label {answer} out of range");
+            }
+            let choices = task[2..]
+                .iter()
+                .map(|ending| Choice::new(task[0], format!(" {ending}")))
+                .collect();
+            Ok(Task { choices, answer })
+        })
+        .collect()
+}
+
+fn parse_winogrande(text: &str) -> Result<Vec<Task>> {
+    let mut tasks = Vec::new();
+    for (i, record) in csv_records(text).into_iter().enumerate() {
+        if i == 0 && record.first().is_some_and(|f| f == "index") {
+            continue;
+        }
+        let [_, sentence, option1, option2, answer] = record.as_slice() else {
+            bail!("record {i}: expected 5 fields, got {}", record.len());
+        };
+        let Some((before, after)) = sentence.split_once('_') else {
+            bail!("record {i}: the sentence has no blank");
+        };
+        let answer = match answer.trim() {
+            "1" => 0,
+            "2" => 1,
+            other => bail!("record {i}: bad answer {other:?}"),
+        };
+        let choices = [option1, option2]
+            .into_iter()
+            .map(|option| Choice::new(format!("{before}{option}"), after))
+            .collect();
+        tasks.push(Task { choices, answer });
+    }
+    Ok(tasks)
+}
+
+fn parse_multiple_choice(text: &str) -> Result<Vec<Task>> {
+    csv_records(text)
+        .into_iter()
+        .enumerate()
+        .map(|(i, record)| {
+            let [question, choices @ .., answer] = record.as_slice() else {
+                bail!("record {i} is empty");
+            };
+            if choices.len() < 2 {
+                bail!("record {i}: need at least two choices");
+            }
+            let answer = answer.trim();
+            let answer = match answer.as_bytes() {
+                [letter @ b'A'..=b'Z'] => usize::from(letter - b'A'),
+                _ => answer
+                    .parse()
+                    .with_context(|| format!("record {i}: bad answer {answer:?}"))?,
+            };
+            if answer >= choices.len() {
+                bail!("record {i}: answer {answer} out of range");
+            }
+            let context = format!("{question}\nAnswer:");
+            let choices = choices
+                .iter()
+                .map(|choice| Choice::new(context.clone(), format!(" {choice}")))
+                .collect();
+            Ok(Task { choices, answer })
+        })
+        .collect()
+}
+
+/// Splits CSV text into records, honouring quoted fields with `""` escapes
+/// and embedded newlines. Blank lines are skipped.
+fn csv_records(text: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                if record.iter().any(|f| !f.is_empty()) {
+                    records.push(std::mem::take(&mut record));
+                } else {
+                    record.clear();
+                }
+            }
+            '\r' if !quoted => {}
+            c => field.push(c),
+        }
+    }
+    record.push(field);
+    if record.iter().any(|f| !f.is_empty()) {
+        records.push(record);
+    }
+    records
+}
+
+/// Accuracy with its standard error and 95% confidence interval.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Accuracy {
+    /// Fraction of tasks answered correctly.
+    pub value: f64,
+    /// Standard error of `value`.
+    pub std_error: f64,
+}
+
+impl Accuracy {
+    /// Accuracy of `correct` out of `total` tasks.
+    pub fn new(correct: usize, total: usize) -> Self {
+        let n = total.max(1) as f64;
+        let value = correct as f64 / n;
+        let std_error = if total > 1 {
+            (value * (1.0 - value) / (n - 1.0)).sqrt()
+        } else {
+            0.0
+        };
+        Self { value, std_error }
+    }
+
+    /// The 95% confidence interval, clamped to `0..=1`.
+    pub fn ci95(self) -> (f64, f64) {
+        let half = 1.96 * self.std_error;
+        ((self.value - half).max(0.0), (self.value + half).min(1.0))
+    }
+}
+
+/// Scores every task and prints the running and final accuracy.
+pub fn run(
+    ctx: &mut LlamaContext<'_>,
+    model: &LlamaModel,
+    kind: TaskKind,
+    tasks: &[Task],
+    add_bos: AddBos,
+) -> Result<Accuracy> {
+    eprintln!("{}: evaluating {} tasks", kind.name(), tasks.len());
+    let mut correct = 0;
+    for (i, task) in tasks.iter().enumerate() {
+        let scores = score_task(ctx, model, task, add_bos)
+            .with_context(|| format!("failed to score task {i}"))?;
+        let best = (0..scores.len())
+            .max_by(|&a, &b| scores[a].total_cmp(&scores[b]))
+            .unwrap_or(0);
+        if best == task.answer {
+            correct += 1;
+        }
+        let acc = Accuracy::new(correct, i + 1);
+        println!(
+            "{}\t{:8.4}\t{:.4}\t{}",
+            i + 1,
+            100.0 * acc.value,
+            scores[task.answer],
+            if best == task.answer { "+" } else { "-" }
+        );
+    }
+
+    let acc = Accuracy::new(correct, tasks.len());
+    let (lo, hi) = acc.ci95();
+    println!();
+    println!(
+        "Final {} score ({} tasks): {:.4} ± {:.4} (95% CI {:.4} - {:.4})",
+        kind.name(),
+        tasks.len(),
+        100.0 * acc.value,
+        100.0 * acc.std_error,
+        100.0 * lo,
+        100.0 * hi
+    );
+    Ok(acc)
+}
+
+/// Mean log-probability per scored token of every choice.
+fn score_task(
+    ctx: &mut LlamaContext<'_>,
+    model: &LlamaModel,
+    task: &Task,
+    add_bos: AddBos,
+) -> Result<Vec<f64>> {
+    let mut full = Vec::with_capacity(task.choices.len());
+    let mut splits = Vec::with_capacity(task.choices.len());
+    for choice in &task.choices {
+        let context = model.str_to_token(&choice.context, add_bos)?;
+        let tokens = model.str_to_token(
+            &format!("{}{}", choice.context, choice.continuation),
+            add_bos,
+        )?;
+        // Tokens can merge across the boundary, so score from the first
+        // token that differs from the context alone.
+        splits.push(common_prefix(&context, &tokens));
+        full.push(tokens);
+    }
+
+    let mut scores = Vec::with_capacity(full.len());
+    if task
+        .choices
+        .iter()
+        .all(|c| c.context == task.choices[0].context)
+    {
+        // Shared context: decode the common tokens once for all choices.
+        let shared = full
+            .iter()
+            .map(|tokens| common_prefix(&full[0], tokens))
+            .chain(splits)
+            .min()
+            .unwrap_or(0);
+        let split = shared_split(shared, &full)?;
+        let continuations: Vec<&[LlamaToken]> = full.iter().map(|t| &t[split..]).collect();
+        for score in ctx.score_many(&full[0][..split], &continuations)? {
+            scores.push(f64::from(score.mean()));
+        }
+    } else {
+        for (tokens, split) in full.iter().zip(splits) {
+            let split = shared_split(split, std::slice::from_ref(tokens))?;
+            let score = ctx.score(&tokens[..split], &tokens[split..])?;
+            scores.push(f64::from(score.mean()));
+        }
+    }
+    Ok(scores)
+}
+
+/// Keeps at least one prefix token and one scored token per choice.
+fn shared_split(split: usize, full: &[Vec<LlamaToken>]) -> Result<usize> {
+    let shortest = full.iter().map(Vec::len).min().unwrap_or(0);
+    if shortest < 2 {
+        bail!("a choice tokenizes to fewer than two tokens");
+    }
+    Ok(split.clamp(1, shortest - 1))
+}
+
+fn common_prefix(a: &[LlamaToken], b: &[LlamaToken]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hellaswag() {
+        let text = "A man is sitting on a roof. he\n2\nstarts.\nruns.\nis using wrap.\nsits.\n";
+        let tasks = parse_tasks(TaskKind::Hellaswag, text).unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].answer, 2);
+        assert_eq!(
+            tasks[0].choices[2],
+            Choice::new("A man is sitting on a roof. he", " is using wrap.")
+        );
+        assert!(parse_tasks(TaskKind::Hellaswag, "ctx\n1\n").is_err());
+    }
+
+    #[test]
+    fn parses_winogrande() {
+        let text = "index,sentence,option1,option2,answer\n\
+                    0,\"Sarah was a much better surgeon than Maria so _ always got the harder cases.\",Sarah,Maria,1\n";
+        let tasks = parse_tasks(TaskKind::Winogrande, text).unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].answer, 0);
+        assert_eq!(
+            tasks[0].choices[1],
+            Choice::new(
+                "Sarah was a much better surgeon than Maria so Maria",
+                " always got the harder cases."
+            )
+        );
+    }
+
+    #[test]
+    fn parses_multiple_choice() {
+        let text = "\"What is 2+2, \"\"roughly\"\"?\",3,4,5,22,B\r\nPick one,x,y,0\n";
+        let tasks = parse_tasks(TaskKind::MultipleChoice, text).unwrap();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[0].answer, 1);
+        assert_eq!(tasks[0].choices.len(), 4);
+        assert_eq!(
+            tasks[0].choices[3],
+            Choice::new("What is 2+2, \"roughly\"?\nAnswer:", " 22")
+        );
+        assert_eq!(tasks[1].answer, 0);
+        assert!(parse_tasks(TaskKind::MultipleChoice, "q,a,b,C\n").is_err());
+    }
+
+    #[test]
+    fn csv_keeps_quoted_newlines_and_skips_blank_lines() {
+        let records = csv_records("a,\"b\nc\"\n\n d,e");
+        assert_eq!(
+            records,
+            vec![
+                vec!["a".to_owned(), "b\nc".to_owned()],
+                vec![" d".to_owned(), "e".to_owned()]
+            ]
+        );
+    }
+
+    #[test]
+    fn accuracy_interval() {
+        let acc = Accuracy::new(75, 100);
+        assert!((acc.value - 0.75).abs() < 1e-12);
+        assert!((acc.std_error - (0.75 * 0.25 / 99.0_f64).sqrt()).abs() < 1e-12);
+        let (lo, hi) = acc.ci95();
+        assert!(lo < 0.75 && hi > 0.75);
+        let (lo, hi) = Accuracy::new(1, 1).ci95();
+        assert!(lo > 0.999 && hi > 0.999);
+    }
+}
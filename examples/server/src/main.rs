@@ -44,6 +44,7 @@
     clippy::case_sensitive_file_extension_comparisons
 )]
 
+mod prompt_cache;
 mod tools;
 
 use actix_multipart::Multipart;
@@ -53,6 +54,8 @@ use clap::Parser;
 use futures_util::{stream, StreamExt as _};
 use hf_hub::{split_id, HFClientSync};
 use llama_cpp_4::prelude::*;
+use llama_cpp_4::token_type::LlamaTokenAttr;
+use prompt_cache::PromptCacheWorker;
 use serde_json::{json, Value};
 use std::{
     collections::HashMap,
@@ -99,6 +102,17 @@ struct Args {
     #[arg(long, default_value_t = 1)]
     parallel: usize,
 
+    /// KV cells reserved for a prefix prompt cache (0 = disabled).
+    /// Text requests then share one long-lived context, reuse the longest
+    /// cached prefix of their prompt (e.g. a system prompt), and only decode
+    /// the rest. Requests on the shared context run one at a time.
+    #[arg(long, default_value_t = 0)]
+    prompt_cache_cells: u32,
+
+    /// Maximum number of prompts kept by the prompt cache.
+    #[arg(long, default_value_t = 8)]
+    prompt_cache_slots: u32,
+
     /// Resolve (and download) the model, print its absolute local path to
     /// stdout, then exit without starting the server.
     /// Useful for scripts that need the cache path before launching.
@@ -515,12 +529,19 @@ struct AppState {
     backend: LlamaBackend,
     model: LlamaModel,
     chat_template: Option<String>,
+    /// How the model writes tool calls, detected from its chat template.
+    tool_format: ToolCallFormat,
+    /// Tags around the model's reasoning, detected from its chat template.
+    reasoning: ReasoningConfig,
     model_name: String,
     default_ctx_size: Option<NonZeroU32>,
     /// Limits the number of concurrent inference calls.
     inference_semaphore: Arc<Semaphore>,
     /// Optional bearer token that every request must present.
     api_key: Option<String>,
+    /// Shared context with a prefix prompt cache — `Some` when
+    /// `--prompt-cache-cells` is non-zero.
+    prompt_cache: Option<PromptCacheWorker>,
     /// In-memory store for files uploaded via `POST /v1/files`.
     file_store: Arc<RwLock<HashMap<String, FileEntry>>>,
     /// Multimodal context — `Some` when `--mmproj` is provided.
@@ -616,6 +637,20 @@ fn parse_max_tokens(req: &Value) -> Result<u32, HttpError> {
     }
 }
 
+fn parse_thinking_budget(req: &Value) -> Result<Option<usize>, HttpError> {
+    match req.get("thinking_budget") {
+        None | Some(Value::Null) => Ok(None),
+        Some(v) => {
+            let n = v
+                .as_u64()
+                .ok_or_else(|| bad_request("'thinking_budget' must be a non-negative integer"))?;
+            usize::try_from(n)
+                .map(Some)
+                .map_err(|_| bad_request("'thinking_budget' is too large"))
+        }
+    }
+}
+
 fn parse_stop_sequences(req: &Value) -> Result<Vec<String>, HttpError> {
     match req.get("stop") {
         None | Some(Value::Null) => Ok(Vec::new()),
@@ -649,6 +684,7 @@ fn to_chat_messages(pairs: Vec<(String, String)>) -> Result<Vec<LlamaChatMessage
 
 /// All sampling / generation parameters extracted from a request.
 #[allow(unused)]
+#[derive(Clone)]
 struct InferenceParams {
     prompt: String,
     temperature: f32,
@@ -659,6 +695,14 @@ struct InferenceParams {
     stop_seqs: Vec<String>,
     /// Optional GBNF grammar string.
     grammar: Option<String>,
+    /// Maximum tokens the model may spend reasoning before its closing tag
+    /// is forced.
+    thinking_budget: Option<usize>,
+    /// Reasoning tags the thinking budget applies to.
+    reasoning: ReasoningConfig,
+    /// The caller splits the reasoning off the output, so reasoning tags
+    /// that are control tokens are rendered into it.
+    split_reasoning: bool,
     /// Raw bytes for each media item (image or audio), in the order their
     /// markers appear in `prompt`.  Populated only when the `mtmd` feature is
     /// active and the request contains multimodal content.
@@ -690,6 +734,7 @@ impl InferenceParams {
             _ => return Err(bad_request("'grammar' must be a GBNF string")),
         };
         let stop_seqs = parse_stop_sequences(req)?;
+        let thinking_budget = parse_thinking_budget(req)?;
         Ok(InferenceParams {
             prompt,
             temperature,
@@ -699,7 +744,10 @@ impl InferenceParams {
             max_tokens,
             stop_seqs,
             grammar,
-            image_bytes: Vec::new(), // populated later by the multimodal path
+            thinking_budget,
+            reasoning: ReasoningConfig::new(), // set by the caller from the model
+            image_bytes: Vec::new(),           // populated later by the multimodal path
+            split_reasoning: false,
         })
     }
 }
@@ -943,7 +991,8 @@ where
     } else {
         chain.push(LlamaSampler::greedy());
     }
-    let sampler = LlamaSampler::chain_simple(chain);
+    let mut sampler = LlamaSampler::chain_simple(chain);
+    let mut budget = thinking_budget(&state.model, params)?;
 
     // ── Decode loop (identical structure to run_inference) ────────────────────
     let max_pos = n_past + params.max_tokens as i32;
@@ -965,15 +1014,24 @@ where
 
         // -1 means "sample from the last position with logits computed".
         // After eval_chunks this is always correct, matching the mtmd-cli.cpp pattern.
-        let token = sampler.sample(&ctx, -1);
+        // Once the thinking budget is spent, the closing tag is decoded instead.
+        let token = match budget.as_mut().and_then(ReasoningBudget::forced_token) {
+            Some(token) => {
+                sampler.accept(token);
+                token
+            }
+            None => sampler.sample(&ctx, -1),
+        };
         if state.model.is_eog_token(token) {
             break;
         }
+        if let Some(budget) = &mut budget {
+            budget
+                .push(token)
+                .map_err(|e| internal_error(format!("thinking budget: {e}")))?;
+        }
 
-        let bytes = state
-            .model
-            .token_to_bytes(token, Special::Plaintext)
-            .map_err(|e| internal_error(format!("token_to_bytes: {e}")))?;
+        let bytes = piece_bytes(&state.model, token, params)?;
         let mut piece = String::with_capacity(8);
         let _ = decoder.decode_to_string(&bytes, &mut piece, false);
         completion_tokens += 1;
@@ -1079,24 +1137,26 @@ where
 
     let n_prompt = tokens.len() as u32;
 
+    // ── Shared context with prompt cache (--prompt-cache-cells) ───────────────
+    if let Some(cache) = &state.prompt_cache {
+        if n_prompt + params.max_tokens <= cache.n_ctx() {
+            match cache.run(tokens.clone(), params, &mut on_piece) {
+                Some(result) => return result,
+                None => tracing::warn!("prompt cache worker unavailable; using a fresh context"),
+            }
+        }
+    }
+
     // When no explicit --ctx-size is set, default to the model's training
     // context but cap it at 4096.  n_ctx_train for modern models can be
     // 32 K–128 K tokens; allocating a full-size KV cache + compute buffer
     // for every request consumes tens of GB and reliably triggers OOM.
     // Users who need a larger window can set --ctx-size explicitly.
-    const DEFAULT_MAX_CTX: u32 = 4096;
-    let n_ctx = state
-        .default_ctx_size
-        .map_or_else(
-            || state.model.n_ctx_train().min(DEFAULT_MAX_CTX),
-            NonZeroU32::get,
-        )
-        .max(n_prompt + params.max_tokens);
+    let n_ctx = default_n_ctx(state).max(n_prompt + params.max_tokens);
 
     // n_batch controls the compute-buffer size inside llama.cpp.  Matching it
     // to n_ctx when n_ctx is large (e.g. 32 K) allocates a huge scratch
     // buffer even if the actual sequence is short.  Cap it independently.
-    const DEFAULT_MAX_BATCH: u32 = 2048;
     let n_batch = n_ctx.min(DEFAULT_MAX_BATCH);
 
     let ctx_params = LlamaContextParams::default()
@@ -1119,10 +1179,28 @@ where
     ctx.decode(&mut batch)
         .map_err(|e| internal_error(format!("prefill: {e}")))?;
 
-    // ── Sampler chain ─────────────────────────────────────────────────────────
+    let mut history = tokens;
+    generate_text(state, &mut ctx, &mut batch, params, &mut history, on_piece)
+}
+
+/// Default context size when `--ctx-size` is not given.
+const DEFAULT_MAX_CTX: u32 = 4096;
+/// Upper bound on `n_batch`, independent of the context size.
+const DEFAULT_MAX_BATCH: u32 = 2048;
+
+/// `--ctx-size`, or the model's training context capped at [`DEFAULT_MAX_CTX`].
+fn default_n_ctx(state: &AppState) -> u32 {
+    state.default_ctx_size.map_or_else(
+        || state.model.n_ctx_train().min(DEFAULT_MAX_CTX),
+        NonZeroU32::get,
+    )
+}
+
+/// Sampler chain for the request's sampling parameters.
+fn build_sampler(model: &LlamaModel, params: &InferenceParams) -> LlamaSampler {
     let mut chain: Vec<LlamaSampler> = Vec::new();
     if let Some(gbnf) = &params.grammar {
-        chain.push(LlamaSampler::grammar(&state.model, gbnf, "root"));
+        chain.push(LlamaSampler::grammar(model, gbnf, "root"));
     }
     if params.temperature > 0.0 {
         if params.top_k > 0 {
@@ -1136,10 +1214,72 @@ where
     } else {
         chain.push(LlamaSampler::greedy());
     }
-    let sampler = LlamaSampler::chain_simple(chain);
+    LlamaSampler::chain_simple(chain)
+}
+
+/// Token budget for the request's reasoning, if it set `thinking_budget`.
+fn thinking_budget<'m>(
+    model: &'m LlamaModel,
+    params: &InferenceParams,
+) -> Result<Option<ReasoningBudget<'m>>, HttpError> {
+    params
+        .thinking_budget
+        .map(|max_tokens| ReasoningBudget::new(model, params.reasoning.clone(), max_tokens))
+        .transpose()
+        .map_err(|e| internal_error(format!("thinking budget: {e}")))
+}
+
+/// Text of a generated token, as passed to `on_piece`.
+///
+/// Control tokens render as nothing, except for reasoning tags such as
+/// `<|START_THINKING|>` when the caller splits the reasoning off.
+fn piece_bytes(
+    model: &LlamaModel,
+    token: LlamaToken,
+    params: &InferenceParams,
+) -> Result<Vec<u8>, HttpError> {
+    let control = model.token_attr(token).contains(LlamaTokenAttr::Control);
+    let special = if control {
+        Special::Tokenize
+    } else {
+        Special::Plaintext
+    };
+    let bytes = model
+        .token_to_bytes(token, special)
+        .map_err(|e| internal_error(format!("token_to_bytes: {e}")))?;
+    if !control {
+        return Ok(bytes);
+    }
+    let is_tag = params.split_reasoning
+        && params
+            .reasoning
+            .tags()
+            .iter()
+            .any(|(open, close)| bytes == open.as_bytes() || bytes == close.as_bytes());
+    Ok(if is_tag { bytes } else { Vec::new() })
+}
+
+/// Decode loop on sequence 0 after the prompt has been prefilled.
+///
+/// `batch` must hold the last prefill chunk, whose final token requested
+/// logits. `history` holds every token decoded into the sequence so far; each
+/// generated token is appended once it has been decoded.
+fn generate_text<F>(
+    state: &AppState,
+    ctx: &mut LlamaContext,
+    batch: &mut LlamaBatch,
+    params: &InferenceParams,
+    history: &mut Vec<LlamaToken>,
+    mut on_piece: F,
+) -> Result<(u32, FinishReason), HttpError>
+where
+    F: FnMut(&str) -> bool,
+{
+    let mut sampler = build_sampler(&state.model, params);
+    let mut budget = thinking_budget(&state.model, params)?;
 
     // ── Decode loop ───────────────────────────────────────────────────────────
-    let mut n_cur = batch.n_tokens();
+    let mut n_cur = history.len() as i32;
     let max_pos = n_cur + params.max_tokens as i32;
     let mut completion_tokens: u32 = 0;
     let mut decoder = encoding_rs::UTF_8.new_decoder();
@@ -1164,15 +1304,25 @@ where
             break;
         }
 
-        let token = sampler.sample(&ctx, batch.n_tokens() - 1);
+        // Once the thinking budget is spent, the closing tag is decoded in
+        // place of sampled tokens.
+        let token = match budget.as_mut().and_then(ReasoningBudget::forced_token) {
+            Some(token) => {
+                sampler.accept(token);
+                token
+            }
+            None => sampler.sample(ctx, batch.n_tokens() - 1),
+        };
         if state.model.is_eog_token(token) {
             break;
         }
+        if let Some(budget) = &mut budget {
+            budget
+                .push(token)
+                .map_err(|e| internal_error(format!("thinking budget: {e}")))?;
+        }
 
-        let bytes = state
-            .model
-            .token_to_bytes(token, Special::Plaintext)
-            .map_err(|e| internal_error(format!("token_to_bytes: {e}")))?;
+        let bytes = piece_bytes(&state.model, token, params)?;
         let mut piece = String::with_capacity(8);
         let _ = decoder.decode_to_string(&bytes, &mut piece, false);
         completion_tokens += 1;
@@ -1218,8 +1368,9 @@ where
             .add(token, n_cur, &[0], true)
             .map_err(|e| internal_error(format!("batch add: {e}")))?;
         n_cur += 1;
-        ctx.decode(&mut batch)
+        ctx.decode(batch)
             .map_err(|e| internal_error(format!("decode: {e}")))?;
+        history.push(token);
     }
 
     // Flush whatever remains in the window when the loop ended without
@@ -1363,6 +1514,8 @@ async fn chat_completions(
     };
 
     // ── Build prompt from messages ───────────────────────────────────────────
+    let mut tool_format = state.tool_format;
+    let mut reasoning = state.reasoning.clone();
     let prompt = {
         let mut msg_pairs = base_msg_pairs;
 
@@ -1379,6 +1532,10 @@ async fn chat_completions(
             Some(Value::Null) | None => None,
             _ => return error_response(bad_request("'chat_template' must be a string")),
         };
+        if let Some(template) = &template_override {
+            tool_format = ToolCallFormat::detect(template);
+            reasoning = ReasoningConfig::detect(template);
+        }
         let template = template_override.or_else(|| state.chat_template.clone());
         match state
             .model
@@ -1389,11 +1546,16 @@ async fn chat_completions(
         }
     };
 
+    // A template that opens the thinking block leaves the reply inside it.
+    let reasoning = reasoning.with_prompt(&prompt);
+
     // ── Sampling params ───────────────────────────────────────────────────────
     let mut params = match InferenceParams::from_request(&parsed, prompt) {
         Ok(p) => p,
         Err(e) => return error_response(e),
     };
+    params.reasoning = reasoning.clone();
+    params.split_reasoning = true;
 
     // ── Resolve image sources → raw bytes (mtmd path only) ───────────────────
     #[cfg(feature = "mtmd")]
@@ -1429,14 +1591,32 @@ async fn chat_completions(
         .and_then(Value::as_str)
         .unwrap_or(&state.model_name)
         .to_owned();
-    let has_tools = !tool_defs.is_empty();
+    let tool_format = (!tool_defs.is_empty()).then_some(tool_format);
     let created = now_secs();
     let id = format!("chatcmpl-{created}");
 
     if streaming {
-        run_chat_stream(state, params, id, model_name, created, has_tools).await
+        run_chat_stream(
+            state,
+            params,
+            id,
+            model_name,
+            created,
+            tool_format,
+            reasoning,
+        )
+        .await
     } else {
-        run_chat_blocking(state, params, id, model_name, created, has_tools).await
+        run_chat_blocking(
+            state,
+            params,
+            id,
+            model_name,
+            created,
+            tool_format,
+            reasoning,
+        )
+        .await
     }
 }
 
@@ -1446,7 +1626,8 @@ async fn run_chat_blocking(
     id: String,
     model_name: String,
     created: u64,
-    has_tools: bool,
+    tool_format: Option<ToolCallFormat>,
+    reasoning: ReasoningConfig,
 ) -> HttpResponse {
     let permit = state.inference_semaphore.clone().acquire_owned().await;
     let state2 = state.clone();
@@ -1465,14 +1646,17 @@ async fn run_chat_blocking(
         Ok(Ok((raw_output, completion_tokens, finish_reason))) => {
             let prompt_tokens = 0u32; // cheap approximation; full count needs a 2nd tokenise pass
 
-            // Parse tool calls out of the raw output.
-            let (content, tool_calls) = if has_tools {
-                extract_tool_calls(&raw_output)
-            } else {
-                (raw_output, vec![])
+            // Separate the reasoning, then parse tool calls out of the answer.
+            let ReasoningText {
+                reasoning: reasoning_content,
+                content,
+            } = reasoning.split(&raw_output);
+            let (content, tool_calls) = match tool_format {
+                Some(format) => extract_tool_calls(&content, format),
+                None => (content, vec![]),
             };
 
-            let (final_finish, message) = if tool_calls.is_empty() {
+            let (final_finish, mut message) = if tool_calls.is_empty() {
                 (
                     finish_reason.as_str(),
                     json!({ "role": "assistant", "content": content }),
@@ -1489,6 +1673,9 @@ async fn run_chat_blocking(
                     }),
                 )
             };
+            if !reasoning_content.is_empty() {
+                message["reasoning_content"] = Value::String(reasoning_content);
+            }
 
             HttpResponse::Ok().content_type("application/json").body(
                 json!({
@@ -1511,13 +1698,26 @@ async fn run_chat_blocking(
     }
 }
 
+/// Chunk delta with the non-empty parts of `text`.
+fn reasoning_delta(text: ReasoningText) -> Value {
+    let mut delta = json!({});
+    if !text.reasoning.is_empty() {
+        delta["reasoning_content"] = Value::String(text.reasoning);
+    }
+    if !text.content.is_empty() {
+        delta["content"] = Value::String(text.content);
+    }
+    delta
+}
+
 async fn run_chat_stream(
     state: web::Data<AppState>,
     params: InferenceParams,
     id: String,
     model_name: String,
     created: u64,
-    has_tools: bool,
+    tool_format: Option<ToolCallFormat>,
+    reasoning: ReasoningConfig,
 ) -> HttpResponse {
     let (tx, rx) = mpsc::channel::<web::Bytes>(32);
     let id2 = id.clone();
@@ -1539,7 +1739,7 @@ async fn run_chat_stream(
         // tool calls before streaming; otherwise stream token-by-token.
         let mut finish_reason = FinishReason::Stop;
 
-        if has_tools {
+        if let Some(format) = tool_format {
             // Buffered mode: collect, parse, then emit.
             let mut raw = String::new();
             if let Ok((_, fr)) = run_inference(&state2, &params, |piece| {
@@ -1549,7 +1749,15 @@ async fn run_chat_stream(
                 finish_reason = fr;
             }
 
-            let (content, tool_calls) = extract_tool_calls(&raw);
+            let split = reasoning.split(&raw);
+            if !split.reasoning.is_empty() {
+                let delta = json!({ "reasoning_content": split.reasoning });
+                let _ = tx.blocking_send(sse_chunk(&json!({
+                    "id": id2, "object": OBJ, "created": created, "model": model2,
+                    "choices": [{"index":0,"delta":delta,"finish_reason":null}]
+                })));
+            }
+            let (content, tool_calls) = extract_tool_calls(&split.content, format);
 
             if tool_calls.is_empty() {
                 // No tool calls — stream content as a single delta.
@@ -1580,16 +1788,23 @@ async fn run_chat_stream(
                 })));
             }
         } else {
-            // Pure streaming: emit each token piece immediately.
-            if let Ok((_, fr)) = run_inference(&state2, &params, |piece| {
-                tx.blocking_send(sse_chunk(&json!({
-                    "id": id2, "object": OBJ, "created": created, "model": model2,
-                    "choices": [{"index":0,"delta":{"content":piece},"finish_reason":null}]
-                })))
-                .is_ok()
-            }) {
+            // Pure streaming: emit each token piece immediately, with the
+            // reasoning as `reasoning_content`.
+            let mut splitter = ReasoningSplitter::new(reasoning);
+            let send = |text: ReasoningText| {
+                text.is_empty()
+                    || tx
+                        .blocking_send(sse_chunk(&json!({
+                            "id": id2, "object": OBJ, "created": created, "model": model2,
+                            "choices": [{"index":0,"delta":reasoning_delta(text),"finish_reason":null}]
+                        })))
+                        .is_ok()
+            };
+            if let Ok((_, fr)) = run_inference(&state2, &params, |piece| send(splitter.push(piece)))
+            {
                 finish_reason = fr;
             }
+            send(splitter.finish());
             let _ = tx.blocking_send(sse_chunk(&json!({
                 "id": id2, "object": OBJ, "created": created, "model": model2,
                 "choices": [{"index":0,"delta":{},"finish_reason":finish_reason.as_str()}]
@@ -1649,10 +1864,11 @@ async fn completions(
         .get("stream")
         .and_then(Value::as_bool)
         .unwrap_or(false);
-    let params = match InferenceParams::from_request(&parsed, prompt) {
+    let mut params = match InferenceParams::from_request(&parsed, prompt) {
         Ok(p) => p,
         Err(e) => return error_response(e),
     };
+    params.reasoning = state.reasoning.clone().with_prompt(&params.prompt);
 
     let model_name = parsed
         .get("model")
@@ -2318,6 +2534,9 @@ async fn main() -> std::io::Result<()> {
     } else {
         tracing::warn!("No built-in chat template — supply 'chat_template' per request");
     }
+    let tool_format = ToolCallFormat::for_model(&model);
+    tracing::info!("Tool call format: {tool_format:?}");
+    let reasoning = ReasoningConfig::for_model(&model);
 
     let parallel = args.parallel.max(1);
     if args.api_key.is_some() {
@@ -2398,19 +2617,47 @@ async fn main() -> std::io::Result<()> {
         );
     }
 
+    // ── Prompt cache (optional) ───────────────────────────────────────────────
+    let (prompt_cache, prompt_cache_jobs) = if args.prompt_cache_cells > 0 {
+        let n_ctx = args
+            .ctx_size
+            .map_or_else(|| model.n_ctx_train().min(DEFAULT_MAX_CTX), NonZeroU32::get);
+        let (worker, jobs) = PromptCacheWorker::new(n_ctx);
+        (Some(worker), Some(jobs))
+    } else {
+        (None, None)
+    };
+
     let state = web::Data::new(AppState {
         backend,
         model,
         chat_template,
+        tool_format,
+        reasoning,
         model_name,
         default_ctx_size: args.ctx_size,
         inference_semaphore: Arc::new(Semaphore::new(parallel)),
         api_key: args.api_key,
+        prompt_cache,
         file_store: Arc::new(RwLock::new(HashMap::new())),
         #[cfg(feature = "mtmd")]
         mtmd_ctx,
     });
 
+    if let Some(jobs) = prompt_cache_jobs {
+        tracing::info!(
+            "Prompt cache enabled ({} cells, {} slots)",
+            args.prompt_cache_cells,
+            args.prompt_cache_slots
+        );
+        prompt_cache::spawn(
+            state.clone().into_inner(),
+            jobs,
+            args.prompt_cache_cells,
+            args.prompt_cache_slots.max(1),
+        )?;
+    }
+
     let addr = format!("{}:{}", args.host, args.port);
     tracing::info!("Listening on http://{addr}  (parallel={parallel})");
     tracing::info!("Endpoints:");
@@ -2535,4 +2782,25 @@ mod tests {
         // Q4_K_M should have the lowest (best) score
         assert!(scores[0].1.contains("Q4_K_M"), "got {scores:?}");
     }
+
+    // ── parse_thinking_budget ────────────────────────────────────────────────
+
+    #[test]
+    fn thinking_budget_is_optional_count() {
+        assert_eq!(parse_thinking_budget(&json!({})).unwrap(), None);
+        assert_eq!(
+            parse_thinking_budget(&json!({"thinking_budget": null})).unwrap(),
+            None
+        );
+        assert_eq!(
+            parse_thinking_budget(&json!({"thinking_budget": 0})).unwrap(),
+            Some(0)
+        );
+        assert_eq!(
+            parse_thinking_budget(&json!({"thinking_budget": 256})).unwrap(),
+            Some(256)
+        );
+        assert!(parse_thinking_budget(&json!({"thinking_budget": -1})).is_err());
+        assert!(parse_thinking_budget(&json!({"thinking_budget": "lots"})).is_err());
+    }
 }
@@ -0,0 +1,172 @@
+//! Shared context with a prefix prompt cache (`--prompt-cache-cells`).
+//!
+//! `LlamaContext` cannot move between threads, so the shared context lives on
+//! a dedicated worker thread for the lifetime of the server. Request handlers
+//! send it a job and receive the generated text back over a channel; when the
+//! handler drops that channel (e.g. a streaming client disconnected), the job
+//! stops at the next token.
+//!
+//! Sequence 0 is the working sequence and sequences `1..=slots` hold the
+//! prompts kept by [`PromptCache`]. The KV cache is unified, so a restored
+//! prefix shares its cells with the cached prompt instead of copying them.
+
+use std::num::NonZeroU32;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+
+use llama_cpp_4::prelude::*;
+
+use crate::{
+    generate_text, internal_error, AppState, FinishReason, HttpError, InferenceParams,
+    DEFAULT_MAX_BATCH,
+};
+
+/// Handle to the worker thread, stored in [`AppState`].
+pub struct PromptCacheWorker {
+    jobs: Sender<Job>,
+    n_ctx: u32,
+}
+
+/// Receiving end of the job queue, handed to [`spawn`].
+pub struct Jobs(Receiver<Job>);
+
+struct Job {
+    tokens: Vec<LlamaToken>,
+    params: InferenceParams,
+    events: SyncSender<Event>,
+}
+
+enum Event {
+    Piece(String),
+    Done(Result<(u32, FinishReason), HttpError>),
+}
+
+impl PromptCacheWorker {
+    /// Creates the handle and its job queue. Requests of up to `n_ctx` tokens
+    /// (prompt plus `max_tokens`) run on the shared context.
+    pub fn new(n_ctx: u32) -> (Self, Jobs) {
+        let (jobs, rx) = mpsc::channel();
+        (Self { jobs, n_ctx }, Jobs(rx))
+    }
+
+    /// Largest prompt-plus-completion length the shared context accepts.
+    pub fn n_ctx(&self) -> u32 {
+        self.n_ctx
+    }
+
+    /// Runs a request on the shared context, calling `on_piece` on this thread
+    /// for each text fragment. Returns `None` if the worker is not running, so
+    /// the caller can fall back to a fresh context.
+    pub fn run<F>(
+        &self,
+        tokens: Vec<LlamaToken>,
+        params: &InferenceParams,
+        mut on_piece: F,
+    ) -> Option<Result<(u32, FinishReason), HttpError>>
+    where
+        F: FnMut(&str) -> bool,
+    {
+        let (events, rx) = mpsc::sync_channel(32);
+        let job = Job {
+            tokens,
+            params: params.clone(),
+            events,
+        };
+        self.jobs.send(job).ok()?;
+        for event in rx {
+            match event {
+                Event::Piece(piece) => {
+                    if !on_piece(&piece) {
+                        // Dropping `rx` stops the job; the caller is gone and
+                        // does not read the counts.
+                        return Some(Ok((0, FinishReason::Stop)));
+                    }
+                }
+                Event::Done(result) => return Some(result),
+            }
+        }
+        Some(Err(internal_error("prompt cache worker stopped")))
+    }
+}
+
+/// Starts the worker thread. It keeps `state` alive for the rest of the
+/// process.
+pub fn spawn(state: Arc<AppState>, jobs: Jobs, cells: u32, slots: u32) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .name("prompt-cache".to_owned())
+        .spawn(move || serve(&state, jobs.0, cells, slots))
+        .map(drop)
+}
+
+fn serve(state: &AppState, jobs: Receiver<Job>, cells: u32, slots: u32) {
+    let Some(n_ctx) = state.prompt_cache.as_ref().map(PromptCacheWorker::n_ctx) else {
+        return;
+    };
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(n_ctx + cells))
+        .with_n_batch(n_ctx.min(DEFAULT_MAX_BATCH))
+        .with_n_seq_max(slots + 1)
+        .with_kv_unified(true);
+    let mut ctx = match state.model.new_context(&state.backend, ctx_params) {
+        Ok(ctx) => ctx,
+        Err(e) => {
+            // Returning drops `jobs`, so requests fall back to fresh contexts.
+            tracing::error!("prompt cache disabled, context init failed: {e}");
+            return;
+        }
+    };
+    let mut cache = PromptCache::new(1..=slots as i32, cells as usize);
+
+    for job in jobs {
+        let result = run_job(state, &mut ctx, &mut cache, &job);
+        let _ = job.events.send(Event::Done(result));
+    }
+}
+
+fn run_job(
+    state: &AppState,
+    ctx: &mut LlamaContext,
+    cache: &mut PromptCache,
+    job: &Job,
+) -> Result<(u32, FinishReason), HttpError> {
+    let tokens = &job.tokens;
+    let reused = cache
+        .restore(ctx, tokens, 0)
+        .map_err(|e| internal_error(format!("prompt cache: {e}")))?;
+    tracing::debug!("prompt cache: reused {reused} of {} tokens", tokens.len());
+
+    // ── Prefill the uncached suffix in n_batch chunks ─────────────────────────
+    let suffix = &tokens[reused..];
+    let n_batch = (ctx.n_batch() as usize).max(1);
+    let mut batch = LlamaBatch::new(n_batch, 1);
+    for (chunk_index, chunk) in suffix.chunks(n_batch).enumerate() {
+        batch.clear();
+        for (offset, &token) in chunk.iter().enumerate() {
+            let i = chunk_index * n_batch + offset;
+            batch
+                .add(token, (reused + i) as i32, &[0], i + 1 == suffix.len())
+                .map_err(|e| internal_error(format!("batch add: {e}")))?;
+        }
+        ctx.decode(&mut batch)
+            .map_err(|e| internal_error(format!("prefill: {e}")))?;
+    }
+
+    // Cache the prompt before generating so that a cancelled request still
+    // warms the cache.
+    cache
+        .store(ctx, tokens, 0)
+        .map_err(|e| internal_error(format!("prompt cache: {e}")))?;
+
+    let mut history = tokens.clone();
+    let result = generate_text(state, ctx, &mut batch, &job.params, &mut history, |piece| {
+        job.events.send(Event::Piece(piece.to_owned())).is_ok()
+    });
+
+    // Keep the completion as well: the next chat turn usually repeats it.
+    if history.len() > tokens.len() {
+        if let Err(e) = cache.store(ctx, &history, 0) {
+            tracing::warn!("prompt cache: {e}");
+        }
+    }
+    result
+}
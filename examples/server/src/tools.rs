@@ -10,7 +10,8 @@
 //!    ```text
 //!    <tool_call>{"name": "fn", "arguments": {...}}</tool_call>
 //!    ```
-//! 4. After generation, the output is scanned for `<tool_call>` blocks.
+//! 4. After generation, the output is scanned for tool calls in the model's own
+//!    format (see [`ToolCallFormat`]), falling back to `<tool_call>` blocks.
 //! 5. If any are found the response gets `finish_reason: "tool_calls"` and a
 //!    `tool_calls` array; otherwise it's a regular assistant message.
 //!
@@ -23,6 +24,7 @@
 //! `role: "tool"` messages are passed through to `apply_chat_template` as-is
 //! (llama.cpp Jinja templates for Llama-3.1, Qwen2.5 etc. handle them natively).
 
+use llama_cpp_4::tools::ToolCallFormat;
 use serde_json::{json, Value};
 use std::fmt::Write as _;
 
@@ -318,66 +320,44 @@ pub fn normalise_messages(req: &Value) -> Result<Vec<(String, String)>, HttpErro
 // Output parsing
 // ---------------------------------------------------------------------------
 
-/// Extract all `<tool_call>...</tool_call>` blocks from `text`.
-/// Returns the tool calls and the text that appeared before the first call
-/// (trimmed), which becomes the `content` field of the assistant message.
-pub fn extract_tool_calls(text: &str) -> (String, Vec<ToolCall>) {
-    let tag_open = "<tool_call>";
-    let tag_close = "</tool_call>";
-
-    let mut calls = Vec::new();
-    let mut pre_text = String::new();
-    let mut found_first = false;
-    let mut remaining = text;
-
-    while let Some(start) = remaining.find(tag_open) {
-        if !found_first {
-            remaining[..start].trim().clone_into(&mut pre_text);
-            found_first = true;
-        }
-        let after_open = &remaining[start + tag_open.len()..];
-        let Some(end) = after_open.find(tag_close) else {
-            // Unclosed tag — try to parse what we have as JSON anyway.
-            let json_candidate = after_open.trim();
-            if let Some(tc) = parse_single_call(json_candidate) {
-                calls.push(tc);
-            }
-            break;
-        };
-        let json_candidate = after_open[..end].trim();
-        if let Some(tc) = parse_single_call(json_candidate) {
-            calls.push(tc);
+/// Extract the tool calls from `text`, written in the model's `format`.
+/// Returns the text outside the calls (trimmed), which becomes the `content`
+/// field of the assistant message, and the calls.
+///
+/// The injected system prompt asks for `<tool_call>` blocks, so Hermes is
+/// tried as well when the model's own format finds nothing.
+pub fn extract_tool_calls(text: &str, format: ToolCallFormat) -> (String, Vec<ToolCall>) {
+    let mut parsed = format.parse(text);
+    if parsed.tool_calls.is_empty() && format != ToolCallFormat::Hermes {
+        let hermes = ToolCallFormat::Hermes.parse(text);
+        if !hermes.tool_calls.is_empty() {
+            parsed = hermes;
         }
-        remaining = &after_open[end + tag_close.len()..];
     }
 
-    (pre_text, calls)
+    let calls = parsed
+        .tool_calls
+        .into_iter()
+        .map(|call| ToolCall {
+            id: call.id.unwrap_or_else(|| new_call_id(&call.name)),
+            call_type: "function",
+            name: call.name,
+            arguments: call.arguments,
+        })
+        .collect();
+    (parsed.content, calls)
 }
 
-fn parse_single_call(json: &str) -> Option<ToolCall> {
-    let v: Value = serde_json::from_str(json).ok()?;
-    let name = v.get("name")?.as_str()?.to_owned();
-    // `arguments` may already be a string (re-serialised) or an object.
-    let arguments = match v.get("arguments") {
-        Some(Value::String(s)) => s.clone(),
-        Some(obj) => serde_json::to_string(obj).unwrap_or_else(|_| "{}".to_owned()),
-        None => "{}".to_owned(),
-    };
-    // Generate a stable-ish short id.
-    let id = format!(
+/// Generate a stable-ish short id for a call the model did not name.
+fn new_call_id(name: &str) -> String {
+    format!(
         "call_{:x}",
         u64::from(
             std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .map_or(0, |d| d.subsec_nanos())
         ) ^ (name.len() as u64 * 0x9E37_79B9)
-    );
-    Some(ToolCall {
-        id,
-        call_type: "function",
-        name,
-        arguments,
-    })
+    )
 }
 
 // ---------------------------------------------------------------------------
@@ -534,7 +514,7 @@ mod tests {
     #[test]
     fn single_tool_call() {
         let out = r#"<tool_call>{"name":"get_weather","arguments":{"city":"Paris"}}</tool_call>"#;
-        let (pre, calls) = extract_tool_calls(out);
+        let (pre, calls) = extract_tool_calls(out, ToolCallFormat::Hermes);
         assert_eq!(pre, "");
         assert_eq!(calls.len(), 1);
         assert_eq!(calls[0].name, "get_weather");
@@ -545,7 +525,7 @@ mod tests {
     #[test]
     fn text_before_tool_call() {
         let out = "Sure, let me check!\n<tool_call>{\"name\":\"fn\",\"arguments\":{}}</tool_call>";
-        let (pre, calls) = extract_tool_calls(out);
+        let (pre, calls) = extract_tool_calls(out, ToolCallFormat::Hermes);
         assert_eq!(pre, "Sure, let me check!");
         assert_eq!(calls.len(), 1);
         assert_eq!(calls[0].name, "fn");
@@ -558,7 +538,7 @@ mod tests {
             "\n",
             r#"<tool_call>{"name":"b","arguments":{"y":2}}</tool_call>"#,
         );
-        let (_, calls) = extract_tool_calls(out);
+        let (_, calls) = extract_tool_calls(out, ToolCallFormat::Hermes);
         assert_eq!(calls.len(), 2);
         assert_eq!(calls[0].name, "a");
         assert_eq!(calls[1].name, "b");
@@ -567,17 +547,17 @@ mod tests {
     #[test]
     fn no_tool_calls_returns_full_text() {
         let out = "Hello! How can I help you today?";
-        let (pre, calls) = extract_tool_calls(out);
-        // pre is only set when at least one <tool_call> is found
+        let (pre, calls) = extract_tool_calls(out, ToolCallFormat::Hermes);
+        // Without calls the whole reply is the content.
         assert!(calls.is_empty());
-        assert_eq!(pre, "");
+        assert_eq!(pre, out);
     }
 
     #[test]
     fn arguments_as_string_passthrough() {
         // Some models emit arguments already as a JSON string (re-serialised).
         let out = r#"<tool_call>{"name":"fn","arguments":"{\"k\":\"v\"}"}</tool_call>"#;
-        let (_, calls) = extract_tool_calls(out);
+        let (_, calls) = extract_tool_calls(out, ToolCallFormat::Hermes);
         assert_eq!(calls.len(), 1);
         // arguments should be the raw value, not double-encoded
         assert!(calls[0].arguments.contains("k"));
@@ -587,14 +567,33 @@ mod tests {
     fn tool_call_id_is_unique() {
         let out1 = r#"<tool_call>{"name":"a","arguments":{}}</tool_call>"#;
         let out2 = r#"<tool_call>{"name":"b","arguments":{}}</tool_call>"#;
-        let (_, c1) = extract_tool_calls(out1);
+        let (_, c1) = extract_tool_calls(out1, ToolCallFormat::Hermes);
         std::thread::sleep(std::time::Duration::from_nanos(1));
-        let (_, c2) = extract_tool_calls(out2);
+        let (_, c2) = extract_tool_calls(out2, ToolCallFormat::Hermes);
         // IDs are derived from subsecond time + name hash; not guaranteed unique
         // in a nanosecond, but should differ across different names.
         let _ = (c1, c2); // just ensure no panic
     }
 
+    #[test]
+    fn model_format_tool_call() {
+        let out = r#"[TOOL_CALLS] [{"name":"get_weather","arguments":{"city":"Paris"},"id":"abcDEF123"}]"#;
+        let (pre, calls) = extract_tool_calls(out, ToolCallFormat::Mistral);
+        assert_eq!(pre, "");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "abcDEF123");
+        assert_eq!(calls[0].name, "get_weather");
+    }
+
+    #[test]
+    fn falls_back_to_hermes_tags() {
+        let out = r#"<tool_call>{"name":"fn","arguments":{}}</tool_call>"#;
+        let (_, calls) = extract_tool_calls(out, ToolCallFormat::Llama3);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "fn");
+        assert!(calls[0].id.starts_with("call_"));
+    }
+
     // ── parse_tools ──────────────────────────────────────────────────────────
 
     #[test]
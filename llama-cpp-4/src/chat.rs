@@ -0,0 +1,501 @@
+//! Multi-turn chat that keeps the conversation in the KV cache.
+//!
+//! [`ChatSession`] owns the message history of one sequence and the tokens
+//! decoded into it. Each [`ChatSession::respond`] renders the conversation with
+//! the model's chat template, tokenizes only the text added since the previous
+//! turn, decodes only the tokens that are not cached yet, and generates the
+//! reply with a [`Generator`].
+//!
+//! When the prompt plus the reply budget no longer fits the context, the
+//! oldest turns after the leading system messages are evicted. The cache is
+//! then reused up to the first evicted token.
+//!
+//! [`ChatSession::save`] and [`ChatSession::load`] persist the conversation
+//! together with the sequence state (`llama_state_seq_save_file`).
+//!
+//! Sessions assume one position per token, so they do not support contexts
+//! with [`LlamaContext::self_extend`] enabled.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use llama_cpp_4::chat::ChatSession;
+//! use llama_cpp_4::prelude::*;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, "model.gguf", &LlamaModelParams::default())?;
+//! let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//!
+//! let mut session = ChatSession::new(&mut ctx);
+//! session.push_message("system", "You are a helpful assistant.")?;
+//! for question in ["Hi!", "What did I just say?"] {
+//!     session.push_message("user", question)?;
+//!     let config = GeneratorConfig::new().with_max_tokens(Some(128));
+//!     let reply = session.respond(LlamaSampler::greedy(), config, |_| {})?;
+//!     println!("{}", reply.text);
+//! }
+//! session.save("chat.session")?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::generate::{FinishReason, GenerateError, GeneratedPiece, Generator, GeneratorConfig};
+use crate::model::{AddBos, LlamaChatMessage};
+use crate::sampling::LlamaSampler;
+use crate::token::LlamaToken;
+use crate::{ApplyChatTemplateError, NewLlamaChatMessageError, StringToTokenError};
+
+/// Tokens kept free for the reply when [`GeneratorConfig::max_tokens`] is
+/// unset.
+pub const DEFAULT_RESERVE: usize = 256;
+
+/// First line of the `.messages` file written by [`ChatSession::save`].
+const MESSAGES_HEADER: &[u8] = b"llama-cpp-4 chat session v1\n";
+
+/// Failure while running or persisting a [`ChatSession`].
+#[derive(Debug, thiserror::Error)]
+pub enum ChatSessionError {
+    /// [`ChatSession::respond`] was called without any message.
+    #[error("the conversation has no messages")]
+    EmptyConversation,
+    /// The chat template could not be applied.
+    #[error(transparent)]
+    Template(#[from] ApplyChatTemplateError),
+    /// The rendered prompt could not be tokenized.
+    #[error(transparent)]
+    Tokenize(#[from] StringToTokenError),
+    /// A role or content contained a null byte.
+    #[error(transparent)]
+    Message(#[from] NewLlamaChatMessageError),
+    /// Prefill or generation failed.
+    #[error(transparent)]
+    Generate(#[from] GenerateError),
+    /// The sequence id or a position did not fit the native types.
+    #[error(transparent)]
+    KvCache(#[from] KvCacheConversionError),
+    /// Reading or writing the message file failed.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// llama.cpp could not write the sequence state.
+    #[error("failed to save the sequence state to {0}")]
+    SaveFailed(PathBuf),
+    /// llama.cpp could not read the sequence state.
+    #[error("failed to load the sequence state from {0}")]
+    LoadFailed(PathBuf),
+    /// The message file is not one written by [`ChatSession::save`].
+    #[error("malformed chat session messages in {0}")]
+    Corrupt(PathBuf),
+}
+
+/// Result of [`ChatSession::respond`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChatReply {
+    /// The assistant's reply, also appended to the conversation.
+    pub text: String,
+    /// Why generation ended.
+    pub finish_reason: FinishReason,
+    /// Prompt tokens already in the KV cache.
+    pub n_reused: usize,
+    /// Prompt tokens decoded for this turn.
+    pub n_decoded: usize,
+    /// Messages evicted to make room.
+    pub n_evicted: usize,
+}
+
+/// A conversation whose decoded tokens stay in one KV sequence between turns.
+#[derive(Debug)]
+pub struct ChatSession<'c, 'm> {
+    ctx: &'c mut LlamaContext<'m>,
+    template: Option<String>,
+    seq_id: i32,
+    reserve: usize,
+    messages: Vec<LlamaChatMessage>,
+    /// Tokens decoded into the sequence, one per position.
+    tokens: Vec<LlamaToken>,
+    /// The last rendered prompt and its token count; its tokens are the first
+    /// tokens of `tokens`.
+    anchor: Option<(String, usize)>,
+}
+
+impl<'c, 'm> ChatSession<'c, 'm> {
+    /// Starts an empty conversation in sequence 0 of `ctx`, using the model's
+    /// own chat template. The sequence is cleared on the first turn.
+    pub fn new(ctx: &'c mut LlamaContext<'m>) -> Self {
+        Self {
+            ctx,
+            template: None,
+            seq_id: 0,
+            reserve: DEFAULT_RESERVE,
+            messages: Vec::new(),
+            tokens: Vec::new(),
+            anchor: None,
+        }
+    }
+
+    /// Chat template passed to [`LlamaModel::apply_chat_template`]
+    /// (`None` for the model's own).
+    ///
+    /// [`LlamaModel::apply_chat_template`]: crate::model::LlamaModel::apply_chat_template
+    #[must_use]
+    pub fn with_template(mut self, template: Option<String>) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// Sequence holding the conversation. Defaults to 0.
+    #[must_use]
+    pub fn with_seq_id(mut self, seq_id: i32) -> Self {
+        self.seq_id = seq_id;
+        self
+    }
+
+    /// Tokens kept free for the reply when the generator config has no
+    /// `max_tokens`. Defaults to [`DEFAULT_RESERVE`].
+    #[must_use]
+    pub fn with_reserve(mut self, reserve: usize) -> Self {
+        self.reserve = reserve;
+        self
+    }
+
+    /// The chat template override, if any.
+    #[must_use]
+    pub fn template(&self) -> Option<&str> {
+        self.template.as_deref()
+    }
+
+    /// Sequence holding the conversation.
+    #[must_use]
+    pub fn seq_id(&self) -> i32 {
+        self.seq_id
+    }
+
+    /// Tokens kept free for the reply.
+    #[must_use]
+    pub fn reserve(&self) -> usize {
+        self.reserve
+    }
+
+    /// The conversation, oldest message first.
+    #[must_use]
+    pub fn messages(&self) -> &[LlamaChatMessage] {
+        &self.messages
+    }
+
+    /// Tokens decoded into the sequence.
+    #[must_use]
+    pub fn tokens(&self) -> &[LlamaToken] {
+        &self.tokens
+    }
+
+    /// The context holding the conversation.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'m> {
+        self.ctx
+    }
+
+    /// Appends a message. It is rendered and decoded by the next
+    /// [`Self::respond`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ChatSessionError::Message`] if either string contains a null
+    /// byte.
+    pub fn push_message(
+        &mut self,
+        role: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Result<(), ChatSessionError> {
+        self.messages
+            .push(LlamaChatMessage::new(role.into(), content.into())?);
+        Ok(())
+    }
+
+    /// Generates the assistant's reply to the conversation and appends it.
+    ///
+    /// `on_piece` sees each generated piece as it is produced. The config's
+    /// sequence id and start position are set by the session, and context
+    /// shifting is disabled in favour of evicting whole turns.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error for an empty conversation, a template or tokenizer
+    /// failure, or a failed decode. No reply is appended; turns evicted
+    /// before the failure stay evicted, and the cache is repaired on the next
+    /// turn.
+    pub fn respond(
+        &mut self,
+        sampler: LlamaSampler,
+        config: GeneratorConfig,
+        mut on_piece: impl FnMut(&GeneratedPiece),
+    ) -> Result<ChatReply, ChatSessionError> {
+        if self.messages.is_empty() {
+            return Err(ChatSessionError::EmptyConversation);
+        }
+        let n_ctx = usize::try_from(self.ctx.n_ctx_seq()).unwrap_or(usize::MAX);
+        let budget = config.max_tokens().unwrap_or(self.reserve);
+        let mut n_evicted = 0;
+        let (prompt, tokens) = loop {
+            let (prompt, tokens) = self.render_prompt()?;
+            if tokens.len().saturating_add(budget) <= n_ctx {
+                break (prompt, tokens);
+            }
+            let Some(turn) = evictable_turn(&self.messages) else {
+                break (prompt, tokens);
+            };
+            n_evicted += turn.len();
+            self.messages.drain(turn);
+            self.anchor = None;
+        };
+
+        // Keep the cached prefix, but always decode at least one token so
+        // that the generator has logits to sample from.
+        let n_reused = common_prefix(&self.tokens, &tokens).min(tokens.len().saturating_sub(1));
+        self.truncate(n_reused)?;
+        let start = i32::try_from(n_reused).map_err(KvCacheConversionError::P0TooLarge)?;
+        let config = config
+            .with_seq_id(self.seq_id)
+            .with_start_pos(Some(start))
+            .with_context_shift(None);
+
+        let mut generator = Generator::new(self.ctx, sampler, &tokens[n_reused..], config)?;
+        self.tokens.clone_from(&tokens);
+        let mut text = String::new();
+        let mut finish_reason = FinishReason::MaxTokens;
+        for piece in generator.by_ref() {
+            let piece = piece?;
+            text.push_str(&piece.text);
+            on_piece(&piece);
+            if let Some(reason) = &piece.finish_reason {
+                finish_reason = reason.clone();
+            }
+        }
+        // The final token is sampled but never decoded.
+        let generated = generator.generated_tokens();
+        let n_kept = generated.len().saturating_sub(1);
+        self.tokens.extend_from_slice(&generated[..n_kept]);
+
+        self.messages
+            .push(LlamaChatMessage::new("assistant".to_owned(), text.clone())?);
+        self.anchor = Some((prompt, tokens.len()));
+        Ok(ChatReply {
+            text,
+            finish_reason,
+            n_reused,
+            n_decoded: tokens.len() - n_reused,
+            n_evicted,
+        })
+    }
+
+    /// Forgets the conversation and clears the sequence.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ChatSessionError::KvCache`] if the sequence id is negative.
+    pub fn clear(&mut self) -> Result<(), ChatSessionError> {
+        self.truncate(0)?;
+        self.messages.clear();
+        self.anchor = None;
+        Ok(())
+    }
+
+    /// Writes the sequence state and its tokens to `path` and the messages to
+    /// `<path>.messages`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ChatSessionError::SaveFailed`] if llama.cpp cannot write the
+    /// state and [`ChatSessionError::Io`] if the messages cannot be written.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `path` is not valid UTF-8 or contains a null byte.
+    pub fn save(&mut self, path: impl AsRef<Path>) -> Result<(), ChatSessionError> {
+        let path = path.as_ref();
+        if self
+            .ctx
+            .state_seq_save_file(path, self.seq_id, &self.tokens)
+            == 0
+        {
+            return Err(ChatSessionError::SaveFailed(path.to_path_buf()));
+        }
+        std::fs::write(messages_path(path), encode_messages(&self.messages))?;
+        Ok(())
+    }
+
+    /// Replaces the conversation with one written by [`Self::save`], loading
+    /// its state into this session's sequence.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ChatSessionError::Io`] or [`ChatSessionError::Corrupt`] if the
+    /// messages cannot be read, and [`ChatSessionError::LoadFailed`] if
+    /// llama.cpp rejects the state (e.g. it is larger than the context). The
+    /// session is empty after a failed state load.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `path` is not valid UTF-8 or contains a null byte.
+    pub fn load(&mut self, path: impl AsRef<Path>) -> Result<(), ChatSessionError> {
+        let path = path.as_ref();
+        let messages = decode_messages(&std::fs::read(messages_path(path))?)
+            .ok_or_else(|| ChatSessionError::Corrupt(path.to_path_buf()))?;
+        self.clear()?;
+        let capacity = usize::try_from(self.ctx.n_ctx_seq()).unwrap_or(usize::MAX);
+        let mut tokens = Vec::new();
+        if self
+            .ctx
+            .state_seq_load_file(path, self.seq_id, &mut tokens, capacity)
+            == 0
+        {
+            return Err(ChatSessionError::LoadFailed(path.to_path_buf()));
+        }
+        self.tokens = tokens;
+        self.messages = messages;
+        Ok(())
+    }
+
+    /// Renders the conversation with a generation prompt and tokenizes it,
+    /// reusing the tokens of the previous prompt when the new one extends it.
+    fn render_prompt(&self) -> Result<(String, Vec<LlamaToken>), ChatSessionError> {
+        let model = self.ctx.model;
+        let prompt = model.apply_chat_template(self.template.as_deref(), &self.messages, true)?;
+        if let Some((anchor, n_anchor)) = &self.anchor {
+            if let Some(delta) = prompt.strip_prefix(anchor.as_str()) {
+                if let Some(cached) = self.tokens.get(..*n_anchor) {
+                    let mut tokens = cached.to_vec();
+                    tokens.extend(model.str_to_token(delta, AddBos::Never)?);
+                    return Ok((prompt, tokens));
+                }
+            }
+        }
+        let tokens = model.str_to_token(&prompt, AddBos::Always)?;
+        Ok((prompt, tokens))
+    }
+
+    /// Drops every cached token from position `n` on.
+    fn truncate(&mut self, n: usize) -> Result<(), KvCacheConversionError> {
+        let seq = u32::try_from(self.seq_id).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+        let p0 = u32::try_from(n).map_err(KvCacheConversionError::P0TooLarge)?;
+        self.ctx.clear_kv_cache_seq(Some(seq), Some(p0), None)?;
+        self.tokens.truncate(n);
+        Ok(())
+    }
+}
+
+fn common_prefix(a: &[LlamaToken], b: &[LlamaToken]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// The oldest turn after the leading system messages: a user message and
+/// everything up to the next user message. `None` when only the current turn
+/// is left.
+fn evictable_turn(messages: &[LlamaChatMessage]) -> Option<Range<usize>> {
+    let start = messages
+        .iter()
+        .position(|message| message.role() != "system")?;
+    let len = messages[start + 1..]
+        .iter()
+        .position(|message| message.role() == "user")?;
+    Some(start..start + 1 + len)
+}
+
+fn messages_path(path: &Path) -> PathBuf {
+    let mut messages = path.as_os_str().to_owned();
+    messages.push(".messages");
+    PathBuf::from(messages)
+}
+
+/// Header line, then per message `<role bytes> <content bytes>\n`, the role,
+/// the content and a newline.
+fn encode_messages(messages: &[LlamaChatMessage]) -> Vec<u8> {
+    let mut out = MESSAGES_HEADER.to_vec();
+    for message in messages {
+        let (role, content) = (message.role(), message.content());
+        out.extend_from_slice(format!("{} {}\n", role.len(), content.len()).as_bytes());
+        out.extend_from_slice(role.as_bytes());
+        out.extend_from_slice(content.as_bytes());
+        out.push(b'\n');
+    }
+    out
+}
+
+fn decode_messages(bytes: &[u8]) -> Option<Vec<LlamaChatMessage>> {
+    let mut rest = bytes.strip_prefix(MESSAGES_HEADER)?;
+    let mut messages = Vec::new();
+    while !rest.is_empty() {
+        let eol = rest.iter().position(|&byte| byte == b'\n')?;
+        let (role_len, content_len) = std::str::from_utf8(&rest[..eol]).ok()?.split_once(' ')?;
+        let role_len: usize = role_len.parse().ok()?;
+        let end = role_len.checked_add(content_len.parse().ok()?)?;
+        rest = &rest[eol + 1..];
+        if rest.get(end) != Some(&b'\n') {
+            return None;
+        }
+        let role = String::from_utf8(rest[..role_len].to_vec()).ok()?;
+        let content = String::from_utf8(rest[role_len..end].to_vec()).ok()?;
+        messages.push(LlamaChatMessage::new(role, content).ok()?);
+        rest = &rest[end + 1..];
+    }
+    Some(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> LlamaChatMessage {
+        LlamaChatMessage::new(role.to_owned(), content.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn evicts_oldest_turn_after_system_messages() {
+        let messages = [
+            message("system", "be brief"),
+            message("user", "a"),
+            message("assistant", "b"),
+            message("user", "c"),
+            message("assistant", "d"),
+            message("user", "e"),
+        ];
+        assert_eq!(evictable_turn(&messages), Some(1..3));
+        assert_eq!(evictable_turn(&messages[3..]), Some(0..2));
+        assert_eq!(evictable_turn(&messages[5..]), None);
+        assert_eq!(evictable_turn(&messages[..2]), None);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = vec![
+            message("system", ""),
+            message("user", "two\nlines 3 4\n"),
+            message("assistant", "ünïcode"),
+        ];
+        let encoded = encode_messages(&messages);
+        assert_eq!(decode_messages(&encoded), Some(messages));
+        assert_eq!(decode_messages(MESSAGES_HEADER), Some(Vec::new()));
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        let encoded = encode_messages(&[message("user", "hello")]);
+        assert_eq!(decode_messages(&encoded[..encoded.len() - 1]), None);
+        assert_eq!(decode_messages(&encoded[1..]), None);
+        let mut bad = MESSAGES_HEADER.to_vec();
+        bad.extend_from_slice(b"9 1\nuser\n");
+        assert_eq!(decode_messages(&bad), None);
+    }
+
+    #[test]
+    fn messages_path_appends_suffix() {
+        assert_eq!(
+            messages_path(Path::new("dir/chat.bin")),
+            PathBuf::from("dir/chat.bin.messages")
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! Sequence classification with labelled outputs.
+//!
+//! Classifier GGUFs (for example BERT-style sentiment or intent models) end in
+//! a classification head with [`LlamaModel::n_cls_out`] outputs. With
+//! embeddings enabled and [`LlamaPoolingType::Rank`] pooling, llama.cpp leaves
+//! those outputs in the sequence embedding
+//! ([`LlamaContext::embeddings_seq_ith`]). [`classify`] batches the inputs
+//! across sequences, reads the outputs, applies the configured
+//! [`ClassifyActivation`] and pairs every score with its label
+//! ([`LlamaModel::cls_label`]).
+//!
+//! # Example
+//!
+//! ```no_run
+//! use llama_cpp_4::classify::{classify, ClassifyActivation};
+//! use llama_cpp_4::prelude::*;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, "sentiment.gguf", &LlamaModelParams::default())?;
+//! let params = LlamaContextParams::default()
+//!     .with_embeddings(true)
+//!     .with_pooling_type(LlamaPoolingType::Rank)
+//!     .with_n_seq_max(8);
+//! let mut ctx = model.new_context(&backend, params)?;
+//!
+//! let texts = ["I love it", "This is awful"];
+//! for (text, scores) in texts.iter().zip(classify(&mut ctx, &texts, ClassifyActivation::Softmax)?) {
+//!     println!("{text}: {scores:?}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::params::LlamaPoolingType;
+use crate::context::LlamaContext;
+use crate::embed::pack;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::{AddBos, LlamaModel};
+use crate::{DecodeError, EmbeddingsError, StringToTokenError};
+
+/// Failure while classifying texts.
+#[derive(Debug, thiserror::Error)]
+pub enum ClassifyError {
+    /// The context does not use rank pooling.
+    #[error("classification needs rank pooling, but the context uses {0:?}")]
+    NotRankPooling(LlamaPoolingType),
+    /// The model has no classification head.
+    #[error("the model has no classification outputs")]
+    NoClassifierHead,
+    /// A text could not be tokenized.
+    #[error(transparent)]
+    Tokenize(#[from] StringToTokenError),
+    /// A token could not be added to the batch.
+    #[error(transparent)]
+    BatchAdd(#[from] BatchAddError),
+    /// Decoding a batch failed.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    /// The outputs could not be read from the context.
+    #[error(transparent)]
+    Embeddings(#[from] EmbeddingsError),
+}
+
+/// How the raw outputs of the classification head become scores.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClassifyActivation {
+    /// Probabilities over mutually exclusive labels that sum to one.
+    #[default]
+    Softmax,
+    /// An independent probability per label, for multi-label models.
+    Sigmoid,
+    /// The raw logits.
+    None,
+}
+
+impl ClassifyActivation {
+    /// Applies the activation to the outputs of one sequence in place.
+    pub fn apply(self, outputs: &mut [f32]) {
+        match self {
+            Self::Softmax => {
+                let max = outputs.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                let mut sum = 0.0;
+                for value in outputs.iter_mut() {
+                    *value = (*value - max).exp();
+                    sum += *value;
+                }
+                for value in outputs.iter_mut() {
+                    *value /= sum;
+                }
+            }
+            Self::Sigmoid => {
+                for value in outputs.iter_mut() {
+                    *value = 1.0 / (1.0 + (-*value).exp());
+                }
+            }
+            Self::None => {}
+        }
+    }
+}
+
+/// Classifies every text and returns, per text, each label with its score in
+/// the order of the model's outputs.
+///
+/// Labels come from [`LlamaModel::cls_label`]; outputs without a label are
+/// named `LABEL_<index>` like in Hugging Face configs. Texts are packed into
+/// batches of at most [`LlamaContext::n_seq_max`] sequences and
+/// `min(n_batch, n_ubatch)` tokens, and texts longer than that are cut at the
+/// end. The memory of the context is cleared before every batch.
+///
+/// # Errors
+///
+/// Returns [`ClassifyError::NotRankPooling`] unless the context uses
+/// [`LlamaPoolingType::Rank`], [`ClassifyError::NoClassifierHead`] for models
+/// without classification outputs, [`ClassifyError::Embeddings`] when
+/// embeddings are not enabled, and tokenization or decode errors.
+pub fn classify(
+    ctx: &mut LlamaContext<'_>,
+    texts: &[impl AsRef<str>],
+    activation: ClassifyActivation,
+) -> Result<Vec<Vec<(String, f32)>>, ClassifyError> {
+    let pooling = ctx.pooling_type();
+    if pooling != LlamaPoolingType::Rank {
+        return Err(ClassifyError::NotRankPooling(pooling));
+    }
+    let model = ctx.model;
+    let labels = labels(model);
+    if labels.is_empty() {
+        return Err(ClassifyError::NoClassifierHead);
+    }
+    let limit = usize::try_from(ctx.n_batch().min(ctx.n_ubatch())).unwrap_or(usize::MAX);
+    let n_seq_max = usize::try_from(ctx.n_seq_max())
+        .unwrap_or(usize::MAX)
+        .max(1);
+
+    let mut inputs = Vec::with_capacity(texts.len());
+    for text in texts {
+        let mut tokens = model.str_to_token(text.as_ref(), AddBos::Always)?;
+        tokens.truncate(limit);
+        inputs.push(tokens);
+    }
+
+    let mut results = Vec::with_capacity(inputs.len());
+    let mut batch = LlamaBatch::new(limit, 1);
+    for group in pack(inputs.iter().map(Vec::len), limit, n_seq_max) {
+        batch.clear();
+        for (seq_id, tokens) in (0..).zip(&inputs[group.clone()]) {
+            batch.add_sequence(tokens, seq_id, false)?;
+        }
+        ctx.clear_kv_cache();
+        ctx.decode(&mut batch)?;
+        for seq_id in (0..).take(group.len()) {
+            let embedding = ctx.embeddings_seq_ith(seq_id)?;
+            let mut scores = embedding[..labels.len().min(embedding.len())].to_vec();
+            activation.apply(&mut scores);
+            results.push(labels.iter().cloned().zip(scores).collect());
+        }
+    }
+    Ok(results)
+}
+
+/// The label of every classification output.
+fn labels(model: &LlamaModel) -> Vec<String> {
+    (0..model.n_cls_out())
+        .map(|i| {
+            model
+                .cls_label(i)
+                .map_or_else(|_| format!("LABEL_{i}"), str::to_owned)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: &[f32], b: &[f32]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-5)
+    }
+
+    #[test]
+    fn softmax_sums_to_one() {
+        let mut outputs = [1.0, 2.0, 3.0];
+        ClassifyActivation::Softmax.apply(&mut outputs);
+        assert!(close(&outputs, &[0.090_030_57, 0.244_728_48, 0.665_240_96]));
+
+        // Large logits must not overflow.
+        let mut outputs = [1000.0, 1000.0];
+        ClassifyActivation::Softmax.apply(&mut outputs);
+        assert!(close(&outputs, &[0.5, 0.5]));
+    }
+
+    #[test]
+    fn sigmoid_is_per_label() {
+        let mut outputs = [0.0, 2.0, -2.0];
+        ClassifyActivation::Sigmoid.apply(&mut outputs);
+        assert!(close(&outputs, &[0.5, 0.880_797_1, 0.119_202_92]));
+    }
+
+    #[test]
+    fn none_keeps_logits() {
+        let mut outputs = [-1.5, 4.0];
+        ClassifyActivation::None.apply(&mut outputs);
+        assert_eq!(outputs, [-1.5, 4.0]);
+    }
+}
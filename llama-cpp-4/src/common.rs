@@ -13,6 +13,8 @@
 //! followed by [`crate::model::LlamaModel::new_context`] when you need inference.
 pub use llama_cpp_sys_4::common::*;
 
+use crate::context::self_extend::{SelfExtendConfig, SelfExtendError};
+
 /// Struct containing common parameters for processing.
 /// ## See more
 /// <https://github.com/ggerganov/llama.cpp/blob/master/common/common.h#L109>
@@ -101,3 +103,31 @@ impl Default for CommonParams {
         }
     }
 }
+
+impl CommonParams {
+    /// Self-Extend settings from `grp_attn_n` / `grp_attn_w`; `None` when
+    /// `grp_attn_n` is 1 (disabled).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SelfExtendError`] for an invalid factor or width.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use llama_cpp_4::common::CommonParams;
+    ///
+    /// let mut params = CommonParams::default();
+    /// assert_eq!(params.self_extend(), Ok(None));
+    /// params.grp_attn_n = 4;
+    /// assert_eq!(params.self_extend().unwrap().unwrap().width(), 512);
+    /// ```
+    pub fn self_extend(&self) -> Result<Option<SelfExtendConfig>, SelfExtendError> {
+        if self.grp_attn_n == 1 {
+            return Ok(None);
+        }
+        let group = u32::try_from(self.grp_attn_n).unwrap_or(0);
+        let width = u32::try_from(self.grp_attn_w).unwrap_or(0);
+        SelfExtendConfig::new(group, width).map(Some)
+    }
+}
@@ -6,6 +6,12 @@
 //!   intermediate tensors (per-layer hidden states, norms, …).
 //! - [`memory_breakdown`] — per-buffer memory usage after load/decode.
 //! - [`kv_cache`] — sequence copy, shift, and clear helpers.
+//! - [`checkpoint`] — snapshots of a sequence for rolling back recurrent models.
+//! - [`incremental`] — incremental prefill of input that is still being edited.
+//! - [`logprobs`] — per-token log-probabilities with top-N alternatives.
+//! - [`score`] — log-likelihood of continuations after a shared prefix.
+//! - [`shift`] — context shifting for generation past `n_ctx`.
+//! - [`self_extend`] — Self-Extend (group attention) position merging.
 
 use std::fmt::{Debug, Formatter};
 use std::num::NonZeroI32;
@@ -17,6 +23,8 @@ use llama_cpp_sys_4::llama_pooling_type;
 use params::{LlamaContextType, LlamaPoolingType};
 use perf::PerfContextData;
 
+use self_extend::SelfExtend;
+
 use crate::llama_batch::LlamaBatch;
 use crate::model::{LlamaLoraAdapter, LlamaModel};
 use crate::token::data::LlamaTokenData;
@@ -27,15 +35,27 @@ use crate::{
     LlamaLoraAdapterSetError,
 };
 
+pub mod checkpoint;
+pub mod incremental;
 pub mod kv_cache;
+pub mod logprobs;
 pub mod memory_breakdown;
 pub mod params;
 pub mod perf;
+pub mod score;
+pub mod self_extend;
 pub mod session;
+pub mod shift;
 pub mod tensor_capture;
 pub mod tensor_transaction;
 
+pub use checkpoint::{CheckpointError, CheckpointRing, SequenceCheckpoint};
+pub use incremental::{IncrementalPrefill, IncrementalPrefillError};
+pub use logprobs::{LogprobsError, TokenLogprob, TokenLogprobs};
 pub use memory_breakdown::MemoryBreakdownEntry;
+pub use score::{ContinuationScore, ScoreError};
+pub use self_extend::{SelfExtendConfig, SelfExtendError};
+pub use shift::{ContextShift, ContextShiftConfig, ContextShiftError};
 pub use tensor_capture::{CapturedTensor, TensorCapture};
 pub use tensor_transaction::{
     CapturedTensorData, TensorAccess, TensorBatchRow, TensorCallbackFailure, TensorDataMut,
@@ -73,6 +93,7 @@ pub struct LlamaContext<'a> {
     embeddings_enabled: bool,
     context_type: LlamaContextType,
     tensor_transactions: Option<Pin<Box<TensorTransactions>>>,
+    self_extend: Option<SelfExtend>,
 }
 
 impl Debug for LlamaContext<'_> {
@@ -115,6 +136,7 @@ impl<'model> LlamaContext<'model> {
     ///     true,
     ///     LlamaContextType::Default,
     ///     None,
+    ///     None,
     /// );
     /// // Now you can use the context
     /// ```
@@ -124,6 +146,7 @@ impl<'model> LlamaContext<'model> {
         embeddings_enabled: bool,
         context_type: LlamaContextType,
         tensor_transactions: Option<Pin<Box<TensorTransactions>>>,
+        self_extend: Option<SelfExtendConfig>,
     ) -> Self {
         Self {
             context: llama_context,
@@ -132,6 +155,7 @@ impl<'model> LlamaContext<'model> {
             embeddings_enabled,
             context_type,
             tensor_transactions,
+            self_extend: self_extend.map(SelfExtend::new),
         }
     }
 
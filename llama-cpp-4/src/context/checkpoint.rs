@@ -0,0 +1,441 @@
+//! Snapshots of a sequence's memory for rolling back recurrent and hybrid
+//! models.
+//!
+//! Removing the tail of a sequence from the KV cache rewinds a transformer,
+//! but not a recurrent model ([`LlamaModel::is_recurrent`]) or the recurrent
+//! layers of a hybrid one ([`LlamaModel::is_hybrid`]): their state is a
+//! summary of every token so far. To go back, the state has to be restored
+//! from a copy taken earlier.
+//!
+//! [`SequenceCheckpoint`] is one such copy, taken with
+//! [`LlamaContext::state_seq_get_data_ext`] and restored with
+//! [`LlamaContext::state_seq_set_data_ext`]. [`CheckpointRing`] takes them
+//! automatically every `interval` tokens, keeps the newest few, and rolls a
+//! sequence back to any length by restoring the closest earlier checkpoint
+//! and decoding the tokens after it again.
+//!
+//! [`LlamaModel::is_recurrent`]: crate::model::LlamaModel::is_recurrent
+//! [`LlamaModel::is_hybrid`]: crate::model::LlamaModel::is_hybrid
+
+use std::collections::VecDeque;
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// `LLAMA_STATE_SEQ_FLAGS_PARTIAL_ONLY`: only the recurrent and SWA parts of
+/// the memory, not the full attention KV cache.
+const STATE_SEQ_FLAGS_PARTIAL_ONLY: u32 = 1;
+
+/// Failure while taking, restoring, or rolling back to a checkpoint.
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum CheckpointError {
+    /// The sequence state changed size between the size query and the copy.
+    #[error("sequence state size changed from {expected} to {actual} bytes")]
+    SizeChanged {
+        /// Size returned by the query.
+        expected: usize,
+        /// Bytes written by the copy.
+        actual: usize,
+    },
+    /// llama.cpp did not accept the saved state.
+    #[error("restoring the checkpoint read {read} of {expected} bytes")]
+    Restore {
+        /// Size of the checkpoint.
+        expected: usize,
+        /// Bytes read by llama.cpp; `0` on failure.
+        read: usize,
+    },
+    /// The sequence id or a position did not fit the native types.
+    #[error(transparent)]
+    KvCache(#[from] KvCacheConversionError),
+    /// A token could not be added to the batch.
+    #[error(transparent)]
+    BatchAdd(#[from] BatchAddError),
+    /// Decoding the replayed tokens failed.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+}
+
+/// A copy of one sequence's memory.
+///
+/// # Examples
+///
+/// ```no_run
+/// use llama_cpp_4::context::checkpoint::SequenceCheckpoint;
+/// use llama_cpp_4::prelude::*;
+///
+/// # fn demo(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+/// let checkpoint = SequenceCheckpoint::capture(ctx, 0)?;
+/// // ... decode a continuation that turns out to be unwanted ...
+/// checkpoint.restore(ctx)?;
+/// assert_eq!(ctx.kv_cache_seq_pos_max(0), checkpoint.pos_max());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, PartialEq, Eq)]
+pub struct SequenceCheckpoint {
+    seq_id: i32,
+    pos_min: i32,
+    pos_max: i32,
+    flags: u32,
+    data: Vec<u8>,
+}
+
+impl std::fmt::Debug for SequenceCheckpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SequenceCheckpoint")
+            .field("seq_id", &self.seq_id)
+            .field("pos_min", &self.pos_min)
+            .field("pos_max", &self.pos_max)
+            .field("partial", &self.is_partial())
+            .field("size", &self.data.len())
+            .finish()
+    }
+}
+
+impl SequenceCheckpoint {
+    /// Copies the full memory of `seq_id`: KV cache and recurrent state.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CheckpointError::SizeChanged`] if the copy did not match the
+    /// reported size.
+    pub fn capture(ctx: &mut LlamaContext<'_>, seq_id: i32) -> Result<Self, CheckpointError> {
+        Self::capture_with_flags(ctx, seq_id, 0)
+    }
+
+    /// Copies only the recurrent and sliding-window state of `seq_id`, which
+    /// for hybrid models is much smaller than the full KV cache. Restoring
+    /// it removes the attention KV cache after [`Self::pos_max`] instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CheckpointError::SizeChanged`] if the copy did not match the
+    /// reported size.
+    pub fn capture_partial(
+        ctx: &mut LlamaContext<'_>,
+        seq_id: i32,
+    ) -> Result<Self, CheckpointError> {
+        Self::capture_with_flags(ctx, seq_id, STATE_SEQ_FLAGS_PARTIAL_ONLY)
+    }
+
+    fn capture_with_flags(
+        ctx: &mut LlamaContext<'_>,
+        seq_id: i32,
+        flags: u32,
+    ) -> Result<Self, CheckpointError> {
+        let size = ctx.state_seq_get_size_ext(seq_id, flags);
+        let mut data = vec![0; size];
+        let written = ctx.state_seq_get_data_ext(&mut data, seq_id, flags);
+        if written != size {
+            return Err(CheckpointError::SizeChanged {
+                expected: size,
+                actual: written,
+            });
+        }
+        Ok(Self {
+            seq_id,
+            pos_min: ctx.memory_seq_pos_min(seq_id),
+            pos_max: ctx.kv_cache_seq_pos_max(seq_id),
+            flags,
+            data,
+        })
+    }
+
+    /// Restores the sequence to the captured state. Everything decoded into
+    /// it since is discarded.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CheckpointError::Restore`] if llama.cpp rejects the state,
+    /// for example because the context was created with different
+    /// parameters.
+    pub fn restore(&self, ctx: &mut LlamaContext<'_>) -> Result<(), CheckpointError> {
+        self.restore_to(ctx, self.seq_id)
+    }
+
+    /// Restores the captured state into `seq_id`, which may differ from the
+    /// sequence it was taken from.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::restore`].
+    pub fn restore_to(
+        &self,
+        ctx: &mut LlamaContext<'_>,
+        seq_id: i32,
+    ) -> Result<(), CheckpointError> {
+        let read = ctx.state_seq_set_data_ext(&self.data, seq_id, self.flags);
+        if read != self.data.len() {
+            return Err(CheckpointError::Restore {
+                expected: self.data.len(),
+                read,
+            });
+        }
+        if self.is_partial() {
+            // The attention cache was not part of the copy; drop what came after.
+            let seq = u32::try_from(seq_id).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+            let p0 = u32::try_from(self.pos_max + 1).map_err(KvCacheConversionError::P0TooLarge)?;
+            ctx.clear_kv_cache_seq(Some(seq), Some(p0), None)?;
+        }
+        Ok(())
+    }
+
+    /// Sequence the checkpoint was taken from.
+    #[must_use]
+    pub fn seq_id(&self) -> i32 {
+        self.seq_id
+    }
+
+    /// Smallest position in the sequence when captured, `-1` if it was empty.
+    #[must_use]
+    pub fn pos_min(&self) -> i32 {
+        self.pos_min
+    }
+
+    /// Largest position in the sequence when captured, `-1` if it was empty.
+    #[must_use]
+    pub fn pos_max(&self) -> i32 {
+        self.pos_max
+    }
+
+    /// Number of tokens the checkpoint covers, counting from position 0.
+    #[must_use]
+    pub fn n_tokens(&self) -> usize {
+        usize::try_from(self.pos_max + 1).unwrap_or(0)
+    }
+
+    /// Whether only the recurrent and sliding-window state was captured.
+    #[must_use]
+    pub fn is_partial(&self) -> bool {
+        self.flags & STATE_SEQ_FLAGS_PARTIAL_ONLY != 0
+    }
+
+    /// Size of the saved state in bytes.
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// Checkpoints of one sequence taken every `interval` tokens, for rolling
+/// back to any length.
+///
+/// The sequence is expected to hold tokens from position 0. Call
+/// [`Self::after_decode`] after every decode into it; rolling back restores
+/// the newest checkpoint shorter than the target length and decodes the
+/// remaining tokens again.
+///
+/// # Examples
+///
+/// ```no_run
+/// use llama_cpp_4::context::checkpoint::CheckpointRing;
+/// use llama_cpp_4::prelude::*;
+///
+/// # fn demo(ctx: &mut LlamaContext, tokens: &[LlamaToken]) -> Result<(), Box<dyn std::error::Error>> {
+/// let mut batch = LlamaBatch::new(512, 1);
+/// let mut ring = CheckpointRing::new(0, 64, 8);
+/// for (pos, &token) in (0..).zip(tokens) {
+///     batch.clear();
+///     batch.add(token, pos, &[0], true)?;
+///     ctx.decode(&mut batch)?;
+///     ring.after_decode(ctx)?;
+/// }
+/// // Edit: keep the first 100 tokens and continue from there.
+/// ring.rollback(ctx, &mut batch, &tokens[..100])?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CheckpointRing {
+    seq_id: i32,
+    interval: usize,
+    capacity: usize,
+    partial: bool,
+    checkpoints: VecDeque<SequenceCheckpoint>,
+}
+
+impl CheckpointRing {
+    /// Checkpoints `seq_id` every `interval` tokens and keeps the newest
+    /// `capacity` of them.
+    #[must_use]
+    pub fn new(seq_id: i32, interval: usize, capacity: usize) -> Self {
+        Self {
+            seq_id,
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            partial: false,
+            checkpoints: VecDeque::new(),
+        }
+    }
+
+    /// Take [partial](SequenceCheckpoint::capture_partial) checkpoints.
+    /// Defaults to `false`; only useful for hybrid models.
+    #[must_use]
+    pub fn with_partial(mut self, partial: bool) -> Self {
+        self.partial = partial;
+        self
+    }
+
+    /// Sequence the checkpoints are taken of.
+    #[must_use]
+    pub fn seq_id(&self) -> i32 {
+        self.seq_id
+    }
+
+    /// Tokens between checkpoints.
+    #[must_use]
+    pub fn interval(&self) -> usize {
+        self.interval
+    }
+
+    /// Maximum checkpoints kept.
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Whether partial checkpoints are taken.
+    #[must_use]
+    pub fn partial(&self) -> bool {
+        self.partial
+    }
+
+    /// The checkpoints, oldest first.
+    pub fn checkpoints(&self) -> impl Iterator<Item = &SequenceCheckpoint> {
+        self.checkpoints.iter()
+    }
+
+    /// Number of checkpoints kept.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    /// Whether no checkpoint is kept.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.checkpoints.is_empty()
+    }
+
+    /// Total bytes held by the checkpoints.
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.checkpoints.iter().map(SequenceCheckpoint::size).sum()
+    }
+
+    /// Drops every checkpoint.
+    pub fn clear(&mut self) {
+        self.checkpoints.clear();
+    }
+
+    /// Takes a checkpoint if the sequence has grown by at least
+    /// [`Self::interval`] tokens since the last one. Returns whether one was
+    /// taken.
+    ///
+    /// # Errors
+    ///
+    /// See [`SequenceCheckpoint::capture`].
+    pub fn after_decode(&mut self, ctx: &mut LlamaContext<'_>) -> Result<bool, CheckpointError> {
+        let n_tokens = usize::try_from(ctx.kv_cache_seq_pos_max(self.seq_id) + 1).unwrap_or(0);
+        let last = self
+            .checkpoints
+            .back()
+            .map_or(0, SequenceCheckpoint::n_tokens);
+        if n_tokens < last + self.interval {
+            return Ok(false);
+        }
+        self.checkpoint(ctx)?;
+        Ok(true)
+    }
+
+    /// Takes a checkpoint now, evicting the oldest one when full.
+    ///
+    /// # Errors
+    ///
+    /// See [`SequenceCheckpoint::capture`].
+    pub fn checkpoint(&mut self, ctx: &mut LlamaContext<'_>) -> Result<(), CheckpointError> {
+        let checkpoint = if self.partial {
+            SequenceCheckpoint::capture_partial(ctx, self.seq_id)?
+        } else {
+            SequenceCheckpoint::capture(ctx, self.seq_id)?
+        };
+        let n_tokens = checkpoint.n_tokens();
+        // A checkpoint at the same or a later length is stale now.
+        while self
+            .checkpoints
+            .back()
+            .is_some_and(|c| c.n_tokens() >= n_tokens)
+        {
+            self.checkpoints.pop_back();
+        }
+        if self.checkpoints.len() == self.capacity {
+            self.checkpoints.pop_front();
+        }
+        self.checkpoints.push_back(checkpoint);
+        Ok(())
+    }
+
+    /// Rolls the sequence back so it holds exactly `tokens`, which must be a
+    /// prefix of what was decoded into it. Returns the number of tokens
+    /// decoded again.
+    ///
+    /// The newest checkpoint covering fewer than `tokens.len()` tokens is
+    /// restored; without one, the sequence is cleared and every token is
+    /// decoded again. Newer checkpoints are dropped. Unless `tokens` is
+    /// empty, at least its last token is decoded, with logits, so the logits
+    /// at `batch.n_tokens() - 1` are ready for sampling. Tokens are decoded
+    /// in chunks that fit both `batch` and the context's `n_batch`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if restoring or decoding fails.
+    pub fn rollback(
+        &mut self,
+        ctx: &mut LlamaContext<'_>,
+        batch: &mut LlamaBatch,
+        tokens: &[LlamaToken],
+    ) -> Result<usize, CheckpointError> {
+        while self
+            .checkpoints
+            .back()
+            .is_some_and(|c| c.n_tokens() >= tokens.len())
+        {
+            self.checkpoints.pop_back();
+        }
+        let start = match self.checkpoints.back() {
+            Some(checkpoint) => {
+                checkpoint.restore(ctx)?;
+                checkpoint.n_tokens()
+            }
+            None => {
+                let seq =
+                    u32::try_from(self.seq_id).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+                ctx.clear_kv_cache_seq(Some(seq), None, None)?;
+                0
+            }
+        };
+
+        let chunk_size = usize::try_from(ctx.n_batch())
+            .unwrap_or(usize::MAX)
+            .min(batch.capacity())
+            .max(1);
+        let mut pos = i32::try_from(start).map_err(KvCacheConversionError::P0TooLarge)?;
+        let replay = &tokens[start..];
+        for (i, chunk) in replay.chunks(chunk_size).enumerate() {
+            batch.clear();
+            let is_last_chunk = (i + 1) * chunk_size >= replay.len();
+            for (j, &token) in chunk.iter().enumerate() {
+                let logits = is_last_chunk && j + 1 == chunk.len();
+                batch.add(token, pos, &[self.seq_id], logits)?;
+                pos += 1;
+            }
+            ctx.decode(batch)?;
+            self.after_decode(ctx)?;
+        }
+        Ok(replay.len())
+    }
+}
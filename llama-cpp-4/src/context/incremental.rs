@@ -0,0 +1,305 @@
+//! Incremental prefill of text that is still being edited.
+//!
+//! [`IncrementalPrefill`] decodes the tokens of an input while the user is
+//! still typing, so that generation can start almost immediately on submit.
+//! Each sync compares the new tokens with the ones already in the KV cache,
+//! removes everything from the first difference on, and decodes only the
+//! rest. Speculative syncs withhold the last
+//! [`stable_margin`](IncrementalPrefill::stable_margin) tokens, because
+//! appending a character often re-tokenizes the tail (`["hel"]` → `["hell"]`
+//! → `["hello"]`).
+//!
+//! The tracked tokens start at [`start_pos`](IncrementalPrefill::start_pos);
+//! positions before it (a system prompt, earlier turns) are never touched.
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// Trailing tokens withheld by [`IncrementalPrefill::prefill_speculative`]
+/// unless configured otherwise.
+pub const DEFAULT_STABLE_MARGIN: usize = 2;
+
+/// Failure while syncing an [`IncrementalPrefill`].
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum IncrementalPrefillError {
+    /// The sequence id or a position did not fit the native types.
+    #[error(transparent)]
+    KvCache(#[from] KvCacheConversionError),
+    /// A token could not be added to the batch.
+    #[error(transparent)]
+    BatchAdd(#[from] BatchAddError),
+    /// Decoding a chunk failed.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+}
+
+/// Tracks which tokens of an edited input are decoded into one sequence.
+///
+/// # Examples
+///
+/// ```no_run
+/// use llama_cpp_4::context::incremental::IncrementalPrefill;
+/// use llama_cpp_4::prelude::*;
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let backend = LlamaBackend::init()?;
+/// let model = LlamaModel::load_from_file(&backend, "model.gguf", &LlamaModelParams::default())?;
+/// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+/// let mut batch = LlamaBatch::new(512, 1);
+///
+/// let mut prefill = IncrementalPrefill::new(0, 512);
+/// for draft in ["The quick", "The quick brown", "The quick red"] {
+///     let tokens = model.str_to_token(draft, AddBos::Always)?;
+///     prefill.prefill_speculative(&mut ctx, &mut batch, &tokens)?;
+/// }
+/// let tokens = model.str_to_token("The quick red fox", AddBos::Always)?;
+/// prefill.flush(&mut ctx, &mut batch, &tokens)?;
+/// // The logits of the last token are at `batch.n_tokens() - 1`.
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct IncrementalPrefill {
+    cached: Vec<LlamaToken>,
+    start_pos: usize,
+    batch_size: usize,
+    seq_id: i32,
+    stable_margin: usize,
+    /// Whether the last decode ended with the last cached token, so its logits
+    /// are still current.
+    logits_current: bool,
+}
+
+impl IncrementalPrefill {
+    /// Tracks tokens from position `start_pos` of sequence 0, decoding at most
+    /// `batch_size` tokens per call to [`LlamaContext::decode`].
+    #[must_use]
+    pub fn new(start_pos: usize, batch_size: usize) -> Self {
+        Self {
+            cached: Vec::new(),
+            start_pos,
+            batch_size: batch_size.max(1),
+            seq_id: 0,
+            stable_margin: DEFAULT_STABLE_MARGIN,
+            logits_current: false,
+        }
+    }
+
+    /// Sequence to decode into. Defaults to 0.
+    #[must_use]
+    pub fn with_seq_id(mut self, seq_id: i32) -> Self {
+        self.seq_id = seq_id;
+        self
+    }
+
+    /// Trailing tokens withheld by [`Self::prefill_speculative`]. Defaults to
+    /// [`DEFAULT_STABLE_MARGIN`].
+    #[must_use]
+    pub fn with_stable_margin(mut self, stable_margin: usize) -> Self {
+        self.stable_margin = stable_margin;
+        self
+    }
+
+    /// Position of the first tracked token.
+    #[must_use]
+    pub fn start_pos(&self) -> usize {
+        self.start_pos
+    }
+
+    /// Maximum tokens per decode.
+    #[must_use]
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Sequence decoded into.
+    #[must_use]
+    pub fn seq_id(&self) -> i32 {
+        self.seq_id
+    }
+
+    /// Trailing tokens withheld by speculative syncs.
+    #[must_use]
+    pub fn stable_margin(&self) -> usize {
+        self.stable_margin
+    }
+
+    /// Tokens currently decoded, starting at [`Self::start_pos`].
+    #[must_use]
+    pub fn cached_tokens(&self) -> &[LlamaToken] {
+        &self.cached
+    }
+
+    /// Number of tokens currently decoded.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.cached.len()
+    }
+
+    /// Whether nothing has been decoded yet.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.cached.is_empty()
+    }
+
+    /// Decodes the stable part of `tokens` while the input is still being
+    /// edited, withholding the last [`Self::stable_margin`] tokens.
+    ///
+    /// Returns the number of tokens decoded by this call.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if trimming the cache, building a batch or decoding
+    /// fails. Tokens of the failed chunk are decoded again by the next sync.
+    pub fn prefill_speculative(
+        &mut self,
+        ctx: &mut LlamaContext<'_>,
+        batch: &mut LlamaBatch,
+        tokens: &[LlamaToken],
+    ) -> Result<usize, IncrementalPrefillError> {
+        let stable_end = tokens.len().saturating_sub(self.stable_margin);
+        self.sync(ctx, batch, &tokens[..stable_end], false)
+    }
+
+    /// Decodes all of `tokens`, including the withheld tail. Call this on
+    /// submit; afterwards the logits of the last token are at
+    /// `batch.n_tokens() - 1`.
+    ///
+    /// Returns the number of tokens decoded by this call. When an edit only
+    /// removed tokens, the new last token is decoded again so that its logits
+    /// are current.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Self::prefill_speculative`].
+    pub fn flush(
+        &mut self,
+        ctx: &mut LlamaContext<'_>,
+        batch: &mut LlamaBatch,
+        tokens: &[LlamaToken],
+    ) -> Result<usize, IncrementalPrefillError> {
+        self.sync(ctx, batch, tokens, true)
+    }
+
+    /// Forgets the tracked tokens without touching the cache, and starts
+    /// tracking at `start_pos`, e.g. after the submitted input became part of
+    /// the history.
+    pub fn reset(&mut self, start_pos: usize) {
+        self.cached.clear();
+        self.start_pos = start_pos;
+        self.logits_current = false;
+    }
+
+    fn sync(
+        &mut self,
+        ctx: &mut LlamaContext<'_>,
+        batch: &mut LlamaBatch,
+        tokens: &[LlamaToken],
+        need_logits: bool,
+    ) -> Result<usize, IncrementalPrefillError> {
+        let current = self.logits_current && self.cached.len() == tokens.len();
+        let keep = keep_len(&self.cached, tokens, need_logits && !current);
+        if keep == self.cached.len() && keep == tokens.len() {
+            return Ok(0);
+        }
+        // Also removes whatever a failed decode left behind the cached tokens.
+        let seq = u32::try_from(self.seq_id).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+        let p0 =
+            u32::try_from(self.start_pos + keep).map_err(KvCacheConversionError::P0TooLarge)?;
+        ctx.clear_kv_cache_seq(Some(seq), Some(p0), None)?;
+        self.cached.truncate(keep);
+        self.logits_current = false;
+
+        let to_decode = &tokens[keep..];
+        for chunk in to_decode.chunks(self.batch_size) {
+            let is_final_chunk = self.cached.len() + chunk.len() == tokens.len();
+            batch.clear();
+            for (i, &token) in chunk.iter().enumerate() {
+                let pos = i32::try_from(self.start_pos + self.cached.len() + i)
+                    .map_err(KvCacheConversionError::P0TooLarge)?;
+                batch.add(
+                    token,
+                    pos,
+                    &[self.seq_id],
+                    is_final_chunk && i + 1 == chunk.len(),
+                )?;
+            }
+            ctx.decode(batch)?;
+            self.cached.extend_from_slice(chunk);
+            self.logits_current = is_final_chunk;
+        }
+        Ok(to_decode.len())
+    }
+}
+
+/// How many of the `cached` tokens to keep before syncing to `tokens`: their
+/// common prefix, minus one when the last token must be decoded again to get
+/// its logits.
+fn keep_len(cached: &[LlamaToken], tokens: &[LlamaToken], need_logits: bool) -> usize {
+    let common = cached
+        .iter()
+        .zip(tokens)
+        .take_while(|(a, b)| a == b)
+        .count();
+    if need_logits && common == tokens.len() {
+        common.saturating_sub(1)
+    } else {
+        common
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::tokens;
+
+    #[test]
+    fn appending_keeps_everything() {
+        let cached = tokens(&[1, 2, 3]);
+        assert_eq!(keep_len(&cached, &tokens(&[1, 2, 3, 4, 5]), false), 3);
+        assert_eq!(keep_len(&cached, &tokens(&[1, 2, 3, 4, 5]), true), 3);
+    }
+
+    #[test]
+    fn edit_diverges_at_first_change() {
+        let cached = tokens(&[1, 2, 3, 4]);
+        assert_eq!(keep_len(&cached, &tokens(&[1, 9, 3, 4]), false), 1);
+        assert_eq!(keep_len(&cached, &tokens(&[9, 2, 3, 4]), false), 0);
+    }
+
+    #[test]
+    fn insert_diverges_at_insertion_point() {
+        let cached = tokens(&[1, 2, 3]);
+        assert_eq!(keep_len(&cached, &tokens(&[1, 2, 7, 3]), false), 2);
+        assert_eq!(keep_len(&cached, &tokens(&[0, 1, 2, 3]), false), 0);
+    }
+
+    #[test]
+    fn delete_trims_to_remaining_prefix() {
+        let cached = tokens(&[1, 2, 3, 4]);
+        assert_eq!(keep_len(&cached, &tokens(&[1, 2]), false), 2);
+        assert_eq!(keep_len(&cached, &tokens(&[1, 3, 4]), false), 1);
+        assert_eq!(keep_len(&cached, &[], false), 0);
+    }
+
+    #[test]
+    fn stale_logits_decode_last_token_again() {
+        let cached = tokens(&[1, 2, 3, 4]);
+        assert_eq!(keep_len(&cached, &tokens(&[1, 2]), true), 1);
+        assert_eq!(keep_len(&cached, &cached, true), 3);
+        assert_eq!(keep_len(&[], &[], true), 0);
+    }
+
+    #[test]
+    fn builder_defaults() {
+        let prefill = IncrementalPrefill::new(7, 0).with_seq_id(3);
+        assert_eq!(prefill.start_pos(), 7);
+        assert_eq!(prefill.batch_size(), 1);
+        assert_eq!(prefill.seq_id(), 3);
+        assert_eq!(prefill.stable_margin(), DEFAULT_STABLE_MARGIN);
+        assert!(prefill.is_empty());
+    }
+}
@@ -0,0 +1,299 @@
+//! Per-token log-probabilities with top-N alternatives.
+//!
+//! [`LlamaContext::token_logprobs_ith`] reads one output row of logits and
+//! returns the chosen token's log-probability together with the `n_top` most
+//! likely alternatives. The normalizer is a single pass over the vocabulary and
+//! the alternatives are selected with a bounded heap, so the cost is
+//! `O(n_vocab · log n_top)` with no full-vocabulary sort or allocation.
+//!
+//! [`LlamaContext::token_logprobs_ith_with_sampler`] first applies a sampler
+//! chain to the candidates and reports probabilities over what remains, e.g.
+//! post-temperature, post-top-k values as shown to a user.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+use crate::context::LlamaContext;
+use crate::model::Special;
+use crate::sampling::LlamaSampler;
+use crate::token::data::LlamaTokenData;
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+use crate::TokenToStringError;
+
+/// Failure while computing token log-probabilities.
+#[derive(Debug, thiserror::Error)]
+pub enum LogprobsError {
+    /// The requested token id is not part of the model vocabulary.
+    #[error("token {0} is outside the vocabulary")]
+    TokenOutOfVocab(i32),
+    /// A token could not be converted to its piece bytes.
+    #[error(transparent)]
+    TokenToString(#[from] TokenToStringError),
+}
+
+/// One candidate token and its log-probability.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenLogprob {
+    /// Token id.
+    pub token: LlamaToken,
+    /// Piece bytes from [`LlamaModel::token_to_bytes`](crate::model::LlamaModel::token_to_bytes).
+    pub bytes: Vec<u8>,
+    /// Natural-log probability.
+    pub logprob: f32,
+}
+
+/// Log-probability of a chosen token and the most likely alternatives.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenLogprobs {
+    /// The chosen token.
+    pub token: LlamaToken,
+    /// Piece bytes of the chosen token.
+    pub bytes: Vec<u8>,
+    /// Natural-log probability of the chosen token; `-inf` when a sampler
+    /// removed it from the candidates.
+    pub logprob: f32,
+    /// The most likely candidates, highest first. Ties are ordered by token id.
+    pub top: Vec<TokenLogprob>,
+}
+
+impl TokenLogprobs {
+    /// Probability of the chosen token.
+    #[must_use]
+    pub fn probability(&self) -> f32 {
+        self.logprob.exp()
+    }
+
+    /// Whether the chosen token is the most likely candidate.
+    ///
+    /// Always `false` when `top` is empty.
+    #[must_use]
+    pub fn is_top(&self) -> bool {
+        self.top.first().is_some_and(|top| top.token == self.token)
+    }
+}
+
+impl LlamaContext<'_> {
+    /// Log-probability of `token` at output row `i`, with the `n_top` most
+    /// likely alternatives from the raw logits.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `token` is outside the vocabulary or a piece cannot
+    /// be converted to bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [`LlamaContext::get_logits_ith`].
+    pub fn token_logprobs_ith(
+        &self,
+        i: i32,
+        token: LlamaToken,
+        n_top: usize,
+    ) -> Result<TokenLogprobs, LogprobsError> {
+        let logits = self.get_logits_ith(i);
+        let logit = usize::try_from(token.0)
+            .ok()
+            .and_then(|index| logits.get(index))
+            .copied()
+            .ok_or(LogprobsError::TokenOutOfVocab(token.0))?;
+        let candidates = (0_i32..)
+            .zip(logits.iter().copied())
+            .map(|(id, logit)| (LlamaToken(id), logit));
+
+        let log_norm = log_normalizer(logits.iter().copied());
+        let top = top_n_by_logit(candidates, n_top);
+        self.build_logprobs(token, logit - log_norm, &top, log_norm)
+    }
+
+    /// Like [`Self::token_logprobs_ith`], but applies `sampler` to the
+    /// candidates first and normalizes over the candidates that remain.
+    ///
+    /// Use a chain without a final selecting sampler (`dist`, `greedy`, …) so
+    /// that reporting does not advance the random state used for sampling.
+    /// Logits set to `-inf` by the chain count as removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a piece cannot be converted to bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [`LlamaContext::get_logits_ith`].
+    pub fn token_logprobs_ith_with_sampler(
+        &self,
+        i: i32,
+        token: LlamaToken,
+        n_top: usize,
+        sampler: &mut LlamaSampler,
+    ) -> Result<TokenLogprobs, LogprobsError> {
+        let mut candidates = LlamaTokenDataArray::from_iter(self.candidates_ith(i), false);
+        candidates.apply_sampler(sampler);
+
+        let log_norm = log_normalizer(candidates.data.iter().map(LlamaTokenData::logit));
+        let logit = candidates
+            .data
+            .iter()
+            .find(|data| data.id() == token)
+            .map_or(f32::NEG_INFINITY, LlamaTokenData::logit);
+        let top = top_n_by_logit(
+            candidates.data.iter().map(|data| (data.id(), data.logit())),
+            n_top,
+        );
+        self.build_logprobs(token, logit - log_norm, &top, log_norm)
+    }
+
+    fn build_logprobs(
+        &self,
+        token: LlamaToken,
+        logprob: f32,
+        top: &[(LlamaToken, f32)],
+        log_norm: f32,
+    ) -> Result<TokenLogprobs, LogprobsError> {
+        let top = top
+            .iter()
+            .map(|&(token, logit)| {
+                Ok(TokenLogprob {
+                    token,
+                    bytes: self.model.token_to_bytes(token, Special::Tokenize)?,
+                    logprob: logit - log_norm,
+                })
+            })
+            .collect::<Result<Vec<_>, LogprobsError>>()?;
+        Ok(TokenLogprobs {
+            token,
+            bytes: self.model.token_to_bytes(token, Special::Tokenize)?,
+            logprob: if logprob.is_nan() {
+                f32::NEG_INFINITY
+            } else {
+                logprob
+            },
+            top,
+        })
+    }
+}
+
+/// `log(sum(exp(logit)))` computed stably in one pass.
+///
+/// Returns `-inf` for an empty or all `-inf` input.
+pub(crate) fn log_normalizer(logits: impl Iterator<Item = f32> + Clone) -> f32 {
+    let max = logits.clone().fold(f32::NEG_INFINITY, f32::max);
+    if !max.is_finite() {
+        return max;
+    }
+    let sum: f64 = logits.map(|logit| f64::from(logit - max).exp()).sum();
+    #[allow(clippy::cast_possible_truncation)]
+    let log_sum = sum.ln() as f32;
+    max + log_sum
+}
+
+/// Candidate ordered by logit, then by lower token id.
+#[derive(Clone, Copy)]
+struct Ranked(LlamaToken, f32);
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.1
+            .total_cmp(&other.1)
+            .then_with(|| other.0 .0.cmp(&self.0 .0))
+    }
+}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+/// The `n` highest-logit candidates, highest first, via a bounded min-heap.
+///
+/// `-inf` and NaN logits are skipped.
+pub(crate) fn top_n_by_logit(
+    candidates: impl Iterator<Item = (LlamaToken, f32)>,
+    n: usize,
+) -> Vec<(LlamaToken, f32)> {
+    if n == 0 {
+        return Vec::new();
+    }
+    let mut heap = BinaryHeap::new();
+    for (token, logit) in candidates {
+        if logit.is_nan() || logit == f32::NEG_INFINITY {
+            continue;
+        }
+        let ranked = Reverse(Ranked(token, logit));
+        if heap.len() < n {
+            heap.push(ranked);
+        } else if heap.peek().is_some_and(|lowest| ranked < *lowest) {
+            heap.pop();
+            heap.push(ranked);
+        }
+    }
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(Ranked(token, logit))| (token, logit))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(values: &[f32]) -> impl Iterator<Item = (LlamaToken, f32)> + '_ {
+        (0_i32..)
+            .zip(values.iter().copied())
+            .map(|(id, logit)| (LlamaToken(id), logit))
+    }
+
+    #[test]
+    fn normalizer_matches_naive_softmax() {
+        let logits = [1.0_f32, 2.0, 3.0, -1.0];
+        let naive = logits.iter().map(|l| l.exp()).sum::<f32>().ln();
+        assert!((log_normalizer(logits.iter().copied()) - naive).abs() < 1e-5);
+    }
+
+    #[test]
+    fn normalizer_is_stable_for_large_logits() {
+        let logits = [1000.0_f32, 1000.0];
+        let expected = 1000.0 + 2.0_f32.ln();
+        assert!((log_normalizer(logits.iter().copied()) - expected).abs() < 1e-3);
+    }
+
+    #[test]
+    fn normalizer_of_removed_candidates_is_neg_inf() {
+        assert_eq!(log_normalizer(std::iter::empty()), f32::NEG_INFINITY);
+        let logits = [f32::NEG_INFINITY; 3];
+        assert_eq!(log_normalizer(logits.iter().copied()), f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn top_n_is_sorted_and_bounded() {
+        let logits = [0.5_f32, 3.0, -2.0, 7.0, 3.0, 1.0];
+        let top = top_n_by_logit(tokens(&logits), 3);
+        assert_eq!(
+            top,
+            vec![
+                (LlamaToken(3), 7.0),
+                (LlamaToken(1), 3.0),
+                (LlamaToken(4), 3.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn top_n_handles_small_inputs_and_removed_tokens() {
+        let logits = [f32::NEG_INFINITY, 2.0, f32::NAN];
+        assert_eq!(
+            top_n_by_logit(tokens(&logits), 5),
+            vec![(LlamaToken(1), 2.0)]
+        );
+        assert!(top_n_by_logit(tokens(&logits), 0).is_empty());
+    }
+}
@@ -13,6 +13,7 @@ use std::pin::Pin;
 
 use thiserror::Error;
 
+use super::self_extend::SelfExtendConfig;
 use super::tensor_transaction::{
     tensor_transaction_callback, tensor_transaction_decode_begin, tensor_transaction_decode_end,
     TensorTransactions,
@@ -68,6 +69,7 @@ pub struct LlamaContextParams {
     owned_samplers: Vec<LlamaSampler>,
     sampler_configs: Vec<llama_cpp_sys_4::llama_sampler_seq_config>,
     pub(crate) tensor_transactions: Option<Pin<Box<TensorTransactions>>>,
+    pub(crate) self_extend: Option<SelfExtendConfig>,
 }
 
 impl LlamaContextParams {
@@ -637,6 +639,32 @@ impl LlamaContextParams {
         self.attn_rot_disabled
     }
 
+    /// Enable Self-Extend (group attention) to run past the model's training
+    /// context. Not a native parameter: the context keeps the setting and
+    /// [`crate::generate::Generator`] applies it while decoding.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_4::context::params::LlamaContextParams;
+    /// use llama_cpp_4::context::self_extend::SelfExtendConfig;
+    ///
+    /// let config = SelfExtendConfig::new(4, 1024).unwrap();
+    /// let params = LlamaContextParams::default().with_self_extend(Some(config));
+    /// assert_eq!(params.self_extend(), Some(config));
+    /// ```
+    #[must_use]
+    pub fn with_self_extend(mut self, self_extend: Option<SelfExtendConfig>) -> Self {
+        self.self_extend = self_extend;
+        self
+    }
+
+    /// Self-Extend settings, if enabled.
+    #[must_use]
+    pub fn self_extend(&self) -> Option<SelfExtendConfig> {
+        self.self_extend
+    }
+
     /// Set the type of pooling.
     ///
     /// # Examples
@@ -705,6 +733,7 @@ impl Default for LlamaContextParams {
             owned_samplers: Vec::new(),
             sampler_configs: Vec::new(),
             tensor_transactions: None,
+            self_extend: None,
         }
     }
 }
@@ -732,6 +761,7 @@ impl Clone for LlamaContextParams {
             owned_samplers: Vec::new(),
             sampler_configs: Vec::new(),
             tensor_transactions: None,
+            self_extend: self.self_extend,
         }
     }
 }
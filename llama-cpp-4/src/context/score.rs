@@ -0,0 +1,286 @@
+//! Log-likelihood of continuations after a shared prefix.
+//!
+//! [`LlamaContext::score_many`] decodes a prefix once into sequence 0, copies
+//! its KV cells to further sequences, and then decodes several candidate
+//! continuations side by side in one batch, one sequence per candidate. Every
+//! continuation token gets its log-probability under the model; a candidate
+//! is *greedy* when each of its tokens was also the most likely one. This is
+//! the scoring behind multiple-choice evaluation (llama.cpp's `hellaswag` and
+//! `winogrande` modes) and behind reranking sampled generations.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use llama_cpp_4::prelude::*;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, "model.gguf", &LlamaModelParams::default())?;
+//! let params = LlamaContextParams::default().with_n_seq_max(4);
+//! let mut ctx = model.new_context(&backend, params)?;
+//!
+//! let prefix = model.str_to_token("The capital of France is", AddBos::Always)?;
+//! let candidates = [" Paris.", " Berlin.", " a mystery."]
+//!     .map(|text| model.str_to_token(text, AddBos::Never))
+//!     .into_iter()
+//!     .collect::<Result<Vec<_>, _>>()?;
+//! let scores = ctx.score_many(&prefix, &candidates)?;
+//! let best = (0..scores.len()).max_by(|&a, &b| scores[a].total.total_cmp(&scores[b].total));
+//! println!("best candidate: {best:?}");
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::logprobs::log_normalizer;
+use crate::context::LlamaContext;
+use crate::embed::pack;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// Failure while scoring continuations.
+#[derive(Debug, thiserror::Error)]
+pub enum ScoreError {
+    /// The prefix is empty, so nothing predicts the first continuation token.
+    #[error("the prefix is empty")]
+    EmptyPrefix,
+    /// A continuation is empty.
+    #[error("continuation {0} is empty")]
+    EmptyContinuation(usize),
+    /// The prefix plus a continuation does not fit in a sequence.
+    #[error("continuation {index} needs {n_tokens} tokens, but a sequence holds {n_ctx}")]
+    ExceedsContext {
+        /// Position of the continuation in the input.
+        index: usize,
+        /// Prefix plus continuation tokens.
+        n_tokens: usize,
+        /// Per-sequence context size.
+        n_ctx: u32,
+    },
+    /// A continuation does not fit in one batch.
+    #[error("continuation {index} has {n_tokens} tokens, but a batch holds {n_batch}")]
+    ExceedsBatch {
+        /// Position of the continuation in the input.
+        index: usize,
+        /// Continuation tokens.
+        n_tokens: usize,
+        /// Batch size of the context.
+        n_batch: u32,
+    },
+    /// A sequence id or position did not fit the native types.
+    #[error(transparent)]
+    KvCache(#[from] KvCacheConversionError),
+    /// A token could not be added to the batch.
+    #[error(transparent)]
+    BatchAdd(#[from] BatchAddError),
+    /// Decoding failed.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+}
+
+/// How likely a continuation is after the prefix.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContinuationScore {
+    /// Natural-log probability of every continuation token.
+    pub logprobs: Vec<f32>,
+    /// Log-likelihood of the whole continuation: the sum of `logprobs`.
+    pub total: f32,
+    /// Whether every token was the most likely one at its position, i.e.
+    /// greedy decoding would have produced the continuation.
+    pub is_greedy: bool,
+}
+
+impl ContinuationScore {
+    /// Log-likelihood per token, for comparing continuations of different
+    /// lengths.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn mean(&self) -> f32 {
+        if self.logprobs.is_empty() {
+            return 0.0;
+        }
+        self.total / self.logprobs.len() as f32
+    }
+
+    fn push(&mut self, logits: &[f32], token: LlamaToken) {
+        let (logprob, is_top) = token_logprob(logits, token);
+        self.logprobs.push(logprob);
+        self.total += logprob;
+        self.is_greedy &= is_top;
+    }
+}
+
+impl LlamaContext<'_> {
+    /// Scores one continuation after `prefix`; see [`Self::score_many`].
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Self::score_many`].
+    pub fn score(
+        &mut self,
+        prefix: &[LlamaToken],
+        continuation: &[LlamaToken],
+    ) -> Result<ContinuationScore, ScoreError> {
+        let mut scores = self.score_many(prefix, &[continuation])?;
+        Ok(scores.remove(0))
+    }
+
+    /// Scores every continuation after `prefix` and returns the scores in
+    /// input order.
+    ///
+    /// The prefix is decoded once; continuations are decoded in batches of at
+    /// most [`Self::n_seq_max`] sequences and [`Self::n_batch`] tokens, each
+    /// on its own copy of the prefix cells. The memory of the context is
+    /// cleared first, and sequences `0..n_seq_max` are used.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error for an empty prefix or continuation, a continuation
+    /// that does not fit the context or a batch, and failed decodes.
+    pub fn score_many(
+        &mut self,
+        prefix: &[LlamaToken],
+        continuations: &[impl AsRef<[LlamaToken]>],
+    ) -> Result<Vec<ContinuationScore>, ScoreError> {
+        if prefix.is_empty() {
+            return Err(ScoreError::EmptyPrefix);
+        }
+        let n_ctx = self.n_ctx_seq();
+        let n_batch = self.n_batch();
+        let n_batch_len = usize::try_from(n_batch).unwrap_or(usize::MAX).max(1);
+        for (index, continuation) in continuations.iter().enumerate() {
+            let n_tokens = continuation.as_ref().len();
+            if n_tokens == 0 {
+                return Err(ScoreError::EmptyContinuation(index));
+            }
+            if prefix.len() + n_tokens > usize::try_from(n_ctx).unwrap_or(usize::MAX) {
+                return Err(ScoreError::ExceedsContext {
+                    index,
+                    n_tokens: prefix.len() + n_tokens,
+                    n_ctx,
+                });
+            }
+            // The last token is scored but never decoded.
+            if n_tokens - 1 > n_batch_len {
+                return Err(ScoreError::ExceedsBatch {
+                    index,
+                    n_tokens,
+                    n_batch,
+                });
+            }
+        }
+
+        // Decode the prefix into sequence 0 and score every first token.
+        self.clear_kv_cache();
+        let mut batch = LlamaBatch::new(n_batch_len, 1);
+        let mut pos = 0_i32;
+        for (i, &token) in prefix.iter().enumerate() {
+            let is_last = i + 1 == prefix.len();
+            batch.add(token, pos, &[0], is_last)?;
+            pos += 1;
+            if is_last || (i + 1) % n_batch_len == 0 {
+                self.decode(&mut batch)?;
+                if !is_last {
+                    batch.clear();
+                }
+            }
+        }
+        let prefix_logits = self.get_logits_ith(batch.n_tokens() - 1);
+        let mut scores: Vec<ContinuationScore> = continuations
+            .iter()
+            .map(|continuation| {
+                let mut score = ContinuationScore {
+                    logprobs: Vec::new(),
+                    total: 0.0,
+                    is_greedy: true,
+                };
+                score.push(prefix_logits, continuation.as_ref()[0]);
+                score
+            })
+            .collect();
+
+        // Score the remaining tokens, one sequence per continuation.
+        let n_seq_max = usize::try_from(self.n_seq_max())
+            .unwrap_or(usize::MAX)
+            .max(1);
+        let n_seqs = n_seq_max.min(continuations.len());
+        for seq_id in (1..).take(n_seqs.saturating_sub(1)) {
+            self.copy_kv_cache_seq(0, seq_id, None, None)?;
+        }
+        let p0 = u32::try_from(prefix.len()).map_err(KvCacheConversionError::P0TooLarge)?;
+        let lengths = continuations.iter().map(|c| c.as_ref().len() - 1);
+        for group in pack(lengths, n_batch_len, n_seq_max) {
+            batch.clear();
+            let mut rows = Vec::new();
+            for (seq_id, index) in (0..).zip(group.clone()) {
+                let continuation = continuations[index].as_ref();
+                for (pos, pair) in (pos..).zip(continuation.windows(2)) {
+                    rows.push((index, pair[1], batch.n_tokens()));
+                    batch.add(pair[0], pos, &[seq_id], true)?;
+                }
+            }
+            if rows.is_empty() {
+                continue;
+            }
+            self.decode(&mut batch)?;
+            for (index, next, row) in rows {
+                scores[index].push(self.get_logits_ith(row), next);
+            }
+            for seq_id in (0..).take(group.len()) {
+                self.clear_kv_cache_seq(Some(seq_id), Some(p0), None)?;
+            }
+        }
+        Ok(scores)
+    }
+}
+
+/// Log-probability of `token` under `logits`, and whether it has the highest
+/// logit.
+fn token_logprob(logits: &[f32], token: LlamaToken) -> (f32, bool) {
+    let Some(&logit) = usize::try_from(token.0).ok().and_then(|i| logits.get(i)) else {
+        return (f32::NEG_INFINITY, false);
+    };
+    let normalizer = log_normalizer(logits.iter().copied());
+    let is_top = logits.iter().all(|&other| other <= logit);
+    (logit - normalizer, is_top)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_logprob_normalizes_and_detects_argmax() {
+        let logits = [0.0, 2.0_f32.ln(), 0.0];
+        let (logprob, is_top) = token_logprob(&logits, LlamaToken(1));
+        assert!((logprob - 0.5_f32.ln()).abs() < 1e-6);
+        assert!(is_top);
+
+        let (logprob, is_top) = token_logprob(&logits, LlamaToken(0));
+        assert!((logprob - 0.25_f32.ln()).abs() < 1e-6);
+        assert!(!is_top);
+
+        assert_eq!(
+            token_logprob(&logits, LlamaToken(7)),
+            (f32::NEG_INFINITY, false)
+        );
+    }
+
+    #[test]
+    fn score_accumulates_tokens() {
+        let logits = [0.0, 0.0];
+        let mut score = ContinuationScore {
+            logprobs: Vec::new(),
+            total: 0.0,
+            is_greedy: true,
+        };
+        score.push(&logits, LlamaToken(0));
+        score.push(&logits, LlamaToken(1));
+        assert_eq!(score.logprobs.len(), 2);
+        assert!((score.total - 2.0 * 0.5_f32.ln()).abs() < 1e-6);
+        assert!((score.mean() - 0.5_f32.ln()).abs() < 1e-6);
+        // Ties count as greedy.
+        assert!(score.is_greedy);
+    }
+}
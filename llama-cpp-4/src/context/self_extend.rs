@@ -0,0 +1,241 @@
+//! Self-Extend (group attention) for running past the training context.
+//!
+//! Self-Extend keeps positions inside the range the model was trained on by
+//! merging every window of [`width`](SelfExtendConfig::width) positions into
+//! `width / group` positions once generation moves past it. Each merge moves
+//! the cached positions with [`LlamaContext::kv_cache_seq_add`] and divides
+//! them with [`LlamaContext::kv_cache_seq_div`], the scheme of llama.cpp's
+//! `--grp-attn-n` / `--grp-attn-w` options. The KV cache still holds every
+//! token, so `n_ctx` bounds the number of tokens, not their positions.
+//!
+//! Enable it with
+//! [`LlamaContextParams::with_self_extend`](crate::context::params::LlamaContextParams::with_self_extend).
+//! [`Generator`](crate::generate::Generator) then applies it during prefill
+//! and generation; code that decodes by hand calls
+//! [`LlamaContext::apply_self_extend`] before each decode and keeps batches
+//! within [`LlamaContext::self_extend_room`].
+
+use std::collections::HashMap;
+use std::num::NonZeroU8;
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+
+/// Invalid Self-Extend settings or a failure while applying them.
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum SelfExtendError {
+    /// The group factor is outside `2..=255`.
+    #[error("group-attention factor must be between 2 and 255, got {0}")]
+    InvalidGroup(u32),
+    /// The width is zero, too large, or not a multiple of the group factor.
+    #[error("group-attention width {width} must be a positive multiple of the factor {group}")]
+    InvalidWidth {
+        /// Requested width.
+        width: u32,
+        /// Requested group factor.
+        group: u32,
+    },
+    /// The context's memory does not support shifting positions.
+    #[error("the KV cache of this context does not support self-extend")]
+    Unsupported,
+    /// Self-Extend and context shifting were both requested.
+    #[error("self-extend cannot be combined with context shifting")]
+    WithContextShift,
+    /// A sequence id or position did not fit the native types.
+    #[error(transparent)]
+    KvCache(#[from] KvCacheConversionError),
+}
+
+/// Self-Extend settings: merge windows of `width` positions by `group`.
+///
+/// # Examples
+///
+/// ```
+/// use llama_cpp_4::context::self_extend::SelfExtendConfig;
+///
+/// let config = SelfExtendConfig::new(4, 1024).unwrap();
+/// assert_eq!(config.group().get(), 4);
+/// assert_eq!(config.width(), 1024);
+/// assert!(SelfExtendConfig::new(4, 1000).is_err());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SelfExtendConfig {
+    group: NonZeroU8,
+    width: u32,
+}
+
+impl SelfExtendConfig {
+    /// Creates the settings for a group factor (`--grp-attn-n`) and window
+    /// width (`--grp-attn-w`).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SelfExtendError::InvalidGroup`] unless `2 <= group <= 255`
+    /// and [`SelfExtendError::InvalidWidth`] unless `width` is a positive
+    /// multiple of `group` that fits in an `i32`.
+    pub fn new(group: u32, width: u32) -> Result<Self, SelfExtendError> {
+        let group_u8 = u8::try_from(group)
+            .ok()
+            .filter(|&group| group >= 2)
+            .and_then(NonZeroU8::new)
+            .ok_or(SelfExtendError::InvalidGroup(group))?;
+        if width == 0 || width % group != 0 || i32::try_from(width).is_err() {
+            return Err(SelfExtendError::InvalidWidth { width, group });
+        }
+        Ok(Self {
+            group: group_u8,
+            width,
+        })
+    }
+
+    /// Number of positions merged into one.
+    #[must_use]
+    pub fn group(&self) -> NonZeroU8 {
+        self.group
+    }
+
+    /// Width of each merged window, in positions before merging.
+    #[must_use]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+}
+
+/// Self-Extend settings together with the progress of each sequence.
+#[derive(Debug)]
+pub(crate) struct SelfExtend {
+    config: SelfExtendConfig,
+    seqs: HashMap<i32, SeqState>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct SeqState {
+    /// Start of the next window to merge (`ga_i` upstream).
+    window_start: i32,
+    /// Positions removed by merging so far.
+    compressed: i32,
+}
+
+impl SelfExtend {
+    pub(crate) fn new(config: SelfExtendConfig) -> Self {
+        Self {
+            config,
+            seqs: HashMap::new(),
+        }
+    }
+}
+
+impl LlamaContext<'_> {
+    /// Self-Extend settings of this context, if enabled.
+    #[must_use]
+    pub fn self_extend(&self) -> Option<SelfExtendConfig> {
+        self.self_extend.as_ref().map(|extend| extend.config)
+    }
+
+    /// Merges every full window before `n_past` in `seq_id` and returns the
+    /// position at which the next token goes. Returns `n_past` unchanged when
+    /// Self-Extend is disabled.
+    ///
+    /// Progress is tracked per sequence and starts over once the sequence is
+    /// empty; removing only part of an extended sequence is not supported.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`SelfExtendError::Unsupported`] when
+    /// [`Self::memory_can_shift`] is false.
+    pub fn apply_self_extend(&mut self, seq_id: i32, n_past: i32) -> Result<i32, SelfExtendError> {
+        let Some(config) = self.self_extend() else {
+            return Ok(n_past);
+        };
+        if !self.memory_can_shift() {
+            return Err(SelfExtendError::Unsupported);
+        }
+        let mut state = self.self_extend_state(seq_id);
+        let n = i32::from(config.group.get());
+        let w = i32::try_from(config.width).unwrap_or(i32::MAX);
+        let to_p0 = |pos: i32| u32::try_from(pos).map_err(KvCacheConversionError::P0TooLarge);
+        let to_p1 = |pos: i32| u32::try_from(pos).map_err(KvCacheConversionError::P1TooLarge);
+
+        let mut n_past = n_past;
+        while n_past >= state.window_start + w {
+            let ga_i = state.window_start;
+            let ib = (n * ga_i) / w;
+            let bd = (w / n) * (n - 1);
+            let dd = (w / n) - ib * bd - w;
+            self.kv_cache_seq_add(seq_id, Some(to_p0(ga_i)?), Some(to_p1(n_past)?), ib * bd)?;
+            self.kv_cache_seq_div(
+                seq_id,
+                Some(to_p0(ga_i + ib * bd)?),
+                Some(to_p1(ga_i + ib * bd + w)?),
+                config.group,
+            )?;
+            self.kv_cache_seq_add(
+                seq_id,
+                Some(to_p0(ga_i + ib * bd + w)?),
+                Some(to_p1(n_past + ib * bd)?),
+                dd,
+            )?;
+            n_past -= bd;
+            state.window_start += w / n;
+            state.compressed += bd;
+        }
+        if let Some(extend) = &mut self.self_extend {
+            extend.seqs.insert(seq_id, state);
+        }
+        Ok(n_past)
+    }
+
+    /// Tokens that can be decoded into `seq_id` from `n_past` before the next
+    /// call to [`Self::apply_self_extend`]. [`usize::MAX`] when Self-Extend is
+    /// disabled.
+    #[must_use]
+    pub fn self_extend_room(&self, seq_id: i32, n_past: i32) -> usize {
+        let Some(config) = self.self_extend() else {
+            return usize::MAX;
+        };
+        let end = i64::from(self.self_extend_state(seq_id).window_start) + i64::from(config.width);
+        usize::try_from(end - i64::from(n_past)).unwrap_or(0)
+    }
+
+    /// Positions removed from `seq_id` by merging so far. The sequence holds
+    /// `n_past + compressed` tokens.
+    #[must_use]
+    pub fn self_extend_compressed(&self, seq_id: i32) -> i32 {
+        self.self_extend_state(seq_id).compressed
+    }
+
+    fn self_extend_state(&self, seq_id: i32) -> SeqState {
+        match &self.self_extend {
+            Some(extend) if self.kv_cache_seq_pos_max(seq_id) >= 0 => {
+                extend.seqs.get(&seq_id).copied().unwrap_or_default()
+            }
+            _ => SeqState::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_validates_group_and_width() {
+        assert!(SelfExtendConfig::new(2, 512).is_ok());
+        assert_eq!(
+            SelfExtendConfig::new(1, 512),
+            Err(SelfExtendError::InvalidGroup(1))
+        );
+        assert_eq!(
+            SelfExtendConfig::new(256, 512),
+            Err(SelfExtendError::InvalidGroup(256))
+        );
+        assert_eq!(
+            SelfExtendConfig::new(3, 512),
+            Err(SelfExtendError::InvalidWidth {
+                width: 512,
+                group: 3
+            })
+        );
+        assert!(SelfExtendConfig::new(2, 0).is_err());
+    }
+}
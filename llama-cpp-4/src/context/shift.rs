@@ -0,0 +1,203 @@
+//! Context shifting for generation past the end of the context window.
+//!
+//! When a sequence fills [`LlamaContext::n_ctx_seq`],
+//! [`LlamaContext::shift_context`] keeps its first `n_keep` positions (usually
+//! the system prompt), removes a block of `n_discard` positions after them with
+//! [`LlamaContext::clear_kv_cache_seq`], and moves everything after that block
+//! back with [`LlamaContext::kv_cache_seq_add`]. Generation then continues at
+//! the returned [`ContextShift::n_past`].
+//!
+//! Shifting needs a KV cache that supports position updates
+//! ([`LlamaContext::memory_can_shift`]). Recurrent and some hybrid models do
+//! not.
+//!
+//! [`Generator`](crate::generate::Generator) shifts automatically when
+//! configured with
+//! [`GeneratorConfig::with_context_shift`](crate::generate::GeneratorConfig::with_context_shift).
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+
+/// Failure while shifting a sequence.
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum ContextShiftError {
+    /// The context's memory does not support shifting positions.
+    #[error("the KV cache of this context does not support shifting")]
+    Unsupported,
+    /// No position after `n_keep` is left to discard.
+    #[error("nothing to discard: keeping {n_keep} of {n_past} positions")]
+    NothingToDiscard {
+        /// Positions that must be kept.
+        n_keep: u32,
+        /// Positions in the sequence.
+        n_past: u32,
+    },
+    /// A sequence id or position did not fit the native types.
+    #[error(transparent)]
+    KvCache(#[from] KvCacheConversionError),
+}
+
+/// How to shift a full sequence.
+///
+/// # Examples
+///
+/// ```
+/// use llama_cpp_4::context::shift::ContextShiftConfig;
+///
+/// let shift = ContextShiftConfig::new(32).with_n_discard(Some(256));
+/// assert_eq!(shift.n_keep(), 32);
+/// assert_eq!(shift.n_discard(), Some(256));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContextShiftConfig {
+    n_keep: u32,
+    n_discard: Option<u32>,
+}
+
+impl ContextShiftConfig {
+    /// Keeps the first `n_keep` positions and discards half of the rest.
+    #[must_use]
+    pub fn new(n_keep: u32) -> Self {
+        Self {
+            n_keep,
+            n_discard: None,
+        }
+    }
+
+    /// Positions discarded after the kept ones. `None` (the default) discards
+    /// half of the positions after `n_keep`, as llama.cpp's examples do.
+    #[must_use]
+    pub fn with_n_discard(mut self, n_discard: Option<u32>) -> Self {
+        self.n_discard = n_discard;
+        self
+    }
+
+    /// Leading positions that are never discarded.
+    #[must_use]
+    pub fn n_keep(&self) -> u32 {
+        self.n_keep
+    }
+
+    /// Explicit discard size, if set.
+    #[must_use]
+    pub fn n_discard(&self) -> Option<u32> {
+        self.n_discard
+    }
+}
+
+/// A shift that was applied to a sequence.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContextShift {
+    /// The shifted sequence.
+    pub seq_id: i32,
+    /// Positions `0..n_keep` were kept in place.
+    pub n_keep: u32,
+    /// Positions `n_keep..n_keep + n_discard` were removed; later positions
+    /// moved back by `n_discard`.
+    pub n_discard: u32,
+    /// Next free position after the shift.
+    pub n_past: i32,
+}
+
+impl ContextShift {
+    /// Applies the shift to a caller-side history that holds one entry per
+    /// position of the sequence, e.g. its tokens.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use llama_cpp_4::context::shift::ContextShift;
+    ///
+    /// let shift = ContextShift { seq_id: 0, n_keep: 2, n_discard: 3, n_past: 3 };
+    /// let mut history = vec![0, 1, 2, 3, 4, 5];
+    /// shift.apply_to(&mut history);
+    /// assert_eq!(history, [0, 1, 5]);
+    /// ```
+    pub fn apply_to<T>(&self, history: &mut Vec<T>) {
+        let len = history.len();
+        let start = usize::try_from(self.n_keep).unwrap_or(usize::MAX).min(len);
+        let end = start
+            .saturating_add(usize::try_from(self.n_discard).unwrap_or(usize::MAX))
+            .min(len);
+        history.drain(start..end);
+    }
+}
+
+impl LlamaContext<'_> {
+    /// Discards a block of positions after the first
+    /// [`n_keep`](ContextShiftConfig::n_keep) in `seq_id` and moves the rest
+    /// back, freeing room at the end of the sequence.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ContextShiftError::Unsupported`] when
+    /// [`Self::memory_can_shift`] is false, and
+    /// [`ContextShiftError::NothingToDiscard`] when every position is kept.
+    pub fn shift_context(
+        &mut self,
+        seq_id: i32,
+        config: &ContextShiftConfig,
+    ) -> Result<ContextShift, ContextShiftError> {
+        if !self.memory_can_shift() {
+            return Err(ContextShiftError::Unsupported);
+        }
+        let seq = u32::try_from(seq_id).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+        let n_past = u32::try_from(self.kv_cache_seq_pos_max(seq_id) + 1).unwrap_or(0);
+        let n_keep = config.n_keep.min(n_past);
+        let n_left = n_past - n_keep;
+        let n_discard = config.n_discard.unwrap_or(n_left / 2).min(n_left);
+        if n_discard == 0 {
+            return Err(ContextShiftError::NothingToDiscard { n_keep, n_past });
+        }
+
+        self.clear_kv_cache_seq(Some(seq), Some(n_keep), Some(n_keep + n_discard))?;
+        let delta = i32::try_from(n_discard).map_err(KvCacheConversionError::P1TooLarge)?;
+        self.kv_cache_seq_add(seq_id, Some(n_keep + n_discard), Some(n_past), -delta)?;
+        Ok(ContextShift {
+            seq_id,
+            n_keep,
+            n_discard,
+            n_past: i32::try_from(n_past - n_discard)
+                .map_err(KvCacheConversionError::P1TooLarge)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shift(n_keep: u32, n_discard: u32) -> ContextShift {
+        ContextShift {
+            seq_id: 0,
+            n_keep,
+            n_discard,
+            n_past: 0,
+        }
+    }
+
+    #[test]
+    fn apply_to_drains_discarded_block() {
+        let mut history: Vec<u32> = (0..10).collect();
+        shift(3, 4).apply_to(&mut history);
+        assert_eq!(history, [0, 1, 2, 7, 8, 9]);
+    }
+
+    #[test]
+    fn apply_to_clamps_to_short_history() {
+        let mut history: Vec<u32> = (0..5).collect();
+        shift(3, 4).apply_to(&mut history);
+        assert_eq!(history, [0, 1, 2]);
+
+        let mut history: Vec<u32> = (0..2).collect();
+        shift(3, 4).apply_to(&mut history);
+        assert_eq!(history, [0, 1]);
+    }
+
+    #[test]
+    fn config_defaults_to_half() {
+        let config = ContextShiftConfig::new(8);
+        assert_eq!(config.n_keep(), 8);
+        assert_eq!(config.n_discard(), None);
+    }
+}
@@ -0,0 +1,718 @@
+//! Generation with diffusion language models.
+//!
+//! Diffusion LMs such as Dream and LLaDA ([`LlamaModel::is_diffusion`]) don't
+//! generate left to right. The prompt is followed by a canvas of
+//! [`LlamaModel::mask`] tokens, the whole sequence is decoded with
+//! non-causal attention, and every step commits some of the masked positions.
+//! [`diffusion_generate`] runs that loop:
+//!
+//! - **Schedule.** Without a block length, all masked positions compete in
+//!   every step and the number committed follows the timestep schedule of
+//!   Dream (controlled by [`DiffusionConfig::with_eps`]). With
+//!   [`DiffusionConfig::with_block_length`] the canvas is filled block by
+//!   block, left to right, with an even share of the steps per block, like
+//!   LLaDA.
+//! - **Remasking.** [`DiffusionAlgorithm`] picks which of the sampled
+//!   positions are kept: the most confident ones, lowest entropy, largest
+//!   margin, random ones, or (`Origin`) each one with the schedule's
+//!   probability. With [`DiffusionConfig::with_alg_temp`] the choice is
+//!   sampled instead of taken greedily.
+//!
+//! Tokens at masked positions are drawn with the caller's [`LlamaSampler`].
+//! The sequence must fit in one micro-batch, so create the context with
+//! `n_batch` and `n_ubatch` of at least prompt plus canvas length.
+//!
+//! ```no_run
+//! use llama_cpp_4::diffusion::{diffusion_generate, DiffusionConfig};
+//! use llama_cpp_4::prelude::*;
+//!
+//! # fn demo(model: &LlamaModel, ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! let prompt = model.str_to_token("Write a haiku about rust.", AddBos::Always)?;
+//! let mut sampler = LlamaSampler::chain_simple([
+//!     LlamaSampler::top_k(40),
+//!     LlamaSampler::temp(0.2),
+//!     LlamaSampler::dist(0),
+//! ]);
+//! let config = DiffusionConfig::for_model(model).with_n_gen(128).with_steps(64);
+//! let output = diffusion_generate(ctx, &mut sampler, &prompt, &config, |_| true)?;
+//! println!("{}", model.detokenize(&output.tokens, false, false)?);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ops::{Deref, DerefMut};
+
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::LlamaModel;
+use crate::sampling::LlamaSampler;
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// GGUF key telling whether logits at position `i` predict position `i + 1`.
+const SHIFT_LOGITS_KEY: &str = "diffusion.shift_logits";
+
+/// Errors raised while generating with a diffusion model.
+#[derive(Debug, thiserror::Error)]
+pub enum DiffusionError {
+    /// The model is not a diffusion model.
+    #[error("the model is not a diffusion model")]
+    NotDiffusion,
+    /// The vocabulary has no mask token.
+    #[error("the vocabulary has no mask token")]
+    NoMaskToken,
+    /// Prompt and canvas do not fit in one micro-batch of the context.
+    #[error("{n_tokens} tokens exceed the limit of {limit} (min of n_ctx, n_batch, n_ubatch)")]
+    TooLong {
+        /// Prompt plus canvas tokens.
+        n_tokens: usize,
+        /// Tokens the context can decode at once.
+        limit: usize,
+    },
+    /// The block length does not split the canvas and steps evenly.
+    #[error("block length {block_length} must divide {n_gen} tokens into blocks that divide {steps} steps")]
+    InvalidBlockLength {
+        /// The configured block length.
+        block_length: usize,
+        /// Canvas length.
+        n_gen: usize,
+        /// Total steps.
+        steps: u32,
+    },
+    /// A token could not be added to the batch.
+    #[error(transparent)]
+    BatchAdd(#[from] BatchAddError),
+    /// Decoding the sequence failed.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+}
+
+/// How the positions committed in a step are chosen among the masked ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DiffusionAlgorithm {
+    /// Commit every masked position with the probability given by the
+    /// schedule.
+    Origin,
+    /// Prefer positions whose distribution has the lowest entropy.
+    Entropy,
+    /// Prefer positions with the largest gap between the two most likely
+    /// tokens.
+    Margin,
+    /// Commit random positions.
+    Random,
+    /// Prefer positions whose sampled token is most likely.
+    #[default]
+    Confidence,
+}
+
+/// Settings for [`diffusion_generate`].
+///
+/// # Examples
+///
+/// ```
+/// use llama_cpp_4::diffusion::{DiffusionAlgorithm, DiffusionConfig};
+///
+/// let config = DiffusionConfig::new()
+///     .with_n_gen(128)
+///     .with_steps(64)
+///     .with_block_length(Some(32))
+///     .with_algorithm(DiffusionAlgorithm::Entropy);
+/// assert_eq!(config.n_gen(), 128);
+/// assert_eq!(config.block_length(), Some(32));
+/// assert!(config.shift_logits());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiffusionConfig {
+    n_gen: usize,
+    steps: u32,
+    algorithm: DiffusionAlgorithm,
+    alg_temp: f32,
+    eps: f32,
+    block_length: Option<usize>,
+    shift_logits: bool,
+    seed: u64,
+}
+
+impl Default for DiffusionConfig {
+    fn default() -> Self {
+        Self {
+            n_gen: 256,
+            steps: 128,
+            algorithm: DiffusionAlgorithm::Confidence,
+            alg_temp: 0.0,
+            eps: 1e-3,
+            block_length: None,
+            shift_logits: true,
+            seed: 0,
+        }
+    }
+}
+
+impl DiffusionConfig {
+    /// 256 tokens in 128 steps, confidence-based and timestep-scheduled.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Defaults with [`Self::shift_logits`] read from the model's
+    /// `diffusion.shift_logits` metadata (`true` when absent, as for Dream;
+    /// LLaDA sets it to `false`).
+    #[must_use]
+    pub fn for_model(model: &LlamaModel) -> Self {
+        let shift_logits = !matches!(
+            model.meta_val_str(SHIFT_LOGITS_KEY, 16).as_deref(),
+            Ok("false")
+        );
+        Self::default().with_shift_logits(shift_logits)
+    }
+
+    /// Number of masked tokens to generate after the prompt.
+    #[must_use]
+    pub fn with_n_gen(mut self, n_gen: usize) -> Self {
+        self.n_gen = n_gen;
+        self
+    }
+
+    /// Total denoising steps, each one decode of the full sequence.
+    #[must_use]
+    pub fn with_steps(mut self, steps: u32) -> Self {
+        self.steps = steps;
+        self
+    }
+
+    /// How committed positions are chosen.
+    #[must_use]
+    pub fn with_algorithm(mut self, algorithm: DiffusionAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Temperature for sampling which positions to commit. `0.0` (the
+    /// default) commits the highest-scoring ones.
+    #[must_use]
+    pub fn with_alg_temp(mut self, alg_temp: f32) -> Self {
+        self.alg_temp = alg_temp;
+        self
+    }
+
+    /// Final timestep of the timestep schedule. Defaults to `1e-3`.
+    #[must_use]
+    pub fn with_eps(mut self, eps: f32) -> Self {
+        self.eps = eps;
+        self
+    }
+
+    /// Fill the canvas in blocks of this many tokens. It must divide
+    /// [`Self::n_gen`], and the number of blocks must divide
+    /// [`Self::steps`]. `None` (the default) uses the timestep schedule.
+    #[must_use]
+    pub fn with_block_length(mut self, block_length: Option<usize>) -> Self {
+        self.block_length = block_length;
+        self
+    }
+
+    /// Whether the logits at position `i` predict the token at `i + 1`
+    /// (Dream) rather than at `i` (LLaDA).
+    #[must_use]
+    pub fn with_shift_logits(mut self, shift_logits: bool) -> Self {
+        self.shift_logits = shift_logits;
+        self
+    }
+
+    /// Seed for the remasking choices. Token sampling uses the sampler's
+    /// own seed.
+    #[must_use]
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Tokens to generate.
+    #[must_use]
+    pub fn n_gen(&self) -> usize {
+        self.n_gen
+    }
+
+    /// Total denoising steps.
+    #[must_use]
+    pub fn steps(&self) -> u32 {
+        self.steps
+    }
+
+    /// Remasking algorithm.
+    #[must_use]
+    pub fn algorithm(&self) -> DiffusionAlgorithm {
+        self.algorithm
+    }
+
+    /// Remasking temperature.
+    #[must_use]
+    pub fn alg_temp(&self) -> f32 {
+        self.alg_temp
+    }
+
+    /// Final timestep.
+    #[must_use]
+    pub fn eps(&self) -> f32 {
+        self.eps
+    }
+
+    /// Block length, if block scheduling is used.
+    #[must_use]
+    pub fn block_length(&self) -> Option<usize> {
+        self.block_length
+    }
+
+    /// Whether logits are shifted by one position.
+    #[must_use]
+    pub fn shift_logits(&self) -> bool {
+        self.shift_logits
+    }
+
+    /// Remasking seed.
+    #[must_use]
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+/// Progress passed to the callback of [`diffusion_generate`] after each step.
+#[derive(Debug, Clone, Copy)]
+pub struct DiffusionStep<'a> {
+    /// Steps done so far, starting at 1.
+    pub step: u32,
+    /// Total steps.
+    pub n_steps: u32,
+    /// The canvas after this step, with [`LlamaModel::mask`] at positions
+    /// that are still masked.
+    pub tokens: &'a [LlamaToken],
+    /// Positions still masked.
+    pub n_masked: usize,
+}
+
+/// The result of [`diffusion_generate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffusionOutput {
+    /// The generated tokens, up to the first end-of-generation token.
+    pub tokens: Vec<LlamaToken>,
+    /// Steps that were run.
+    pub n_steps: u32,
+    /// Whether the callback stopped generation early; masked positions may
+    /// then remain in `tokens`.
+    pub cancelled: bool,
+}
+
+/// Generates [`DiffusionConfig::n_gen`] tokens after `prompt` by iterative
+/// unmasking.
+///
+/// Switches the context to non-causal attention for the run and back to
+/// causal attention when it returns, and clears its memory before every
+/// step; the sequence is decoded on sequence `0`. `on_step` sees the
+/// canvas after every step and can return `false` to stop early.
+///
+/// # Errors
+///
+/// Returns an error for non-diffusion models, vocabularies without a mask
+/// token, sequences that don't fit in one micro-batch, block lengths that
+/// don't divide the canvas and steps, and failed decodes.
+#[allow(clippy::cast_precision_loss, clippy::too_many_lines)]
+pub fn diffusion_generate(
+    ctx: &mut LlamaContext<'_>,
+    sampler: &mut LlamaSampler,
+    prompt: &[LlamaToken],
+    config: &DiffusionConfig,
+    mut on_step: impl FnMut(&DiffusionStep<'_>) -> bool,
+) -> Result<DiffusionOutput, DiffusionError> {
+    let model = ctx.model;
+    if !model.is_diffusion() {
+        return Err(DiffusionError::NotDiffusion);
+    }
+    let mask = model.mask();
+    if mask.0 < 0 {
+        return Err(DiffusionError::NoMaskToken);
+    }
+    let n_tokens = prompt.len() + config.n_gen;
+    let limit = ctx.n_ctx().min(ctx.n_batch()).min(ctx.n_ubatch());
+    let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+    if n_tokens > limit {
+        return Err(DiffusionError::TooLong { n_tokens, limit });
+    }
+    let steps = config.steps.max(1);
+    let (n_blocks, block_length) = match config.block_length {
+        None => (1, config.n_gen),
+        Some(block_length) => {
+            let n_blocks = if block_length == 0 {
+                0
+            } else {
+                config.n_gen / block_length
+            };
+            let valid = n_blocks > 0
+                && config.n_gen % block_length == 0
+                && u32::try_from(n_blocks).is_ok_and(|n| steps % n == 0);
+            if !valid {
+                return Err(DiffusionError::InvalidBlockLength {
+                    block_length,
+                    n_gen: config.n_gen,
+                    steps,
+                });
+            }
+            (n_blocks, block_length)
+        }
+    };
+    let steps_per_block = steps / u32::try_from(n_blocks).unwrap_or(1).max(1);
+
+    let mut ctx = NonCausal::new(ctx);
+    let mut tokens = prompt.to_vec();
+    tokens.resize(n_tokens, mask);
+    let mut batch = LlamaBatch::new(n_tokens, 1);
+    let mut rng = SplitMix64(config.seed);
+    let mut n_steps = 0;
+
+    for block in 0..n_blocks {
+        let start = prompt.len() + block * block_length;
+        let end = start + block_length;
+        let n_masked = tokens[start..end].iter().filter(|&&t| t == mask).count();
+        let block_transfers = transfer_counts(n_masked, steps_per_block);
+        for step in 0..steps_per_block {
+            let masked: Vec<usize> = (start..end).filter(|&i| tokens[i] == mask).collect();
+            if masked.is_empty() {
+                break;
+            }
+
+            batch.clear();
+            for (pos, &token) in (0..).zip(&tokens) {
+                batch.add(token, pos, &[0], true)?;
+            }
+            ctx.clear_kv_cache();
+            ctx.decode(&mut batch)?;
+
+            let row = |pos: usize| {
+                let row = if config.shift_logits {
+                    pos.saturating_sub(1)
+                } else {
+                    pos
+                };
+                i32::try_from(row).unwrap_or(i32::MAX)
+            };
+            let (n_transfer, p_transfer) = if config.block_length.is_some() {
+                block_transfer(&block_transfers, masked.len(), step)
+            } else {
+                timestep_transfer(masked.len(), step, steps, config.eps)
+            };
+
+            if config.algorithm == DiffusionAlgorithm::Origin {
+                for &pos in &masked {
+                    if rng.next_f32() < p_transfer {
+                        tokens[pos] = sample(&ctx, sampler, row(pos)).0;
+                    }
+                }
+            } else {
+                let mut scored = Vec::with_capacity(masked.len());
+                for &pos in &masked {
+                    let (token, candidates) = sample(&ctx, sampler, row(pos));
+                    let score = match config.algorithm {
+                        DiffusionAlgorithm::Random => rng.next_f32(),
+                        algorithm => confidence(algorithm, &candidates, token),
+                    };
+                    scored.push((pos, token, score));
+                }
+                let scores: Vec<f32> = scored.iter().map(|&(_, _, score)| score).collect();
+                for i in select(&scores, n_transfer, config.alg_temp, &mut rng) {
+                    let (pos, token, _) = scored[i];
+                    tokens[pos] = token;
+                }
+            }
+
+            n_steps += 1;
+            let progress = DiffusionStep {
+                step: n_steps,
+                n_steps: steps,
+                tokens: &tokens[prompt.len()..],
+                n_masked: tokens[prompt.len()..]
+                    .iter()
+                    .filter(|&&t| t == mask)
+                    .count(),
+            };
+            if !on_step(&progress) {
+                return Ok(output(model, &tokens[prompt.len()..], n_steps, true));
+            }
+        }
+    }
+    Ok(output(model, &tokens[prompt.len()..], n_steps, false))
+}
+
+/// A context switched to non-causal attention that switches back when
+/// dropped, so every exit from [`diffusion_generate`] restores it.
+struct NonCausal<'a, 'm>(&'a mut LlamaContext<'m>);
+
+impl<'a, 'm> NonCausal<'a, 'm> {
+    fn new(ctx: &'a mut LlamaContext<'m>) -> Self {
+        ctx.set_causal_attn(false);
+        Self(ctx)
+    }
+}
+
+impl<'m> Deref for NonCausal<'_, 'm> {
+    type Target = LlamaContext<'m>;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl DerefMut for NonCausal<'_, '_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0
+    }
+}
+
+impl Drop for NonCausal<'_, '_> {
+    fn drop(&mut self) {
+        self.0.set_causal_attn(true);
+    }
+}
+
+fn output(
+    model: &LlamaModel,
+    canvas: &[LlamaToken],
+    n_steps: u32,
+    cancelled: bool,
+) -> DiffusionOutput {
+    let end = canvas
+        .iter()
+        .position(|&t| model.is_eog_token(t))
+        .unwrap_or(canvas.len());
+    DiffusionOutput {
+        tokens: canvas[..end].to_vec(),
+        n_steps,
+        cancelled,
+    }
+}
+
+/// Samples the token for one row and returns it with the candidates that
+/// survived the sampler.
+fn sample(
+    ctx: &LlamaContext<'_>,
+    sampler: &mut LlamaSampler,
+    row: i32,
+) -> (LlamaToken, LlamaTokenDataArray) {
+    let mut candidates = LlamaTokenDataArray::from_iter(ctx.candidates_ith(row), false);
+    candidates.apply_sampler(sampler);
+    let token = candidates.selected_token().unwrap_or_else(|| {
+        candidates
+            .data
+            .iter()
+            .max_by(|a, b| a.logit().total_cmp(&b.logit()))
+            .map_or(LlamaToken(0), |d| d.id())
+    });
+    (token, candidates)
+}
+
+/// How sure the model is about `token`, higher meaning more sure, computed
+/// over the candidates left by the sampler.
+fn confidence(
+    algorithm: DiffusionAlgorithm,
+    candidates: &LlamaTokenDataArray,
+    token: LlamaToken,
+) -> f32 {
+    let logits: Vec<f32> = candidates.data.iter().map(|d| d.logit()).collect();
+    let probs = softmax(&logits);
+    match algorithm {
+        DiffusionAlgorithm::Entropy => probs
+            .iter()
+            .filter(|&&p| p > 0.0)
+            .map(|&p| p * p.ln())
+            .sum(),
+        DiffusionAlgorithm::Margin => {
+            let (mut first, mut second) = (0.0_f32, 0.0_f32);
+            for &p in &probs {
+                if p > first {
+                    second = first;
+                    first = p;
+                } else if p > second {
+                    second = p;
+                }
+            }
+            first - second
+        }
+        _ => candidates
+            .data
+            .iter()
+            .position(|d| d.id() == token)
+            .map_or(0.0, |i| probs[i]),
+    }
+}
+
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = logits.iter().map(|&l| (l - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+/// Splits `n_masked` commits over `steps` steps, front-loading the
+/// remainder.
+fn transfer_counts(n_masked: usize, steps: u32) -> Vec<usize> {
+    let steps = steps.max(1) as usize;
+    let base = n_masked / steps;
+    let remainder = n_masked % steps;
+    (0..steps)
+        .map(|i| base + usize::from(i < remainder))
+        .collect()
+}
+
+/// Commits and commit probability for one step of a block. The last step
+/// commits every mask left in the block, which later blocks never revisit.
+#[allow(clippy::cast_precision_loss)]
+fn block_transfer(block_transfers: &[usize], n_masked: usize, step: u32) -> (usize, f32) {
+    let step = step as usize;
+    if step + 1 >= block_transfers.len() {
+        return (n_masked, 1.0);
+    }
+    let n = block_transfers[step];
+    (n, n as f32 / n_masked as f32)
+}
+
+/// Commits and commit probability for one step of the timestep schedule,
+/// which moves from `t = 1` to `t = eps`. The last step commits everything.
+#[allow(
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+fn timestep_transfer(n_masked: usize, step: u32, steps: u32, eps: f32) -> (usize, f32) {
+    if step + 1 >= steps {
+        return (n_masked, 1.0);
+    }
+    let t = 1.0 - step as f32 / steps as f32 * (1.0 - eps);
+    let s = 1.0 - (step + 1) as f32 / steps as f32 * (1.0 - eps);
+    let p = 1.0 - s / t;
+    ((n_masked as f32 * p) as usize, p)
+}
+
+/// Indices of the `n` positions to commit: the highest scores, or with a
+/// positive `temp` a sample without replacement from `softmax(score / temp)`
+/// (Gumbel top-k).
+fn select(scores: &[f32], n: usize, temp: f32, rng: &mut SplitMix64) -> Vec<usize> {
+    let mut keys: Vec<(usize, f32)> = scores
+        .iter()
+        .enumerate()
+        .map(|(i, &score)| {
+            if temp > 0.0 {
+                let gumbel = -(-rng.next_f32().max(f32::MIN_POSITIVE).ln()).ln();
+                (i, score / temp + gumbel)
+            } else {
+                (i, score)
+            }
+        })
+        .collect();
+    keys.sort_by(|a, b| b.1.total_cmp(&a.1));
+    keys.into_iter().take(n).map(|(i, _)| i).collect()
+}
+
+/// Small deterministic generator for the remasking choices.
+#[derive(Debug)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    #[allow(clippy::cast_precision_loss)]
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1_u64 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::data::LlamaTokenData;
+
+    fn candidates(logits: &[f32]) -> LlamaTokenDataArray {
+        LlamaTokenDataArray::from_iter(
+            (0..)
+                .zip(logits)
+                .map(|(i, &l)| LlamaTokenData::new(LlamaToken(i), l, 0.0)),
+            false,
+        )
+    }
+
+    #[test]
+    fn transfer_counts_cover_every_mask() {
+        assert_eq!(transfer_counts(10, 4), vec![3, 3, 2, 2]);
+        assert_eq!(transfer_counts(2, 4), vec![1, 1, 0, 0]);
+        assert_eq!(transfer_counts(5, 0), vec![5]);
+    }
+
+    #[test]
+    fn block_schedule_finishes_on_last_step() {
+        let counts = transfer_counts(10, 4);
+        let (n, p) = block_transfer(&counts, 10, 0);
+        assert_eq!(n, 3);
+        assert!((p - 0.3).abs() < 1e-6);
+        // Origin may leave more masks than planned; the last step takes them all.
+        let (n, p) = block_transfer(&counts, 5, 3);
+        assert_eq!(n, 5);
+        assert!((p - 1.0).abs() < 1e-6);
+        assert_eq!(block_transfer(&[4], 4, 0).0, 4);
+    }
+
+    #[test]
+    fn timestep_schedule_finishes_on_last_step() {
+        let (n, p) = timestep_transfer(100, 0, 4, 0.0);
+        assert_eq!(n, 25);
+        assert!((p - 0.25).abs() < 1e-6);
+        let (n, p) = timestep_transfer(100, 1, 4, 0.0);
+        assert_eq!(n, 33);
+        assert!((p - 1.0 / 3.0).abs() < 1e-6);
+        assert_eq!(timestep_transfer(7, 3, 4, 0.0).0, 7);
+    }
+
+    #[test]
+    fn confidence_prefers_peaked_distributions() {
+        let peaked = candidates(&[5.0, 0.0, 0.0]);
+        let flat = candidates(&[0.0, 0.0, 0.0]);
+        for algorithm in [
+            DiffusionAlgorithm::Confidence,
+            DiffusionAlgorithm::Entropy,
+            DiffusionAlgorithm::Margin,
+        ] {
+            assert!(
+                confidence(algorithm, &peaked, LlamaToken(0))
+                    > confidence(algorithm, &flat, LlamaToken(0)),
+                "{algorithm:?}"
+            );
+        }
+        let p = confidence(DiffusionAlgorithm::Confidence, &flat, LlamaToken(1));
+        assert!((p - 1.0 / 3.0).abs() < 1e-6);
+        assert!(confidence(DiffusionAlgorithm::Margin, &flat, LlamaToken(0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn select_takes_top_scores_or_samples() {
+        let mut rng = SplitMix64(7);
+        assert_eq!(select(&[0.1, 0.9, 0.5], 2, 0.0, &mut rng), vec![1, 2]);
+        let sampled = select(&[0.1, 0.9, 0.5], 2, 1.0, &mut rng);
+        assert_eq!(sampled.len(), 2);
+        assert_ne!(sampled[0], sampled[1]);
+        assert!(select(&[0.3], 0, 0.0, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn rng_is_uniform_in_unit_interval() {
+        let mut rng = SplitMix64(1);
+        let values: Vec<f32> = (0..1000).map(|_| rng.next_f32()).collect();
+        assert!(values.iter().all(|v| (0.0..1.0).contains(v)));
+        let mean = values.iter().sum::<f32>() / 1000.0;
+        assert!((mean - 0.5).abs() < 0.05);
+    }
+}
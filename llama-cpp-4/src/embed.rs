@@ -0,0 +1,400 @@
+//! Batched text embeddings.
+//!
+//! [`embed`] tokenizes many texts, packs them into as few batches as possible
+//! (one sequence id per text, up to [`LlamaContext::n_seq_max`] sequences and
+//! `n_batch` tokens per batch), and reads the output the context's
+//! [`LlamaPoolingType`] produces: one row per text for mean, CLS and last
+//! pooling, or one row per token without pooling. Rows can be truncated to
+//! their leading dimensions (Matryoshka models) and L2-normalized, and are
+//! returned as one contiguous row-major matrix, [`Embeddings`].
+//!
+//! The context must be created with
+//! [`with_embeddings(true)`](crate::context::params::LlamaContextParams::with_embeddings).
+//! Rank pooling produces relevance scores rather than embeddings; use
+//! [`rerank`](crate::rerank) or [`classify`](crate::classify) for it.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use llama_cpp_4::embed::{cosine_similarity, embed, EmbedConfig};
+//! use llama_cpp_4::prelude::*;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, "bge-small.gguf", &LlamaModelParams::default())?;
+//! let params = LlamaContextParams::default()
+//!     .with_embeddings(true)
+//!     .with_n_seq_max(16);
+//! let mut ctx = model.new_context(&backend, params)?;
+//!
+//! let texts = ["A cat sat on the mat.", "A kitten rested on the rug."];
+//! let config = EmbedConfig::new().with_normalize(true).with_dimensions(Some(256));
+//! let embeddings = embed(&mut ctx, &texts, &config)?;
+//! println!("{}", cosine_similarity(embeddings.row(0), embeddings.row(1)));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ops::Range;
+
+use crate::context::params::LlamaPoolingType;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::AddBos;
+use crate::{DecodeError, EmbeddingsError, StringToTokenError};
+
+/// Failure while embedding texts.
+#[derive(Debug, thiserror::Error)]
+pub enum EmbedError {
+    /// The context uses rank pooling, which produces scores, not embeddings.
+    #[error("rank pooling produces scores; use rerank or classify instead")]
+    RankPooling,
+    /// A text does not fit in one batch and truncation is disabled.
+    #[error("text {index} has {n_tokens} tokens, but a batch holds {limit}")]
+    TooLong {
+        /// Position of the text in the input.
+        index: usize,
+        /// Token count of the text.
+        n_tokens: usize,
+        /// Tokens one batch can hold.
+        limit: usize,
+    },
+    /// A text could not be tokenized.
+    #[error(transparent)]
+    Tokenize(#[from] StringToTokenError),
+    /// A token could not be added to the batch.
+    #[error(transparent)]
+    BatchAdd(#[from] BatchAddError),
+    /// Decoding a batch failed.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    /// The embeddings could not be read from the context.
+    #[error(transparent)]
+    Embeddings(#[from] EmbeddingsError),
+}
+
+/// Post-processing and truncation options for [`embed`].
+///
+/// # Examples
+///
+/// ```
+/// use llama_cpp_4::embed::EmbedConfig;
+///
+/// let config = EmbedConfig::new()
+///     .with_normalize(true)
+///     .with_dimensions(Some(128))
+///     .with_truncate(true);
+/// assert!(config.normalize());
+/// assert_eq!(config.dimensions(), Some(128));
+/// assert!(config.truncate());
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EmbedConfig {
+    normalize: bool,
+    dimensions: Option<usize>,
+    truncate: bool,
+}
+
+impl EmbedConfig {
+    /// Raw embeddings of full length; texts too long for a batch are an
+    /// error.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scale every row to unit L2 norm, after truncating its dimensions.
+    #[must_use]
+    pub fn with_normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Keep only the first `dimensions` values of every row, for models
+    /// trained with Matryoshka representation learning. `None` keeps all.
+    #[must_use]
+    pub fn with_dimensions(mut self, dimensions: Option<usize>) -> Self {
+        self.dimensions = dimensions;
+        self
+    }
+
+    /// Cut texts that do not fit in one batch instead of failing.
+    #[must_use]
+    pub fn with_truncate(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Whether rows are L2-normalized.
+    #[must_use]
+    pub fn normalize(&self) -> bool {
+        self.normalize
+    }
+
+    /// Number of leading dimensions kept.
+    #[must_use]
+    pub fn dimensions(&self) -> Option<usize> {
+        self.dimensions
+    }
+
+    /// Whether texts that do not fit are cut.
+    #[must_use]
+    pub fn truncate(&self) -> bool {
+        self.truncate
+    }
+}
+
+/// A row-major matrix of embeddings returned by [`embed`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Embeddings {
+    data: Vec<f32>,
+    n_embd: usize,
+    /// Rows of every input text.
+    inputs: Vec<Range<usize>>,
+}
+
+impl Embeddings {
+    /// Length of every row.
+    #[must_use]
+    pub fn n_embd(&self) -> usize {
+        self.n_embd
+    }
+
+    /// Number of rows: one per text, or one per token without pooling.
+    #[must_use]
+    pub fn n_rows(&self) -> usize {
+        self.data.len().checked_div(self.n_embd).unwrap_or(0)
+    }
+
+    /// Number of input texts.
+    #[must_use]
+    pub fn n_inputs(&self) -> usize {
+        self.inputs.len()
+    }
+
+    /// Row `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index >= self.n_rows()`.
+    #[must_use]
+    pub fn row(&self, index: usize) -> &[f32] {
+        &self.data[index * self.n_embd..(index + 1) * self.n_embd]
+    }
+
+    /// The rows of input text `index`, contiguous: a single row with pooling,
+    /// one row per token without.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index >= self.n_inputs()`.
+    #[must_use]
+    pub fn input(&self, index: usize) -> &[f32] {
+        let rows = &self.inputs[index];
+        &self.data[rows.start * self.n_embd..rows.end * self.n_embd]
+    }
+
+    /// Iterates over the rows.
+    pub fn rows(&self) -> impl Iterator<Item = &[f32]> {
+        self.data.chunks_exact(self.n_embd.max(1))
+    }
+
+    /// The whole matrix, row-major.
+    #[must_use]
+    pub fn as_slice(&self) -> &[f32] {
+        &self.data
+    }
+
+    /// Takes the row-major matrix.
+    #[must_use]
+    pub fn into_vec(self) -> Vec<f32> {
+        self.data
+    }
+
+    fn push_row(&mut self, row: &[f32], config: &EmbedConfig) {
+        let row = &row[..config
+            .dimensions
+            .map_or(row.len(), |dims| dims.min(row.len()))];
+        self.n_embd = row.len();
+        let start = self.data.len();
+        self.data.extend_from_slice(row);
+        if config.normalize {
+            normalize(&mut self.data[start..]);
+        }
+    }
+}
+
+/// Embeds every text; see the [module documentation](self).
+///
+/// Texts are tokenized with [`AddBos::Always`], which also appends the end
+/// tokens the vocabulary asks for. The memory of the context is cleared
+/// before every batch.
+///
+/// # Errors
+///
+/// Returns [`EmbedError::RankPooling`] for rank-pooling contexts,
+/// [`EmbedError::Embeddings`] when embeddings are not enabled,
+/// [`EmbedError::TooLong`] for a text that does not fit in a batch unless
+/// truncation is enabled, and tokenization or decode errors.
+pub fn embed(
+    ctx: &mut LlamaContext<'_>,
+    texts: &[impl AsRef<str>],
+    config: &EmbedConfig,
+) -> Result<Embeddings, EmbedError> {
+    let pooling = ctx.pooling_type();
+    if pooling == LlamaPoolingType::Rank {
+        return Err(EmbedError::RankPooling);
+    }
+    let per_token = pooling == LlamaPoolingType::None;
+    let model = ctx.model;
+    // Non-causal models must see a whole text in one micro-batch, and causal
+    // ones keep every token of the batch in memory.
+    let limit =
+        usize::try_from(ctx.n_batch().min(ctx.n_ubatch()).min(ctx.n_ctx())).unwrap_or(usize::MAX);
+    let n_seq_max = usize::try_from(ctx.n_seq_max())
+        .unwrap_or(usize::MAX)
+        .max(1);
+
+    let mut inputs = Vec::with_capacity(texts.len());
+    for (index, text) in texts.iter().enumerate() {
+        let mut tokens = model.str_to_token(text.as_ref(), AddBos::Always)?;
+        if tokens.len() > limit {
+            if !config.truncate {
+                return Err(EmbedError::TooLong {
+                    index,
+                    n_tokens: tokens.len(),
+                    limit,
+                });
+            }
+            tokens.truncate(limit);
+        }
+        inputs.push(tokens);
+    }
+
+    let mut embeddings = Embeddings::default();
+    let mut batch = LlamaBatch::new(limit, 1);
+    for group in pack(inputs.iter().map(Vec::len), limit, n_seq_max) {
+        batch.clear();
+        for (seq_id, tokens) in (0..).zip(&inputs[group.clone()]) {
+            batch.add_sequence(tokens, seq_id, per_token)?;
+        }
+        ctx.clear_kv_cache();
+        ctx.decode(&mut batch)?;
+
+        let mut token_index = 0;
+        for (seq_id, tokens) in (0..).zip(&inputs[group]) {
+            let start = embeddings.n_rows();
+            if per_token {
+                for _ in tokens {
+                    embeddings.push_row(ctx.embeddings_ith(token_index)?, config);
+                    token_index += 1;
+                }
+            } else {
+                embeddings.push_row(ctx.embeddings_seq_ith(seq_id)?, config);
+            }
+            let end = embeddings.n_rows();
+            embeddings.inputs.push(start..end);
+        }
+    }
+    Ok(embeddings)
+}
+
+/// Scales `values` to unit L2 norm. A zero vector is left unchanged.
+pub fn normalize(values: &mut [f32]) {
+    let norm = values
+        .iter()
+        .fold(0.0_f32, |acc, &value| value.mul_add(value, acc))
+        .sqrt();
+    if norm > 0.0 {
+        for value in values {
+            *value /= norm;
+        }
+    }
+}
+
+/// Cosine similarity of two vectors of equal length, as llama.cpp's
+/// `common_embd_similarity_cos` computes it: two zero vectors are identical,
+/// one zero vector is unrelated to anything.
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let (mut dot, mut norm_a, mut norm_b) = (0.0_f64, 0.0_f64, 0.0_f64);
+    for (&x, &y) in a.iter().zip(b) {
+        let (x, y) = (f64::from(x), f64::from(y));
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return if norm_a == 0.0 && norm_b == 0.0 {
+            1.0
+        } else {
+            0.0
+        };
+    }
+    (dot / (norm_a.sqrt() * norm_b.sqrt())) as f32
+}
+
+/// Groups consecutive items of the given lengths into runs of at most
+/// `max_seqs` items and `max_tokens` tokens. Every item must fit on its own.
+pub(crate) fn pack(
+    lengths: impl IntoIterator<Item = usize>,
+    max_tokens: usize,
+    max_seqs: usize,
+) -> Vec<Range<usize>> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    let mut n_tokens = 0;
+    let mut end = 0;
+    for len in lengths {
+        if end > start && (end - start == max_seqs || n_tokens + len > max_tokens) {
+            groups.push(start..end);
+            start = end;
+            n_tokens = 0;
+        }
+        n_tokens += len;
+        end += 1;
+    }
+    if end > start {
+        groups.push(start..end);
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_respects_token_and_sequence_limits() {
+        assert_eq!(pack([3, 3, 3, 3], 7, 8), vec![0..2, 2..4]);
+        assert_eq!(pack([1, 1, 1, 1, 1], 100, 2), vec![0..2, 2..4, 4..5]);
+        assert_eq!(pack([7, 1], 7, 8), vec![0..1, 1..2]);
+        assert!(pack([], 7, 8).is_empty());
+    }
+
+    #[test]
+    fn rows_are_truncated_then_normalized() {
+        let config = EmbedConfig::new()
+            .with_dimensions(Some(2))
+            .with_normalize(true);
+        let mut embeddings = Embeddings::default();
+        embeddings.push_row(&[3.0, 4.0, 100.0], &config);
+        embeddings.push_row(&[0.0, 0.0, 1.0], &config);
+        embeddings.inputs = vec![0..1, 1..2];
+        assert_eq!(embeddings.n_embd(), 2);
+        assert_eq!(embeddings.n_rows(), 2);
+        assert_eq!(embeddings.row(0), &[0.6, 0.8]);
+        assert_eq!(embeddings.input(1), &[0.0, 0.0]);
+        assert_eq!(embeddings.as_slice().len(), 4);
+    }
+
+    #[test]
+    fn cosine_similarity_handles_zero_vectors() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[1.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 1.0], &[-1.0, -1.0]) + 1.0).abs() < 1e-6);
+        assert!((cosine_similarity(&[0.0, 0.0], &[0.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]).abs() < 1e-6);
+    }
+}
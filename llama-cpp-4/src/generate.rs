@@ -0,0 +1,940 @@
+//! High-level streaming text generation.
+//!
+//! [`Generator`] owns the loop that every generation program otherwise
+//! re-implements: prefill the prompt (split into `n_batch`-sized decodes),
+//! sample, decode the sampled token, detokenize it incrementally, and check the
+//! stop conditions. It is an [`Iterator`] of [`GeneratedPiece`]s; the last
+//! piece carries the [`FinishReason`].
+//!
+//! Text is produced through a [`StreamDetokenizer`], so multi-byte characters
+//! split across tokens are emitted whole. Stop strings are matched on the
+//! decoded text: any trailing text that could still grow into a stop string is
+//! held back until the next token disambiguates it, and the stop string itself
+//! is never emitted.
+//!
+//! [`GeneratorConfig::with_reasoning_budget`] caps a reasoning model's
+//! thinking: once the budget is spent, the closing tag is decoded in place of
+//! sampled tokens and the model continues with its answer.
+//!
+//! For encoder–decoder models such as T5, [`Generator::seq2seq`] runs the
+//! encoder on the input and starts the decoder from
+//! [`LlamaModel::decode_start_token`].
+//!
+//! ```no_run
+//! use llama_cpp_4::generate::{Generator, GeneratorConfig};
+//! use llama_cpp_4::prelude::*;
+//!
+//! # fn demo(model: &LlamaModel, ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! let prompt = model.str_to_token("Once upon a time", AddBos::Always)?;
+//! let sampler = LlamaSampler::chain_simple([LlamaSampler::temp(0.8), LlamaSampler::dist(0)]);
+//! let config = GeneratorConfig::new()
+//!     .with_max_tokens(Some(64))
+//!     .with_stop_strings(["\n\n"]);
+//!
+//! for piece in Generator::new(ctx, sampler, &prompt, config)? {
+//!     let piece = piece?;
+//!     print!("{}", piece.text);
+//!     if let Some(reason) = piece.finish_reason {
+//!         println!("\n[{reason:?}]");
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::self_extend::SelfExtendError;
+use crate::context::shift::{ContextShift, ContextShiftConfig, ContextShiftError};
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::{LlamaModel, Special};
+use crate::reasoning::{ReasoningBudget, ReasoningConfig};
+use crate::sampling::LlamaSampler;
+use crate::token::detokenizer::{DetokenizeError, StreamDetokenizer};
+use crate::token::{LlamaToken, TOKEN_NULL};
+use crate::{DecodeError, EncodeError, StringToTokenError};
+
+/// Errors raised while generating.
+#[derive(Debug, thiserror::Error)]
+pub enum GenerateError {
+    /// The prompt contained no tokens, so there are no logits to sample from.
+    #[error("prompt is empty")]
+    EmptyPrompt,
+    /// The prompt does not fit in the context window.
+    #[error("prompt of {n_prompt} tokens at position {start} exceeds the {n_ctx}-token context")]
+    PromptTooLong {
+        /// Prompt token count.
+        n_prompt: usize,
+        /// Position of the first prompt token.
+        start: i32,
+        /// Per-sequence context size.
+        n_ctx: u32,
+    },
+    /// A token could not be added to the decode batch.
+    #[error(transparent)]
+    BatchAdd(#[from] BatchAddError),
+    /// The context failed to decode a batch.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    /// A sampled token could not be detokenized.
+    #[error(transparent)]
+    Detokenize(#[from] DetokenizeError),
+    /// A closing tag for the reasoning budget could not be tokenized.
+    #[error(transparent)]
+    Tokenize(#[from] StringToTokenError),
+    /// The context could not be shifted.
+    #[error(transparent)]
+    ContextShift(#[from] ContextShiftError),
+    /// Self-Extend could not be applied.
+    #[error(transparent)]
+    SelfExtend(#[from] SelfExtendError),
+    /// [`Generator::seq2seq`] was used with a model that has no encoder.
+    #[error("the model has no encoder")]
+    NoEncoder,
+    /// The encoder input does not fit in one micro-batch.
+    #[error("encoder input of {n_input} tokens exceeds n_ubatch = {n_ubatch}")]
+    InputTooLong {
+        /// Input token count.
+        n_input: usize,
+        /// Micro-batch size of the context.
+        n_ubatch: u32,
+    },
+    /// The encoder failed.
+    #[error(transparent)]
+    Encode(#[from] EncodeError),
+}
+
+/// Why generation ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The model produced an end-of-generation token or one of
+    /// [`GeneratorConfig::stop_tokens`].
+    Eog,
+    /// [`GeneratorConfig::max_tokens`] tokens were generated.
+    MaxTokens,
+    /// The generated text contained this stop string.
+    StopString(String),
+    /// The sequence reached the end of the context window and context
+    /// shifting is not enabled.
+    ContextFull,
+}
+
+/// One step of generation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GeneratedPiece {
+    /// The sampled token.
+    pub token: LlamaToken,
+    /// Text released by this step. May be empty while a multi-byte character
+    /// or a possible stop-string prefix is held back, and may include text
+    /// held back by earlier steps.
+    pub text: String,
+    /// Set on the final piece only.
+    pub finish_reason: Option<FinishReason>,
+    /// Set when the sequence was shifted to make room for this token. Apply it
+    /// to a caller-side token history before appending `token`.
+    pub context_shift: Option<ContextShift>,
+}
+
+/// Stop conditions and placement for a [`Generator`].
+///
+/// # Examples
+///
+/// ```
+/// use llama_cpp_4::generate::GeneratorConfig;
+///
+/// let config = GeneratorConfig::new()
+///     .with_max_tokens(Some(128))
+///     .with_stop_strings(["</s>", "\nUser:"])
+///     .with_seq_id(2);
+/// assert_eq!(config.max_tokens(), Some(128));
+/// assert_eq!(config.stop_strings().len(), 2);
+/// assert_eq!(config.seq_id(), 2);
+/// assert!(config.stop_on_eog());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratorConfig {
+    max_tokens: Option<usize>,
+    stop_strings: Vec<String>,
+    stop_on_eog: bool,
+    stop_tokens: Vec<LlamaToken>,
+    seq_id: i32,
+    start_pos: Option<i32>,
+    special: Special,
+    context_shift: Option<ContextShiftConfig>,
+    reasoning_budget: Option<usize>,
+    reasoning: ReasoningConfig,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self {
+            max_tokens: None,
+            stop_strings: Vec::new(),
+            stop_on_eog: true,
+            stop_tokens: Vec::new(),
+            seq_id: 0,
+            start_pos: None,
+            special: Special::Plaintext,
+            context_shift: None,
+            reasoning_budget: None,
+            reasoning: ReasoningConfig::new(),
+        }
+    }
+}
+
+impl GeneratorConfig {
+    /// Unlimited generation on sequence `0` that stops at end-of-generation
+    /// tokens.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop after this many generated tokens. `None` means no limit; with
+    /// `Some(0)` the prompt is decoded but nothing is sampled.
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: Option<usize>) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Stop when the generated text contains any of these strings. Empty
+    /// strings are ignored.
+    #[must_use]
+    pub fn with_stop_strings(mut self, stops: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.stop_strings = stops
+            .into_iter()
+            .map(Into::into)
+            .filter(|stop: &String| !stop.is_empty())
+            .collect();
+        self
+    }
+
+    /// Whether to stop at tokens for which
+    /// [`LlamaModel::is_eog_token`] holds. Defaults to `true`.
+    #[must_use]
+    pub fn with_stop_on_eog(mut self, stop_on_eog: bool) -> Self {
+        self.stop_on_eog = stop_on_eog;
+        self
+    }
+
+    /// Further tokens that end generation like end-of-generation tokens,
+    /// with [`FinishReason::Eog`]. They are not rendered into the text.
+    #[must_use]
+    pub fn with_stop_tokens(mut self, tokens: impl IntoIterator<Item = LlamaToken>) -> Self {
+        self.stop_tokens = tokens.into_iter().collect();
+        self
+    }
+
+    /// Sequence the prompt and generated tokens are decoded into.
+    #[must_use]
+    pub fn with_seq_id(mut self, seq_id: i32) -> Self {
+        self.seq_id = seq_id;
+        self
+    }
+
+    /// Position of the first prompt token. `None` (the default) continues
+    /// after the last position already cached for the sequence.
+    #[must_use]
+    pub fn with_start_pos(mut self, start_pos: Option<i32>) -> Self {
+        self.start_pos = start_pos;
+        self
+    }
+
+    /// How special tokens are rendered into [`GeneratedPiece::text`].
+    /// Defaults to [`Special::Plaintext`].
+    #[must_use]
+    pub fn with_special(mut self, special: Special) -> Self {
+        self.special = special;
+        self
+    }
+
+    /// Shift the sequence instead of finishing with
+    /// [`FinishReason::ContextFull`] when it fills the context. Defaults to
+    /// `None`. Requires [`LlamaContext::memory_can_shift`] and cannot be
+    /// combined with [`LlamaContext::self_extend`]; the
+    /// [`BatchScheduler`](crate::scheduler::BatchScheduler) does not shift.
+    #[must_use]
+    pub fn with_context_shift(mut self, context_shift: Option<ContextShiftConfig>) -> Self {
+        self.context_shift = context_shift;
+        self
+    }
+
+    /// Close the reasoning after this many tokens inside it by injecting the
+    /// closing tag, so the model goes on to answer. Defaults to `None`; the
+    /// [`BatchScheduler`](crate::scheduler::BatchScheduler) ignores it.
+    #[must_use]
+    pub fn with_reasoning_budget(mut self, reasoning_budget: Option<usize>) -> Self {
+        self.reasoning_budget = reasoning_budget;
+        self
+    }
+
+    /// Tags the reasoning budget applies to. Defaults to
+    /// [`ReasoningConfig::new`]; use [`ReasoningConfig::with_prompt`] when
+    /// the prompt opens the reasoning.
+    #[must_use]
+    pub fn with_reasoning(mut self, reasoning: ReasoningConfig) -> Self {
+        self.reasoning = reasoning;
+        self
+    }
+
+    /// Maximum generated tokens.
+    #[must_use]
+    pub fn max_tokens(&self) -> Option<usize> {
+        self.max_tokens
+    }
+
+    /// Stop strings.
+    #[must_use]
+    pub fn stop_strings(&self) -> &[String] {
+        &self.stop_strings
+    }
+
+    /// Whether end-of-generation tokens stop generation.
+    #[must_use]
+    pub fn stop_on_eog(&self) -> bool {
+        self.stop_on_eog
+    }
+
+    /// Extra tokens that end generation.
+    #[must_use]
+    pub fn stop_tokens(&self) -> &[LlamaToken] {
+        &self.stop_tokens
+    }
+
+    /// Target sequence id.
+    #[must_use]
+    pub fn seq_id(&self) -> i32 {
+        self.seq_id
+    }
+
+    /// Explicit start position, if set.
+    #[must_use]
+    pub fn start_pos(&self) -> Option<i32> {
+        self.start_pos
+    }
+
+    /// Special-token rendering.
+    #[must_use]
+    pub fn special(&self) -> Special {
+        self.special
+    }
+
+    /// Context shift settings, if enabled.
+    #[must_use]
+    pub fn context_shift(&self) -> Option<ContextShiftConfig> {
+        self.context_shift
+    }
+
+    /// Maximum reasoning tokens, if limited.
+    #[must_use]
+    pub fn reasoning_budget(&self) -> Option<usize> {
+        self.reasoning_budget
+    }
+
+    /// Reasoning tags for the budget.
+    #[must_use]
+    pub fn reasoning(&self) -> &ReasoningConfig {
+        &self.reasoning
+    }
+}
+
+/// Streaming generator over a [`LlamaContext`].
+///
+/// Created with [`Generator::new`], which decodes the prompt eagerly. Each
+/// call to [`Iterator::next`] samples one token; the token is decoded lazily
+/// at the start of the following call, so finishing never costs an extra
+/// decode. After a piece with a [`FinishReason`] or an error the iterator is
+/// fused.
+#[derive(Debug)]
+pub struct Generator<'c, 'm> {
+    ctx: &'c mut LlamaContext<'m>,
+    sampler: LlamaSampler,
+    config: GeneratorConfig,
+    batch: LlamaBatch,
+    output: SequenceOutput<'m>,
+    budget: Option<ReasoningBudget<'m>>,
+    generated: Vec<LlamaToken>,
+    n_past: i32,
+    logits_index: i32,
+    undecoded: Option<LlamaToken>,
+    finished: bool,
+}
+
+impl<'c, 'm> Generator<'c, 'm> {
+    /// Decodes `prompt` into the configured sequence and prepares sampling.
+    ///
+    /// Prompts longer than [`LlamaContext::n_batch`] are decoded in several
+    /// batches; only the last prompt token requests logits. When the context
+    /// has [`LlamaContext::self_extend`] set, it is applied before every
+    /// batch.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error for an empty prompt, a prompt that does not fit the
+    /// context, context shifting on a context that cannot shift or uses
+    /// Self-Extend, a reasoning closing tag that cannot be tokenized, or a
+    /// failed decode.
+    pub fn new(
+        ctx: &'c mut LlamaContext<'m>,
+        sampler: LlamaSampler,
+        prompt: &[LlamaToken],
+        config: GeneratorConfig,
+    ) -> Result<Self, GenerateError> {
+        if prompt.is_empty() {
+            return Err(GenerateError::EmptyPrompt);
+        }
+        if config.context_shift.is_some() {
+            if ctx.self_extend().is_some() {
+                return Err(SelfExtendError::WithContextShift.into());
+            }
+            if !ctx.memory_can_shift() {
+                return Err(ContextShiftError::Unsupported.into());
+            }
+        }
+        let model = ctx.model;
+        let config_max_tokens = config.max_tokens;
+        let budget = config
+            .reasoning_budget
+            .map(|max_tokens| ReasoningBudget::new(model, config.reasoning.clone(), max_tokens))
+            .transpose()?;
+        let start = config
+            .start_pos
+            .unwrap_or_else(|| ctx.kv_cache_seq_pos_max(config.seq_id) + 1)
+            .max(0);
+        let n_ctx = ctx.n_ctx_seq();
+        let n_cached = i64::from(start) + i64::from(ctx.self_extend_compressed(config.seq_id));
+        let fits = n_cached + i64::try_from(prompt.len()).unwrap_or(i64::MAX) <= i64::from(n_ctx);
+        if !fits {
+            return Err(GenerateError::PromptTooLong {
+                n_prompt: prompt.len(),
+                start,
+                n_ctx,
+            });
+        }
+
+        let n_batch = usize::try_from(ctx.n_batch()).unwrap_or(usize::MAX).max(1);
+        let mut batch = LlamaBatch::new(n_batch.min(prompt.len()), 1);
+        let n_past = decode_prompt(ctx, &mut batch, prompt, start, config.seq_id, n_batch)?;
+        let logits_index = batch.n_tokens() - 1;
+
+        Ok(Self {
+            ctx,
+            sampler,
+            output: SequenceOutput::new(model, &config),
+            budget,
+            config,
+            batch,
+            generated: Vec::new(),
+            n_past,
+            logits_index,
+            undecoded: None,
+            finished: config_max_tokens == Some(0),
+        })
+    }
+
+    /// Encodes `input` with the encoder of an encoder–decoder model, then
+    /// decodes [`LlamaModel::decode_start_token`] (or BOS when the model has
+    /// none) at position 0 of the configured sequence and prepares sampling.
+    ///
+    /// Tokenize the input with [`AddBos::Always`](crate::model::AddBos::Always) so
+    /// that the end-of-sequence token T5 models expect is appended. Earlier
+    /// decoder state of the sequence is cleared; the output streams like any
+    /// other [`Generator`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`GenerateError::NoEncoder`] for decoder-only models,
+    /// [`GenerateError::InputTooLong`] when the input exceeds
+    /// [`LlamaContext::n_ubatch`], and the errors of [`LlamaContext::encode`]
+    /// and [`Generator::new`].
+    pub fn seq2seq(
+        ctx: &'c mut LlamaContext<'m>,
+        sampler: LlamaSampler,
+        input: &[LlamaToken],
+        config: GeneratorConfig,
+    ) -> Result<Self, GenerateError> {
+        let model = ctx.model;
+        if !model.has_encoder() {
+            return Err(GenerateError::NoEncoder);
+        }
+        if input.is_empty() {
+            return Err(GenerateError::EmptyPrompt);
+        }
+        let n_ubatch = ctx.n_ubatch();
+        if input.len() > usize::try_from(n_ubatch).unwrap_or(usize::MAX) {
+            return Err(GenerateError::InputTooLong {
+                n_input: input.len(),
+                n_ubatch,
+            });
+        }
+
+        let mut batch = LlamaBatch::new(input.len(), 1);
+        batch.add_sequence(input, config.seq_id, false)?;
+        ctx.encode(&mut batch)?;
+
+        // A non-negative sequence id always converts back, so this cannot fail.
+        if let Ok(seq_id) = u32::try_from(config.seq_id) {
+            let _ = ctx.clear_kv_cache_seq(Some(seq_id), None, None);
+        }
+        let start = match model.decode_start_token() {
+            TOKEN_NULL => model.token_bos(),
+            token => token,
+        };
+        Self::new(ctx, sampler, &[start], config.with_start_pos(Some(0)))
+    }
+
+    /// Tokens generated so far, including a final end-of-generation token.
+    #[must_use]
+    pub fn generated_tokens(&self) -> &[LlamaToken] {
+        &self.generated
+    }
+
+    /// Next position to be written in the sequence.
+    ///
+    /// Counts the most recently sampled token only once it has been decoded.
+    #[must_use]
+    pub fn n_past(&self) -> i32 {
+        self.n_past
+    }
+
+    /// Whether a finishing piece or an error has been returned.
+    #[must_use]
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The configuration this generator was created with.
+    #[must_use]
+    pub fn config(&self) -> &GeneratorConfig {
+        &self.config
+    }
+
+    /// The context being generated into.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'m> {
+        self.ctx
+    }
+
+    /// The sampler chain.
+    pub fn sampler_mut(&mut self) -> &mut LlamaSampler {
+        &mut self.sampler
+    }
+
+    /// Ends generation and returns the sampler chain.
+    #[must_use]
+    pub fn into_sampler(self) -> LlamaSampler {
+        self.sampler
+    }
+
+    /// Runs generation to completion and returns the text and finish reason.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error raised by a step.
+    pub fn collect_text(mut self) -> Result<(String, FinishReason), GenerateError> {
+        let mut text = String::new();
+        if self.finished {
+            return Ok((text, FinishReason::MaxTokens));
+        }
+        loop {
+            let piece = self.step()?;
+            text.push_str(&piece.text);
+            if let Some(reason) = piece.finish_reason {
+                self.finished = true;
+                return Ok((text, reason));
+            }
+        }
+    }
+
+    fn step(&mut self) -> Result<GeneratedPiece, GenerateError> {
+        if let Some(token) = self.undecoded.take() {
+            self.n_past = self
+                .ctx
+                .apply_self_extend(self.config.seq_id, self.n_past)?;
+            self.batch.clear();
+            self.batch
+                .add(token, self.n_past, &[self.config.seq_id], true)?;
+            self.ctx.decode(&mut self.batch)?;
+            self.n_past += 1;
+            self.logits_index = 0;
+        }
+
+        let forced = self.budget.as_mut().and_then(ReasoningBudget::forced_token);
+        let token = match forced {
+            Some(token) => {
+                self.sampler.accept(token);
+                token
+            }
+            None => self.sampler.sample(self.ctx, self.logits_index),
+        };
+        if let Some(budget) = &mut self.budget {
+            budget.push(token)?;
+        }
+        self.generated.push(token);
+
+        // With Self-Extend the sequence holds more tokens than positions.
+        let n_tokens =
+            i64::from(self.n_past) + i64::from(self.ctx.self_extend_compressed(self.config.seq_id));
+        let context_full = n_tokens >= i64::from(self.ctx.n_ctx_seq());
+        let shift = self.config.context_shift.filter(|_| context_full);
+        let mut piece =
+            self.output
+                .piece(token, self.generated.len(), context_full && shift.is_none())?;
+        if piece.finish_reason.is_none() {
+            if let Some(shift) = shift {
+                let event = self.ctx.shift_context(self.config.seq_id, &shift)?;
+                self.n_past = event.n_past;
+                piece.context_shift = Some(event);
+            }
+            self.undecoded = Some(token);
+        }
+        Ok(piece)
+    }
+}
+
+impl Iterator for Generator<'_, '_> {
+    type Item = Result<GeneratedPiece, GenerateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let step = self.step();
+        self.finished = match &step {
+            Ok(piece) => piece.finish_reason.is_some(),
+            Err(_) => true,
+        };
+        Some(step)
+    }
+}
+
+impl std::iter::FusedIterator for Generator<'_, '_> {}
+
+/// Turns the sampled tokens of one sequence into released text and applies the
+/// per-token stop conditions of a [`GeneratorConfig`].
+///
+/// Shared by [`Generator`] and [`BatchScheduler`](crate::scheduler::BatchScheduler)
+/// so both report identical pieces for identical tokens.
+#[derive(Debug)]
+pub(crate) struct SequenceOutput<'m> {
+    model: &'m LlamaModel,
+    special: Special,
+    stop_on_eog: bool,
+    stop_tokens: Vec<LlamaToken>,
+    max_tokens: Option<usize>,
+    detokenizer: StreamDetokenizer<'m>,
+    stops: StopMatcher,
+}
+
+impl<'m> SequenceOutput<'m> {
+    pub(crate) fn new(model: &'m LlamaModel, config: &GeneratorConfig) -> Self {
+        Self {
+            model,
+            special: config.special,
+            stop_on_eog: config.stop_on_eog,
+            stop_tokens: config.stop_tokens.clone(),
+            max_tokens: config.max_tokens,
+            detokenizer: StreamDetokenizer::new(model, config.special),
+            stops: StopMatcher::new(config.stop_strings.clone()),
+        }
+    }
+
+    /// Builds the piece for the `n_generated`-th sampled `token`.
+    /// `context_full` reports that the sequence has no room to decode it.
+    pub(crate) fn piece(
+        &mut self,
+        token: LlamaToken,
+        n_generated: usize,
+        context_full: bool,
+    ) -> Result<GeneratedPiece, DetokenizeError> {
+        let eog = self.stop_on_eog && self.model.is_eog_token(token);
+        if eog || self.stop_tokens.contains(&token) {
+            return Ok(self.finish(token, String::new(), FinishReason::Eog));
+        }
+
+        let text = self.detokenizer.push(token)?;
+        let text = match self.stops.push(&text) {
+            StopScan::Emit(text) => text,
+            StopScan::Stopped { text, stop } => {
+                return Ok(GeneratedPiece {
+                    token,
+                    text,
+                    finish_reason: Some(FinishReason::StopString(stop)),
+                    context_shift: None,
+                });
+            }
+        };
+
+        if self.max_tokens.is_some_and(|max| n_generated >= max) {
+            return Ok(self.finish(token, text, FinishReason::MaxTokens));
+        }
+        if context_full {
+            return Ok(self.finish(token, text, FinishReason::ContextFull));
+        }
+        Ok(GeneratedPiece {
+            token,
+            text,
+            finish_reason: None,
+            context_shift: None,
+        })
+    }
+
+    /// Builds the final piece, flushing held-back bytes and text.
+    fn finish(
+        &mut self,
+        token: LlamaToken,
+        mut text: String,
+        reason: FinishReason,
+    ) -> GeneratedPiece {
+        let detokenizer = std::mem::replace(
+            &mut self.detokenizer,
+            StreamDetokenizer::new(self.model, self.special),
+        );
+        let tail = match detokenizer.finish() {
+            Ok(tail) => tail,
+            Err(DetokenizeError::IncompleteUtf8(bytes)) => {
+                String::from_utf8_lossy(&bytes).into_owned()
+            }
+            Err(_) => String::new(),
+        };
+        match self.stops.finish(&tail) {
+            StopScan::Emit(tail) => {
+                text.push_str(&tail);
+                GeneratedPiece {
+                    token,
+                    text,
+                    finish_reason: Some(reason),
+                    context_shift: None,
+                }
+            }
+            StopScan::Stopped { text: tail, stop } => {
+                text.push_str(&tail);
+                GeneratedPiece {
+                    token,
+                    text,
+                    finish_reason: Some(FinishReason::StopString(stop)),
+                    context_shift: None,
+                }
+            }
+        }
+    }
+}
+
+/// Decodes `prompt` at `start` in chunks of at most `n_batch` tokens,
+/// applying Self-Extend between chunks and requesting logits for the final
+/// token only. Returns the next free position.
+pub(crate) fn decode_prompt(
+    ctx: &mut LlamaContext<'_>,
+    batch: &mut LlamaBatch,
+    prompt: &[LlamaToken],
+    start: i32,
+    seq_id: i32,
+    n_batch: usize,
+) -> Result<i32, GenerateError> {
+    let mut pos = start;
+    let mut rest = prompt;
+    while !rest.is_empty() {
+        // Self-Extend merges full windows between batches, so a batch must
+        // not run past the current window.
+        pos = ctx.apply_self_extend(seq_id, pos)?;
+        let len = rest
+            .len()
+            .min(n_batch)
+            .min(ctx.self_extend_room(seq_id, pos));
+        let (chunk, tail) = rest.split_at(len);
+        batch.clear();
+        for (offset, &token) in chunk.iter().enumerate() {
+            let is_last = tail.is_empty() && offset + 1 == len;
+            batch.add(token, pos, &[seq_id], is_last)?;
+            pos += 1;
+        }
+        ctx.decode(batch)?;
+        rest = tail;
+    }
+    Ok(pos)
+}
+
+/// Result of feeding text to a [`StopMatcher`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum StopScan {
+    /// No stop string yet; this text is safe to release.
+    Emit(String),
+    /// A stop string matched; `text` precedes it.
+    Stopped {
+        /// Text before the stop string.
+        text: String,
+        /// The matched stop string.
+        stop: String,
+    },
+}
+
+/// Incremental stop-string matcher with holdback across chunk boundaries.
+///
+/// Text that ends with a proper prefix of any stop string is withheld until
+/// later text either completes the stop string or rules it out.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct StopMatcher {
+    stops: Vec<String>,
+    pending: String,
+}
+
+impl StopMatcher {
+    pub(crate) fn new(stops: Vec<String>) -> Self {
+        Self {
+            stops: stops.into_iter().filter(|stop| !stop.is_empty()).collect(),
+            pending: String::new(),
+        }
+    }
+
+    /// Feeds `text`, returning what can be released.
+    pub(crate) fn push(&mut self, text: &str) -> StopScan {
+        self.pending.push_str(text);
+        if let Some(scan) = self.take_match() {
+            return scan;
+        }
+        let hold = self.holdback();
+        let release = self.pending.len() - hold;
+        let rest = self.pending.split_off(release);
+        StopScan::Emit(std::mem::replace(&mut self.pending, rest))
+    }
+
+    /// Feeds the final `text` and releases everything still withheld.
+    pub(crate) fn finish(&mut self, text: &str) -> StopScan {
+        self.pending.push_str(text);
+        self.take_match()
+            .unwrap_or_else(|| StopScan::Emit(std::mem::take(&mut self.pending)))
+    }
+
+    /// Earliest stop-string occurrence in the pending text; on ties the
+    /// longest stop string wins.
+    fn take_match(&mut self) -> Option<StopScan> {
+        let (position, stop) = self
+            .stops
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()).map(|at| (at, stop)))
+            .min_by(|(a, a_stop), (b, b_stop)| a.cmp(b).then(b_stop.len().cmp(&a_stop.len())))?;
+        let stop = stop.clone();
+        self.pending.truncate(position);
+        Some(StopScan::Stopped {
+            text: std::mem::take(&mut self.pending),
+            stop,
+        })
+    }
+
+    /// Length of the longest pending suffix that is a proper prefix of a stop
+    /// string.
+    fn holdback(&self) -> usize {
+        self.stops
+            .iter()
+            .filter_map(|stop| {
+                (1..stop.len())
+                    .rev()
+                    .filter(|&len| stop.is_char_boundary(len))
+                    .find(|&len| self.pending.ends_with(&stop[..len]))
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(stops: &[&str]) -> StopMatcher {
+        StopMatcher::new(stops.iter().map(ToString::to_string).collect())
+    }
+
+    fn emitted(scan: StopScan) -> String {
+        match scan {
+            StopScan::Emit(text) => text,
+            StopScan::Stopped { .. } => panic!("unexpected stop: {scan:?}"),
+        }
+    }
+
+    #[test]
+    fn releases_text_without_stop_strings() {
+        let mut stops = matcher(&[]);
+        assert_eq!(emitted(stops.push("hello ")), "hello ");
+        assert_eq!(emitted(stops.finish("world")), "world");
+    }
+
+    #[test]
+    fn holds_back_possible_prefix_across_pieces() {
+        let mut stops = matcher(&["</end>"]);
+        assert_eq!(emitted(stops.push("answer </")), "answer ");
+        assert_eq!(emitted(stops.push("e")), "");
+        assert_eq!(
+            stops.push("nd> trailing"),
+            StopScan::Stopped {
+                text: String::new(),
+                stop: "</end>".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn releases_prefix_that_is_ruled_out() {
+        let mut stops = matcher(&["\n\nUser:"]);
+        assert_eq!(emitted(stops.push("line\n\n")), "line");
+        assert_eq!(emitted(stops.push("Us")), "");
+        assert_eq!(emitted(stops.push("ually")), "\n\nUsually");
+    }
+
+    #[test]
+    fn stop_inside_a_single_piece() {
+        let mut stops = matcher(&["STOP"]);
+        assert_eq!(
+            stops.push("abcSTOPdef"),
+            StopScan::Stopped {
+                text: "abc".to_owned(),
+                stop: "STOP".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn earliest_and_longest_stop_wins() {
+        let mut stops = matcher(&["b", "ab", "abc"]);
+        assert_eq!(
+            stops.push("xabcd"),
+            StopScan::Stopped {
+                text: "x".to_owned(),
+                stop: "abc".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn holdback_respects_char_boundaries() {
+        let mut stops = matcher(&["é!"]);
+        assert_eq!(emitted(stops.push("café")), "caf");
+        assert_eq!(emitted(stops.push("?")), "é?");
+    }
+
+    #[test]
+    fn finish_flushes_held_back_text() {
+        let mut stops = matcher(&["###"]);
+        assert_eq!(emitted(stops.push("x##")), "x");
+        assert_eq!(emitted(stops.finish("")), "##");
+    }
+
+    #[test]
+    fn finish_still_detects_stop() {
+        let mut stops = matcher(&["###"]);
+        assert_eq!(emitted(stops.push("x##")), "x");
+        assert_eq!(
+            stops.finish("#"),
+            StopScan::Stopped {
+                text: String::new(),
+                stop: "###".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn empty_stop_strings_are_ignored() {
+        let mut stops = matcher(&[""]);
+        assert_eq!(emitted(stops.push("abc")), "abc");
+    }
+}
@@ -0,0 +1,548 @@
+//! Fill-in-the-middle (FIM) code completion.
+//!
+//! Code models trained for infilling complete the text between a prefix and a
+//! suffix when both are wrapped in the vocabulary's FIM tokens
+//! ([`LlamaModel::token_fim_pre`], [`LlamaModel::token_fim_suf`],
+//! [`LlamaModel::token_fim_mid`]). [`InfillRequest`] describes the text around
+//! the cursor, plus optional files from the same repository;
+//! [`InfillRequest::build_prompt`] assembles the prompt and trims it to a token
+//! budget, and [`infill`] runs it through a [`Generator`].
+//!
+//! With the default [`FimLayout::Psm`] the prompt is laid out like
+//! `llama-server`'s `/infill` endpoint:
+//!
+//! ```text
+//! [BOS] [<fim_rep>repo\n] [<fim_sep>file\n text]... [<fim_sep>current\n]
+//!     <fim_pre> prefix <fim_suf> suffix <fim_mid>
+//! ```
+//!
+//! The repository parts use [`LlamaModel::token_fim_rep`] and
+//! [`LlamaModel::token_fim_sep`] when the vocabulary has them; otherwise the
+//! extra files are separated by a plain-text snippet marker.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use llama_cpp_4::infill::{infill, infill_sampler, InfillRequest};
+//! use llama_cpp_4::prelude::*;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, "coder.gguf", &LlamaModelParams::default())?;
+//! let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//!
+//! let request = InfillRequest::new("fn add(a: i32, b: i32) -> i32 {\n    ", "\n}\n")
+//!     .with_file_name("src/math.rs");
+//! let config = GeneratorConfig::new().with_max_tokens(Some(64));
+//! let output = infill(&mut ctx, infill_sampler(&model), &request, config, |_| {})?;
+//! println!("{}", output.text);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::generate::{FinishReason, GenerateError, GeneratedPiece, Generator, GeneratorConfig};
+use crate::model::{AddBos, LlamaModel};
+use crate::sampling::LlamaSampler;
+use crate::token::{present, LlamaToken};
+use crate::StringToTokenError;
+
+/// Tokens kept free for the completion when [`GeneratorConfig::max_tokens`]
+/// is unset.
+pub const DEFAULT_RESERVE: usize = 128;
+
+/// Repository name written after [`LlamaModel::token_fim_rep`], as in
+/// `llama-server`.
+pub const DEFAULT_REPO_NAME: &str = "myproject";
+
+/// Separates extra files when the vocabulary has no FIM separator token.
+const SNIPPET_SEPARATOR: &str = "\n\n--- snippet ---\n\n";
+
+/// Failure while building or running a FIM prompt.
+#[derive(Debug, thiserror::Error)]
+pub enum InfillError {
+    /// The vocabulary lacks a FIM prefix, suffix or middle token.
+    #[error("the model has no fill-in-the-middle tokens")]
+    Unsupported,
+    /// The FIM tokens and file header alone exceed the token budget.
+    #[error("the infill prompt needs {needed} tokens, but only {budget} are available")]
+    BudgetTooSmall {
+        /// Tokens needed before any prefix or suffix text.
+        needed: usize,
+        /// Tokens available for the prompt.
+        budget: usize,
+    },
+    /// Some text could not be tokenized.
+    #[error(transparent)]
+    Tokenize(#[from] StringToTokenError),
+    /// The sequence id did not fit the native types.
+    #[error(transparent)]
+    KvCache(#[from] KvCacheConversionError),
+    /// Prefill or generation failed.
+    #[error(transparent)]
+    Generate(#[from] GenerateError),
+}
+
+/// Order of the prefix and suffix in the prompt.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FimLayout {
+    /// `<fim_pre> prefix <fim_suf> suffix <fim_mid>`, used by most models.
+    #[default]
+    Psm,
+    /// `<fim_suf> suffix <fim_pre> prefix <fim_mid>`, for models trained in
+    /// suffix-prefix-middle order (`--spm-infill` in llama.cpp).
+    Spm,
+}
+
+/// Another file of the repository, given to the model as context.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InfillFile {
+    /// Path or name of the file.
+    pub name: String,
+    /// Contents of the file, or the relevant part of it.
+    pub text: String,
+}
+
+impl InfillFile {
+    /// Creates a context file.
+    pub fn new(name: impl Into<String>, text: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            text: text.into(),
+        }
+    }
+}
+
+/// The text around the cursor and the repository context of one completion.
+///
+/// # Examples
+///
+/// ```
+/// use llama_cpp_4::infill::{FimLayout, InfillFile, InfillRequest};
+///
+/// let request = InfillRequest::new("let x = ", ";\n")
+///     .with_file_name("src/main.rs")
+///     .with_files([InfillFile::new("src/lib.rs", "pub fn answer() -> u32 { 42 }\n")])
+///     .with_layout(FimLayout::Spm);
+/// assert_eq!(request.prefix(), "let x = ");
+/// assert_eq!(request.files().len(), 1);
+/// assert_eq!(request.layout(), FimLayout::Spm);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InfillRequest {
+    prefix: String,
+    suffix: String,
+    file_name: Option<String>,
+    files: Vec<InfillFile>,
+    repo_name: String,
+    layout: FimLayout,
+}
+
+/// A FIM prompt built by [`InfillRequest::build_prompt`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InfillPrompt {
+    /// The prompt, ending with [`LlamaModel::token_fim_mid`].
+    pub tokens: Vec<LlamaToken>,
+    /// Prefix tokens kept, counted back from the cursor.
+    pub n_prefix: usize,
+    /// Suffix tokens kept, counted from the cursor.
+    pub n_suffix: usize,
+    /// Repository context tokens kept, counted back from the current file.
+    pub n_extra: usize,
+    /// Whether the prefix, suffix or repository context was cut to fit.
+    pub truncated: bool,
+}
+
+/// Result of [`infill`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InfillOutput {
+    /// The text to insert at the cursor.
+    pub text: String,
+    /// Why generation ended. A FIM token that is not an end-of-generation
+    /// token also ends it with [`FinishReason::Eog`].
+    pub finish_reason: FinishReason,
+    /// The prompt that was decoded.
+    pub prompt: InfillPrompt,
+}
+
+impl InfillRequest {
+    /// Completes the text between `prefix` and `suffix`.
+    pub fn new(prefix: impl Into<String>, suffix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            suffix: suffix.into(),
+            file_name: None,
+            files: Vec::new(),
+            repo_name: DEFAULT_REPO_NAME.to_owned(),
+            layout: FimLayout::default(),
+        }
+    }
+
+    /// Name of the file being edited. Written before the prefix when the
+    /// vocabulary has a FIM separator token.
+    #[must_use]
+    pub fn with_file_name(mut self, file_name: impl Into<String>) -> Self {
+        self.file_name = Some(file_name.into());
+        self
+    }
+
+    /// Other files of the repository, most relevant last. When the budget is
+    /// tight, the earliest ones are cut first.
+    #[must_use]
+    pub fn with_files(mut self, files: impl IntoIterator<Item = InfillFile>) -> Self {
+        self.files = files.into_iter().collect();
+        self
+    }
+
+    /// Repository name written before the files. Defaults to
+    /// [`DEFAULT_REPO_NAME`].
+    #[must_use]
+    pub fn with_repo_name(mut self, repo_name: impl Into<String>) -> Self {
+        self.repo_name = repo_name.into();
+        self
+    }
+
+    /// Order of the prefix and suffix. Defaults to [`FimLayout::Psm`].
+    #[must_use]
+    pub fn with_layout(mut self, layout: FimLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Text before the cursor.
+    #[must_use]
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Text after the cursor.
+    #[must_use]
+    pub fn suffix(&self) -> &str {
+        &self.suffix
+    }
+
+    /// Name of the file being edited.
+    #[must_use]
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+
+    /// Other files of the repository.
+    #[must_use]
+    pub fn files(&self) -> &[InfillFile] {
+        &self.files
+    }
+
+    /// Repository name written before the files.
+    #[must_use]
+    pub fn repo_name(&self) -> &str {
+        &self.repo_name
+    }
+
+    /// Order of the prefix and suffix.
+    #[must_use]
+    pub fn layout(&self) -> FimLayout {
+        self.layout
+    }
+
+    /// Tokenizes and assembles the prompt in at most `budget` tokens.
+    ///
+    /// The prefix keeps the tokens closest to the cursor and gets at least
+    /// three quarters of the room left by the FIM tokens, the suffix keeps
+    /// its start and gets the rest, and the repository context fills
+    /// whatever both leave unused.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`InfillError::Unsupported`] when the model has no FIM tokens,
+    /// [`InfillError::BudgetTooSmall`] when the FIM tokens do not fit, and
+    /// [`InfillError::Tokenize`] when some text contains a null byte.
+    pub fn build_prompt(
+        &self,
+        model: &LlamaModel,
+        budget: usize,
+    ) -> Result<InfillPrompt, InfillError> {
+        let fim = FimTokens::of(model)?;
+        let tokenize = |text: &str| model.str_to_token(text, AddBos::Never);
+
+        let mut extra = Vec::new();
+        if !self.files.is_empty() {
+            if let Some(rep) = fim.rep {
+                extra.push(rep);
+                extra.extend(tokenize(&format!("{}\n", self.repo_name))?);
+            }
+            for file in &self.files {
+                match fim.sep {
+                    Some(sep) => {
+                        extra.push(sep);
+                        extra.extend(tokenize(&format!("{}\n", file.name))?);
+                    }
+                    None => extra.extend(tokenize(SNIPPET_SEPARATOR)?),
+                }
+                extra.extend(tokenize(&file.text)?);
+            }
+        }
+        let mut header = Vec::new();
+        if let Some(sep) = fim.sep {
+            if let Some(name) = &self.file_name {
+                header.push(sep);
+                header.extend(tokenize(&format!("{name}\n"))?);
+            }
+        }
+        let prefix = tokenize(&self.prefix)?;
+        let suffix = tokenize(&self.suffix)?;
+
+        let needed = usize::from(fim.bos.is_some()) + 3 + header.len();
+        let room = budget
+            .checked_sub(needed)
+            .ok_or(InfillError::BudgetTooSmall { needed, budget })?;
+        let (n_prefix, n_suffix, n_extra) =
+            split_budget(room, prefix.len(), suffix.len(), extra.len());
+        let tokens = assemble(
+            &fim,
+            self.layout,
+            &extra[extra.len() - n_extra..],
+            &header,
+            &prefix[prefix.len() - n_prefix..],
+            &suffix[..n_suffix],
+        );
+        Ok(InfillPrompt {
+            tokens,
+            n_prefix,
+            n_suffix,
+            n_extra,
+            truncated: n_prefix < prefix.len() || n_suffix < suffix.len() || n_extra < extra.len(),
+        })
+    }
+}
+
+/// The default sampler chain for infilling: top-k 40, then
+/// [`LlamaSampler::infill`], then greedy selection.
+#[must_use]
+pub fn infill_sampler(model: &LlamaModel) -> LlamaSampler {
+    LlamaSampler::chain_simple([
+        LlamaSampler::top_k(40),
+        LlamaSampler::infill(model),
+        LlamaSampler::greedy(),
+    ])
+}
+
+/// Builds the prompt for `request`, decodes it into the configured sequence
+/// and generates the middle, calling `on_piece` for every piece.
+///
+/// The sequence is cleared first. The prompt budget is the per-sequence
+/// context size minus [`GeneratorConfig::max_tokens`] (or
+/// [`DEFAULT_RESERVE`]). Generation stops at end-of-generation and FIM
+/// tokens; `sampler` should contain [`LlamaSampler::infill`], as
+/// [`infill_sampler`] does.
+///
+/// # Errors
+///
+/// Returns an error when the prompt cannot be built (see
+/// [`InfillRequest::build_prompt`]) or generation fails.
+pub fn infill(
+    ctx: &mut LlamaContext<'_>,
+    sampler: LlamaSampler,
+    request: &InfillRequest,
+    config: GeneratorConfig,
+    mut on_piece: impl FnMut(&GeneratedPiece),
+) -> Result<InfillOutput, InfillError> {
+    let model = ctx.model;
+    let n_ctx = usize::try_from(ctx.n_ctx_seq()).unwrap_or(usize::MAX);
+    let reserve = config.max_tokens().unwrap_or(DEFAULT_RESERVE);
+    let prompt = request.build_prompt(model, n_ctx.saturating_sub(reserve))?;
+
+    let seq_id = u32::try_from(config.seq_id()).map_err(KvCacheConversionError::SeqIdTooLarge)?;
+    ctx.clear_kv_cache_seq(Some(seq_id), None, None)?;
+    let fim = FimTokens::of(model)?;
+    let stops: Vec<LlamaToken> = config
+        .stop_tokens()
+        .iter()
+        .copied()
+        .chain(fim.completion_stops())
+        .collect();
+    let config = config
+        .with_start_pos(Some(0))
+        .with_context_shift(None)
+        .with_stop_tokens(stops);
+
+    let mut text = String::new();
+    let mut finish_reason = FinishReason::MaxTokens;
+    for piece in Generator::new(ctx, sampler, &prompt.tokens, config)? {
+        let piece = piece?;
+        text.push_str(&piece.text);
+        on_piece(&piece);
+        if let Some(reason) = &piece.finish_reason {
+            finish_reason = reason.clone();
+        }
+    }
+    Ok(InfillOutput {
+        text,
+        finish_reason,
+        prompt,
+    })
+}
+
+/// The special tokens of one vocabulary used in FIM prompts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FimTokens {
+    bos: Option<LlamaToken>,
+    pre: LlamaToken,
+    suf: LlamaToken,
+    mid: LlamaToken,
+    pad: Option<LlamaToken>,
+    rep: Option<LlamaToken>,
+    sep: Option<LlamaToken>,
+}
+
+impl FimTokens {
+    fn of(model: &LlamaModel) -> Result<Self, InfillError> {
+        let required = |token| present(token).ok_or(InfillError::Unsupported);
+        Ok(Self {
+            bos: present(model.token_bos()).filter(|_| model.add_bos_token()),
+            pre: required(model.token_fim_pre())?,
+            suf: required(model.token_fim_suf())?,
+            mid: required(model.token_fim_mid())?,
+            pad: present(model.token_fim_pad()),
+            rep: present(model.token_fim_rep()),
+            sep: present(model.token_fim_sep()),
+        })
+    }
+
+    /// The FIM tokens, which never belong in the completion and end it.
+    fn completion_stops(&self) -> impl Iterator<Item = LlamaToken> {
+        [
+            Some(self.pre),
+            Some(self.suf),
+            Some(self.mid),
+            self.pad,
+            self.rep,
+            self.sep,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// Splits `room` prompt tokens between the prefix, the suffix and the extra
+/// context. The prefix may use three quarters of `room` plus whatever the
+/// suffix leaves, the suffix the rest; the extra context fills what remains.
+fn split_budget(
+    room: usize,
+    n_prefix: usize,
+    n_suffix: usize,
+    n_extra: usize,
+) -> (usize, usize, usize) {
+    let prefix_share = room - room / 4;
+    let take_prefix = n_prefix.min(prefix_share.max(room.saturating_sub(n_suffix)));
+    let take_suffix = n_suffix.min(room - take_prefix);
+    let take_extra = n_extra.min(room - take_prefix - take_suffix);
+    (take_prefix, take_suffix, take_extra)
+}
+
+/// Lays out an already trimmed prompt.
+fn assemble(
+    fim: &FimTokens,
+    layout: FimLayout,
+    extra: &[LlamaToken],
+    header: &[LlamaToken],
+    prefix: &[LlamaToken],
+    suffix: &[LlamaToken],
+) -> Vec<LlamaToken> {
+    let mut tokens =
+        Vec::with_capacity(extra.len() + header.len() + prefix.len() + suffix.len() + 4);
+    tokens.extend(fim.bos);
+    tokens.extend_from_slice(extra);
+    tokens.extend_from_slice(header);
+    let push_prefix = |tokens: &mut Vec<LlamaToken>| {
+        tokens.push(fim.pre);
+        tokens.extend_from_slice(prefix);
+    };
+    match layout {
+        FimLayout::Psm => {
+            push_prefix(&mut tokens);
+            tokens.push(fim.suf);
+            tokens.extend_from_slice(suffix);
+        }
+        FimLayout::Spm => {
+            tokens.push(fim.suf);
+            tokens.extend_from_slice(suffix);
+            push_prefix(&mut tokens);
+        }
+    }
+    tokens.push(fim.mid);
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::{tokens, TOKEN_NULL};
+
+    fn fim() -> FimTokens {
+        FimTokens {
+            bos: Some(LlamaToken(1)),
+            pre: LlamaToken(100),
+            suf: LlamaToken(101),
+            mid: LlamaToken(102),
+            pad: None,
+            rep: Some(LlamaToken(103)),
+            sep: Some(LlamaToken(104)),
+        }
+    }
+
+    #[test]
+    fn psm_layout() {
+        let prompt = assemble(
+            &fim(),
+            FimLayout::Psm,
+            &tokens(&[103, 7]),
+            &tokens(&[104, 8]),
+            &tokens(&[10, 11]),
+            &tokens(&[20]),
+        );
+        assert_eq!(
+            prompt,
+            tokens(&[1, 103, 7, 104, 8, 100, 10, 11, 101, 20, 102])
+        );
+    }
+
+    #[test]
+    fn spm_layout_without_bos() {
+        let fim = FimTokens { bos: None, ..fim() };
+        let prompt = assemble(
+            &fim,
+            FimLayout::Spm,
+            &[],
+            &[],
+            &tokens(&[10]),
+            &tokens(&[20, 21]),
+        );
+        assert_eq!(prompt, tokens(&[101, 20, 21, 100, 10, 102]));
+    }
+
+    #[test]
+    fn budget_keeps_everything_that_fits() {
+        assert_eq!(split_budget(100, 10, 5, 20), (10, 5, 20));
+        assert_eq!(split_budget(100, 10, 5, 200), (10, 5, 85));
+    }
+
+    #[test]
+    fn budget_favours_prefix_three_to_one() {
+        assert_eq!(split_budget(100, 500, 500, 50), (75, 25, 0));
+        // Room the suffix does not need goes to the prefix and vice versa.
+        assert_eq!(split_budget(100, 500, 10, 0), (90, 10, 0));
+        assert_eq!(split_budget(100, 10, 500, 0), (10, 90, 0));
+        assert_eq!(split_budget(0, 10, 10, 10), (0, 0, 0));
+    }
+
+    #[test]
+    fn fim_tokens_are_recognized() {
+        let stops: Vec<LlamaToken> = fim().completion_stops().collect();
+        assert!(stops.contains(&LlamaToken(102)));
+        assert!(stops.contains(&LlamaToken(104)));
+        assert!(!stops.contains(&LlamaToken(1)));
+        assert!(!stops.contains(&TOKEN_NULL));
+    }
+}
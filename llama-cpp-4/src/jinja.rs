@@ -0,0 +1,811 @@
+//! Chat templates rendered from their Jinja source (`jinja` feature).
+//!
+//! [`LlamaModel::apply_chat_template`] only understands the templates that
+//! llama.cpp recognises by heuristics (see
+//! [`LlamaModel::chat_builtin_templates`]), and only plain-text messages.
+//! [`ChatTemplate`] instead renders the template stored in the model's
+//! `tokenizer.chat_template` metadata with [`minijinja`], configured like
+//! Hugging Face `transformers` (`trim_blocks`, `lstrip_blocks`, Python string
+//! and dict methods, `tojson`, `raise_exception`, `strftime_now`), so tools,
+//! tool calls, structured content and flags like `enable_thinking` reach the
+//! template as the model authors intended.
+//!
+//! The template sees the usual variables: `messages`, `tools` (`none` if
+//! there are none), `add_generation_prompt`, `bos_token`, `eos_token`,
+//! `enable_thinking`, and anything added with
+//! [`ChatTemplateInputs::with_extra_context`].
+//!
+//! Messages use the `OpenAI` chat format. Before rendering, string
+//! `arguments` of tool calls are parsed into objects (most templates apply
+//! `tojson` to them), and array `content` is joined into a string for
+//! templates that do not handle typed content.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use llama_cpp_4::jinja::{ChatTemplate, ChatTemplateInputs};
+//! use llama_cpp_4::prelude::*;
+//! use serde_json::json;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, "model.gguf", &LlamaModelParams::default())?;
+//!
+//! let inputs = ChatTemplateInputs::new(vec![
+//!     json!({"role": "system", "content": "You are a helpful assistant."}),
+//!     json!({"role": "user", "content": "What is the weather in Paris?"}),
+//! ])
+//! .with_tools(vec![json!({
+//!     "type": "function",
+//!     "function": {
+//!         "name": "get_weather",
+//!         "parameters": {"type": "object", "properties": {"city": {"type": "string"}}},
+//!     },
+//! })]);
+//!
+//! // Render the model's own template ...
+//! let prompt = ChatTemplate::from_model(&model)?.render(&inputs)?;
+//! // ... or fall back to llama.cpp's builtin templates if that fails.
+//! let prompt = model.apply_chat_template_jinja(&inputs)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::Write as _;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use minijinja::value::Kwargs;
+use minijinja::{Environment, ErrorKind, State, Value};
+use serde::Serialize;
+use serde_json::Value as Json;
+
+use crate::model::{LlamaChatMessage, LlamaModel};
+use crate::{
+    ApplyChatTemplateError, ChatTemplateError, NewLlamaChatMessageError, StringFromModelError,
+};
+
+/// Text used to probe whether a template renders typed content.
+const PROBE: &str = "<<llama-cpp-4 probe>>";
+
+/// Failure while rendering a chat template.
+#[derive(Debug, thiserror::Error)]
+pub enum JinjaError {
+    /// The template could not be parsed or rendering it failed, including
+    /// errors raised by the template itself with `raise_exception`.
+    #[error("chat template: {0:#}")]
+    Template(#[from] minijinja::Error),
+    /// The model has no chat template.
+    #[error(transparent)]
+    MissingTemplate(#[from] ChatTemplateError),
+    /// The text of the BOS or EOS token could not be read.
+    #[error(transparent)]
+    TokenText(#[from] StringFromModelError),
+    /// The builtin fallback failed.
+    #[error(transparent)]
+    Builtin(#[from] ApplyChatTemplateError),
+    /// A message could not be passed to the builtin fallback.
+    #[error(transparent)]
+    Message(#[from] NewLlamaChatMessageError),
+}
+
+/// What a template does with its inputs, detected when it is loaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChatTemplateCaps {
+    /// The template refers to `tools`.
+    pub supports_tools: bool,
+    /// The template renders the text of array `content`.
+    pub supports_typed_content: bool,
+    /// The template refers to `enable_thinking`.
+    pub supports_thinking: bool,
+}
+
+/// Variables passed to a [`ChatTemplate`].
+#[derive(Debug, Clone)]
+pub struct ChatTemplateInputs {
+    messages: Vec<Json>,
+    tools: Vec<Json>,
+    add_generation_prompt: bool,
+    enable_thinking: bool,
+    extra_context: serde_json::Map<String, Json>,
+}
+
+impl ChatTemplateInputs {
+    /// Inputs for `OpenAI`-style `messages`: objects with a `role`, a
+    /// `content` string or array of parts, and optionally `tool_calls`,
+    /// `tool_call_id`, `name` or `reasoning_content`.
+    #[must_use]
+    pub fn new(messages: Vec<Json>) -> Self {
+        Self {
+            messages,
+            tools: Vec::new(),
+            add_generation_prompt: true,
+            enable_thinking: true,
+            extra_context: serde_json::Map::new(),
+        }
+    }
+
+    /// Inputs for plain-text messages.
+    #[must_use]
+    pub fn from_messages(messages: &[LlamaChatMessage]) -> Self {
+        Self::new(
+            messages
+                .iter()
+                .map(|m| serde_json::json!({"role": m.role(), "content": m.content()}))
+                .collect(),
+        )
+    }
+
+    /// Tool definitions in the `OpenAI` `tools` format. Defaults to none.
+    #[must_use]
+    pub fn with_tools(mut self, tools: Vec<Json>) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    /// End the prompt with the header of an assistant turn. Defaults to
+    /// `true`.
+    #[must_use]
+    pub fn with_add_generation_prompt(mut self, add_generation_prompt: bool) -> Self {
+        self.add_generation_prompt = add_generation_prompt;
+        self
+    }
+
+    /// Let reasoning models think before answering. Defaults to `true`;
+    /// ignored by templates without `enable_thinking`.
+    #[must_use]
+    pub fn with_enable_thinking(mut self, enable_thinking: bool) -> Self {
+        self.enable_thinking = enable_thinking;
+        self
+    }
+
+    /// Sets another template variable, e.g. `date_string` or
+    /// `reasoning_effort`.
+    #[must_use]
+    pub fn with_extra_context(mut self, key: impl Into<String>, value: Json) -> Self {
+        self.extra_context.insert(key.into(), value);
+        self
+    }
+
+    /// The messages.
+    #[must_use]
+    pub fn messages(&self) -> &[Json] {
+        &self.messages
+    }
+
+    /// The tool definitions.
+    #[must_use]
+    pub fn tools(&self) -> &[Json] {
+        &self.tools
+    }
+
+    /// Whether an assistant turn header is appended.
+    #[must_use]
+    pub fn add_generation_prompt(&self) -> bool {
+        self.add_generation_prompt
+    }
+
+    /// Whether thinking is enabled.
+    #[must_use]
+    pub fn enable_thinking(&self) -> bool {
+        self.enable_thinking
+    }
+}
+
+/// A compiled Jinja chat template.
+pub struct ChatTemplate {
+    env: Environment<'static>,
+    bos_token: String,
+    eos_token: String,
+    caps: ChatTemplateCaps,
+}
+
+impl std::fmt::Debug for ChatTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChatTemplate")
+            .field("bos_token", &self.bos_token)
+            .field("eos_token", &self.eos_token)
+            .field("caps", &self.caps)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ChatTemplate {
+    /// Compiles `source`. `bos_token` and `eos_token` are the texts the
+    /// template inserts for `{{ bos_token }}` and `{{ eos_token }}`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`JinjaError::Template`] if the template does not parse.
+    pub fn new(source: &str, bos_token: &str, eos_token: &str) -> Result<Self, JinjaError> {
+        let mut env = Environment::new();
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(unknown_method);
+        env.add_filter("tojson", tojson);
+        env.add_function("raise_exception", raise_exception);
+        env.add_function("strftime_now", strftime_now);
+        env.add_template_owned("chat", source.to_owned())?;
+
+        let mut template = Self {
+            env,
+            bos_token: bos_token.to_owned(),
+            eos_token: eos_token.to_owned(),
+            caps: ChatTemplateCaps {
+                supports_tools: source.contains("tools"),
+                supports_typed_content: false,
+                supports_thinking: source.contains("enable_thinking"),
+            },
+        };
+        let probe = ChatTemplateInputs::new(vec![serde_json::json!({
+            "role": "user",
+            "content": [{"type": "text", "text": PROBE}],
+        })])
+        .with_add_generation_prompt(false);
+        // Templates that print `content` as is show the probe too, inside
+        // the printed list.
+        template.caps.supports_typed_content = template
+            .render_prepared(probe.messages.clone(), &probe)
+            .is_ok_and(|text| text.contains(PROBE) && !text.contains("\"type\""));
+        Ok(template)
+    }
+
+    /// Compiles the model's `tokenizer.chat_template`, with the texts of its
+    /// BOS and EOS tokens.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the model has no chat template or it does not
+    /// parse.
+    pub fn from_model(model: &LlamaModel) -> Result<Self, JinjaError> {
+        let source = model.chat_template_source(None)?;
+        Self::new(
+            &source,
+            model.token_get_text(model.token_bos()).unwrap_or_default(),
+            model.token_get_text(model.token_eos())?,
+        )
+    }
+
+    /// The text inserted for `bos_token`.
+    #[must_use]
+    pub fn bos_token(&self) -> &str {
+        &self.bos_token
+    }
+
+    /// The text inserted for `eos_token`.
+    #[must_use]
+    pub fn eos_token(&self) -> &str {
+        &self.eos_token
+    }
+
+    /// What the template supports.
+    #[must_use]
+    pub fn caps(&self) -> ChatTemplateCaps {
+        self.caps
+    }
+
+    /// Renders the template.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`JinjaError::Template`] if rendering fails, e.g. because the
+    /// template rejects the conversation with `raise_exception`.
+    pub fn render(&self, inputs: &ChatTemplateInputs) -> Result<String, JinjaError> {
+        let messages = inputs
+            .messages
+            .iter()
+            .map(|message| self.prepare_message(message))
+            .collect();
+        self.render_prepared(messages, inputs)
+    }
+
+    fn render_prepared(
+        &self,
+        messages: Vec<Json>,
+        inputs: &ChatTemplateInputs,
+    ) -> Result<String, JinjaError> {
+        let mut context = inputs.extra_context.clone();
+        context.insert("messages".into(), Json::Array(messages));
+        context.insert(
+            "tools".into(),
+            if inputs.tools.is_empty() {
+                Json::Null
+            } else {
+                Json::Array(inputs.tools.clone())
+            },
+        );
+        context.insert(
+            "add_generation_prompt".into(),
+            inputs.add_generation_prompt.into(),
+        );
+        context.insert("enable_thinking".into(), inputs.enable_thinking.into());
+        context.insert("bos_token".into(), self.bos_token.clone().into());
+        context.insert("eos_token".into(), self.eos_token.clone().into());
+
+        let template = self.env.get_template("chat")?;
+        Ok(template.render(Value::from_serialize(&context))?)
+    }
+
+    /// Adapts a message to what the template expects.
+    fn prepare_message(&self, message: &Json) -> Json {
+        let mut message = message.clone();
+        let Some(fields) = message.as_object_mut() else {
+            return message;
+        };
+        if !self.caps.supports_typed_content {
+            if let Some(Json::Array(parts)) = fields.get("content") {
+                let text = parts
+                    .iter()
+                    .filter_map(|part| match part {
+                        Json::String(text) => Some(text.as_str()),
+                        part => part.get("text").and_then(Json::as_str),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                fields.insert("content".into(), text.into());
+            }
+        }
+        if let Some(Json::Array(calls)) = fields.get_mut("tool_calls") {
+            for call in calls {
+                let function = if call.get("function").is_some() {
+                    &mut call["function"]
+                } else {
+                    call
+                };
+                if let Some(arguments) = function.get_mut("arguments") {
+                    if let Some(parsed) = arguments
+                        .as_str()
+                        .and_then(|text| serde_json::from_str::<Json>(text).ok())
+                        .filter(Json::is_object)
+                    {
+                        *arguments = parsed;
+                    }
+                }
+            }
+        }
+        message
+    }
+}
+
+impl LlamaModel {
+    /// Renders the model's Jinja chat template with [`ChatTemplate`], and
+    /// falls back to [`Self::apply_chat_template`] if the model has none or
+    /// rendering fails. The fallback ignores tools and flattens structured
+    /// content to text.
+    ///
+    /// If the model adds a BOS token when tokenizing, a BOS token rendered
+    /// by the template is removed, so the result can be tokenized with
+    /// [`AddBos::Always`](crate::model::AddBos::Always) like the output of
+    /// the builtin templates.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the fallback fails as well.
+    pub fn apply_chat_template_jinja(
+        &self,
+        inputs: &ChatTemplateInputs,
+    ) -> Result<String, JinjaError> {
+        let rendered = ChatTemplate::from_model(self).and_then(|template| {
+            let text = template.render(inputs)?;
+            Ok(match text.strip_prefix(template.bos_token()) {
+                Some(rest) if self.add_bos_token() && !template.bos_token().is_empty() => {
+                    rest.to_owned()
+                }
+                _ => text,
+            })
+        });
+        match rendered {
+            Ok(text) => Ok(text),
+            Err(err) => {
+                tracing::warn!(%err, "falling back to the builtin chat template");
+                let messages = inputs
+                    .messages
+                    .iter()
+                    .map(|message| {
+                        LlamaChatMessage::new(
+                            message["role"].as_str().unwrap_or("user").to_owned(),
+                            content_text(&message["content"]),
+                        )
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let template = self.chat_template_source(None).ok();
+                Ok(self.apply_chat_template(
+                    template.as_deref(),
+                    &messages,
+                    inputs.add_generation_prompt,
+                )?)
+            }
+        }
+    }
+}
+
+/// The text of a message `content`: a string, or the text parts of an array.
+fn content_text(content: &Json) -> String {
+    match content {
+        Json::String(text) => text.clone(),
+        Json::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part.get("text").and_then(Json::as_str))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// Python methods on values (`str.strip()`, `dict.items()`, ...), plus
+/// `dict.get(key, default)`, which `pycompat` only has without a default.
+fn unknown_method(
+    state: &State<'_, '_>,
+    value: &Value,
+    method: &str,
+    args: &[Value],
+) -> Result<Value, minijinja::Error> {
+    if let (Some(object), "get", [key, default]) = (value.as_object(), method, args) {
+        return Ok(object.get_value(key).unwrap_or_else(|| default.clone()));
+    }
+    minijinja_contrib::pycompat::unknown_method_callback(state, value, method, args)
+}
+
+/// `raise_exception(message)`: aborts rendering with `message`.
+fn raise_exception(message: String) -> Result<Value, minijinja::Error> {
+    Err(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+}
+
+/// `tojson` as defined by `transformers`: Python's `json.dumps` with
+/// `ensure_ascii=False`, so `", "` and `": "` separators and no HTML
+/// escaping. Accepts `indent`, `separators` and `sort_keys`.
+#[allow(clippy::needless_pass_by_value)] // filters take `Kwargs` by value
+fn tojson(value: &Value, kwargs: Kwargs) -> Result<Value, minijinja::Error> {
+    let indent: Option<usize> = kwargs.get("indent")?;
+    let separators: Option<Vec<String>> = kwargs.get("separators")?;
+    let sort_keys: Option<bool> = kwargs.get("sort_keys")?;
+    kwargs.assert_all_used()?;
+
+    let mut json = serde_json::to_value(value).map_err(|err| {
+        minijinja::Error::new(ErrorKind::BadSerialization, "cannot convert to JSON")
+            .with_source(err)
+    })?;
+    if sort_keys.unwrap_or(false) {
+        sort_json_keys(&mut json);
+    }
+    let (item_separator, key_separator) = match separators.as_deref() {
+        Some([item, key]) => (item.clone(), key.clone()),
+        Some(_) => {
+            return Err(minijinja::Error::new(
+                ErrorKind::InvalidOperation,
+                "separators must be a pair",
+            ))
+        }
+        None if indent.is_some() => (",".to_owned(), ": ".to_owned()),
+        None => (", ".to_owned(), ": ".to_owned()),
+    };
+    let formatter = PyFormatter {
+        indent: indent.map(|n| " ".repeat(n)),
+        item_separator,
+        key_separator,
+        depth: 0,
+        has_value: false,
+    };
+    let mut out = Vec::new();
+    let mut serializer = serde_json::Serializer::with_formatter(&mut out, formatter);
+    json.serialize(&mut serializer).map_err(|err| {
+        minijinja::Error::new(ErrorKind::BadSerialization, "cannot write JSON").with_source(err)
+    })?;
+    Ok(Value::from_safe_string(
+        String::from_utf8(out).unwrap_or_default(),
+    ))
+}
+
+fn sort_json_keys(json: &mut Json) {
+    match json {
+        Json::Object(map) => {
+            let mut entries: Vec<_> = std::mem::take(map).into_iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            for (key, mut value) in entries {
+                sort_json_keys(&mut value);
+                map.insert(key, value);
+            }
+        }
+        Json::Array(items) => items.iter_mut().for_each(sort_json_keys),
+        _ => {}
+    }
+}
+
+/// Writes JSON the way Python's `json.dumps` does.
+struct PyFormatter {
+    indent: Option<String>,
+    item_separator: String,
+    key_separator: String,
+    depth: usize,
+    has_value: bool,
+}
+
+impl PyFormatter {
+    fn newline<W: ?Sized + std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        if let Some(indent) = &self.indent {
+            writer.write_all(b"\n")?;
+            for _ in 0..self.depth {
+                writer.write_all(indent.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn begin<W: ?Sized + std::io::Write>(
+        &mut self,
+        writer: &mut W,
+        open: &[u8],
+    ) -> std::io::Result<()> {
+        self.depth += 1;
+        self.has_value = false;
+        writer.write_all(open)
+    }
+
+    fn end<W: ?Sized + std::io::Write>(
+        &mut self,
+        writer: &mut W,
+        close: &[u8],
+    ) -> std::io::Result<()> {
+        self.depth -= 1;
+        if self.has_value {
+            self.newline(writer)?;
+        }
+        writer.write_all(close)
+    }
+
+    fn item<W: ?Sized + std::io::Write>(&self, writer: &mut W, first: bool) -> std::io::Result<()> {
+        if !first {
+            writer.write_all(self.item_separator.as_bytes())?;
+        }
+        self.newline(writer)
+    }
+}
+
+impl serde_json::ser::Formatter for PyFormatter {
+    fn begin_array<W: ?Sized + std::io::Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        self.begin(writer, b"[")
+    }
+
+    fn end_array<W: ?Sized + std::io::Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        self.end(writer, b"]")
+    }
+
+    fn begin_array_value<W: ?Sized + std::io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> std::io::Result<()> {
+        self.item(writer, first)
+    }
+
+    fn end_array_value<W: ?Sized + std::io::Write>(
+        &mut self,
+        _writer: &mut W,
+    ) -> std::io::Result<()> {
+        self.has_value = true;
+        Ok(())
+    }
+
+    fn begin_object<W: ?Sized + std::io::Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        self.begin(writer, b"{")
+    }
+
+    fn end_object<W: ?Sized + std::io::Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        self.end(writer, b"}")
+    }
+
+    fn begin_object_key<W: ?Sized + std::io::Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> std::io::Result<()> {
+        self.item(writer, first)
+    }
+
+    fn begin_object_value<W: ?Sized + std::io::Write>(
+        &mut self,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        writer.write_all(self.key_separator.as_bytes())
+    }
+
+    fn end_object_value<W: ?Sized + std::io::Write>(
+        &mut self,
+        _writer: &mut W,
+    ) -> std::io::Result<()> {
+        self.has_value = true;
+        Ok(())
+    }
+}
+
+/// `strftime_now(format)`: the current UTC time formatted with the common
+/// `strftime` directives (`%Y %y %m %d %e %H %M %S %b %B %a %A %j %%`).
+fn strftime_now(format: &str) -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    strftime(format, secs)
+}
+
+fn strftime(format: &str, secs: u64) -> String {
+    const MONTHS: [&str; 12] = [
+        "January",
+        "February",
+        "March",
+        "April",
+        "May",
+        "June",
+        "July",
+        "August",
+        "September",
+        "October",
+        "November",
+        "December",
+    ];
+    const DAYS: [&str; 7] = [
+        "Thursday",
+        "Friday",
+        "Saturday",
+        "Sunday",
+        "Monday",
+        "Tuesday",
+        "Wednesday",
+    ];
+
+    let days = secs / 86_400;
+    let rem = secs % 86_400;
+    let (year, month, day) = civil_from_days(days);
+    let weekday = DAYS[usize::try_from(days % 7).unwrap_or(0)];
+    let month_name = MONTHS[usize::try_from(month - 1).unwrap_or(0)];
+    let yday = days - days_from_civil(year, 1, 1) + 1;
+
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        // Writing to a `String` cannot fail.
+        let _ = match chars.next() {
+            Some('Y') => write!(out, "{year}"),
+            Some('y') => write!(out, "{:02}", year % 100),
+            Some('m') => write!(out, "{month:02}"),
+            Some('d') => write!(out, "{day:02}"),
+            Some('e') => write!(out, "{day:2}"),
+            Some('H') => write!(out, "{:02}", rem / 3600),
+            Some('M') => write!(out, "{:02}", rem / 60 % 60),
+            Some('S') => write!(out, "{:02}", rem % 60),
+            Some('b') => write!(out, "{}", &month_name[..3]),
+            Some('B') => write!(out, "{month_name}"),
+            Some('a') => write!(out, "{}", &weekday[..3]),
+            Some('A') => write!(out, "{weekday}"),
+            Some('j') => write!(out, "{yday:03}"),
+            Some('%') | None => write!(out, "%"),
+            Some(other) => write!(out, "%{other}"),
+        };
+    }
+    out
+}
+
+/// Year, month and day of a day count since 1970-01-01 (Howard Hinnant's
+/// `civil_from_days`).
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Inverse of [`civil_from_days`].
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year % 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(source: &str, inputs: &ChatTemplateInputs) -> String {
+        ChatTemplate::new(source, "<s>", "</s>")
+            .unwrap()
+            .render(inputs)
+            .unwrap()
+    }
+
+    #[test]
+    fn tojson_matches_python() {
+        let inputs = ChatTemplateInputs::new(Vec::new())
+            .with_extra_context("x", json!({"b": [1, 2.5, "é<>"], "a": null, "c": {}}));
+        assert_eq!(
+            render("{{ x | tojson }}", &inputs),
+            r#"{"b": [1, 2.5, "é<>"], "a": null, "c": {}}"#
+        );
+        assert_eq!(
+            render("{{ x | tojson(indent=2, sort_keys=true) }}", &inputs),
+            "{\n  \"a\": null,\n  \"b\": [\n    1,\n    2.5,\n    \"é<>\"\n  ],\n  \"c\": {}\n}"
+        );
+        assert_eq!(
+            render("{{ x.b | tojson(separators=(',', ':')) }}", &inputs),
+            r#"[1,2.5,"é<>"]"#
+        );
+    }
+
+    #[test]
+    fn python_methods_and_raise_exception() {
+        let inputs = ChatTemplateInputs::new(vec![json!({"role": "user", "content": " a,b "})]);
+        assert_eq!(
+            render(
+                "{{ messages[0].content.strip().split(',') | join('|') }}{{ messages[0].get('name', 'x') }}",
+                &inputs
+            ),
+            "a|bx"
+        );
+        let template =
+            ChatTemplate::new("{{ raise_exception('no system role') }}", "", "").unwrap();
+        let err = template.render(&inputs).unwrap_err();
+        assert!(err.to_string().contains("no system role"), "{err}");
+    }
+
+    #[test]
+    fn tools_none_and_trim_blocks() {
+        let source = "{% if tools is not none %}\ntools\n{% endif %}\n{{ bos_token }}x";
+        let inputs = ChatTemplateInputs::new(Vec::new());
+        assert_eq!(render(source, &inputs), "<s>x");
+        let inputs = inputs.with_tools(vec![json!({"type": "function"})]);
+        assert_eq!(render(source, &inputs), "tools\n<s>x");
+    }
+
+    #[test]
+    fn prepares_content_and_tool_arguments() {
+        let source =
+            "{% for m in messages %}{{ m.content or '' }}{% for c in m.tool_calls or [] %}\
+                      {{ c.function.arguments.city }}{% endfor %}{% endfor %}";
+        let template = ChatTemplate::new(source, "", "").unwrap();
+        assert!(!template.caps().supports_typed_content);
+        let inputs = ChatTemplateInputs::new(vec![
+            json!({"role": "user", "content": [
+                {"type": "text", "text": "a"},
+                {"type": "image_url", "image_url": {"url": "x"}},
+                {"type": "text", "text": "b"},
+            ]}),
+            json!({"role": "assistant", "content": null, "tool_calls": [
+                {"type": "function", "function": {"name": "f", "arguments": "{\"city\": \"Paris\"}"}},
+            ]}),
+        ]);
+        assert_eq!(template.render(&inputs).unwrap(), "a\nbParis");
+    }
+
+    #[test]
+    fn detects_typed_content() {
+        let source =
+            "{% for m in messages %}{% for p in m.content %}{{ p.text }}{% endfor %}{% endfor %}";
+        let template = ChatTemplate::new(source, "", "").unwrap();
+        assert!(template.caps().supports_typed_content);
+        assert!(!template.caps().supports_tools);
+    }
+
+    #[test]
+    fn strftime_formats() {
+        // 2024-07-26 13:25:09 UTC, a Friday
+        let secs = 1_722_000_309;
+        assert_eq!(strftime("%d %b %Y", secs), "26 Jul 2024");
+        assert_eq!(
+            strftime("%A %B %e %H:%M:%S %j %y%%", secs),
+            "Friday July 26 13:25:09 208 24%"
+        );
+        assert_eq!(days_from_civil(2024, 7, 26), secs / 86_400);
+    }
+}
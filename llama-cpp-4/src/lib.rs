@@ -77,6 +77,12 @@
 //! - `openmp` enables OpenMP multi-core CPU parallelism (on by default).
 //! - `rpc` enables RPC backend support for distributed inference across multiple machines.
 //! - `mtmd` enables multimodal (image + audio) support via `libmtmd`.
+//! - `tokio` enables [`stream::TokenStream`], an async token stream fed by a worker thread.
+//! - `jinja` enables [`jinja::ChatTemplate`], which renders the model's Jinja chat template
+//!   (tools, structured content, `enable_thinking`) instead of llama.cpp's builtin templates.
+//! - `tools` enables [`tools::ToolCallFormat`] and [`tools::ToolCallParser`], which extract
+//!   tool calls from model output in the Hermes, Llama 3, Mistral, Qwen3-Coder and Functionary
+//!   formats.
 use std::ffi::NulError;
 use std::fmt::Debug;
 use std::num::NonZeroI32;
@@ -86,22 +92,38 @@ use std::os::raw::c_int;
 use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
+pub mod chat;
+pub mod classify;
 pub mod common;
 pub mod context;
+pub mod diffusion;
 pub mod eagle;
+pub mod embed;
 pub mod fit;
+pub mod generate;
 #[cfg(feature = "ggml")]
 pub mod ggml;
+pub mod infill;
+#[cfg(feature = "jinja")]
+pub mod jinja;
 pub mod llama_backend;
 pub mod llama_batch;
 pub mod model;
 pub mod mtp;
 pub mod prelude;
+pub mod prompt_cache;
 pub mod quantize;
+pub mod reasoning;
+pub mod rerank;
 pub mod sampling;
+pub mod scheduler;
 pub mod speculative;
+#[cfg(feature = "tokio")]
+pub mod stream;
 pub mod token;
 pub mod token_type;
+#[cfg(feature = "tools")]
+pub mod tools;
 
 #[cfg(feature = "rpc")]
 pub mod rpc;
@@ -150,6 +150,12 @@ impl LlamaBatch {
         }
     }
 
+    /// Returns the maximum number of tokens the batch can hold.
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        self.allocated
+    }
+
     /// Returns the number of tokens in the batch.
     #[must_use]
     pub fn n_tokens(&self) -> i32 {
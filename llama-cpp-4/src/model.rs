@@ -40,6 +40,10 @@ use crate::{
 
 pub mod params;
 
+/// Buffer size of the first attempt to read a chat template; recent templates
+/// with tool support are well over the 1 KiB of the builtin ones.
+const CHAT_TEMPLATE_BUF_SIZE: usize = 64 * 1024;
+
 /// Opaque ggml backend device handle returned by [`LlamaModel::get_device`].
 ///
 /// Use [`Self::name`], [`Self::description`], [`Self::device_type`], and
@@ -530,6 +534,19 @@ impl LlamaChatMessage {
             content: CString::new(content)?,
         })
     }
+
+    /// The message role, e.g. `"user"`.
+    #[must_use]
+    pub fn role(&self) -> &str {
+        // Built from a `String`, so always valid UTF-8.
+        self.role.to_str().unwrap_or_default()
+    }
+
+    /// The message text.
+    #[must_use]
+    pub fn content(&self) -> &str {
+        self.content.to_str().unwrap_or_default()
+    }
 }
 
 /// How to determine if we should prepend a bos token to tokens
@@ -1848,6 +1865,38 @@ impl LlamaModel {
         Ok(template.to_owned())
     }
 
+    /// Reads `tokenizer.chat_template`, or `tokenizer.chat_template.<name>`
+    /// for a named template such as `tool_use`, whatever its size.
+    pub(crate) fn chat_template_source(
+        &self,
+        name: Option<&str>,
+    ) -> Result<String, ChatTemplateError> {
+        let key = match name {
+            Some(name) => format!("tokenizer.chat_template.{name}"),
+            None => "tokenizer.chat_template".to_owned(),
+        };
+        let key = CString::new(key).map_err(|_| ChatTemplateError::MissingTemplate(-1))?;
+        let mut buf_size = CHAT_TEMPLATE_BUF_SIZE;
+        loop {
+            let mut buf = vec![0u8; buf_size];
+            let ret = unsafe {
+                llama_model_meta_val_str(
+                    self.model.as_ptr(),
+                    key.as_ptr(),
+                    buf.as_mut_ptr().cast::<c_char>(),
+                    buf_size,
+                )
+            };
+            let len = usize::try_from(ret).map_err(|_| ChatTemplateError::MissingTemplate(ret))?;
+            if len < buf_size {
+                buf.truncate(len);
+                return String::from_utf8(buf).map_err(|e| e.utf8_error().into());
+            }
+            // The value was truncated; `len` is its full length.
+            buf_size = len + 1;
+        }
+    }
+
     /// Loads a model from a file.
     ///
     /// This function loads a model from a specified file path and returns the corresponding `LlamaModel` instance.
@@ -2135,6 +2184,16 @@ impl LlamaModel {
     pub fn new_context(
         &self,
         _: &LlamaBackend,
+        params: LlamaContextParams,
+    ) -> Result<LlamaContext<'_>, LlamaContextLoadError> {
+        self.init_context(params)
+    }
+
+    /// Body of [`Self::new_context`] for callers that proved backend
+    /// initialization before moving to a worker thread, where the borrowed
+    /// backend token cannot follow.
+    pub(crate) fn init_context(
+        &self,
         mut params: LlamaContextParams,
     ) -> Result<LlamaContext<'_>, LlamaContextLoadError> {
         // Apply TurboQuant attn-rotation preference before the KV cache is
@@ -2174,6 +2233,7 @@ impl LlamaModel {
             embeddings_enabled,
             context_type,
             tensor_transactions,
+            params.self_extend,
         ))
     }
 
@@ -14,19 +14,32 @@
 //! | Category | Re-exported types |
 //! |---|---|
 //! | Inference | [`LlamaBackend`], [`LlamaModel`], [`LlamaModelParams`], [`LlamaContext`], [`LlamaContextParams`], [`LlamaBatch`], [`LlamaSampler`], [`LlamaSamplerParams`], [`LlamaToken`], [`LlamaTokenDataArray`] |
-//! | Tokenising | [`AddBos`], [`Special`] |
-//! | Chat | [`LlamaChatMessage`] |
+//! | Tokenising | [`AddBos`], [`Special`], [`TokenSpan`], [`TokenOffsetsError`] |
+//! | Chat | [`LlamaChatMessage`], [`ChatSession`], [`ChatReply`], [`ChatSessionError`] |
 //! | Model introspection | [`LlamaBackendDevice`], [`LlamaBackendDeviceType`] |
 //! | Context params | [`LlamaFlashAttnType`], [`LlamaContextType`], [`LlamaAttentionType`], [`RopeScalingType`], [`LlamaPoolingType`], [`ParamsCloneError`] |
 //! | KV overrides | [`ParamOverrideValue`] |
 //! | Errors | [`Result`], [`LLamaCppError`], [`DecodeError`], [`EncodeError`], [`EmbeddingsError`], [`BatchAddError`], [`ApplyChatTemplateError`], [`NewLlamaChatMessageError`] |
 //! | Memory / fit | [`get_device_memory_data`], [`fit_params`], [`FitParams`], [`FitParamsResult`], [`FitParamsError`], [`DeviceMemoryReport`], [`MemoryBreakdownEntry`] |
 //! | Tensor capture | [`TensorCapture`], [`CapturedTensor`] |
+//! | Generation | [`Generator`], [`GeneratorConfig`], [`GeneratedPiece`], [`FinishReason`], [`GenerateError`] |
+//! | Reasoning | [`ReasoningBudget`], [`ReasoningConfig`], [`ReasoningSplitter`], [`ReasoningStream`], [`ReasoningText`] |
+//! | Infill | [`InfillRequest`], [`InfillFile`], [`InfillOutput`], [`InfillError`] |
+//! | Diffusion | [`diffusion_generate`], [`DiffusionConfig`], [`DiffusionAlgorithm`], [`DiffusionStep`], [`DiffusionOutput`], [`DiffusionError`] |
+//! | Embeddings | [`embed`], [`EmbedConfig`], [`Embeddings`], [`EmbedError`], [`cosine_similarity`] |
+//! | Reranking / classification | [`rerank`], [`format_rerank`], [`RerankError`], [`classify`], [`ClassifyActivation`], [`ClassifyError`] |
+//! | Scheduling | [`BatchScheduler`], [`RequestId`], [`SchedulerEvent`] |
+//! | Prompt cache | [`PromptCache`], [`IncrementalPrefill`], [`IncrementalPrefillError`] |
+//! | Checkpoints | [`SequenceCheckpoint`], [`CheckpointRing`], [`CheckpointError`] |
+//! | Long context | [`ContextShiftConfig`], [`ContextShift`], [`ContextShiftError`], [`SelfExtendConfig`], [`SelfExtendError`] |
+//! | Logprobs | [`TokenLogprobs`], [`TokenLogprob`], [`LogprobsError`], [`ContinuationScore`], [`ScoreError`] |
 //! | Speculative | [`MtpSession`], [`MtpSessionConfig`], [`Eagle3Session`], [`Eagle3SessionConfig`] |
 //! | Quantization | [`QuantizeParams`], [`TensorTypeOverride`], [`GgmlType`], [`LlamaFtype`], [`model_quantize`], [`attn_rot_disabled`], [`set_attn_rot_disabled`] |
 //! | Utilities | [`ggml_time_us`], [`llama_time_us`], [`print_system_info`], [`supports_gpu_offload`], [`max_devices`] |
 //!
 //! With the `mtmd` feature: [`MtmdContext`], [`MtmdBitmap`], …\
+//! With the `jinja` feature: [`ChatTemplate`], [`ChatTemplateInputs`], [`ChatTemplateCaps`], [`JinjaError`]\
+//! With the `tools` feature: [`ToolCallFormat`], [`ToolCallParser`], [`ToolCallEvent`], [`ToolCall`], [`ParsedToolCalls`]\
 //! With the `rpc` feature: `RpcBackend`, `RpcServer`, and `RpcError` in `llama_cpp_4::rpc`.
 //!
 //! # Text generation
@@ -115,15 +128,9 @@
 //!         )
 //!         .unwrap();
 //!
-//!     let tokens = model.str_to_token("Hello", AddBos::Always).unwrap();
-//!     let mut batch = LlamaBatch::new(512, 1);
-//!     for (i, &tok) in tokens.iter().enumerate() {
-//!         batch
-//!             .add(tok, i as i32, &[0], i == tokens.len() - 1)
-//!             .unwrap();
-//!     }
-//!     ctx.decode(&mut batch).unwrap();
-//!     let _vec = ctx.embeddings_seq_ith(0).unwrap();
+//!     let config = EmbedConfig::new().with_normalize(true);
+//!     let embeddings = embed(&mut ctx, &["Hello", "Hi there"], &config).unwrap();
+//!     let _similarity = cosine_similarity(embeddings.row(0), embeddings.row(1));
 //! }
 //! ```
 //!
@@ -149,17 +156,31 @@
 
 // ── Core inference ────────────────────────────────────────────────────────────
 
+pub use crate::chat::{ChatReply, ChatSession, ChatSessionError};
+pub use crate::classify::{classify, ClassifyActivation, ClassifyError};
 pub use crate::context::params::{
     LlamaAttentionType, LlamaContextParams, LlamaContextType, LlamaFlashAttnType, LlamaPoolingType,
     ParamsCloneError, RopeScalingType,
 };
 pub use crate::context::{
-    CapturedTensor, CapturedTensorData, LlamaContext, MemoryBreakdownEntry, TensorAccess,
-    TensorBatchRow, TensorCallbackFailure, TensorCapture, TensorDataMut, TensorElementType,
-    TensorFiniteValidation, TensorRowMapping, TensorSelector, TensorShape, TensorTransaction,
-    TensorTransactionError, TensorTransactionHandler, TensorTransactions, TensorWriteback,
+    CapturedTensor, CapturedTensorData, CheckpointError, CheckpointRing, ContextShift,
+    ContextShiftConfig, ContextShiftError, ContinuationScore, IncrementalPrefill,
+    IncrementalPrefillError, LlamaContext, LogprobsError, MemoryBreakdownEntry, ScoreError,
+    SelfExtendConfig, SelfExtendError, SequenceCheckpoint, TensorAccess, TensorBatchRow,
+    TensorCallbackFailure, TensorCapture, TensorDataMut, TensorElementType, TensorFiniteValidation,
+    TensorRowMapping, TensorSelector, TensorShape, TensorTransaction, TensorTransactionError,
+    TensorTransactionHandler, TensorTransactions, TensorWriteback, TokenLogprob, TokenLogprobs,
     TransactionalTensorCapture,
 };
+pub use crate::diffusion::{
+    diffusion_generate, DiffusionAlgorithm, DiffusionConfig, DiffusionError, DiffusionOutput,
+    DiffusionStep,
+};
+pub use crate::embed::{cosine_similarity, embed, EmbedConfig, EmbedError, Embeddings};
+pub use crate::generate::{
+    FinishReason, GenerateError, GeneratedPiece, Generator, GeneratorConfig,
+};
+pub use crate::infill::{InfillError, InfillFile, InfillOutput, InfillRequest};
 pub use crate::llama_backend::LlamaBackend;
 pub use crate::llama_batch::{BatchAddError, LlamaBatch};
 pub use crate::model::params::kv_overrides::ParamOverrideValue;
@@ -167,10 +188,17 @@ pub use crate::model::params::LlamaModelParams;
 pub use crate::model::{
     AddBos, LlamaBackendDevice, LlamaBackendDeviceType, LlamaChatMessage, LlamaModel, Special,
 };
+pub use crate::prompt_cache::PromptCache;
+pub use crate::reasoning::{
+    ReasoningBudget, ReasoningConfig, ReasoningSplitter, ReasoningStream, ReasoningText,
+};
+pub use crate::rerank::{format_rerank, rerank, RerankError};
 pub use crate::sampling::{LlamaSampler, LlamaSamplerParams};
+pub use crate::scheduler::{BatchScheduler, RequestId, SchedulerEvent};
 pub use crate::speculative::{SpeculativeStateError, MAX_SPECULATIVE_STATE_BYTES};
 pub use crate::token::data_array::LlamaTokenDataArray;
 pub use crate::token::detokenizer::{DetokenizeError, StreamDetokenizer};
+pub use crate::token::offsets::{TokenOffsetsError, TokenSpan};
 pub use crate::token::LlamaToken;
 
 // ── Errors & results ────────────────────────────────────────────────────────
@@ -200,6 +228,16 @@ pub use crate::mtmd::{
     MtmdProgressCallback,
 };
 
+// ── Jinja chat templates (feature `jinja`) ──────────────────────────────────
+
+#[cfg(feature = "jinja")]
+pub use crate::jinja::{ChatTemplate, ChatTemplateCaps, ChatTemplateInputs, JinjaError};
+
+// ── Tool calls (feature `tools`) ────────────────────────────────────────────
+
+#[cfg(feature = "tools")]
+pub use crate::tools::{ParsedToolCalls, ToolCall, ToolCallEvent, ToolCallFormat, ToolCallParser};
+
 // ── Remote backend (feature `rpc`) ──────────────────────────────────────────
 
 #[cfg(feature = "rpc")]
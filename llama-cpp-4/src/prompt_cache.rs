@@ -0,0 +1,539 @@
+//! Prefix-sharing prompt cache.
+//!
+//! [`PromptCache`] keeps the KV cache of earlier prompts so that a new prompt
+//! only decodes the part that differs, e.g. everything after a long shared
+//! system prompt. Cached prompts live in spare sequences of the same context
+//! and are indexed by a radix tree over their tokens:
+//!
+//! - [`PromptCache::restore`] copies the longest cached prefix of a prompt
+//!   into a working sequence with [`LlamaContext::copy_kv_cache_seq`] and
+//!   returns its length; the caller decodes the remaining suffix.
+//! - [`PromptCache::store`] records a sequence after it has been decoded.
+//!
+//! Copies only share KV cells in a unified KV cache, so create the context with
+//! `with_kv_unified(true)` and an `n_seq_max` that covers the working
+//! sequences plus the storage sequences given to [`PromptCache::new`].
+//!
+//! The cache is bounded by its storage sequences and by `max_cells`, the
+//! number of distinct token positions in the tree. When prompts are stored
+//! after restoring their prefix from the cache, that is the number of KV cells
+//! the cache keeps alive. Least recently used prompts are evicted first, and a
+//! stored prompt replaces a cached prompt that is a prefix of it.
+//!
+//! ```no_run
+//! use llama_cpp_4::generate::{Generator, GeneratorConfig};
+//! use llama_cpp_4::prelude::*;
+//! use llama_cpp_4::prompt_cache::PromptCache;
+//!
+//! # fn demo(model: &LlamaModel, ctx: &mut LlamaContext, cache: &mut PromptCache) -> Result<(), Box<dyn std::error::Error>> {
+//! // Sequence 0 is the working sequence; the cache owns the others.
+//! let prompt = model.str_to_token("<long system prompt> user question", AddBos::Always)?;
+//! let reused = cache.restore(ctx, &prompt, 0)?;
+//!
+//! let config = GeneratorConfig::new()
+//!     .with_max_tokens(Some(64))
+//!     .with_start_pos(Some(i32::try_from(reused)?));
+//! let generator = Generator::new(ctx, LlamaSampler::greedy(), &prompt[reused..], config)?;
+//! let (text, _reason) = generator.collect_text()?;
+//! cache.store(ctx, &prompt, 0)?;
+//! # let _ = text;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::kv_cache::KvCacheConversionError;
+use crate::context::LlamaContext;
+use crate::token::LlamaToken;
+
+/// Radix-tree index of cached prompts over a [`LlamaContext`]'s sequences.
+///
+/// See the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct PromptCache {
+    tree: PrefixTree,
+    free_seqs: Vec<i32>,
+    max_cells: usize,
+    clock: u64,
+}
+
+impl PromptCache {
+    /// Creates an empty cache that stores prompts in `storage_seqs` and keeps
+    /// at most `max_cells` token positions.
+    ///
+    /// The storage sequences must not be used for anything else while the
+    /// cache is alive.
+    #[must_use]
+    pub fn new(storage_seqs: impl IntoIterator<Item = i32>, max_cells: usize) -> Self {
+        let mut free_seqs: Vec<i32> = storage_seqs.into_iter().collect();
+        // Pop from the back in ascending order.
+        free_seqs.sort_unstable_by(|a, b| b.cmp(a));
+        free_seqs.dedup();
+        Self {
+            tree: PrefixTree::default(),
+            free_seqs,
+            max_cells,
+            clock: 0,
+        }
+    }
+
+    /// Number of cached prompts.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.tree.n_entries()
+    }
+
+    /// Whether nothing is cached.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Distinct token positions currently held.
+    #[must_use]
+    pub fn n_cells(&self) -> usize {
+        self.tree.n_cells
+    }
+
+    /// Upper bound on [`Self::n_cells`].
+    #[must_use]
+    pub fn max_cells(&self) -> usize {
+        self.max_cells
+    }
+
+    /// Length of the longest cached prefix of `tokens`, without touching the
+    /// context.
+    #[must_use]
+    pub fn longest_prefix(&self, tokens: &[LlamaToken]) -> usize {
+        self.tree
+            .longest_prefix(tokens)
+            .map_or(0, |(_, matched)| matched)
+    }
+
+    /// Clears `dest_seq` and copies the longest cached prefix of `tokens` into
+    /// it at positions `0..n`, returning `n`.
+    ///
+    /// At most `tokens.len() - 1` tokens are reused, so there is always at
+    /// least one token left to decode for fresh logits. Decode
+    /// `tokens[n..]` starting at position `n`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a sequence id is negative or a position does not
+    /// fit in an `i32`.
+    pub fn restore(
+        &mut self,
+        ctx: &mut LlamaContext<'_>,
+        tokens: &[LlamaToken],
+        dest_seq: i32,
+    ) -> Result<usize, KvCacheConversionError> {
+        ctx.clear_kv_cache_seq(Some(seq_index(dest_seq)?), None, None)?;
+        let usable = &tokens[..tokens.len().saturating_sub(1)];
+        let Some((leaf, matched)) = self.tree.longest_prefix(usable) else {
+            return Ok(0);
+        };
+        self.clock += 1;
+        let src_seq = self.tree.touch(leaf, self.clock);
+        ctx.copy_kv_cache_seq(src_seq, dest_seq, Some(0), Some(position(matched)?))?;
+        Ok(matched)
+    }
+
+    /// Caches `tokens`, whose KV entries must already be decoded in `src_seq`
+    /// at positions `0..tokens.len()`. Later positions of `src_seq` are
+    /// ignored.
+    ///
+    /// Evicts least recently used prompts until the new one fits. Returns
+    /// `false` if it cannot fit even in an empty cache, or if the cache has no
+    /// storage sequences.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a sequence id is negative or a position does not
+    /// fit in an `i32`.
+    pub fn store(
+        &mut self,
+        ctx: &mut LlamaContext<'_>,
+        tokens: &[LlamaToken],
+        src_seq: i32,
+    ) -> Result<bool, KvCacheConversionError> {
+        if tokens.is_empty() || tokens.len() > self.max_cells {
+            return Ok(false);
+        }
+        self.clock += 1;
+        loop {
+            let matched = match self.tree.longest_prefix(tokens) {
+                Some((leaf, matched)) if matched == tokens.len() => {
+                    // An equal or longer cached prompt already covers it.
+                    self.tree.touch(leaf, self.clock);
+                    return Ok(true);
+                }
+                Some((_, matched)) => matched,
+                None => 0,
+            };
+            let new_cells = tokens.len() - matched;
+            if !self.free_seqs.is_empty() && self.tree.n_cells + new_cells <= self.max_cells {
+                break;
+            }
+            match self.tree.remove_lru() {
+                Some(seq) => self.release(ctx, seq)?,
+                None => return Ok(false),
+            }
+        }
+
+        let seq = self.free_seqs.pop().expect("checked above");
+        ctx.clear_kv_cache_seq(Some(seq_index(seq)?), None, None)?;
+        ctx.copy_kv_cache_seq(src_seq, seq, Some(0), Some(position(tokens.len())?))?;
+        if let Some(superseded) = self.tree.insert(tokens, seq, self.clock) {
+            self.release(ctx, superseded)?;
+        }
+        Ok(true)
+    }
+
+    /// Drops every cached prompt and clears the storage sequences.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a storage sequence id is negative.
+    pub fn clear(&mut self, ctx: &mut LlamaContext<'_>) -> Result<(), KvCacheConversionError> {
+        while let Some(seq) = self.tree.remove_lru() {
+            self.release(ctx, seq)?;
+        }
+        Ok(())
+    }
+
+    fn release(
+        &mut self,
+        ctx: &mut LlamaContext<'_>,
+        seq: i32,
+    ) -> Result<(), KvCacheConversionError> {
+        ctx.clear_kv_cache_seq(Some(seq_index(seq)?), None, None)?;
+        self.free_seqs.push(seq);
+        Ok(())
+    }
+}
+
+fn seq_index(seq: i32) -> Result<u32, KvCacheConversionError> {
+    u32::try_from(seq).map_err(KvCacheConversionError::SeqIdTooLarge)
+}
+
+fn position(n: usize) -> Result<u32, KvCacheConversionError> {
+    // Positions are passed on as `i32`; reject what would not fit there.
+    i32::try_from(n)
+        .and_then(u32::try_from)
+        .map_err(KvCacheConversionError::P1TooLarge)
+}
+
+const ROOT: usize = 0;
+
+/// A cached prompt: the storage sequence holding it and its last use.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    seq: i32,
+    last_used: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    /// Tokens on the edge from the parent.
+    edge: Vec<LlamaToken>,
+    parent: usize,
+    children: Vec<usize>,
+    entry: Option<Entry>,
+}
+
+/// Radix tree over cached prompts.
+///
+/// Invariants: entries sit exactly on the non-root leaves, and non-root inner
+/// nodes have at least two children. Any leaf below the point where a lookup
+/// stops therefore shares the whole matched prefix.
+#[derive(Debug, Clone)]
+struct PrefixTree {
+    nodes: Vec<Node>,
+    vacant: Vec<usize>,
+    n_cells: usize,
+}
+
+impl Default for PrefixTree {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node::default()],
+            vacant: Vec::new(),
+            n_cells: 0,
+        }
+    }
+}
+
+/// Where a walk down the tree stopped.
+#[derive(Debug, Clone, Copy)]
+struct Descent {
+    /// Last node entered; the walk may have stopped inside its edge.
+    node: usize,
+    /// Tokens matched from the root.
+    matched: usize,
+    /// Tokens matched on `node`'s edge.
+    in_edge: usize,
+}
+
+impl PrefixTree {
+    fn n_entries(&self) -> usize {
+        self.nodes
+            .iter()
+            .filter(|node| node.entry.is_some())
+            .count()
+    }
+
+    fn descend(&self, tokens: &[LlamaToken]) -> Descent {
+        let mut descent = Descent {
+            node: ROOT,
+            matched: 0,
+            in_edge: 0,
+        };
+        loop {
+            let Some(&next) = tokens.get(descent.matched) else {
+                return descent;
+            };
+            let child = self.nodes[descent.node]
+                .children
+                .iter()
+                .copied()
+                .find(|&child| self.nodes[child].edge.first() == Some(&next));
+            let Some(child) = child else {
+                return descent;
+            };
+            let edge = &self.nodes[child].edge;
+            let common = edge
+                .iter()
+                .zip(&tokens[descent.matched..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            descent = Descent {
+                node: child,
+                matched: descent.matched + common,
+                in_edge: common,
+            };
+            if common < edge.len() {
+                return descent;
+            }
+        }
+    }
+
+    /// Most recently used leaf sharing the longest prefix with `tokens`, and
+    /// the length of that prefix. `None` when nothing is shared.
+    fn longest_prefix(&self, tokens: &[LlamaToken]) -> Option<(usize, usize)> {
+        let descent = self.descend(tokens);
+        if descent.matched == 0 {
+            return None;
+        }
+        let leaf = self.freshest_leaf(descent.node)?;
+        Some((leaf, descent.matched))
+    }
+
+    fn freshest_leaf(&self, node: usize) -> Option<usize> {
+        let mut stack = vec![node];
+        let mut best: Option<(usize, u64)> = None;
+        while let Some(node) = stack.pop() {
+            let current = &self.nodes[node];
+            if let Some(entry) = current.entry {
+                let fresher = match best {
+                    Some((_, last_used)) => entry.last_used > last_used,
+                    None => true,
+                };
+                if fresher {
+                    best = Some((node, entry.last_used));
+                }
+            }
+            stack.extend_from_slice(&current.children);
+        }
+        best.map(|(leaf, _)| leaf)
+    }
+
+    /// Marks `leaf` as used and returns its sequence.
+    fn touch(&mut self, leaf: usize, now: u64) -> i32 {
+        let entry = self.nodes[leaf]
+            .entry
+            .as_mut()
+            .expect("lookups only return leaves");
+        entry.last_used = now;
+        entry.seq
+    }
+
+    /// Adds `tokens` stored in `seq`. Returns the sequence of a cached prompt
+    /// that was a strict prefix of `tokens` and is now redundant.
+    ///
+    /// `tokens` must not already be covered by a cached prompt.
+    fn insert(&mut self, tokens: &[LlamaToken], seq: i32, now: u64) -> Option<i32> {
+        let descent = self.descend(tokens);
+        debug_assert!(
+            descent.matched < tokens.len(),
+            "covered prompts are not inserted"
+        );
+        let parent = if descent.in_edge < self.nodes[descent.node].edge.len() {
+            self.split(descent.node, descent.in_edge)
+        } else {
+            descent.node
+        };
+
+        let leaf = self.alloc(Node {
+            edge: tokens[descent.matched..].to_vec(),
+            parent,
+            children: Vec::new(),
+            entry: Some(Entry {
+                seq,
+                last_used: now,
+            }),
+        });
+        self.n_cells += tokens.len() - descent.matched;
+        self.nodes[parent].children.push(leaf);
+
+        let superseded = self.nodes[parent].entry.take().map(|entry| entry.seq);
+        if superseded.is_some() {
+            self.merge_into_child(parent);
+        }
+        superseded
+    }
+
+    /// Removes the least recently used prompt and returns its sequence.
+    fn remove_lru(&mut self) -> Option<i32> {
+        let leaf = (0..self.nodes.len())
+            .filter_map(|node| self.nodes[node].entry.map(|entry| (node, entry.last_used)))
+            .min_by_key(|&(_, last_used)| last_used)
+            .map(|(node, _)| node)?;
+        let entry = self.nodes[leaf].entry.take().expect("selected by entry");
+        let parent = self.nodes[leaf].parent;
+        self.n_cells -= self.nodes[leaf].edge.len();
+        self.nodes[parent].children.retain(|&child| child != leaf);
+        self.free(leaf);
+        if self.nodes[parent].children.len() == 1 {
+            self.merge_into_child(parent);
+        }
+        Some(entry.seq)
+    }
+
+    /// Splits `node`'s edge after `at` tokens and returns the new upper node.
+    fn split(&mut self, node: usize, at: usize) -> usize {
+        let parent = self.nodes[node].parent;
+        let lower = self.nodes[node].edge.split_off(at);
+        let upper_edge = std::mem::replace(&mut self.nodes[node].edge, lower);
+        let upper = self.alloc(Node {
+            edge: upper_edge,
+            parent,
+            children: vec![node],
+            entry: None,
+        });
+        self.nodes[node].parent = upper;
+        for child in &mut self.nodes[parent].children {
+            if *child == node {
+                *child = upper;
+            }
+        }
+        upper
+    }
+
+    /// Folds a non-root node without an entry into its only child.
+    fn merge_into_child(&mut self, node: usize) {
+        if node == ROOT {
+            return;
+        }
+        let [child] = self.nodes[node].children[..] else {
+            return;
+        };
+        let parent = self.nodes[node].parent;
+        let mut edge = std::mem::take(&mut self.nodes[node].edge);
+        edge.append(&mut self.nodes[child].edge);
+        self.nodes[child].edge = edge;
+        self.nodes[child].parent = parent;
+        for sibling in &mut self.nodes[parent].children {
+            if *sibling == node {
+                *sibling = child;
+            }
+        }
+        self.free(node);
+    }
+
+    fn alloc(&mut self, node: Node) -> usize {
+        if let Some(index) = self.vacant.pop() {
+            self.nodes[index] = node;
+            index
+        } else {
+            self.nodes.push(node);
+            self.nodes.len() - 1
+        }
+    }
+
+    fn free(&mut self, node: usize) {
+        self.nodes[node] = Node::default();
+        self.vacant.push(node);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::tokens;
+
+    fn seq_of(tree: &PrefixTree, ids: &[i32]) -> Option<(i32, usize)> {
+        tree.longest_prefix(&tokens(ids))
+            .map(|(leaf, matched)| (tree.nodes[leaf].entry.unwrap().seq, matched))
+    }
+
+    #[test]
+    fn matches_longest_shared_prefix() {
+        let mut tree = PrefixTree::default();
+        assert_eq!(tree.insert(&tokens(&[1, 2, 3, 4]), 10, 1), None);
+        assert_eq!(tree.insert(&tokens(&[1, 2, 5]), 11, 2), None);
+        assert_eq!(tree.n_cells, 5);
+        assert_eq!(tree.n_entries(), 2);
+
+        assert_eq!(seq_of(&tree, &[1, 2, 3, 9]), Some((10, 3)));
+        assert_eq!(seq_of(&tree, &[1, 2, 5, 6]), Some((11, 3)));
+        assert_eq!(seq_of(&tree, &[7]), None);
+        // Both leaves share `[1, 2]`; the most recently used one wins.
+        assert_eq!(seq_of(&tree, &[1, 2, 8]), Some((11, 2)));
+        tree.touch(tree.longest_prefix(&tokens(&[1, 2, 3])).unwrap().0, 3);
+        assert_eq!(seq_of(&tree, &[1, 2, 8]), Some((10, 2)));
+    }
+
+    #[test]
+    fn longer_prompt_supersedes_its_prefix() {
+        let mut tree = PrefixTree::default();
+        tree.insert(&tokens(&[1, 2]), 10, 1);
+        assert_eq!(tree.insert(&tokens(&[1, 2, 3]), 11, 2), Some(10));
+        assert_eq!(tree.n_entries(), 1);
+        assert_eq!(tree.n_cells, 3);
+        assert_eq!(seq_of(&tree, &[1, 2]), Some((11, 2)));
+    }
+
+    #[test]
+    fn eviction_is_lru_and_merges_edges() {
+        let mut tree = PrefixTree::default();
+        tree.insert(&tokens(&[1, 2, 3]), 10, 1);
+        tree.insert(&tokens(&[1, 2, 4]), 11, 2);
+        tree.insert(&tokens(&[5]), 12, 3);
+        assert_eq!(tree.n_cells, 5);
+
+        assert_eq!(tree.remove_lru(), Some(10));
+        assert_eq!(tree.n_cells, 4);
+        // `[1, 2]` and `[4]` were folded back into one edge.
+        let root_children = &tree.nodes[ROOT].children;
+        assert!(root_children
+            .iter()
+            .any(|&child| tree.nodes[child].edge == tokens(&[1, 2, 4])));
+        assert_eq!(seq_of(&tree, &[1, 2, 3]), Some((11, 2)));
+
+        assert_eq!(tree.remove_lru(), Some(11));
+        assert_eq!(tree.remove_lru(), Some(12));
+        assert_eq!(tree.remove_lru(), None);
+        assert_eq!(tree.n_cells, 0);
+        assert_eq!(tree.nodes[ROOT].children, Vec::<usize>::new());
+    }
+
+    #[test]
+    fn nodes_are_reused_after_removal() {
+        let mut tree = PrefixTree::default();
+        for round in 0..10 {
+            tree.insert(&tokens(&[1, 2, round]), round, 1);
+            tree.insert(&tokens(&[1, 3, round]), round + 100, 2);
+            while tree.remove_lru().is_some() {}
+        }
+        assert!(tree.nodes.len() <= 5);
+    }
+}
@@ -0,0 +1,599 @@
+//! Separating a reasoning model's thinking from its answer.
+//!
+//! Reasoning models write their chain of thought between tags such as
+//! `<think>` and `</think>` before the answer. [`ReasoningSplitter`] sorts
+//! streamed text into reasoning and content as it arrives, holding back only
+//! text that may still turn into a tag; [`ReasoningStream`] does the same
+//! for tokens on top of a [`StreamDetokenizer`]. [`ReasoningBudget`] closes
+//! the reasoning after a number of tokens by injecting the closing tag.
+//!
+//! [`ReasoningConfig::for_model`] picks the tags from the model's chat
+//! template:
+//!
+//! | Models | Tags |
+//! |--------|------|
+//! | `DeepSeek` R1, Qwen 3, `QwQ`, GLM 4.5, Granite | `<think>` … `</think>` |
+//! | Magistral | `[THINK]` … `[/THINK]` |
+//! | Command R7B | `<\|START_THINKING\|>` … `<\|END_THINKING\|>` |
+//! | Seed-OSS | `<seed:think>` … `</seed:think>` |
+//!
+//! Some of these tags are control tokens, which are only rendered with
+//! [`Special::Tokenize`]. Templates that open the thinking block themselves
+//! (`DeepSeek` R1, Qwen 3 with `enable_thinking`) leave the model inside it;
+//! [`ReasoningConfig::with_prompt`] detects that from the rendered prompt.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use llama_cpp_4::prelude::*;
+//! use llama_cpp_4::reasoning::{ReasoningConfig, ReasoningSplitter};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, "model.gguf", &LlamaModelParams::default())?;
+//! let prompt = "<|im_start|>user\nHi!<|im_end|>\n<|im_start|>assistant\n";
+//!
+//! let mut splitter = ReasoningSplitter::new(ReasoningConfig::for_model(&model).with_prompt(prompt));
+//! for piece in ["<think>\nThe user greets", " me.\n</thi", "nk>\n\nHello!"] {
+//!     let text = splitter.push(piece);
+//!     eprint!("{}", text.reasoning);
+//!     print!("{}", text.content);
+//! }
+//! let _rest = splitter.finish();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+
+use crate::model::{AddBos, LlamaModel, Special};
+use crate::token::detokenizer::{DetokenizeError, StreamDetokenizer};
+use crate::token::LlamaToken;
+use crate::StringToTokenError;
+
+/// Tag pairs recognised in chat templates by [`ReasoningConfig::detect`],
+/// most specific first.
+const KNOWN_TAGS: [(&str, &str); 4] = [
+    ("[THINK]", "[/THINK]"),
+    ("<|START_THINKING|>", "<|END_THINKING|>"),
+    ("<seed:think>", "</seed:think>"),
+    ("<think>", "</think>"),
+];
+
+/// The tags that delimit reasoning.
+///
+/// # Examples
+///
+/// ```
+/// use llama_cpp_4::reasoning::ReasoningConfig;
+///
+/// let config = ReasoningConfig::new().with_tags("[THINK]", "[/THINK]");
+/// assert_eq!(config.tags().len(), 2);
+/// assert!(!config.starts_in_reasoning());
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReasoningConfig {
+    tags: Vec<(String, String)>,
+    starts_in_reasoning: bool,
+}
+
+impl Default for ReasoningConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReasoningConfig {
+    /// `<think>` … `</think>`, starting outside the reasoning.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            tags: vec![("<think>".to_owned(), "</think>".to_owned())],
+            starts_in_reasoning: false,
+        }
+    }
+
+    /// The tags a chat template uses, always including `<think>` …
+    /// `</think>`.
+    #[must_use]
+    pub fn detect(template: &str) -> Self {
+        let mut config = Self {
+            tags: Vec::new(),
+            starts_in_reasoning: false,
+        };
+        for (open, close) in KNOWN_TAGS {
+            if template.contains(open) {
+                config = config.with_tags(open, close);
+            }
+        }
+        config.with_tags("<think>", "</think>")
+    }
+
+    /// The tags used by the model's chat template.
+    #[must_use]
+    pub fn for_model(model: &LlamaModel) -> Self {
+        model
+            .chat_template_source(None)
+            .map_or_else(|_| Self::new(), |template| Self::detect(&template))
+    }
+
+    /// Adds a pair of tags. The first pair opened by the reply is the one
+    /// whose closing tag ends the reasoning.
+    #[must_use]
+    pub fn with_tags(mut self, open: impl Into<String>, close: impl Into<String>) -> Self {
+        let pair = (open.into(), close.into());
+        if !pair.0.is_empty() && !pair.1.is_empty() && !self.tags.contains(&pair) {
+            self.tags.push(pair);
+        }
+        self
+    }
+
+    /// Whether the reply starts inside the reasoning, closed by the first
+    /// pair's closing tag.
+    #[must_use]
+    pub fn with_starts_in_reasoning(mut self, starts_in_reasoning: bool) -> Self {
+        self.starts_in_reasoning = starts_in_reasoning;
+        self
+    }
+
+    /// Starts inside the reasoning if `prompt` ends with an opening tag, as
+    /// when the chat template opens the thinking block for the model. That
+    /// pair is moved to the front.
+    #[must_use]
+    pub fn with_prompt(mut self, prompt: &str) -> Self {
+        let prompt = prompt.trim_end();
+        if let Some(i) = self
+            .tags
+            .iter()
+            .position(|(open, _)| prompt.ends_with(open.as_str()))
+        {
+            let pair = self.tags.remove(i);
+            self.tags.insert(0, pair);
+            self.starts_in_reasoning = true;
+        }
+        self
+    }
+
+    /// The `(open, close)` tag pairs.
+    #[must_use]
+    pub fn tags(&self) -> &[(String, String)] {
+        &self.tags
+    }
+
+    /// Whether the reply starts inside the reasoning.
+    #[must_use]
+    pub fn starts_in_reasoning(&self) -> bool {
+        self.starts_in_reasoning
+    }
+
+    /// Splits a complete reply, trimming both parts.
+    #[must_use]
+    pub fn split(&self, text: &str) -> ReasoningText {
+        let mut splitter = ReasoningSplitter::new(self.clone());
+        let mut split = splitter.push(text);
+        split.append(&splitter.finish());
+        ReasoningText {
+            reasoning: split.reasoning.trim().to_owned(),
+            content: split.content.trim().to_owned(),
+        }
+    }
+}
+
+/// Text sorted into reasoning and content.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReasoningText {
+    /// Text inside the reasoning tags, without the tags.
+    pub reasoning: String,
+    /// Text outside the reasoning tags.
+    pub content: String,
+}
+
+impl ReasoningText {
+    /// Whether both parts are empty.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.reasoning.is_empty() && self.content.is_empty()
+    }
+
+    /// Appends both parts of `other`.
+    pub fn append(&mut self, other: &Self) {
+        self.reasoning.push_str(&other.reasoning);
+        self.content.push_str(&other.content);
+    }
+}
+
+/// Sorts streamed text into reasoning and content.
+///
+/// Tags are removed, as is whitespace at the start of each part. Text that
+/// may be the start of a tag is held back until the next
+/// [`push`](ReasoningSplitter::push) decides it, or until
+/// [`finish`](ReasoningSplitter::finish).
+///
+/// # Examples
+///
+/// ```
+/// use llama_cpp_4::reasoning::{ReasoningConfig, ReasoningSplitter};
+///
+/// let mut splitter = ReasoningSplitter::new(ReasoningConfig::new());
+/// let first = splitter.push("<think>Plan: greet.</th");
+/// assert_eq!(first.reasoning, "Plan: greet.");
+/// assert!(splitter.in_reasoning());
+///
+/// let second = splitter.push("ink>\n\nHello!");
+/// assert_eq!(second.content, "Hello!");
+/// assert!(!splitter.in_reasoning());
+/// ```
+#[derive(Clone, Debug)]
+pub struct ReasoningSplitter {
+    config: ReasoningConfig,
+    buffer: String,
+    /// Index of the open tag pair while inside the reasoning.
+    open: Option<usize>,
+    /// Whitespace is skipped until the current part has text.
+    at_part_start: bool,
+}
+
+impl ReasoningSplitter {
+    /// Creates a splitter for `config`.
+    #[must_use]
+    pub fn new(config: ReasoningConfig) -> Self {
+        let open = (config.starts_in_reasoning && !config.tags.is_empty()).then_some(0);
+        Self {
+            config,
+            buffer: String::new(),
+            open,
+            at_part_start: true,
+        }
+    }
+
+    /// The configuration.
+    #[must_use]
+    pub fn config(&self) -> &ReasoningConfig {
+        &self.config
+    }
+
+    /// Whether the text so far ends inside the reasoning.
+    #[must_use]
+    pub fn in_reasoning(&self) -> bool {
+        self.open.is_some()
+    }
+
+    /// The closing tag that ends the current reasoning, if inside it.
+    #[must_use]
+    pub fn closing_tag(&self) -> Option<&str> {
+        self.open.map(|i| self.config.tags[i].1.as_str())
+    }
+
+    /// Feeds the next piece of text and returns what it releases.
+    pub fn push(&mut self, text: &str) -> ReasoningText {
+        self.buffer.push_str(text);
+        let mut out = ReasoningText::default();
+        loop {
+            match self.find_tag() {
+                Ok((start, len, pair)) => {
+                    self.emit(start, &mut out);
+                    self.buffer.drain(..len);
+                    self.open = match self.open {
+                        Some(_) => None,
+                        None => Some(pair),
+                    };
+                    self.at_part_start = true;
+                }
+                Err(safe) => {
+                    self.emit(safe, &mut out);
+                    return out;
+                }
+            }
+        }
+    }
+
+    /// Ends the text and returns everything still held back.
+    #[must_use]
+    pub fn finish(mut self) -> ReasoningText {
+        let mut out = ReasoningText::default();
+        let rest = self.buffer.len();
+        self.emit(rest, &mut out);
+        out
+    }
+
+    /// Finds the next tag that switches part: `Ok((start, tag length, pair))`,
+    /// or `Err(n)` with the number of bytes that cannot start one.
+    fn find_tag(&self) -> Result<(usize, usize, usize), usize> {
+        let tags: Vec<(usize, &str)> = match self.open {
+            Some(pair) => vec![(pair, self.config.tags[pair].1.as_str())],
+            None => self
+                .config
+                .tags
+                .iter()
+                .enumerate()
+                .map(|(pair, (open, _))| (pair, open.as_str()))
+                .collect(),
+        };
+
+        let found = tags
+            .iter()
+            .filter_map(|&(pair, tag)| self.buffer.find(tag).map(|start| (start, tag.len(), pair)))
+            .min_by_key(|&(start, len, _)| (start, std::cmp::Reverse(len)));
+        if let Some(found) = found {
+            return Ok(found);
+        }
+
+        let held = tags
+            .iter()
+            .flat_map(|(_, tag)| tag.char_indices().skip(1).map(|(i, _)| &tag[..i]))
+            .filter(|prefix| self.buffer.ends_with(prefix))
+            .map(str::len)
+            .max()
+            .unwrap_or(0);
+        Err(self.buffer.len() - held)
+    }
+
+    /// Moves the first `len` bytes of the buffer into the current part.
+    fn emit(&mut self, len: usize, out: &mut ReasoningText) {
+        let mut text: String = self.buffer.drain(..len).collect();
+        if self.at_part_start {
+            let trimmed = text.trim_start();
+            if trimmed.is_empty() {
+                return;
+            }
+            text = trimmed.to_owned();
+            self.at_part_start = false;
+        }
+        if self.open.is_some() {
+            out.reasoning.push_str(&text);
+        } else {
+            out.content.push_str(&text);
+        }
+    }
+}
+
+/// A [`ReasoningSplitter`] fed with tokens through a [`StreamDetokenizer`].
+#[derive(Debug)]
+pub struct ReasoningStream<'a> {
+    detokenizer: StreamDetokenizer<'a>,
+    splitter: ReasoningSplitter,
+}
+
+impl<'a> ReasoningStream<'a> {
+    /// Creates a stream over `model`. Use [`Special::Tokenize`] when the
+    /// tags are control tokens.
+    #[must_use]
+    pub fn new(model: &'a LlamaModel, special: Special, config: ReasoningConfig) -> Self {
+        Self {
+            detokenizer: StreamDetokenizer::new(model, special),
+            splitter: ReasoningSplitter::new(config),
+        }
+    }
+
+    /// Whether the tokens so far end inside the reasoning.
+    #[must_use]
+    pub fn in_reasoning(&self) -> bool {
+        self.splitter.in_reasoning()
+    }
+
+    /// The underlying splitter.
+    #[must_use]
+    pub fn splitter(&self) -> &ReasoningSplitter {
+        &self.splitter
+    }
+
+    /// Feeds one token and returns the text it releases.
+    ///
+    /// # Errors
+    ///
+    /// See [`StreamDetokenizer::push`].
+    pub fn push(&mut self, token: LlamaToken) -> Result<ReasoningText, DetokenizeError> {
+        let text = self.detokenizer.push(token)?;
+        Ok(self.splitter.push(&text))
+    }
+
+    /// Ends the stream and returns everything still held back.
+    ///
+    /// # Errors
+    ///
+    /// See [`StreamDetokenizer::finish`].
+    pub fn finish(self) -> Result<ReasoningText, DetokenizeError> {
+        let text = self.detokenizer.finish()?;
+        let mut splitter = self.splitter;
+        let mut out = splitter.push(&text);
+        out.append(&splitter.finish());
+        Ok(out)
+    }
+}
+
+/// Caps the number of tokens a model may spend reasoning.
+///
+/// Feed every generated token to [`push`](ReasoningBudget::push). Once
+/// `max_tokens` tokens have been generated inside the reasoning,
+/// [`forced_token`](ReasoningBudget::forced_token) yields the tokens of the
+/// closing tag; decode those instead of sampling (and pass them to
+/// [`LlamaSampler::accept`](crate::sampling::LlamaSampler::accept)) so the
+/// model continues with its answer. The budget covers all reasoning blocks
+/// of the reply together.
+///
+/// Tags are matched on text rendered with [`Special::Tokenize`], so tags
+/// that are control tokens are recognised.
+#[derive(Debug)]
+pub struct ReasoningBudget<'a> {
+    stream: ReasoningStream<'a>,
+    max_tokens: usize,
+    /// Tokens injected to close each tag pair of the configuration.
+    closing: Vec<Vec<LlamaToken>>,
+    n_reasoning: usize,
+    forced: VecDeque<LlamaToken>,
+    /// Set from queueing a closing tag until the reasoning has ended.
+    forcing: bool,
+}
+
+impl<'a> ReasoningBudget<'a> {
+    /// Creates a budget of `max_tokens` reasoning tokens for the tags of
+    /// `config`. With `0`, reasoning is closed as soon as it opens.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a closing tag cannot be tokenized.
+    pub fn new(
+        model: &'a LlamaModel,
+        config: ReasoningConfig,
+        max_tokens: usize,
+    ) -> Result<Self, StringToTokenError> {
+        let closing = config
+            .tags
+            .iter()
+            .map(|(_, close)| model.str_to_token(&format!("\n{close}\n\n"), AddBos::Never))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            stream: ReasoningStream::new(model, Special::Tokenize, config),
+            max_tokens,
+            closing,
+            n_reasoning: 0,
+            forced: VecDeque::new(),
+            forcing: false,
+        })
+    }
+
+    /// The maximum number of reasoning tokens.
+    #[must_use]
+    pub fn max_tokens(&self) -> usize {
+        self.max_tokens
+    }
+
+    /// Tokens generated inside the reasoning so far, including forced ones.
+    #[must_use]
+    pub fn n_reasoning_tokens(&self) -> usize {
+        self.n_reasoning
+    }
+
+    /// Whether the tokens so far end inside the reasoning.
+    #[must_use]
+    pub fn in_reasoning(&self) -> bool {
+        self.stream.in_reasoning()
+    }
+
+    /// Whether the budget has been used up.
+    #[must_use]
+    pub fn is_exhausted(&self) -> bool {
+        self.n_reasoning >= self.max_tokens
+    }
+
+    /// The next token to decode instead of sampling, while the closing tag
+    /// of an exhausted budget is being injected.
+    pub fn forced_token(&mut self) -> Option<LlamaToken> {
+        if !self.forcing && self.in_reasoning() && self.is_exhausted() {
+            let closing = self
+                .stream
+                .splitter
+                .open
+                .and_then(|pair| self.closing.get(pair));
+            if let Some(tokens) = closing {
+                self.forced.extend(tokens);
+                self.forcing = true;
+            }
+        }
+        self.forced.pop_front()
+    }
+
+    /// Records a generated token, sampled or forced.
+    ///
+    /// # Errors
+    ///
+    /// See [`StreamDetokenizer::push`].
+    pub fn push(&mut self, token: LlamaToken) -> Result<(), DetokenizeError> {
+        self.stream.push(token)?;
+        if self.in_reasoning() {
+            self.n_reasoning += 1;
+        } else if self.forced.is_empty() {
+            self.forcing = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `text` one character at a time.
+    fn stream(config: &ReasoningConfig, text: &str) -> ReasoningText {
+        let mut splitter = ReasoningSplitter::new(config.clone());
+        let mut out = ReasoningText::default();
+        for (i, c) in text.char_indices() {
+            out.append(&splitter.push(&text[i..i + c.len_utf8()]));
+        }
+        out.append(&splitter.finish());
+        out
+    }
+
+    #[test]
+    fn splits_think_tags() {
+        let config = ReasoningConfig::new();
+        let text = "<think>\nThe user says hi.\n</think>\n\nHello! 👋";
+        let expected = ReasoningText {
+            reasoning: "The user says hi.\n".to_owned(),
+            content: "Hello! 👋".to_owned(),
+        };
+        assert_eq!(stream(&config, text), expected);
+
+        let mut splitter = ReasoningSplitter::new(config.clone());
+        let mut whole = splitter.push(text);
+        whole.append(&splitter.finish());
+        assert_eq!(whole, expected);
+
+        let split = config.split(text);
+        assert_eq!(split.reasoning, "The user says hi.");
+        assert_eq!(split.content, "Hello! 👋");
+    }
+
+    #[test]
+    fn prompt_that_opens_the_block_starts_in_reasoning() {
+        let config = ReasoningConfig::new().with_prompt("<|im_start|>assistant\n<think>\n");
+        assert!(config.starts_in_reasoning());
+        let split = config.split("Thinking.</think>Answer.");
+        assert_eq!(split.reasoning, "Thinking.");
+        assert_eq!(split.content, "Answer.");
+
+        let config = ReasoningConfig::new().with_prompt("<|im_start|>assistant\n");
+        assert!(!config.starts_in_reasoning());
+    }
+
+    #[test]
+    fn detects_tags_from_template() {
+        let config =
+            ReasoningConfig::detect("{%- if thinking %}[THINK]{{ thinking }}[/THINK]{% endif %}");
+        assert_eq!(
+            config.tags()[0],
+            ("[THINK]".to_owned(), "[/THINK]".to_owned())
+        );
+        let text = "[THINK]Hmm.[/THINK]Yes.<think>Sure?</think> Sure.";
+        let split = config.split(text);
+        assert_eq!(split.reasoning, "Hmm.Sure?");
+        assert_eq!(split.content, "Yes.Sure.");
+        assert_eq!(stream(&config, text), split);
+
+        assert_eq!(
+            ReasoningConfig::detect("{{ messages }}"),
+            ReasoningConfig::new()
+        );
+    }
+
+    #[test]
+    fn text_without_tags_is_content() {
+        let config = ReasoningConfig::new();
+        let text = "a < b and <thin crust> pizza";
+        assert_eq!(
+            stream(&config, text),
+            ReasoningText {
+                reasoning: String::new(),
+                content: text.to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn unterminated_reasoning_stays_reasoning() {
+        let config = ReasoningConfig::new();
+        let split = config.split("<think>Still thinking</thi");
+        assert_eq!(split.reasoning, "Still thinking</thi");
+        assert_eq!(split.content, "");
+    }
+}
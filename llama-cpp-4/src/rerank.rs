@@ -0,0 +1,196 @@
+//! Query–document relevance scoring with reranker (cross-encoder) models.
+//!
+//! Reranker GGUFs score a query and a document read together. The context
+//! must be created with embeddings enabled and
+//! [`LlamaPoolingType::Rank`]; the model's classification head then leaves
+//! the relevance score in the first value of each sequence embedding
+//! ([`LlamaContext::embeddings_seq_ith`]).
+//!
+//! [`rerank`] formats every pair like `llama-server`'s `/rerank` endpoint
+//! (see [`format_rerank`]) and packs as many pairs as fit into one batch,
+//! one sequence id per pair.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use llama_cpp_4::rerank::rerank;
+//! use llama_cpp_4::prelude::*;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, "reranker.gguf", &LlamaModelParams::default())?;
+//! let params = LlamaContextParams::default()
+//!     .with_embeddings(true)
+//!     .with_pooling_type(LlamaPoolingType::Rank)
+//!     .with_n_seq_max(8);
+//! let mut ctx = model.new_context(&backend, params)?;
+//!
+//! let documents = ["Paris is the capital of France.", "Bananas are yellow."];
+//! let scores = rerank(&mut ctx, "What is the capital of France?", &documents)?;
+//! assert!(scores[0] > scores[1]);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::params::LlamaPoolingType;
+use crate::context::LlamaContext;
+use crate::embed::pack;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::{AddBos, LlamaModel};
+use crate::token::{present, LlamaToken};
+use crate::{DecodeError, EmbeddingsError, StringToTokenError};
+
+/// Failure while scoring query–document pairs.
+#[derive(Debug, thiserror::Error)]
+pub enum RerankError {
+    /// The context does not use rank pooling.
+    #[error("reranking needs rank pooling, but the context uses {0:?}")]
+    NotRankPooling(LlamaPoolingType),
+    /// The query alone does not leave room for any document token.
+    #[error("the query needs {n_tokens} tokens, but a batch holds {limit}")]
+    QueryTooLong {
+        /// Tokens of the formatted query, without a document.
+        n_tokens: usize,
+        /// Tokens one decode can hold.
+        limit: usize,
+    },
+    /// The query or a document could not be tokenized.
+    #[error(transparent)]
+    Tokenize(#[from] StringToTokenError),
+    /// A token could not be added to the batch.
+    #[error(transparent)]
+    BatchAdd(#[from] BatchAddError),
+    /// Decoding a batch failed.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    /// The score could not be read from the context.
+    #[error(transparent)]
+    Embeddings(#[from] EmbeddingsError),
+}
+
+/// Lays out one query–document pair as reranker models expect:
+/// `[BOS] query [EOS] [SEP] document [EOS]`.
+///
+/// Each special token is added only when the vocabulary asks for it
+/// ([`LlamaModel::add_bos_token`], [`LlamaModel::add_eos_token`] and the
+/// vocabulary's `add_sep` flag). The classification token stands in for a
+/// missing BOS, and the separator for a missing EOS.
+#[must_use]
+pub fn format_rerank(
+    model: &LlamaModel,
+    query: &[LlamaToken],
+    document: &[LlamaToken],
+) -> Vec<LlamaToken> {
+    let vocab = model.get_vocab();
+    let sep = present(model.token_sep());
+    let bos = present(model.token_bos())
+        .or_else(|| present(model.token_cls()))
+        .filter(|_| model.add_bos_token());
+    let eos = present(model.token_eos())
+        .or(sep)
+        .filter(|_| model.add_eos_token());
+    let sep = sep.filter(|_| vocab.get_add_sep());
+    layout(bos, eos, sep, query, document)
+}
+
+/// Scores every document against `query` and returns the scores in document
+/// order; higher means more relevant.
+///
+/// Scores are the raw outputs of the classification head. Pairs are packed
+/// into batches of at most [`LlamaContext::n_seq_max`] sequences and
+/// `min(n_batch, n_ubatch)` tokens, since the whole batch must be encoded at
+/// once. Documents too long for one batch are cut at the end. The memory of
+/// the context is cleared before every batch.
+///
+/// # Errors
+///
+/// Returns [`RerankError::NotRankPooling`] unless the context uses
+/// [`LlamaPoolingType::Rank`], [`RerankError::Embeddings`] when embeddings are
+/// not enabled, [`RerankError::QueryTooLong`] when the query leaves no room
+/// for a document, and tokenization or decode errors.
+pub fn rerank(
+    ctx: &mut LlamaContext<'_>,
+    query: &str,
+    documents: &[impl AsRef<str>],
+) -> Result<Vec<f32>, RerankError> {
+    let pooling = ctx.pooling_type();
+    if pooling != LlamaPoolingType::Rank {
+        return Err(RerankError::NotRankPooling(pooling));
+    }
+    let model = ctx.model;
+    let limit = usize::try_from(ctx.n_batch().min(ctx.n_ubatch())).unwrap_or(usize::MAX);
+    let n_seq_max = usize::try_from(ctx.n_seq_max())
+        .unwrap_or(usize::MAX)
+        .max(1);
+
+    let query = model.str_to_token(query, AddBos::Never)?;
+    let n_query = format_rerank(model, &query, &[]).len();
+    if n_query >= limit {
+        return Err(RerankError::QueryTooLong {
+            n_tokens: n_query,
+            limit,
+        });
+    }
+    let mut pairs = Vec::with_capacity(documents.len());
+    for document in documents {
+        let mut document = model.str_to_token(document.as_ref(), AddBos::Never)?;
+        document.truncate(limit - n_query);
+        pairs.push(format_rerank(model, &query, &document));
+    }
+
+    let mut scores = Vec::with_capacity(pairs.len());
+    let mut batch = LlamaBatch::new(limit, 1);
+    for group in pack(pairs.iter().map(Vec::len), limit, n_seq_max) {
+        batch.clear();
+        for (seq_id, pair) in (0..).zip(&pairs[group.clone()]) {
+            batch.add_sequence(pair, seq_id, false)?;
+        }
+        ctx.clear_kv_cache();
+        ctx.decode(&mut batch)?;
+        for seq_id in (0..).take(group.len()) {
+            let embedding = ctx.embeddings_seq_ith(seq_id)?;
+            scores.push(embedding.first().copied().unwrap_or(f32::NAN));
+        }
+    }
+    Ok(scores)
+}
+
+fn layout(
+    bos: Option<LlamaToken>,
+    eos: Option<LlamaToken>,
+    sep: Option<LlamaToken>,
+    query: &[LlamaToken],
+    document: &[LlamaToken],
+) -> Vec<LlamaToken> {
+    let mut tokens = Vec::with_capacity(query.len() + document.len() + 4);
+    tokens.extend(bos);
+    tokens.extend_from_slice(query);
+    tokens.extend(eos);
+    tokens.extend(sep);
+    tokens.extend_from_slice(document);
+    tokens.extend(eos);
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::tokens;
+
+    #[test]
+    fn layout_places_special_tokens() {
+        let (bos, eos, sep) = (
+            Some(LlamaToken(1)),
+            Some(LlamaToken(2)),
+            Some(LlamaToken(3)),
+        );
+        assert_eq!(
+            layout(bos, eos, sep, &tokens(&[10, 11]), &tokens(&[20])),
+            tokens(&[1, 10, 11, 2, 3, 20, 2])
+        );
+        assert_eq!(
+            layout(None, eos, None, &tokens(&[10]), &tokens(&[20])),
+            tokens(&[10, 2, 20, 2])
+        );
+    }
+}
@@ -1,4 +1,8 @@
 //! Safe wrapper around `llama_sampler`.
+//!
+//! Submodules:
+//!
+//! - [`custom`] — samplers implemented in Rust and bridged into native chains.
 
 use std::borrow::Borrow;
 use std::ffi::{c_char, CString};
@@ -24,6 +28,8 @@ use crate::model::LlamaModel;
 use crate::token::data_array::LlamaTokenDataArray;
 use crate::token::LlamaToken;
 
+pub mod custom;
+
 /// A safe wrapper around `llama_sampler`.
 pub struct LlamaSampler {
     pub(crate) sampler: NonNull<llama_sampler>,
@@ -0,0 +1,523 @@
+//! Rust-implemented samplers bridged into native sampler chains.
+//!
+//! Implement [`CustomSampler`] and wrap the value with [`LlamaSampler::custom`]
+//! to obtain an ordinary [`LlamaSampler`]. The wrapper is backed by a native
+//! `llama_sampler_i` vtable, so it can be applied to a
+//! [`LlamaTokenDataArray`](crate::token::data_array::LlamaTokenDataArray),
+//! placed inside [`LlamaSampler::chain`], driven by [`LlamaSampler::sample`],
+//! or attached to a sequence through
+//! [`LlamaContextParams::with_sampler_seq_configs`](crate::LlamaContextParams::with_sampler_seq_configs).
+//! Custom samplers always run on the CPU; a chain that contains one stops
+//! offloading at that sampler.
+//!
+//! Every callback made by llama.cpp into Rust runs inside
+//! [`catch_unwind`]. A panic never unwinds across the FFI boundary: it is
+//! recorded as a [`CustomSamplerFailure`], the sampler is poisoned, and every
+//! later callback leaves the candidates untouched. Use
+//! [`LlamaSampler::custom_with_monitor`] to observe such failures.
+//!
+//! # Example
+//!
+//! ```
+//! use llama_cpp_4::sampling::custom::{CustomSampler, SamplerCandidates};
+//! use llama_cpp_4::sampling::LlamaSampler;
+//! use llama_cpp_4::token::data::LlamaTokenData;
+//! use llama_cpp_4::token::data_array::LlamaTokenDataArray;
+//! use llama_cpp_4::token::LlamaToken;
+//!
+//! /// Bans one token by pushing its logit to negative infinity.
+//! #[derive(Clone)]
+//! struct Ban(LlamaToken);
+//!
+//! impl CustomSampler for Ban {
+//!     fn name(&self) -> &str {
+//!         "ban"
+//!     }
+//!
+//!     fn apply(&mut self, candidates: &mut SamplerCandidates<'_>) {
+//!         for data in candidates.as_mut_slice() {
+//!             if data.id() == self.0 {
+//!                 data.set_logit(f32::NEG_INFINITY);
+//!             }
+//!         }
+//!     }
+//! }
+//!
+//! let mut chain = LlamaSampler::chain_simple([
+//!     LlamaSampler::custom(Ban(LlamaToken(2))),
+//!     LlamaSampler::greedy(),
+//! ]);
+//! let mut data_array = LlamaTokenDataArray::new(vec![
+//!     LlamaTokenData::new(LlamaToken(0), 0., 0.),
+//!     LlamaTokenData::new(LlamaToken(1), 1., 0.),
+//!     LlamaTokenData::new(LlamaToken(2), 2., 0.),
+//! ], false);
+//! data_array.apply_sampler(&mut chain);
+//! assert_eq!(data_array.selected_token(), Some(LlamaToken(1)));
+//! ```
+
+use std::any::Any;
+use std::ffi::{c_char, CString};
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr::{self, NonNull};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use llama_cpp_sys_4::{
+    llama_sampler, llama_sampler_context_t, llama_sampler_i, llama_sampler_init, llama_token,
+    llama_token_data_array,
+};
+
+use crate::sampling::LlamaSampler;
+use crate::token::data::LlamaTokenData;
+use crate::token::LlamaToken;
+
+/// Maximum retained custom sampler failure bytes.
+pub const MAX_CUSTOM_SAMPLER_FAILURE_BYTES: usize = 1_024;
+
+/// A sampler implemented in Rust.
+///
+/// Only [`apply`](Self::apply) is required. Cloning is provided for every
+/// `Clone` implementor through [`CustomSamplerClone`], which lets
+/// [`LlamaSampler::clone_sampler`] duplicate chains that contain this sampler.
+pub trait CustomSampler: CustomSamplerClone + Send + 'static {
+    /// Name reported by [`LlamaSampler::name`].
+    ///
+    /// Read once when the sampler is wrapped; interior NUL bytes are removed.
+    fn name(&self) -> &str {
+        "custom"
+    }
+
+    /// Modifies the candidate set in place.
+    fn apply(&mut self, candidates: &mut SamplerCandidates<'_>);
+
+    /// Observes a token accepted into the sequence.
+    fn accept(&mut self, _token: LlamaToken) {}
+
+    /// Clears any per-sequence state.
+    fn reset(&mut self) {}
+}
+
+/// Object-safe cloning for [`CustomSampler`].
+///
+/// Implemented automatically for every `CustomSampler + Clone`.
+pub trait CustomSamplerClone {
+    /// Returns an independent boxed copy of this sampler.
+    fn clone_box(&self) -> Box<dyn CustomSampler>;
+}
+
+impl<T: CustomSampler + Clone> CustomSamplerClone for T {
+    fn clone_box(&self) -> Box<dyn CustomSampler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Mutable view of the native candidate array passed to
+/// [`CustomSampler::apply`].
+///
+/// The candidate count may only shrink, so the view never exposes memory
+/// beyond what llama.cpp handed in.
+pub struct SamplerCandidates<'a> {
+    raw: &'a mut llama_token_data_array,
+}
+
+impl fmt::Debug for SamplerCandidates<'_> {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("SamplerCandidates")
+            .field("len", &self.len())
+            .field("selected", &self.selected())
+            .field("sorted", &self.is_sorted())
+            .finish()
+    }
+}
+
+impl SamplerCandidates<'_> {
+    /// Number of candidates.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.raw.size
+    }
+
+    /// Whether no candidates remain.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.raw.size == 0
+    }
+
+    /// Candidates as a shared slice.
+    #[must_use]
+    pub fn as_slice(&self) -> &[LlamaTokenData] {
+        if self.raw.size == 0 || self.raw.data.is_null() {
+            return &[];
+        }
+        // SAFETY: llama.cpp hands in `size` initialized entries and
+        // `LlamaTokenData` is a transparent wrapper around `llama_token_data`.
+        unsafe { std::slice::from_raw_parts(self.raw.data.cast::<LlamaTokenData>(), self.raw.size) }
+    }
+
+    /// Candidates as a mutable slice.
+    #[must_use]
+    pub fn as_mut_slice(&mut self) -> &mut [LlamaTokenData] {
+        if self.raw.size == 0 || self.raw.data.is_null() {
+            return &mut [];
+        }
+        // SAFETY: see `as_slice`; the view holds the only borrow of the array.
+        unsafe {
+            std::slice::from_raw_parts_mut(self.raw.data.cast::<LlamaTokenData>(), self.raw.size)
+        }
+    }
+
+    /// Keeps only the first `len` candidates. Larger values are ignored.
+    ///
+    /// A selection that falls outside the kept range is cleared.
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.raw.size {
+            self.raw.size = len;
+            if self.selected().is_none() {
+                self.raw.selected = -1;
+            }
+        }
+    }
+
+    /// Index of the selected candidate, if any.
+    #[must_use]
+    pub fn selected(&self) -> Option<usize> {
+        usize::try_from(self.raw.selected)
+            .ok()
+            .filter(|&index| index < self.raw.size)
+    }
+
+    /// The selected token, if any.
+    #[must_use]
+    pub fn selected_token(&self) -> Option<LlamaToken> {
+        self.as_slice()
+            .get(self.selected()?)
+            .map(LlamaTokenData::id)
+    }
+
+    /// Selects the candidate at `index`, or clears the selection with `None`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of range.
+    pub fn set_selected(&mut self, index: Option<usize>) {
+        self.raw.selected = match index {
+            Some(index) => {
+                assert!(index < self.raw.size, "selected index out of range");
+                i64::try_from(index).expect("selected index exceeds i64")
+            }
+            None => -1,
+        };
+    }
+
+    /// Whether the candidates are sorted by descending logit.
+    #[must_use]
+    pub fn is_sorted(&self) -> bool {
+        self.raw.sorted
+    }
+
+    /// Records whether the candidates are sorted by descending logit.
+    ///
+    /// Later native samplers trust this flag, so only set it when it holds.
+    pub fn set_sorted(&mut self, sorted: bool) {
+        self.raw.sorted = sorted;
+    }
+}
+
+/// Custom sampler callback in which a panic was contained.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CustomSamplerCallback {
+    /// [`CustomSampler::apply`].
+    Apply,
+    /// [`CustomSampler::accept`].
+    Accept,
+    /// [`CustomSampler::reset`].
+    Reset,
+    /// [`CustomSamplerClone::clone_box`].
+    Clone,
+}
+
+/// Contained panic from a [`CustomSampler`] callback.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CustomSamplerFailure {
+    callback: CustomSamplerCallback,
+    message: String,
+}
+
+impl CustomSamplerFailure {
+    fn new(callback: CustomSamplerCallback, message: impl Into<String>) -> Self {
+        let mut message = message.into();
+        if message.len() > MAX_CUSTOM_SAMPLER_FAILURE_BYTES {
+            let mut end = MAX_CUSTOM_SAMPLER_FAILURE_BYTES;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+        Self { callback, message }
+    }
+
+    fn from_panic(callback: CustomSamplerCallback, payload: &(dyn Any + Send)) -> Self {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| (*message).to_owned())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "custom sampler panicked".to_owned());
+        Self::new(callback, message)
+    }
+
+    /// Returns the callback that panicked.
+    #[must_use]
+    pub const fn callback(&self) -> CustomSamplerCallback {
+        self.callback
+    }
+
+    /// Returns the bounded panic message.
+    #[must_use]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for CustomSamplerFailure {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "custom sampler {:?} callback panicked: {}",
+            self.callback, self.message
+        )
+    }
+}
+
+/// Shared observer for failures of a custom sampler and all of its clones.
+#[derive(Clone, Debug, Default)]
+pub struct CustomSamplerMonitor {
+    failure: Arc<Mutex<Option<CustomSamplerFailure>>>,
+}
+
+impl CustomSamplerMonitor {
+    /// Returns the first contained failure, if any.
+    #[must_use]
+    pub fn failure(&self) -> Option<CustomSamplerFailure> {
+        self.failure
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Returns whether any callback has panicked.
+    #[must_use]
+    pub fn has_failed(&self) -> bool {
+        self.failure().is_some()
+    }
+
+    fn record(&self, failure: CustomSamplerFailure) {
+        tracing::warn!(%failure, "custom sampler poisoned");
+        let mut slot = self.failure.lock().unwrap_or_else(PoisonError::into_inner);
+        if slot.is_none() {
+            *slot = Some(failure);
+        }
+    }
+}
+
+/// Native sampler context: the boxed Rust sampler plus its stable name.
+///
+/// `inner` is `None` once a callback has panicked.
+struct CustomSamplerState {
+    name: CString,
+    inner: Option<Box<dyn CustomSampler>>,
+    monitor: CustomSamplerMonitor,
+}
+
+impl CustomSamplerState {
+    fn run(&mut self, callback: CustomSamplerCallback, body: impl FnOnce(&mut dyn CustomSampler)) {
+        let Some(inner) = self.inner.as_mut() else {
+            return;
+        };
+        if let Err(payload) = catch_unwind(AssertUnwindSafe(|| body(inner.as_mut()))) {
+            self.inner = None;
+            self.monitor
+                .record(CustomSamplerFailure::from_panic(callback, payload.as_ref()));
+        }
+    }
+}
+
+static CUSTOM_SAMPLER_IFACE: llama_sampler_i = custom_sampler_iface();
+
+const fn custom_sampler_iface() -> llama_sampler_i {
+    // SAFETY: every field is an optional function pointer, for which the
+    // all-zero pattern is `None`. The backend hooks therefore stay unset.
+    let mut iface: llama_sampler_i = unsafe { std::mem::zeroed() };
+    iface.name = Some(custom_sampler_name);
+    iface.accept = Some(custom_sampler_accept);
+    iface.apply = Some(custom_sampler_apply);
+    iface.reset = Some(custom_sampler_reset);
+    iface.clone = Some(custom_sampler_clone);
+    iface.free = Some(custom_sampler_free);
+    iface
+}
+
+/// Wraps `state` in a native sampler, reclaiming it if llama.cpp fails.
+fn init_custom_sampler(state: CustomSamplerState) -> Option<NonNull<llama_sampler>> {
+    let ctx: llama_sampler_context_t = Box::into_raw(Box::new(state)).cast();
+    // SAFETY: the vtable is a static that llama.cpp never writes through, and
+    // `ctx` is released exclusively by `custom_sampler_free`.
+    let sampler =
+        unsafe { llama_sampler_init(ptr::addr_of!(CUSTOM_SAMPLER_IFACE).cast_mut(), ctx) };
+    let sampler = NonNull::new(sampler);
+    if sampler.is_none() {
+        // SAFETY: llama.cpp did not take ownership of `ctx`.
+        drop(unsafe { Box::from_raw(ctx.cast::<CustomSamplerState>()) });
+    }
+    sampler
+}
+
+/// # Safety
+///
+/// `smpl` must be a live sampler created by `init_custom_sampler`.
+unsafe fn custom_state<'a>(smpl: *const llama_sampler) -> Option<&'a mut CustomSamplerState> {
+    if smpl.is_null() {
+        return None;
+    }
+    unsafe { (*smpl).ctx.cast::<CustomSamplerState>().as_mut() }
+}
+
+unsafe extern "C" fn custom_sampler_name(smpl: *const llama_sampler) -> *const c_char {
+    // SAFETY: llama.cpp only calls this vtable with samplers it created from it.
+    match unsafe { custom_state(smpl) } {
+        Some(state) => state.name.as_ptr(),
+        None => c"custom".as_ptr(),
+    }
+}
+
+unsafe extern "C" fn custom_sampler_accept(smpl: *mut llama_sampler, token: llama_token) {
+    // SAFETY: llama.cpp only calls this vtable with samplers it created from it.
+    if let Some(state) = unsafe { custom_state(smpl) } {
+        state.run(CustomSamplerCallback::Accept, |inner| {
+            inner.accept(LlamaToken(token));
+        });
+    }
+}
+
+unsafe extern "C" fn custom_sampler_apply(
+    smpl: *mut llama_sampler,
+    cur_p: *mut llama_token_data_array,
+) {
+    // SAFETY: llama.cpp only calls this vtable with samplers it created from
+    // it, and `cur_p` is live for the duration of the call.
+    let (Some(state), Some(raw)) = (unsafe { custom_state(smpl) }, unsafe { cur_p.as_mut() })
+    else {
+        return;
+    };
+    state.run(CustomSamplerCallback::Apply, |inner| {
+        inner.apply(&mut SamplerCandidates { raw });
+    });
+}
+
+unsafe extern "C" fn custom_sampler_reset(smpl: *mut llama_sampler) {
+    // SAFETY: llama.cpp only calls this vtable with samplers it created from it.
+    if let Some(state) = unsafe { custom_state(smpl) } {
+        state.run(CustomSamplerCallback::Reset, |inner| inner.reset());
+    }
+}
+
+unsafe extern "C" fn custom_sampler_clone(smpl: *const llama_sampler) -> *mut llama_sampler {
+    // SAFETY: llama.cpp only calls this vtable with samplers it created from it.
+    let Some(state) = (unsafe { custom_state(smpl) }) else {
+        return ptr::null_mut();
+    };
+    let inner = state.inner.as_ref().and_then(|inner| {
+        catch_unwind(AssertUnwindSafe(|| inner.clone_box()))
+            .map_err(|payload| {
+                state.monitor.record(CustomSamplerFailure::from_panic(
+                    CustomSamplerCallback::Clone,
+                    payload.as_ref(),
+                ));
+            })
+            .ok()
+    });
+    // A failed clone yields a poisoned pass-through copy rather than null,
+    // which native chain cloning would dereference.
+    init_custom_sampler(CustomSamplerState {
+        name: state.name.clone(),
+        inner,
+        monitor: state.monitor.clone(),
+    })
+    .map_or(ptr::null_mut(), NonNull::as_ptr)
+}
+
+unsafe extern "C" fn custom_sampler_free(smpl: *mut llama_sampler) {
+    if smpl.is_null() {
+        return;
+    }
+    // SAFETY: the context was produced by `Box::into_raw` in
+    // `init_custom_sampler` and llama.cpp frees each sampler exactly once.
+    let ctx = unsafe { (*smpl).ctx.cast::<CustomSamplerState>() };
+    if !ctx.is_null() {
+        // Dropping user state must not unwind into C either.
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(unsafe { Box::from_raw(ctx) })));
+    }
+}
+
+impl LlamaSampler {
+    /// Wraps a Rust [`CustomSampler`] in a native sampler.
+    ///
+    /// See [`custom`](crate::sampling::custom) for how panics are contained.
+    ///
+    /// # Panics
+    ///
+    /// Panics if llama.cpp returns a null pointer.
+    #[must_use]
+    pub fn custom(sampler: impl CustomSampler) -> Self {
+        Self::custom_with_monitor(sampler).0
+    }
+
+    /// Same as [`Self::custom`], also returning a monitor that reports any
+    /// contained panic from this sampler or its clones.
+    ///
+    /// # Panics
+    ///
+    /// Panics if llama.cpp returns a null pointer.
+    ///
+    /// # Example
+    /// ```rust
+    /// use llama_cpp_4::sampling::custom::{CustomSampler, CustomSamplerCallback, SamplerCandidates};
+    /// use llama_cpp_4::sampling::LlamaSampler;
+    /// use llama_cpp_4::token::{data::LlamaTokenData, data_array::LlamaTokenDataArray, LlamaToken};
+    ///
+    /// #[derive(Clone)]
+    /// struct Broken;
+    ///
+    /// impl CustomSampler for Broken {
+    ///     fn apply(&mut self, _candidates: &mut SamplerCandidates<'_>) {
+    ///         panic!("boom");
+    ///     }
+    /// }
+    ///
+    /// let (mut sampler, monitor) = LlamaSampler::custom_with_monitor(Broken);
+    /// let mut data_array = LlamaTokenDataArray::new(
+    ///     vec![LlamaTokenData::new(LlamaToken(0), 1., 0.)],
+    ///     false,
+    /// );
+    /// data_array.apply_sampler(&mut sampler);
+    ///
+    /// let failure = monitor.failure().unwrap();
+    /// assert_eq!(failure.callback(), CustomSamplerCallback::Apply);
+    /// assert_eq!(failure.message(), "boom");
+    /// assert_eq!(data_array.data[0].logit(), 1.);
+    /// ```
+    #[must_use]
+    pub fn custom_with_monitor(sampler: impl CustomSampler) -> (Self, CustomSamplerMonitor) {
+        let monitor = CustomSamplerMonitor::default();
+        let name = CString::new(sampler.name().replace('\0', ""))
+            .expect("interior NUL bytes were removed");
+        let state = CustomSamplerState {
+            name,
+            inner: Some(Box::new(sampler)),
+            monitor: monitor.clone(),
+        };
+        let sampler = init_custom_sampler(state).expect("sampler_init returned null");
+        (Self { sampler }, monitor)
+    }
+}
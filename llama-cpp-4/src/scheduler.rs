@@ -0,0 +1,401 @@
+//! Continuous batching of independent requests on one context.
+//!
+//! [`BatchScheduler`] multiplexes many generation requests onto a single
+//! [`LlamaContext`]. Every running request owns one sequence id. Each call to
+//! [`BatchScheduler::step`] builds one shared [`LlamaBatch`] and runs one
+//! decode. The batch holds:
+//!
+//! - the next token of every request that is already generating, and
+//! - as many pending prompt tokens as still fit in `n_batch`.
+//!
+//! After the decode, each request that produced logits is sampled with its own
+//! [`LlamaSampler`] and reported as a [`SchedulerEvent`].
+//!
+//! Requests can be submitted at any time, including between steps while other
+//! requests are running. They wait in a queue until a sequence is free. Long
+//! prompts are prefilled in chunks, so they never stall the requests that are
+//! already streaming. When a request finishes or is cancelled, its sequence is
+//! removed from the KV cache with [`LlamaContext::clear_kv_cache_seq`] and
+//! handed to the next queued request.
+//!
+//! The number of concurrent requests is [`LlamaContext::n_seq_max`]. Set it
+//! with [`LlamaContextParams::with_n_seq_max`]. Without a unified KV cache
+//! each sequence gets `n_ctx / n_seq_max` positions
+//! ([`LlamaContext::n_ctx_seq`]).
+//!
+//! [`LlamaContextParams::with_n_seq_max`]: crate::context::params::LlamaContextParams::with_n_seq_max
+//!
+//! ```no_run
+//! use llama_cpp_4::generate::GeneratorConfig;
+//! use llama_cpp_4::prelude::*;
+//! use llama_cpp_4::scheduler::BatchScheduler;
+//!
+//! # fn demo(model: &LlamaModel, ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! let mut scheduler = BatchScheduler::new(ctx);
+//! for prompt in ["Once upon a time", "The capital of France is"] {
+//!     let tokens = model.str_to_token(prompt, AddBos::Always)?;
+//!     let config = GeneratorConfig::new().with_max_tokens(Some(32));
+//!     scheduler.submit(&tokens, LlamaSampler::greedy(), config)?;
+//! }
+//!
+//! while !scheduler.is_idle() {
+//!     for event in scheduler.step()? {
+//!         let piece = event.piece?;
+//!         println!("{:?}: {:?}", event.request, piece.text);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+
+use crate::context::LlamaContext;
+use crate::generate::{GenerateError, GeneratedPiece, GeneratorConfig, SequenceOutput};
+use crate::llama_batch::LlamaBatch;
+use crate::sampling::LlamaSampler;
+use crate::token::LlamaToken;
+
+/// Identifies a request submitted to a [`BatchScheduler`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RequestId(u64);
+
+impl RequestId {
+    /// The raw id. Ids are assigned in submission order, starting at `0`.
+    #[must_use]
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+/// One sampled token of one request, returned by [`BatchScheduler::step`].
+#[derive(Debug)]
+pub struct SchedulerEvent {
+    /// The request the piece belongs to.
+    pub request: RequestId,
+    /// The piece, or the error that ended the request. A request is finished
+    /// after a piece with a [`FinishReason`](crate::generate::FinishReason) or
+    /// after an error.
+    pub piece: Result<GeneratedPiece, GenerateError>,
+}
+
+/// A request waiting for a free sequence.
+#[derive(Debug)]
+struct Queued {
+    id: RequestId,
+    prompt: Vec<LlamaToken>,
+    sampler: LlamaSampler,
+    config: GeneratorConfig,
+}
+
+/// A request that owns a sequence.
+#[derive(Debug)]
+struct Slot<'m> {
+    id: RequestId,
+    prompt: Vec<LlamaToken>,
+    /// Prompt tokens already decoded.
+    n_prefilled: usize,
+    sampler: LlamaSampler,
+    output: SequenceOutput<'m>,
+    n_generated: usize,
+    /// Next free position in the sequence.
+    n_past: i32,
+    /// Sampled token that still has to be decoded.
+    undecoded: Option<LlamaToken>,
+}
+
+/// What one slot contributes to the batch being built.
+#[derive(Debug, Clone, Copy)]
+struct Planned {
+    seq: usize,
+    n_tokens: usize,
+    /// Batch row whose logits are sampled after the decode.
+    logits_row: Option<i32>,
+}
+
+/// Continuous-batching scheduler over a [`LlamaContext`].
+///
+/// See the [module documentation](self) for the scheduling policy.
+///
+/// [`GeneratorConfig::seq_id`] and [`GeneratorConfig::start_pos`] are ignored:
+/// the scheduler picks the sequence and always starts at position `0`.
+#[derive(Debug)]
+pub struct BatchScheduler<'c, 'm> {
+    ctx: &'c mut LlamaContext<'m>,
+    batch: LlamaBatch,
+    n_batch: usize,
+    /// Indexed by sequence id.
+    slots: Vec<Option<Slot<'m>>>,
+    queue: VecDeque<Queued>,
+    /// Sequence that is served first in the next step, for fairness when the
+    /// batch cannot hold every request.
+    cursor: usize,
+    next_id: u64,
+}
+
+impl<'c, 'm> BatchScheduler<'c, 'm> {
+    /// Creates a scheduler that uses all [`LlamaContext::n_seq_max`] sequences
+    /// of `ctx`.
+    ///
+    /// Sequences are cleared when a request is admitted, so the KV cache does
+    /// not need to be empty.
+    #[must_use]
+    pub fn new(ctx: &'c mut LlamaContext<'m>) -> Self {
+        let n_batch = usize::try_from(ctx.n_batch()).unwrap_or(usize::MAX).max(1);
+        let n_seq = usize::try_from(ctx.n_seq_max()).unwrap_or(1).max(1);
+        Self {
+            ctx,
+            batch: LlamaBatch::new(n_batch, 1),
+            n_batch,
+            slots: (0..n_seq).map(|_| None).collect(),
+            queue: VecDeque::new(),
+            cursor: 0,
+            next_id: 0,
+        }
+    }
+
+    /// Queues a request. It starts prefilling in the next [`Self::step`] that
+    /// has a free sequence.
+    ///
+    /// Every request samples at least one token: a `max_tokens` of `Some(0)`
+    /// is treated as `Some(1)`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`GenerateError::EmptyPrompt`] or
+    /// [`GenerateError::PromptTooLong`] when the prompt can never run.
+    pub fn submit(
+        &mut self,
+        prompt: &[LlamaToken],
+        sampler: LlamaSampler,
+        config: GeneratorConfig,
+    ) -> Result<RequestId, GenerateError> {
+        if prompt.is_empty() {
+            return Err(GenerateError::EmptyPrompt);
+        }
+        let n_ctx = self.ctx.n_ctx_seq();
+        if !u32::try_from(prompt.len()).is_ok_and(|n_prompt| n_prompt <= n_ctx) {
+            return Err(GenerateError::PromptTooLong {
+                n_prompt: prompt.len(),
+                start: 0,
+                n_ctx,
+            });
+        }
+        let max_tokens = config.max_tokens().map(|max| max.max(1));
+        let id = RequestId(self.next_id);
+        self.next_id += 1;
+        self.queue.push_back(Queued {
+            id,
+            prompt: prompt.to_vec(),
+            sampler,
+            config: config.with_max_tokens(max_tokens),
+        });
+        Ok(id)
+    }
+
+    /// Cancels a queued or running request and frees its sequence. Returns
+    /// `false` if the request is unknown or already finished.
+    pub fn cancel(&mut self, request: RequestId) -> bool {
+        if let Some(index) = self.queue.iter().position(|queued| queued.id == request) {
+            self.queue.remove(index);
+            return true;
+        }
+        let seq = self
+            .slots
+            .iter()
+            .position(|slot| slot.as_ref().is_some_and(|slot| slot.id == request));
+        match seq {
+            Some(seq) => {
+                self.release(seq);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether no request is queued or running.
+    #[must_use]
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.slots.iter().all(Option::is_none)
+    }
+
+    /// Requests that own a sequence.
+    #[must_use]
+    pub fn n_running(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    /// Requests waiting for a sequence.
+    #[must_use]
+    pub fn n_queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// The context being scheduled onto.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'m> {
+        self.ctx
+    }
+
+    /// Admits queued requests, decodes one shared batch, and samples every
+    /// request whose logits are ready.
+    ///
+    /// Tokens of generating requests are placed first so streaming latency
+    /// does not depend on other requests' prompt lengths; pending prompt
+    /// chunks fill the remaining space. Returns no events when there is no
+    /// work, or when every running request is still prefilling.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the shared decode fails, e.g. with
+    /// [`DecodeError::NoKvCacheSlot`](crate::DecodeError::NoKvCacheSlot) when the
+    /// cache is full. No request advances in that case: the step can be retried
+    /// after [`Self::cancel`]ing a request. Errors that affect a single request
+    /// are reported in its [`SchedulerEvent`] instead.
+    pub fn step(&mut self) -> Result<Vec<SchedulerEvent>, GenerateError> {
+        self.admit();
+        let plan = self.plan()?;
+        if plan.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.ctx.decode(&mut self.batch)?;
+        self.cursor = (self.cursor + 1) % self.slots.len();
+
+        let n_ctx = self.ctx.n_ctx_seq();
+        let mut events = Vec::new();
+        for planned in plan {
+            let slot = self.slots[planned.seq]
+                .as_mut()
+                .expect("planned sequences are occupied");
+            let n_tokens = i32::try_from(planned.n_tokens).expect("batch size fits in i32");
+            slot.n_past += n_tokens;
+            if slot.undecoded.take().is_none() {
+                slot.n_prefilled += planned.n_tokens;
+            }
+            let Some(row) = planned.logits_row else {
+                continue;
+            };
+
+            let token = slot.sampler.sample(self.ctx, row);
+            slot.n_generated += 1;
+            let context_full = u32::try_from(slot.n_past).is_ok_and(|n_past| n_past >= n_ctx);
+            let piece = slot
+                .output
+                .piece(token, slot.n_generated, context_full)
+                .map_err(GenerateError::from);
+            let finished = match &piece {
+                Ok(piece) => piece.finish_reason.is_some(),
+                Err(_) => true,
+            };
+            let request = slot.id;
+            if finished {
+                self.release(planned.seq);
+            } else {
+                slot.undecoded = Some(token);
+            }
+            events.push(SchedulerEvent { request, piece });
+        }
+        Ok(events)
+    }
+
+    /// Moves queued requests into free sequences.
+    fn admit(&mut self) {
+        for seq in 0..self.slots.len() {
+            if self.slots[seq].is_some() {
+                continue;
+            }
+            let Some(queued) = self.queue.pop_front() else {
+                break;
+            };
+            self.clear_seq(seq);
+            let model = self.ctx.model;
+            self.slots[seq] = Some(Slot {
+                id: queued.id,
+                prompt: queued.prompt,
+                n_prefilled: 0,
+                sampler: queued.sampler,
+                output: SequenceOutput::new(model, &queued.config),
+                n_generated: 0,
+                n_past: 0,
+                undecoded: None,
+            });
+        }
+    }
+
+    /// Fills the batch: pending tokens of generating requests first, then
+    /// prompt chunks, both starting at `cursor`.
+    fn plan(&mut self) -> Result<Vec<Planned>, GenerateError> {
+        self.batch.clear();
+        let n_seq = self.slots.len();
+        let order: Vec<usize> = (0..n_seq).map(|i| (self.cursor + i) % n_seq).collect();
+        let mut plan = Vec::new();
+        let mut room = self.n_batch;
+
+        for &seq in &order {
+            if room == 0 {
+                break;
+            }
+            let Some(slot) = &self.slots[seq] else {
+                continue;
+            };
+            let Some(token) = slot.undecoded else {
+                continue;
+            };
+            let row = self.batch.n_tokens();
+            self.batch.add(token, slot.n_past, &[seq_id(seq)], true)?;
+            room -= 1;
+            plan.push(Planned {
+                seq,
+                n_tokens: 1,
+                logits_row: Some(row),
+            });
+        }
+
+        for &seq in &order {
+            if room == 0 {
+                break;
+            }
+            let Some(slot) = &self.slots[seq] else {
+                continue;
+            };
+            if slot.undecoded.is_some() || slot.n_prefilled == slot.prompt.len() {
+                continue;
+            }
+            let chunk = &slot.prompt[slot.n_prefilled..];
+            let chunk = &chunk[..chunk.len().min(room)];
+            let completes_prompt = slot.n_prefilled + chunk.len() == slot.prompt.len();
+            let mut logits_row = None;
+            for (pos, (offset, &token)) in (slot.n_past..).zip(chunk.iter().enumerate()) {
+                let logits = completes_prompt && offset + 1 == chunk.len();
+                if logits {
+                    logits_row = Some(self.batch.n_tokens());
+                }
+                self.batch.add(token, pos, &[seq_id(seq)], logits)?;
+            }
+            room -= chunk.len();
+            plan.push(Planned {
+                seq,
+                n_tokens: chunk.len(),
+                logits_row,
+            });
+        }
+        Ok(plan)
+    }
+
+    /// Drops the request on `seq` and frees its cache entries.
+    fn release(&mut self, seq: usize) {
+        self.slots[seq] = None;
+        self.clear_seq(seq);
+    }
+
+    fn clear_seq(&mut self, seq: usize) {
+        let seq = u32::try_from(seq).expect("sequence index fits in u32");
+        // Removing a whole sequence cannot fail; the result only reports
+        // partial removals of recurrent state.
+        let _ = self.ctx.clear_kv_cache_seq(Some(seq), None, None);
+    }
+}
+
+fn seq_id(seq: usize) -> i32 {
+    i32::try_from(seq).expect("sequence index fits in i32")
+}
@@ -0,0 +1,309 @@
+//! Async token streaming on a dedicated worker thread (`tokio` feature).
+//!
+//! [`TokenStream`] runs a [`Generator`] on its own OS thread and exposes the
+//! pieces as a [`futures_core::Stream`]. The worker and the consumer are
+//! connected by a bounded channel, so a slow consumer applies backpressure:
+//! the worker blocks before sampling further tokens once
+//! [`TokenStreamBuilder::with_capacity`] pieces are queued.
+//!
+//! Dropping the stream (or calling [`TokenStream::cancel`]) is cooperative
+//! cancellation: a flag is raised that the context's abort callback polls, so
+//! even an in-flight prefill decode stops at the next graph node, and the
+//! worker exits without sending anything further.
+//!
+//! Neither [`LlamaContextParams`] nor [`LlamaSampler`] can cross threads, so
+//! both are built on the worker from `Send` closures.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use llama_cpp_4::generate::GeneratorConfig;
+//! use llama_cpp_4::prelude::*;
+//! use llama_cpp_4::stream::TokenStream;
+//!
+//! # async fn demo(backend: &'static LlamaBackend, model: Arc<LlamaModel>) -> Result<(), Box<dyn std::error::Error>> {
+//! let prompt = model.str_to_token("Hello", AddBos::Always)?;
+//! let mut stream = TokenStream::builder(model, prompt)
+//!     .with_config(GeneratorConfig::new().with_max_tokens(Some(64)))
+//!     .with_sampler(|_model| LlamaSampler::greedy())
+//!     .spawn(backend)?;
+//!
+//! while let Some(piece) = stream.next_piece().await {
+//!     print!("{}", piece?.text);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ffi::c_void;
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread::JoinHandle;
+
+use tokio::sync::mpsc;
+
+use crate::context::params::LlamaContextParams;
+use crate::generate::{GenerateError, GeneratedPiece, Generator, GeneratorConfig};
+use crate::llama_backend::LlamaBackend;
+use crate::model::LlamaModel;
+use crate::sampling::LlamaSampler;
+use crate::token::LlamaToken;
+use crate::LlamaContextLoadError;
+
+/// Default number of pieces buffered between the worker and the consumer.
+pub const DEFAULT_STREAM_CAPACITY: usize = 16;
+
+/// Errors delivered by a [`TokenStream`].
+#[derive(Debug, thiserror::Error)]
+pub enum StreamError {
+    /// The worker thread could not be spawned.
+    #[error("failed to spawn generation worker: {0}")]
+    Spawn(#[from] std::io::Error),
+    /// The worker could not create its context.
+    #[error(transparent)]
+    ContextLoad(#[from] LlamaContextLoadError),
+    /// Generation failed.
+    #[error(transparent)]
+    Generate(#[from] GenerateError),
+    /// The worker panicked; carries the panic message when it was a string.
+    #[error("generation worker panicked: {0}")]
+    WorkerPanicked(String),
+}
+
+type ParamsFactory = Box<dyn FnOnce() -> LlamaContextParams + Send>;
+type SamplerFactory = Box<dyn FnOnce(&LlamaModel) -> LlamaSampler + Send>;
+
+/// Configures and spawns a [`TokenStream`].
+pub struct TokenStreamBuilder {
+    model: Arc<LlamaModel>,
+    prompt: Vec<LlamaToken>,
+    config: GeneratorConfig,
+    context_params: ParamsFactory,
+    sampler: SamplerFactory,
+    capacity: usize,
+}
+
+impl fmt::Debug for TokenStreamBuilder {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("TokenStreamBuilder")
+            .field("n_prompt", &self.prompt.len())
+            .field("config", &self.config)
+            .field("capacity", &self.capacity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TokenStreamBuilder {
+    /// Generator settings. Defaults to [`GeneratorConfig::default`].
+    #[must_use]
+    pub fn with_config(mut self, config: GeneratorConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Builds the context parameters on the worker thread. Defaults to
+    /// [`LlamaContextParams::default`].
+    #[must_use]
+    pub fn with_context_params(
+        mut self,
+        context_params: impl FnOnce() -> LlamaContextParams + Send + 'static,
+    ) -> Self {
+        self.context_params = Box::new(context_params);
+        self
+    }
+
+    /// Builds the sampler chain on the worker thread. Defaults to
+    /// [`LlamaSampler::greedy`].
+    #[must_use]
+    pub fn with_sampler(
+        mut self,
+        sampler: impl FnOnce(&LlamaModel) -> LlamaSampler + Send + 'static,
+    ) -> Self {
+        self.sampler = Box::new(sampler);
+        self
+    }
+
+    /// Maximum queued pieces before the worker blocks. Clamped to at least
+    /// `1`; defaults to [`DEFAULT_STREAM_CAPACITY`].
+    #[must_use]
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Starts the worker thread.
+    ///
+    /// The worker is detached and may outlive the caller, so it takes a
+    /// `'static` backend (kept in a `static` [`OnceLock`](std::sync::OnceLock)
+    /// or leaked), which keeps llama.cpp initialized until it exits.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StreamError::Spawn`] if the OS refuses to create the thread.
+    /// Every later failure is delivered through the stream.
+    pub fn spawn(self, backend: &'static LlamaBackend) -> Result<TokenStream, StreamError> {
+        let (tx, rx) = mpsc::channel(self.capacity);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let worker_cancelled = Arc::clone(&cancelled);
+        let worker = std::thread::Builder::new()
+            .name("llama-token-stream".to_owned())
+            .spawn(move || run_worker(self, backend, &tx, &worker_cancelled))?;
+        Ok(TokenStream {
+            rx,
+            cancelled,
+            worker,
+        })
+    }
+}
+
+/// A [`futures_core::Stream`] of generated pieces produced on a worker thread.
+///
+/// The stream ends after the piece carrying a
+/// [`FinishReason`](crate::generate::FinishReason), after an error, or after
+/// cancellation.
+pub struct TokenStream {
+    rx: mpsc::Receiver<Result<GeneratedPiece, StreamError>>,
+    cancelled: Arc<AtomicBool>,
+    worker: JoinHandle<()>,
+}
+
+impl fmt::Debug for TokenStream {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("TokenStream")
+            .field("cancelled", &self.is_cancelled())
+            .field("worker_finished", &self.is_worker_finished())
+            .finish_non_exhaustive()
+    }
+}
+
+impl TokenStream {
+    /// Starts configuring a stream that generates from `prompt`.
+    #[must_use]
+    pub fn builder(model: Arc<LlamaModel>, prompt: Vec<LlamaToken>) -> TokenStreamBuilder {
+        TokenStreamBuilder {
+            model,
+            prompt,
+            config: GeneratorConfig::default(),
+            context_params: Box::new(LlamaContextParams::default),
+            sampler: Box::new(|_| LlamaSampler::greedy()),
+            capacity: DEFAULT_STREAM_CAPACITY,
+        }
+    }
+
+    /// Receives the next piece without requiring `StreamExt`.
+    pub async fn next_piece(&mut self) -> Option<Result<GeneratedPiece, StreamError>> {
+        self.rx.recv().await
+    }
+
+    /// Requests cancellation. Pieces already queued can still be received.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    /// Whether cancellation was requested.
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Whether the worker thread has exited.
+    #[must_use]
+    pub fn is_worker_finished(&self) -> bool {
+        self.worker.is_finished()
+    }
+}
+
+impl futures_core::Stream for TokenStream {
+    type Item = Result<GeneratedPiece, StreamError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl Drop for TokenStream {
+    fn drop(&mut self) {
+        // Never join here: dropping inside an async task must not block the
+        // executor. The worker observes the flag (or the closed channel) and
+        // exits on its own.
+        self.cancel();
+        self.rx.close();
+    }
+}
+
+/// Abort callback polled by ggml between graph nodes.
+unsafe extern "C" fn abort_when_cancelled(data: *mut c_void) -> bool {
+    // SAFETY: `data` points at the `AtomicBool` owned by the worker, which
+    // outlives the context the callback is installed on.
+    unsafe { data.cast::<AtomicBool>().as_ref() }.is_some_and(|flag| flag.load(Ordering::Acquire))
+}
+
+fn run_worker(
+    builder: TokenStreamBuilder,
+    backend: &LlamaBackend,
+    tx: &mpsc::Sender<Result<GeneratedPiece, StreamError>>,
+    cancelled: &Arc<AtomicBool>,
+) {
+    let outcome = catch_unwind(AssertUnwindSafe(|| {
+        generate_into(builder, backend, tx, cancelled)
+    }));
+    let error = match outcome {
+        Ok(Ok(())) => return,
+        Ok(Err(error)) => error,
+        Err(payload) => StreamError::WorkerPanicked(
+            payload
+                .downcast_ref::<&str>()
+                .map(|message| (*message).to_owned())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default(),
+        ),
+    };
+    // An error caused by our own abort is not worth reporting.
+    if !cancelled.load(Ordering::Acquire) {
+        let _ = tx.blocking_send(Err(error));
+    }
+}
+
+fn generate_into(
+    builder: TokenStreamBuilder,
+    backend: &LlamaBackend,
+    tx: &mpsc::Sender<Result<GeneratedPiece, StreamError>>,
+    cancelled: &Arc<AtomicBool>,
+) -> Result<(), StreamError> {
+    let TokenStreamBuilder {
+        model,
+        prompt,
+        config,
+        context_params,
+        sampler,
+        ..
+    } = builder;
+
+    let mut ctx = model.new_context(backend, context_params())?;
+    // SAFETY: the flag is kept alive by `cancelled` for the whole function,
+    // and `ctx` is dropped before this function returns.
+    unsafe {
+        ctx.set_abort_callback(
+            Some(abort_when_cancelled),
+            Arc::as_ptr(cancelled).cast_mut().cast(),
+        );
+    }
+    let sampler = sampler(&model);
+
+    for piece in Generator::new(&mut ctx, sampler, &prompt, config)? {
+        if cancelled.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        // Blocks while the channel is full: this is the backpressure point.
+        if tx.blocking_send(piece.map_err(StreamError::from)).is_err() {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
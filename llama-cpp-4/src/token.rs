@@ -7,6 +7,7 @@ use std::mem::ManuallyDrop;
 pub mod data;
 pub mod data_array;
 pub mod detokenizer;
+pub mod offsets;
 
 /// A safe wrapper for `llama_token`.
 ///
@@ -62,6 +63,20 @@ impl LlamaToken {
     }
 }
 
+/// `LLAMA_TOKEN_NULL`, returned for special tokens the vocabulary lacks.
+pub(crate) const TOKEN_NULL: LlamaToken = LlamaToken(-1);
+
+/// `token`, or `None` when it is [`TOKEN_NULL`].
+pub(crate) fn present(token: LlamaToken) -> Option<LlamaToken> {
+    (token != TOKEN_NULL).then_some(token)
+}
+
+/// Tokens with the given ids.
+#[cfg(test)]
+pub(crate) fn tokens(ids: &[i32]) -> Vec<LlamaToken> {
+    ids.iter().copied().map(LlamaToken).collect()
+}
+
 /// Converts a vector of `llama_token` to a vector of `LlamaToken` without memory allocation,
 /// and consumes the original vector. This conversion is safe because `LlamaToken` is repr(transparent),
 /// meaning it is just a wrapper around the raw `llama_token` type.
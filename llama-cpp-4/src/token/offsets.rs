@@ -0,0 +1,152 @@
+//! Byte offsets of tokens in the text they were tokenized from.
+//!
+//! [`LlamaModel::str_to_token`] returns token ids only.
+//! [`LlamaModel::str_to_token_with_offsets`] also reports which bytes of the
+//! input each token covers, for tagging spans, attribution views, or cutting
+//! a prompt at an exact character.
+//!
+//! Offsets are found by walking the input alongside each token's raw piece
+//! ([`LlamaModel::token_to_raw_bytes`] with [`Special::Tokenize`]):
+//!
+//! - SPM vocabularies prefix the first word with a space that is not in the
+//!   input; such a token covers the word without the space.
+//! - Byte-fallback and byte-level BPE tokens may hold part of a multi-byte
+//!   character, so a range can start or end inside a character.
+//! - Tokens the tokenizer adds itself, such as BOS, get an empty range.
+//!
+//! ```no_run
+//! use llama_cpp_4::model::{AddBos, LlamaModel};
+//! # fn demo(model: &LlamaModel) -> Result<(), Box<dyn std::error::Error>> {
+//! let text = "Hello world";
+//! for span in model.str_to_token_with_offsets(text, AddBos::Always)? {
+//!     println!("{} {:?}", span.token, &text.as_bytes()[span.range]);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ops::Range;
+
+use crate::model::{AddBos, LlamaModel, Special};
+use crate::token::LlamaToken;
+use crate::{StringToTokenError, TokenToStringError};
+
+/// Errors raised by [`LlamaModel::str_to_token_with_offsets`].
+#[derive(Debug, thiserror::Error)]
+pub enum TokenOffsetsError {
+    /// The text could not be tokenized.
+    #[error(transparent)]
+    Tokenize(#[from] StringToTokenError),
+    /// A token could not be converted to its piece.
+    #[error(transparent)]
+    Piece(#[from] TokenToStringError),
+}
+
+/// A token and the bytes of the input it was produced from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenSpan {
+    /// The token.
+    pub token: LlamaToken,
+    /// Byte range of the token in the input. Empty for tokens that are not
+    /// in the input.
+    pub range: Range<usize>,
+}
+
+impl LlamaModel {
+    /// Tokenizes `text` like [`Self::str_to_token`] and returns the byte
+    /// range of the input each token covers.
+    ///
+    /// The ranges are in order, do not overlap, and cover the input when the
+    /// tokenizer reproduces it exactly. See the [module docs](self) for how
+    /// SPM prefix spaces and byte tokens are handled.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the text cannot be tokenized or a token cannot be
+    /// converted to its piece.
+    pub fn str_to_token_with_offsets(
+        &self,
+        text: &str,
+        add_bos: AddBos,
+    ) -> Result<Vec<TokenSpan>, TokenOffsetsError> {
+        let tokens = self.str_to_token(text, add_bos)?;
+        let mut piece = Vec::new();
+        let mut pos = 0;
+        tokens
+            .into_iter()
+            .map(|token| {
+                self.token_to_raw_bytes_into(token, Special::Tokenize, &mut piece)?;
+                let range = align_piece(text.as_bytes(), pos, &piece);
+                pos = range.end;
+                Ok(TokenSpan { token, range })
+            })
+            .collect()
+    }
+}
+
+/// Matches `piece` against `text` at `pos` and returns the bytes it covers.
+///
+/// A piece that does not match is retried without a leading space (the
+/// SPM prefix space); if that fails too it covers nothing.
+fn align_piece(text: &[u8], pos: usize, piece: &[u8]) -> Range<usize> {
+    let rest = &text[pos..];
+    let len = if !piece.is_empty() && rest.starts_with(piece) {
+        piece.len()
+    } else {
+        piece
+            .strip_prefix(b" ")
+            .filter(|word| !word.is_empty() && rest.starts_with(word))
+            .map_or(0, <[u8]>::len)
+    };
+    pos..pos + len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::align_piece;
+    use std::ops::Range;
+
+    /// Aligns `pieces` one after another, as the tokenizer produced them.
+    fn align(text: &str, pieces: &[&[u8]]) -> Vec<Range<usize>> {
+        let mut pos = 0;
+        pieces
+            .iter()
+            .map(|piece| {
+                let range = align_piece(text.as_bytes(), pos, piece);
+                pos = range.end;
+                range
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spm_prefix_space_is_not_in_input() {
+        let ranges = align("Hello world", &[b"<s>", b" Hello", b" world"]);
+        assert_eq!(ranges, [0..0, 0..5, 5..11]);
+    }
+
+    #[test]
+    fn spm_byte_fallback_splits_characters() {
+        // "é" is 0xC3 0xA9; SPM emits a lone "▁" and two byte tokens.
+        let ranges = align("é!", &[b" ", &[0xC3], &[0xA9], b"!"]);
+        assert_eq!(ranges, [0..0, 0..1, 1..2, 2..3]);
+    }
+
+    #[test]
+    fn bpe_pieces_keep_their_spaces() {
+        // "日本" is E6 97 A5 E6 9C AC; byte-level BPE may split it anywhere.
+        let text = "a 日本";
+        let ranges = align(
+            text,
+            &[b"a", b" ", &[0xE6, 0x97], &[0xA5, 0xE6, 0x9C, 0xAC]],
+        );
+        assert_eq!(ranges, [0..1, 1..2, 2..4, 4..8]);
+        assert_eq!(ranges.last().unwrap().end, text.len());
+    }
+
+    #[test]
+    fn unmatched_pieces_are_empty() {
+        let ranges = align("Hi", &[b"Hi", b"</s>"]);
+        assert_eq!(ranges, [0..2, 2..2]);
+    }
+}
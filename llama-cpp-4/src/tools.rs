@@ -0,0 +1,1061 @@
+//! Tool-call parsing for the formats models are trained on (`tools` feature).
+//!
+//! Every model family writes tool calls in its own syntax:
+//!
+//! | [`ToolCallFormat`] | Models | Syntax |
+//! |--------------------|--------|--------|
+//! | `Hermes` | Hermes 2 Pro, Qwen 2.5, Qwen 3 | `<tool_call>{"name": …, "arguments": {…}}</tool_call>` |
+//! | `Qwen3Coder` | Qwen3-Coder | `<tool_call><function=name><parameter=key>value</parameter></function></tool_call>` |
+//! | `Llama3` | Llama 3.1 – 3.3 | `{"name": …, "parameters": {…}}`, `<\|python_tag\|>`, `<function=name>{…}</function>` |
+//! | `Mistral` | Mistral, Mixtral, Magistral | `[TOOL_CALLS] [{"name": …, "arguments": {…}, "id": …}]`, `[TOOL_CALLS]name[ARGS]{…}` |
+//! | `Functionary` | Functionary v3.2 | `>>>name\n{…}`, with `>>>all\n` before plain text |
+//!
+//! [`ToolCallFormat::detect`] picks the format from a chat template and
+//! [`ToolCallFormat::for_model`] from the model's metadata.
+//! [`ToolCallFormat::parse`] splits a finished reply into its text and its
+//! calls; [`ToolCallParser`] does the same while tokens stream in, holding
+//! back only the text that may still turn into a call.
+//!
+//! Arguments are kept as JSON text, like the `arguments` field of the `OpenAI`
+//! API. Text that looks like a call but does not parse is returned as content.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use llama_cpp_4::prelude::*;
+//! use llama_cpp_4::tools::{ToolCallEvent, ToolCallFormat, ToolCallParser};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, "model.gguf", &LlamaModelParams::default())?;
+//! let format = ToolCallFormat::for_model(&model);
+//!
+//! // A finished reply ...
+//! let reply = format.parse("<tool_call>{\"name\": \"get_weather\", \"arguments\": {}}</tool_call>");
+//! for call in &reply.tool_calls {
+//!     println!("{}({})", call.name, call.arguments);
+//! }
+//!
+//! // ... or one that is still streaming.
+//! let mut parser = ToolCallParser::new(format);
+//! for piece in ["Let me check. <tool", "_call>{\"name\": \"get_weather\", ", "\"arguments\": {}}</tool_call>"] {
+//!     for event in parser.push(piece) {
+//!         match event {
+//!             ToolCallEvent::Content(text) => print!("{text}"),
+//!             ToolCallEvent::ToolCall(call) => println!("\n-> {}", call.name),
+//!         }
+//!     }
+//! }
+//! let _rest = parser.finish();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::Write as _;
+
+use serde_json::{Map, Value};
+
+use crate::model::LlamaModel;
+
+const HERMES_OPEN: &str = "<tool_call>";
+const HERMES_CLOSE: &str = "</tool_call>";
+const PYTHON_TAG: &str = "<|python_tag|>";
+const FUNCTION_OPEN: &str = "<function=";
+const FUNCTION_CLOSE: &str = "</function>";
+const PARAMETER_OPEN: &str = "<parameter=";
+const PARAMETER_CLOSE: &str = "</parameter>";
+const MISTRAL_TOOL_CALLS: &str = "[TOOL_CALLS]";
+const MISTRAL_CALL_ID: &str = "[CALL_ID]";
+const MISTRAL_ARGS: &str = "[ARGS]";
+const FUNCTIONARY_RECIPIENT: &str = ">>>";
+const FUNCTIONARY_ALL: &str = "all";
+
+/// The syntax a model uses to call tools.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ToolCallFormat {
+    /// A JSON object between `<tool_call>` and `</tool_call>` (Hermes 2 Pro,
+    /// Qwen 2.5, Qwen 3). Also the fallback for unknown templates.
+    #[default]
+    Hermes,
+    /// `<function=name>` with one `<parameter=key>` element per argument
+    /// inside `<tool_call>` (Qwen3-Coder).
+    Qwen3Coder,
+    /// A reply that is a single `{"name": …, "parameters": {…}}` object,
+    /// `<|python_tag|>` calls and `<function=name>{…}</function>` (Llama 3.x).
+    Llama3,
+    /// A JSON array after `[TOOL_CALLS]`, or `[TOOL_CALLS]name[ARGS]{…}` in
+    /// newer models (Mistral).
+    Mistral,
+    /// `>>>name` followed by the JSON arguments on the next line, with
+    /// `>>>all` before plain text (Functionary v3.2).
+    Functionary,
+}
+
+impl ToolCallFormat {
+    /// Picks the format a chat template renders tool calls in.
+    ///
+    /// Templates without tool support yield [`ToolCallFormat::Hermes`].
+    #[must_use]
+    pub fn detect(template: &str) -> Self {
+        if template.contains(MISTRAL_TOOL_CALLS) {
+            Self::Mistral
+        } else if template.contains(FUNCTION_OPEN) && template.contains(PARAMETER_OPEN) {
+            Self::Qwen3Coder
+        } else if template.contains(">>>all") {
+            Self::Functionary
+        } else if template.contains(HERMES_OPEN) {
+            Self::Hermes
+        } else if template.contains(PYTHON_TAG) || template.contains("ipython") {
+            Self::Llama3
+        } else {
+            Self::Hermes
+        }
+    }
+
+    /// Picks the format from the model's `tokenizer.chat_template.tool_use`
+    /// template, or its default chat template if it has none.
+    #[must_use]
+    pub fn for_model(model: &LlamaModel) -> Self {
+        model
+            .chat_template_source(Some("tool_use"))
+            .or_else(|_| model.chat_template_source(None))
+            .ok()
+            .map_or_else(Self::default, |template| Self::detect(&template))
+    }
+
+    /// Splits a finished reply into its text and its tool calls.
+    ///
+    /// The text around the calls is concatenated and trimmed.
+    #[must_use]
+    pub fn parse(self, text: &str) -> ParsedToolCalls {
+        let mut parser = ToolCallParser::new(self);
+        let mut events = parser.push(text);
+        events.extend(parser.finish());
+
+        let mut parsed = ParsedToolCalls::default();
+        for event in events {
+            match event {
+                ToolCallEvent::Content(text) => parsed.content.push_str(&text),
+                ToolCallEvent::ToolCall(call) => parsed.tool_calls.push(call),
+            }
+        }
+        parsed.content = parsed.content.trim().to_owned();
+        parsed
+    }
+
+    /// Writes tool calls the way the model would, e.g. to replay an
+    /// assistant turn. [`ToolCallFormat::parse`] reads the result back.
+    #[must_use]
+    pub fn format(self, calls: &[ToolCall]) -> String {
+        let calls = calls.iter().map(|call| {
+            let arguments = serde_json::from_str(&call.arguments)
+                .unwrap_or_else(|_| Value::String(call.arguments.clone()));
+            (call, arguments)
+        });
+        match self {
+            Self::Hermes => calls
+                .map(|(call, arguments)| {
+                    let object =
+                        json_object([("name", call.name.clone().into()), ("arguments", arguments)]);
+                    format!("{HERMES_OPEN}\n{object}\n{HERMES_CLOSE}")
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Self::Qwen3Coder => calls
+                .map(|(call, arguments)| {
+                    let mut out = format!("{HERMES_OPEN}\n{FUNCTION_OPEN}{}>\n", call.name);
+                    if let Value::Object(arguments) = arguments {
+                        for (key, value) in arguments {
+                            let value = match value {
+                                Value::String(text) => text,
+                                value => value.to_string(),
+                            };
+                            let _ =
+                                write!(out, "{PARAMETER_OPEN}{key}>\n{value}\n{PARAMETER_CLOSE}\n");
+                        }
+                    }
+                    out + FUNCTION_CLOSE + "\n" + HERMES_CLOSE
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Self::Llama3 => calls
+                .map(|(call, arguments)| {
+                    json_object([
+                        ("name", call.name.clone().into()),
+                        ("parameters", arguments),
+                    ])
+                    .to_string()
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Self::Mistral => {
+                let array: Vec<Value> = calls
+                    .map(|(call, arguments)| {
+                        let mut object = json_object([
+                            ("name", call.name.clone().into()),
+                            ("arguments", arguments),
+                        ]);
+                        if let (Some(id), Value::Object(map)) = (&call.id, &mut object) {
+                            map.insert("id".to_owned(), id.clone().into());
+                        }
+                        object
+                    })
+                    .collect();
+                format!("{MISTRAL_TOOL_CALLS} {}", Value::Array(array))
+            }
+            Self::Functionary => calls.fold(String::new(), |mut out, (call, arguments)| {
+                let _ = write!(out, "{FUNCTIONARY_RECIPIENT}{}\n{arguments}", call.name);
+                out
+            }),
+        }
+    }
+
+    /// The literal markers that open a call.
+    fn markers(self) -> &'static [&'static str] {
+        match self {
+            Self::Hermes | Self::Qwen3Coder => &[HERMES_OPEN],
+            Self::Llama3 => &[PYTHON_TAG, FUNCTION_OPEN],
+            Self::Mistral => &[MISTRAL_TOOL_CALLS],
+            Self::Functionary => &[FUNCTIONARY_RECIPIENT],
+        }
+    }
+
+    /// Finds where the next call starts in `text`.
+    ///
+    /// `at_start` is set while the reply has been nothing but whitespace and
+    /// calls, where Llama 3 and Functionary calls need no marker.
+    fn find_call(self, text: &str, at_start: bool) -> Scan {
+        if at_start {
+            match self {
+                Self::Llama3 => {
+                    let trimmed = text.trim_start();
+                    if trimmed.is_empty() {
+                        return Scan::Hold(0);
+                    }
+                    if trimmed.starts_with('{') {
+                        return Scan::Found(text.len() - trimmed.len());
+                    }
+                }
+                Self::Functionary if !text.starts_with(FUNCTIONARY_RECIPIENT) => {
+                    match text.find('\n') {
+                        None if is_function_name(text) => return Scan::Hold(0),
+                        Some(newline) if is_function_name(&text[..newline]) => {
+                            return Scan::Found(0)
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let markers = self.markers();
+        match markers.iter().filter_map(|marker| text.find(marker)).min() {
+            Some(start) => Scan::Found(start),
+            None => Scan::Hold(text.len() - partial_marker_len(text, markers)),
+        }
+    }
+
+    /// Length of the marker `text` starts with when it turns out not to be
+    /// a call; it is passed through as content.
+    fn marker_len(self, text: &str) -> usize {
+        if self == Self::Llama3 && text.starts_with('{') {
+            return 1;
+        }
+        self.markers()
+            .iter()
+            .find(|marker| text.starts_with(**marker))
+            .map_or(0, |marker| marker.len())
+    }
+
+    /// Parses the call(s) at the start of `text`.
+    fn parse_call(self, text: &str, eof: bool) -> Step {
+        match self {
+            Self::Hermes => parse_hermes(text, eof, |body| {
+                serde_json::from_str(body.trim())
+                    .ok()
+                    .and_then(|value| call_from_json(&value, false))
+            }),
+            Self::Qwen3Coder => parse_hermes(text, eof, parse_xml_function),
+            Self::Llama3 => parse_llama3(text, eof),
+            Self::Mistral => parse_mistral(text, eof),
+            Self::Functionary => parse_functionary(text, eof),
+        }
+    }
+}
+
+/// A tool call emitted by the model.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ToolCall {
+    /// The call id, for formats where the model writes one (Mistral).
+    pub id: Option<String>,
+    /// The name of the function to call.
+    pub name: String,
+    /// The arguments as JSON text, usually an object.
+    pub arguments: String,
+}
+
+impl ToolCall {
+    /// Creates a call without an id.
+    #[must_use]
+    pub fn new(name: impl Into<String>, arguments: impl Into<String>) -> Self {
+        Self {
+            id: None,
+            name: name.into(),
+            arguments: arguments.into(),
+        }
+    }
+
+    /// Sets the call id.
+    #[must_use]
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+}
+
+/// A reply split by [`ToolCallFormat::parse`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParsedToolCalls {
+    /// The text outside the tool calls, trimmed.
+    pub content: String,
+    /// The tool calls, in the order they were written.
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// Output of [`ToolCallParser`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolCallEvent {
+    /// Text that is not part of a tool call.
+    Content(String),
+    /// A complete tool call.
+    ToolCall(ToolCall),
+}
+
+/// Splits a streamed reply into text and tool calls as it arrives.
+///
+/// [`ToolCallParser::push`] returns text as soon as it cannot be the start
+/// of a call, and each call once it is complete. [`ToolCallParser::finish`]
+/// flushes the rest at the end of the reply; unterminated calls are still
+/// returned if their arguments are complete.
+#[derive(Debug, Clone)]
+pub struct ToolCallParser {
+    format: ToolCallFormat,
+    buffer: String,
+    in_call: bool,
+    at_start: bool,
+}
+
+impl ToolCallParser {
+    /// Creates a parser for `format`.
+    #[must_use]
+    pub fn new(format: ToolCallFormat) -> Self {
+        Self {
+            format,
+            buffer: String::new(),
+            in_call: false,
+            at_start: true,
+        }
+    }
+
+    /// The format being parsed.
+    #[must_use]
+    pub fn format(&self) -> ToolCallFormat {
+        self.format
+    }
+
+    /// Feeds the next piece of the reply.
+    pub fn push(&mut self, text: &str) -> Vec<ToolCallEvent> {
+        self.buffer.push_str(text);
+        let mut events = Vec::new();
+        self.drain(false, &mut events);
+        events
+    }
+
+    /// Ends the reply and returns everything still held back.
+    #[must_use]
+    pub fn finish(mut self) -> Vec<ToolCallEvent> {
+        let mut events = Vec::new();
+        self.drain(true, &mut events);
+        let rest = self.buffer.len();
+        self.emit_content(rest, &mut events);
+        events
+    }
+
+    fn drain(&mut self, eof: bool, events: &mut Vec<ToolCallEvent>) {
+        loop {
+            if self.in_call {
+                match self.format.parse_call(&self.buffer, eof) {
+                    Step::Calls(calls, consumed) => {
+                        self.buffer.drain(..consumed);
+                        events.extend(calls.into_iter().map(ToolCallEvent::ToolCall));
+                    }
+                    Step::Text(consumed) => {
+                        self.buffer.drain(..consumed);
+                        self.at_start = false;
+                    }
+                    Step::Invalid => {
+                        let len = self.format.marker_len(&self.buffer);
+                        self.emit_content(len, events);
+                        self.at_start = false;
+                    }
+                    Step::Incomplete => return,
+                }
+                self.in_call = false;
+            } else {
+                match self.format.find_call(&self.buffer, self.at_start) {
+                    Scan::Found(start) => {
+                        self.emit_content(start, events);
+                        self.in_call = true;
+                    }
+                    Scan::Hold(safe) => {
+                        self.emit_content(safe, events);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn emit_content(&mut self, len: usize, events: &mut Vec<ToolCallEvent>) {
+        if len == 0 {
+            return;
+        }
+        let text: String = self.buffer.drain(..len).collect();
+        if !text.trim().is_empty() {
+            self.at_start = false;
+        }
+        events.push(ToolCallEvent::Content(text));
+    }
+}
+
+/// Where the next call starts.
+enum Scan {
+    /// A call may start at this offset.
+    Found(usize),
+    /// No call yet; text up to this offset is safe to emit.
+    Hold(usize),
+}
+
+/// The result of parsing at a call marker.
+enum Step {
+    /// Complete calls and the number of bytes they span.
+    Calls(Vec<ToolCall>, usize),
+    /// A header of this many bytes introducing plain text.
+    Text(usize),
+    /// The call is not complete yet.
+    Incomplete,
+    /// The marker does not start a call.
+    Invalid,
+}
+
+impl Step {
+    /// Shifts the span of complete calls by `offset` bytes.
+    fn offset(self, offset: usize) -> Self {
+        match self {
+            Self::Calls(calls, consumed) => Self::Calls(calls, offset + consumed),
+            Self::Text(consumed) => Self::Text(offset + consumed),
+            step => step,
+        }
+    }
+}
+
+/// [`Step::Incomplete`], or [`Step::Invalid`] at the end of the reply.
+fn partial(eof: bool) -> Step {
+    if eof {
+        Step::Invalid
+    } else {
+        Step::Incomplete
+    }
+}
+
+/// A JSON value at the start of a string.
+enum JsonPrefix {
+    /// The value and the number of bytes it spans.
+    Complete(Value, usize),
+    /// The text so far is the start of a value.
+    Partial,
+    /// The text is not JSON.
+    Invalid,
+}
+
+fn json_prefix(text: &str) -> JsonPrefix {
+    let mut values = serde_json::Deserializer::from_str(text).into_iter::<Value>();
+    match values.next() {
+        Some(Ok(value)) => JsonPrefix::Complete(value, values.byte_offset()),
+        Some(Err(e)) if e.is_eof() => JsonPrefix::Partial,
+        None => JsonPrefix::Partial,
+        Some(Err(_)) => JsonPrefix::Invalid,
+    }
+}
+
+fn json_object<const N: usize>(entries: [(&str, Value); N]) -> Value {
+    Value::Object(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value))
+            .collect(),
+    )
+}
+
+/// JSON text of a call's arguments; strings are taken as already encoded.
+fn arguments_text(arguments: Value) -> String {
+    match arguments {
+        Value::String(text) => text,
+        arguments => arguments.to_string(),
+    }
+}
+
+/// Reads a call from `{"name": …, "arguments" | "parameters": …, "id": …}`,
+/// optionally nested in `{"function": …}` as in the `OpenAI` API.
+fn call_from_json(value: &Value, require_arguments: bool) -> Option<ToolCall> {
+    let function = value
+        .get("function")
+        .filter(|function| function.is_object())
+        .unwrap_or(value);
+    let name = function.get("name")?.as_str()?;
+    if name.is_empty() {
+        return None;
+    }
+    let arguments = function
+        .get("arguments")
+        .or_else(|| function.get("parameters"));
+    if require_arguments && arguments.is_none() {
+        return None;
+    }
+    Some(ToolCall {
+        id: value.get("id").and_then(Value::as_str).map(str::to_owned),
+        name: name.to_owned(),
+        arguments: arguments
+            .cloned()
+            .map_or_else(|| "{}".to_owned(), arguments_text),
+    })
+}
+
+/// Whether `text` is a plausible function name.
+fn is_function_name(text: &str) -> bool {
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// Length of the longest suffix of `text` that is a proper prefix of one of
+/// the markers.
+fn partial_marker_len(text: &str, markers: &[&str]) -> usize {
+    markers
+        .iter()
+        .flat_map(|marker| (1..marker.len()).rev().map(move |len| &marker[..len]))
+        .filter(|prefix| text.ends_with(prefix))
+        .map(str::len)
+        .max()
+        .unwrap_or(0)
+}
+
+/// `<tool_call>…</tool_call>`, with the body read by `parse_body`.
+fn parse_hermes(text: &str, eof: bool, parse_body: impl Fn(&str) -> Option<ToolCall>) -> Step {
+    let Some(body) = text.strip_prefix(HERMES_OPEN) else {
+        return Step::Invalid;
+    };
+    let (body, consumed) = match body.find(HERMES_CLOSE) {
+        Some(end) => (&body[..end], HERMES_OPEN.len() + end + HERMES_CLOSE.len()),
+        None if eof => (body, text.len()),
+        None => return Step::Incomplete,
+    };
+    match parse_body(body) {
+        Some(call) => Step::Calls(vec![call], consumed),
+        None => Step::Invalid,
+    }
+}
+
+/// `<function=name><parameter=key>value</parameter>…</function>`.
+fn parse_xml_function(body: &str) -> Option<ToolCall> {
+    let rest = body.trim_start().strip_prefix(FUNCTION_OPEN)?;
+    let (name, mut rest) = rest.split_once('>')?;
+    let name = name.trim();
+    if !is_function_name(name) {
+        return None;
+    }
+
+    let mut arguments = Map::new();
+    while let Some(parameter) = rest.trim_start().strip_prefix(PARAMETER_OPEN) {
+        let (key, parameter) = parameter.split_once('>')?;
+        let (value, parameter) = parameter.split_once(PARAMETER_CLOSE)?;
+        arguments.insert(key.trim().to_owned(), xml_value(value));
+        rest = parameter;
+    }
+    let rest = rest.trim_start();
+    if !(rest.is_empty() || rest.starts_with(FUNCTION_CLOSE)) {
+        return None;
+    }
+    Some(ToolCall::new(name, Value::Object(arguments).to_string()))
+}
+
+/// A parameter value: JSON if it parses as anything but a string, the raw
+/// text otherwise.
+fn xml_value(text: &str) -> Value {
+    let text = text.strip_prefix('\n').unwrap_or(text);
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    match serde_json::from_str(text) {
+        Ok(Value::String(_)) | Err(_) => Value::String(text.to_owned()),
+        Ok(value) => value,
+    }
+}
+
+fn parse_llama3(text: &str, eof: bool) -> Step {
+    if let Some(body) = text.strip_prefix(PYTHON_TAG) {
+        let trimmed = body.trim_start();
+        let offset = text.len() - trimmed.len();
+        if trimmed.is_empty() {
+            return partial(eof);
+        }
+        let step = if trimmed.starts_with('{') {
+            parse_json_call(trimmed, eof, false)
+        } else {
+            parse_python_call(trimmed, eof)
+        };
+        return step.offset(offset);
+    }
+
+    if let Some(body) = text.strip_prefix(FUNCTION_OPEN) {
+        let Some((name, rest)) = body.split_once('>') else {
+            return if eof || !(body.is_empty() || is_function_name(body)) {
+                Step::Invalid
+            } else {
+                Step::Incomplete
+            };
+        };
+        if !is_function_name(name) {
+            return Step::Invalid;
+        }
+        let (arguments, consumed) = match rest.find(FUNCTION_CLOSE) {
+            Some(end) => (
+                &rest[..end],
+                text.len() - rest.len() + end + FUNCTION_CLOSE.len(),
+            ),
+            None if eof => (rest, text.len()),
+            None => return Step::Incomplete,
+        };
+        return match serde_json::from_str(arguments.trim()) {
+            Ok(arguments) => Step::Calls(
+                vec![ToolCall::new(name, arguments_text(arguments))],
+                consumed,
+            ),
+            Err(_) => Step::Invalid,
+        };
+    }
+
+    if text.starts_with('{') {
+        return parse_json_call(text, eof, true);
+    }
+    Step::Invalid
+}
+
+/// A JSON call object at the start of `text`.
+fn parse_json_call(text: &str, eof: bool, require_arguments: bool) -> Step {
+    match json_prefix(text) {
+        JsonPrefix::Complete(value, consumed) => match call_from_json(&value, require_arguments) {
+            Some(call) => Step::Calls(vec![call], consumed),
+            None => Step::Invalid,
+        },
+        JsonPrefix::Partial => partial(eof),
+        JsonPrefix::Invalid => Step::Invalid,
+    }
+}
+
+/// A builtin Llama 3 tool call such as `brave_search.call(query="…")`.
+fn parse_python_call(text: &str, eof: bool) -> Step {
+    const CALL: &str = ".call(";
+
+    let Some(open) = text.find(CALL) else {
+        let name = text.strip_suffix('.').unwrap_or(text);
+        return if eof || !is_function_name(name) {
+            Step::Invalid
+        } else {
+            Step::Incomplete
+        };
+    };
+    let name = &text[..open];
+    if !is_function_name(name) {
+        return Step::Invalid;
+    }
+
+    let args_start = open + CALL.len();
+    let mut arguments = Map::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut argument_start = args_start;
+    for (i, c) in text[args_start..].char_indices() {
+        let i = args_start + i;
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, ',' | ')') => {
+                let argument = text[argument_start..i].trim();
+                if !argument.is_empty() {
+                    let Some((key, value)) = argument.split_once('=') else {
+                        return Step::Invalid;
+                    };
+                    arguments.insert(key.trim().to_owned(), python_value(value.trim()));
+                }
+                if c == ')' {
+                    let call = ToolCall::new(name, Value::Object(arguments).to_string());
+                    return Step::Calls(vec![call], i + 1);
+                }
+                argument_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    partial(eof)
+}
+
+/// A Python literal as JSON.
+fn python_value(text: &str) -> Value {
+    for q in ['"', '\''] {
+        if let Some(inner) = text.strip_prefix(q).and_then(|text| text.strip_suffix(q)) {
+            let mut value = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                if c != '\\' {
+                    value.push(c);
+                    continue;
+                }
+                match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some(escaped) => value.push(escaped),
+                    None => value.push('\\'),
+                }
+            }
+            return Value::String(value);
+        }
+    }
+    match text {
+        "True" => Value::Bool(true),
+        "False" => Value::Bool(false),
+        "None" => Value::Null,
+        _ => serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_owned())),
+    }
+}
+
+fn parse_mistral(text: &str, eof: bool) -> Step {
+    let Some(body) = text.strip_prefix(MISTRAL_TOOL_CALLS) else {
+        return Step::Invalid;
+    };
+    let trimmed = body.trim_start();
+    let offset = text.len() - trimmed.len();
+    if trimmed.is_empty() {
+        return partial(eof);
+    }
+
+    if trimmed.starts_with('[') || trimmed.starts_with('{') {
+        return match json_prefix(trimmed) {
+            JsonPrefix::Complete(value, consumed) => {
+                let calls = match &value {
+                    Value::Array(items) => items
+                        .iter()
+                        .map(|item| call_from_json(item, false))
+                        .collect::<Option<Vec<_>>>(),
+                    object => call_from_json(object, false).map(|call| vec![call]),
+                };
+                match calls {
+                    Some(calls) if !calls.is_empty() => Step::Calls(calls, offset + consumed),
+                    _ => Step::Invalid,
+                }
+            }
+            JsonPrefix::Partial => partial(eof),
+            JsonPrefix::Invalid => Step::Invalid,
+        };
+    }
+
+    // name[ARGS]{…} or name[CALL_ID]id[ARGS]{…}
+    let Some(args_at) = trimmed.find(MISTRAL_ARGS) else {
+        return if eof || trimmed.contains(char::is_whitespace) {
+            Step::Invalid
+        } else {
+            Step::Incomplete
+        };
+    };
+    let head = &trimmed[..args_at];
+    let (name, id) = match head.split_once(MISTRAL_CALL_ID) {
+        Some((name, id)) => (name, Some(id)),
+        None => (head, None),
+    };
+    if !is_function_name(name) {
+        return Step::Invalid;
+    }
+    let arguments_at = args_at + MISTRAL_ARGS.len();
+    match json_prefix(&trimmed[arguments_at..]) {
+        JsonPrefix::Complete(arguments, consumed) => {
+            let mut call = ToolCall::new(name, arguments_text(arguments));
+            call.id = id.map(str::to_owned);
+            Step::Calls(vec![call], offset + arguments_at + consumed)
+        }
+        JsonPrefix::Partial => partial(eof),
+        JsonPrefix::Invalid => Step::Invalid,
+    }
+}
+
+fn parse_functionary(text: &str, eof: bool) -> Step {
+    let body = text.strip_prefix(FUNCTIONARY_RECIPIENT).unwrap_or(text);
+    let offset = text.len() - body.len();
+    let Some(newline) = body.find('\n') else {
+        return if eof || !(body.is_empty() || is_function_name(body)) {
+            Step::Invalid
+        } else {
+            Step::Incomplete
+        };
+    };
+    let name = body[..newline].trim();
+    if !is_function_name(name) {
+        return Step::Invalid;
+    }
+    let arguments_at = offset + newline + 1;
+    if name == FUNCTIONARY_ALL {
+        return Step::Text(arguments_at);
+    }
+    match json_prefix(&text[arguments_at..]) {
+        JsonPrefix::Complete(arguments, consumed) => Step::Calls(
+            vec![ToolCall::new(name, arguments_text(arguments))],
+            arguments_at + consumed,
+        ),
+        JsonPrefix::Partial => partial(eof),
+        JsonPrefix::Invalid => Step::Invalid,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_FORMATS: [ToolCallFormat; 5] = [
+        ToolCallFormat::Hermes,
+        ToolCallFormat::Qwen3Coder,
+        ToolCallFormat::Llama3,
+        ToolCallFormat::Mistral,
+        ToolCallFormat::Functionary,
+    ];
+
+    /// Feeds `text` one character at a time and merges adjacent content.
+    fn stream(format: ToolCallFormat, text: &str) -> Vec<ToolCallEvent> {
+        let mut parser = ToolCallParser::new(format);
+        let mut events = Vec::new();
+        for (i, c) in text.char_indices() {
+            events.extend(parser.push(&text[i..i + c.len_utf8()]));
+        }
+        events.extend(parser.finish());
+
+        let mut merged: Vec<ToolCallEvent> = Vec::new();
+        for event in events {
+            match (merged.last_mut(), event) {
+                (Some(ToolCallEvent::Content(last)), ToolCallEvent::Content(text)) => {
+                    last.push_str(&text);
+                }
+                (_, event) => merged.push(event),
+            }
+        }
+        merged
+    }
+
+    fn weather(city: &str) -> ToolCall {
+        ToolCall::new("get_weather", format!(r#"{{"city":"{city}","days":3}}"#))
+    }
+
+    #[test]
+    fn detects_format_from_template() {
+        let cases = [
+            ("{{- '[TOOL_CALLS] [' }}", ToolCallFormat::Mistral),
+            (
+                "<tool_call>\n<function={{ name }}>\n<parameter={{ key }}>",
+                ToolCallFormat::Qwen3Coder,
+            ),
+            (
+                "{{ '>>>all\\n' + message['content'] }}",
+                ToolCallFormat::Functionary,
+            ),
+            (
+                "<tool_call>\\n{{ tool_call | tojson }}",
+                ToolCallFormat::Hermes,
+            ),
+            (
+                "<|start_header_id|>ipython<|end_header_id|>{{ '<|python_tag|>' }}",
+                ToolCallFormat::Llama3,
+            ),
+            (
+                "{{ bos_token }}{{ message['content'] }}",
+                ToolCallFormat::Hermes,
+            ),
+        ];
+        for (template, format) in cases {
+            assert_eq!(ToolCallFormat::detect(template), format, "{template}");
+        }
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        let calls = [weather("Paris"), weather("Zürich")];
+        for format in ALL_FORMATS {
+            let calls: Vec<ToolCall> = if format == ToolCallFormat::Mistral {
+                calls
+                    .iter()
+                    .cloned()
+                    .zip(["abcDEF123", "ghiJKL456"])
+                    .map(|(call, id)| call.with_id(id))
+                    .collect()
+            } else {
+                calls.to_vec()
+            };
+            let text = format.format(&calls);
+            let parsed = format.parse(&text);
+            assert_eq!(parsed.tool_calls, calls, "{format:?}: {text}");
+            assert_eq!(parsed.content, "", "{format:?}: {text}");
+
+            let streamed: Vec<ToolCall> = stream(format, &text)
+                .into_iter()
+                .filter_map(|event| match event {
+                    ToolCallEvent::ToolCall(call) => Some(call),
+                    ToolCallEvent::Content(text) => {
+                        assert!(text.trim().is_empty(), "{format:?}: {text:?}");
+                        None
+                    }
+                })
+                .collect();
+            assert_eq!(streamed, calls, "{format:?}: {text}");
+        }
+    }
+
+    #[test]
+    fn parses_model_output() {
+        let cases = [
+            (
+                ToolCallFormat::Hermes,
+                "Let me check.\n<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call>",
+                "Let me check.",
+                vec![ToolCall::new("get_weather", r#"{"city":"Paris"}"#)],
+            ),
+            (
+                ToolCallFormat::Qwen3Coder,
+                "<tool_call>\n<function=run>\n<parameter=command>\nls -la\n</parameter>\n<parameter=timeout>\n30\n</parameter>\n</function>\n</tool_call>",
+                "",
+                vec![ToolCall::new("run", r#"{"command":"ls -la","timeout":30}"#)],
+            ),
+            (
+                ToolCallFormat::Llama3,
+                "{\"name\": \"get_weather\", \"parameters\": {\"city\": \"Paris\"}}",
+                "",
+                vec![ToolCall::new("get_weather", r#"{"city":"Paris"}"#)],
+            ),
+            (
+                ToolCallFormat::Llama3,
+                "<|python_tag|>brave_search.call(query=\"weather in \\\"Paris\\\"\", count=3)",
+                "",
+                vec![ToolCall::new(
+                    "brave_search",
+                    r#"{"query":"weather in \"Paris\"","count":3}"#,
+                )],
+            ),
+            (
+                ToolCallFormat::Llama3,
+                "Sure. <function=get_weather>{\"city\": \"Paris\"}</function>",
+                "Sure.",
+                vec![ToolCall::new("get_weather", r#"{"city":"Paris"}"#)],
+            ),
+            (
+                ToolCallFormat::Mistral,
+                "[TOOL_CALLS]get_weather[ARGS]{\"city\": \"Paris\"}",
+                "",
+                vec![ToolCall::new("get_weather", r#"{"city":"Paris"}"#)],
+            ),
+            (
+                ToolCallFormat::Mistral,
+                "[TOOL_CALLS]get_weather[CALL_ID]a1b2c3d4e[ARGS]{}",
+                "",
+                vec![ToolCall::new("get_weather", "{}").with_id("a1b2c3d4e")],
+            ),
+            (
+                ToolCallFormat::Functionary,
+                "all\nLet me check.>>>get_weather\n{\"city\": \"Paris\"}",
+                "Let me check.",
+                vec![ToolCall::new("get_weather", r#"{"city":"Paris"}"#)],
+            ),
+        ];
+        for (format, text, content, calls) in cases {
+            let parsed = format.parse(text);
+            assert_eq!(parsed.content, content, "{format:?}: {text}");
+            assert_eq!(parsed.tool_calls, calls, "{format:?}: {text}");
+
+            let streamed = stream(format, text);
+            let streamed_calls: Vec<ToolCall> = streamed
+                .iter()
+                .filter_map(|event| match event {
+                    ToolCallEvent::ToolCall(call) => Some(call.clone()),
+                    ToolCallEvent::Content(_) => None,
+                })
+                .collect();
+            assert_eq!(streamed_calls, calls, "{format:?}: {text}");
+        }
+    }
+
+    #[test]
+    fn text_that_is_not_a_call_stays_content() {
+        let cases = [
+            (
+                ToolCallFormat::Hermes,
+                "Use <tool_call>not json</tool_call> here.",
+            ),
+            (
+                ToolCallFormat::Hermes,
+                "Unfinished <tool_call>{\"name\": \"f\"",
+            ),
+            (ToolCallFormat::Llama3, "{\"name\": \"Bob\", \"age\": 42}"),
+            (ToolCallFormat::Mistral, "[TOOL_CALLS] is how I call tools."),
+            (
+                ToolCallFormat::Functionary,
+                "In Python, >>> print(1) prints 1.",
+            ),
+            (ToolCallFormat::Functionary, "Hello"),
+        ];
+        for (format, text) in cases {
+            let parsed = format.parse(text);
+            assert!(parsed.tool_calls.is_empty(), "{format:?}: {text}");
+            assert_eq!(parsed.content, text, "{format:?}");
+            assert_eq!(
+                stream(format, text),
+                vec![ToolCallEvent::Content(text.to_owned())],
+                "{format:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn unterminated_call_is_accepted_at_the_end() {
+        let parsed = ToolCallFormat::Hermes.parse(
+            "<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}",
+        );
+        assert_eq!(
+            parsed.tool_calls,
+            vec![ToolCall::new("get_weather", r#"{"city":"Paris"}"#)]
+        );
+    }
+
+    #[test]
+    fn streams_content_before_the_marker() {
+        let mut parser = ToolCallParser::new(ToolCallFormat::Hermes);
+        assert_eq!(
+            parser.push("Checking <tool"),
+            vec![ToolCallEvent::Content("Checking ".to_owned())]
+        );
+        assert_eq!(parser.push("_call>{\"name\": \"f\", "), vec![]);
+        assert_eq!(
+            parser.push("\"arguments\": {}}</tool_call> Done."),
+            vec![
+                ToolCallEvent::ToolCall(ToolCall::new("f", "{}")),
+                ToolCallEvent::Content(" Done.".to_owned()),
+            ]
+        );
+        assert_eq!(parser.finish(), vec![]);
+    }
+}
@@ -4,5 +4,9 @@
 //! 1. `LLAMA_TEST_MODEL` — explicit path to a `.gguf` file
 //! 2. `../target/test-models/stories260K.gguf` (from [`scripts/fetch-test-model.sh`])
 //! 3. Vocab-only `ggml-vocab-llama-bpe.gguf` from the `llama-cpp-sys-4` build tree
+//!
+//! Encoder–decoder tests use a separate T5 checkpoint:
+//! `LLAMA_TEST_T5_MODEL`, then `../target/test-models/t5-small.gguf`
+//! (a random-weight T5 written by `fetch-test-model.sh`).
 
 pub mod model;
@@ -1,6 +1,6 @@
 //! Locate and load GGUF files for integration tests.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Mutex, OnceLock};
 
 use llama_cpp_4::llama_backend::LlamaBackend;
@@ -86,7 +86,25 @@ fn default_cached_test_model() -> Option<PathBuf> {
 }
 
 fn vocab_only_fixture() -> Option<ModelFixture> {
-    let build_dir = PathBuf::from("target/debug/build");
+    Some(ModelFixture {
+        path: vocab_path("ggml-vocab-llama-bpe.gguf")?,
+        vocab_only: true,
+    })
+}
+
+/// Cargo's target directory: `CARGO_TARGET_DIR` when set, else the
+/// workspace's `target/`.
+fn target_dir() -> PathBuf {
+    std::env::var_os("CARGO_TARGET_DIR").map_or_else(
+        || Path::new(env!("CARGO_MANIFEST_DIR")).join("../target"),
+        PathBuf::from,
+    )
+}
+
+/// Find one of the vocab-only GGUFs shipped in llama.cpp's `models/`
+/// directory within the `llama-cpp-sys-4` build tree.
+fn vocab_path(file: &str) -> Option<PathBuf> {
+    let build_dir = target_dir().join("debug/build");
     let entries = std::fs::read_dir(&build_dir).ok()?;
     for entry in entries.flatten() {
         let name = entry.file_name();
@@ -94,20 +112,23 @@ fn vocab_only_fixture() -> Option<ModelFixture> {
             .to_str()
             .is_some_and(|n| n.starts_with("llama-cpp-sys-4-"))
         {
-            let vocab_path = entry
-                .path()
-                .join("out/llama.cpp/models/ggml-vocab-llama-bpe.gguf");
+            let vocab_path = entry.path().join("out/llama.cpp/models").join(file);
             if vocab_path.is_file() {
-                return Some(ModelFixture {
-                    path: vocab_path,
-                    vocab_only: true,
-                });
+                return Some(vocab_path);
             }
         }
     }
     None
 }
 
+/// Load a vocab-only GGUF such as `ggml-vocab-llama-spm.gguf`, or `None`
+/// when the build tree does not have it.
+pub fn load_vocab(file: &str) -> Option<LlamaModel> {
+    let path = vocab_path(file)?;
+    let params = std::pin::pin!(LlamaModelParams::default().with_vocab_only(true));
+    LlamaModel::load_from_file(backend(), &path, &params).ok()
+}
+
 /// Load a model for tests. Returns `(model, vocab_only)`.
 pub fn load_model() -> Option<(LlamaModel, bool)> {
     let fixture = find_test_model()?;
@@ -132,6 +153,24 @@ pub fn load_full_model() -> Option<LlamaModel> {
     Some(model)
 }
 
+/// File name of the optional encoder–decoder fixture in `target/test-models`.
+pub const T5_TEST_MODEL: &str = "t5-small.gguf";
+
+/// Load the T5 fixture, or `None` when unavailable.
+pub fn load_t5_model() -> Option<LlamaModel> {
+    let path = std::env::var("LLAMA_TEST_T5_MODEL")
+        .map(PathBuf::from)
+        .ok()
+        .into_iter()
+        .chain([
+            PathBuf::from("../target/test-models").join(T5_TEST_MODEL),
+            PathBuf::from("target/test-models").join(T5_TEST_MODEL),
+        ])
+        .find(|path| path.is_file())?;
+    let params = std::pin::pin!(LlamaModelParams::default());
+    LlamaModel::load_from_file(backend(), &path, &params).ok()
+}
+
 /// Path to the loaded test model, if any.
 pub fn test_model_path() -> Option<PathBuf> {
     find_test_model().map(|f| f.path)
@@ -0,0 +1,87 @@
+"""Regenerates the golden outputs in this directory.
+
+Renders every case in `cases.json` with Jinja2 configured the way Hugging Face
+`transformers` renders chat templates, after the same message preparation as
+`llama_cpp_4::jinja::ChatTemplate`. Run from this directory:
+
+    python3 generate.py
+"""
+
+import json
+
+import jinja2
+import jinja2.ext
+from jinja2.sandbox import ImmutableSandboxedEnvironment
+
+PROBE = "<<llama-cpp-4 probe>>"
+
+
+def raise_exception(message):
+    raise jinja2.exceptions.TemplateError(message)
+
+
+def tojson(x, ensure_ascii=False, indent=None, separators=None, sort_keys=False):
+    return json.dumps(x, ensure_ascii=ensure_ascii, indent=indent, separators=separators, sort_keys=sort_keys)
+
+
+def compile_template(source):
+    env = ImmutableSandboxedEnvironment(trim_blocks=True, lstrip_blocks=True, extensions=[jinja2.ext.loopcontrols])
+    env.filters["tojson"] = tojson
+    env.globals["raise_exception"] = raise_exception
+    return env.from_string(source)
+
+
+def render(template, case, messages):
+    context = dict(case.get("extra_context", {}))
+    context.update(
+        messages=messages,
+        tools=case.get("tools") or None,
+        add_generation_prompt=case.get("add_generation_prompt", True),
+        enable_thinking=case.get("enable_thinking", True),
+        bos_token=case["bos_token"],
+        eos_token=case["eos_token"],
+    )
+    return template.render(**context)
+
+
+def supports_typed_content(template, case):
+    probe = {"role": "user", "content": [{"type": "text", "text": PROBE}]}
+    try:
+        text = render(template, {**case, "tools": None, "extra_context": {}, "add_generation_prompt": False}, [probe])
+    except Exception:
+        return False
+    return PROBE in text and '"type"' not in text and "'type'" not in text
+
+
+def prepare(message, typed):
+    message = json.loads(json.dumps(message))
+    if not typed and isinstance(message.get("content"), list):
+        parts = [p if isinstance(p, str) else p.get("text") for p in message["content"]]
+        message["content"] = "\n".join(p for p in parts if isinstance(p, str))
+    for call in message.get("tool_calls") or []:
+        function = call.get("function", call)
+        arguments = function.get("arguments")
+        if isinstance(arguments, str):
+            try:
+                parsed = json.loads(arguments)
+            except ValueError:
+                continue
+            if isinstance(parsed, dict):
+                function["arguments"] = parsed
+    return message
+
+
+def main():
+    with open("cases.json", encoding="utf-8") as f:
+        cases = json.load(f)
+    for case in cases:
+        with open(case["template"], encoding="utf-8") as f:
+            template = compile_template(f.read())
+        typed = supports_typed_content(template, case)
+        messages = [prepare(m, typed) for m in case["messages"]]
+        with open(case["name"] + ".txt", "w", encoding="utf-8") as f:
+            f.write(render(template, case, messages))
+
+
+if __name__ == "__main__":
+    main()
@@ -12,6 +12,12 @@
 //!     cargo test -p llama-cpp-4 --test test_integration -- --test-threads=1
 //! ```
 //!
+//! `integration_seq2seq_t5` needs a T5 GGUF from `LLAMA_TEST_T5_MODEL`, or
+//! `target/test-models/t5-small.gguf`. `fetch-test-model.sh` writes a tiny
+//! random-weight T5 there when numpy and llama.cpp's gguf-py are installed;
+//! the test only checks the encoder–decoder mechanics, so any T5 works. With
+//! `LLAMA_TEST_REQUIRE_T5` set, a missing T5 fails the test instead.
+//!
 //! Tests skip (pass) when no full model is available. Each test holds a
 //! process-wide lock ([`support::model::llama_guard`]) across its entire
 //! llama.cpp interaction, because model loading, context creation, decode, and
@@ -28,7 +34,10 @@ use std::num::NonZeroU32;
 use llama_cpp_4::fit::{fit_params, get_device_memory_data, FitParams};
 use llama_cpp_4::prelude::*;
 
-use support::model::{backend, llama_guard, load_full_model, skip_no_model, test_model_path};
+use support::model::{
+    backend, llama_guard, load_full_model, load_t5_model, skip_no_model, test_model_path,
+    T5_TEST_MODEL,
+};
 
 #[test]
 fn integration_model_loads_and_has_weights() {
@@ -204,6 +213,765 @@ fn integration_greedy_generation() {
     );
 }
 
+#[test]
+fn integration_token_logprobs() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(128))
+        .with_n_batch(128);
+    let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+
+    let tokens = model
+        .str_to_token("Once upon a time", AddBos::Always)
+        .unwrap();
+    let mut batch = LlamaBatch::new(128, 1);
+    for (i, &tok) in tokens.iter().enumerate() {
+        batch
+            .add(tok, i as i32, &[0], i == tokens.len() - 1)
+            .unwrap();
+    }
+    ctx.decode(&mut batch).unwrap();
+    let last = batch.n_tokens() - 1;
+
+    let greedy = LlamaSampler::greedy().sample(&ctx, last);
+    let logprobs = ctx.token_logprobs_ith(last, greedy, 5).unwrap();
+    assert_eq!(logprobs.token, greedy);
+    assert!(logprobs.is_top(), "greedy token must rank first");
+    assert_eq!(logprobs.top.len(), 5);
+    assert!(logprobs.logprob <= 0.0);
+    assert!(logprobs
+        .top
+        .windows(2)
+        .all(|pair| pair[0].logprob >= pair[1].logprob));
+    let mass: f32 = logprobs.top.iter().map(|alt| alt.logprob.exp()).sum();
+    assert!(mass <= 1.0 + 1e-4);
+
+    // After top-k only k candidates carry probability mass, summing to one.
+    let mut filter = LlamaSampler::chain_simple([LlamaSampler::top_k(3), LlamaSampler::temp(0.7)]);
+    let filtered = ctx
+        .token_logprobs_ith_with_sampler(last, greedy, 10, &mut filter)
+        .unwrap();
+    assert_eq!(filtered.top.len(), 3);
+    let mass: f32 = filtered.top.iter().map(|alt| alt.logprob.exp()).sum();
+    assert!((mass - 1.0).abs() < 1e-4, "top-k mass {mass}");
+    assert!(filtered.logprob >= logprobs.logprob);
+
+    assert!(ctx
+        .token_logprobs_ith(last, LlamaToken(model.n_vocab()), 1)
+        .is_err());
+}
+
+#[test]
+fn integration_score_continuations() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(256))
+        .with_n_batch(128)
+        .with_n_seq_max(2);
+    let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+
+    let prefix = model
+        .str_to_token("The capital of France is", AddBos::Always)
+        .unwrap();
+    let mut batch = LlamaBatch::new(128, 1);
+    for (i, &tok) in prefix.iter().enumerate() {
+        batch
+            .add(tok, i as i32, &[0], i == prefix.len() - 1)
+            .unwrap();
+    }
+    ctx.decode(&mut batch).unwrap();
+    let sampler = LlamaSampler::greedy();
+    let mut greedy = Vec::new();
+    let mut logit_idx = batch.n_tokens() - 1;
+    for pos in prefix.len()..prefix.len() + 3 {
+        let token = sampler.sample(&ctx, logit_idx);
+        greedy.push(token);
+        batch.clear();
+        batch.add(token, pos as i32, &[0], true).unwrap();
+        ctx.decode(&mut batch).unwrap();
+        logit_idx = 0;
+    }
+
+    let mut other = greedy.clone();
+    other[2] = LlamaToken((other[2].0 + 1) % model.n_vocab());
+    let scores = ctx.score_many(&prefix, &[&greedy, &other]).unwrap();
+    assert_eq!(scores.len(), 2);
+    assert!(
+        scores[0].is_greedy,
+        "greedy continuation must score as greedy"
+    );
+    assert!(!scores[1].is_greedy);
+    assert_eq!(scores[0].logprobs.len(), 3);
+    assert!(scores[0].total <= 0.0);
+    assert!(scores[0].total > scores[1].total);
+    let sum: f32 = scores[0].logprobs.iter().sum();
+    assert!((scores[0].total - sum).abs() < 1e-4);
+
+    let single = ctx.score(&prefix, &greedy).unwrap();
+    assert!((single.total - scores[0].total).abs() < 1e-2);
+    assert!(matches!(
+        ctx.score(&[], &greedy),
+        Err(ScoreError::EmptyPrefix)
+    ));
+}
+
+#[test]
+fn integration_checkpoint_rollback() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(256))
+        .with_n_batch(128);
+    let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+
+    let tokens = model
+        .str_to_token(
+            "One two three four five six seven eight nine ten eleven twelve",
+            AddBos::Always,
+        )
+        .unwrap();
+    assert!(tokens.len() >= 12);
+    let mut batch = LlamaBatch::new(128, 1);
+    let mut ring = CheckpointRing::new(0, 4, 2);
+    for (i, &tok) in tokens.iter().enumerate() {
+        batch.clear();
+        batch.add(tok, i as i32, &[0], true).unwrap();
+        ctx.decode(&mut batch).unwrap();
+        ring.after_decode(&mut ctx).unwrap();
+    }
+    assert_eq!(ring.len(), 2, "oldest checkpoints must be evicted");
+    assert!(ring.checkpoints().all(|c| c.n_tokens() % 4 == 0));
+
+    // Roll back to just past the oldest checkpoint and compare with a fresh
+    // prefill
+    let n_keep = ring.checkpoints().next().unwrap().n_tokens() + 2;
+    let replayed = ring
+        .rollback(&mut ctx, &mut batch, &tokens[..n_keep])
+        .unwrap();
+    assert_eq!(replayed, 2);
+    assert_eq!(ctx.kv_cache_seq_pos_max(0), n_keep as i32 - 1);
+    let rolled_back = ctx.get_logits_ith(batch.n_tokens() - 1).to_vec();
+
+    ctx.clear_kv_cache();
+    batch.clear();
+    for (i, &tok) in tokens[..n_keep].iter().enumerate() {
+        batch.add(tok, i as i32, &[0], i == n_keep - 1).unwrap();
+    }
+    ctx.decode(&mut batch).unwrap();
+    let fresh = ctx.get_logits_ith(batch.n_tokens() - 1);
+    let max_diff = rolled_back
+        .iter()
+        .zip(fresh)
+        .map(|(a, b)| (a - b).abs())
+        .fold(0.0f32, f32::max);
+    assert!(max_diff < 1e-1, "logits differ by {max_diff}");
+
+    // A single snapshot restores the sequence exactly
+    let checkpoint = SequenceCheckpoint::capture(&mut ctx, 0).unwrap();
+    assert!(checkpoint.size() > 0);
+    ctx.clear_kv_cache_seq(Some(0), None, None).unwrap();
+    assert_eq!(ctx.kv_cache_seq_pos_max(0), -1);
+    checkpoint.restore(&mut ctx).unwrap();
+    assert_eq!(ctx.kv_cache_seq_pos_max(0), checkpoint.pos_max());
+}
+
+#[test]
+fn integration_checkpoint_rollback_small_batch() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(256))
+        .with_n_batch(128);
+    let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+
+    let tokens = model
+        .str_to_token(
+            "One two three four five six seven eight nine ten eleven twelve",
+            AddBos::Always,
+        )
+        .unwrap();
+    // The batch holds fewer tokens than the replay, so it must be chunked by
+    // the batch's capacity rather than by n_batch.
+    let mut batch = LlamaBatch::new(4, 1);
+    assert!(tokens.len() > 2 * batch.capacity());
+    let mut ring = CheckpointRing::new(0, 64, 2);
+    for (i, &tok) in tokens.iter().enumerate() {
+        batch.clear();
+        batch.add(tok, i as i32, &[0], true).unwrap();
+        ctx.decode(&mut batch).unwrap();
+        ring.after_decode(&mut ctx).unwrap();
+    }
+    assert!(ring.is_empty());
+
+    let n_keep = tokens.len() - 1;
+    let replayed = ring
+        .rollback(&mut ctx, &mut batch, &tokens[..n_keep])
+        .unwrap();
+    assert_eq!(replayed, n_keep);
+    assert_eq!(ctx.kv_cache_seq_pos_max(0), n_keep as i32 - 1);
+    assert!(batch.n_tokens() > 0);
+    assert!(!ctx.get_logits_ith(batch.n_tokens() - 1).is_empty());
+}
+
+#[test]
+fn integration_generator_chunks_prompt_and_stops() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+
+    // n_batch smaller than the prompt forces chunked prefill.
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(256))
+        .with_n_batch(4)
+        .with_n_ubatch(4);
+    let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+
+    let prompt = model
+        .str_to_token("Once upon a time there was a little girl", AddBos::Always)
+        .unwrap();
+    assert!(prompt.len() > 4);
+
+    let config = GeneratorConfig::new().with_max_tokens(Some(12));
+    let mut generator = Generator::new(&mut ctx, LlamaSampler::greedy(), &prompt, config).unwrap();
+    assert_eq!(generator.n_past(), prompt.len() as i32);
+
+    let mut text = String::new();
+    let mut reason = None;
+    for piece in generator.by_ref() {
+        let piece = piece.unwrap();
+        text.push_str(&piece.text);
+        reason = piece.finish_reason;
+    }
+    let reason = reason.expect("final piece carries a finish reason");
+    assert!(matches!(
+        reason,
+        FinishReason::MaxTokens | FinishReason::Eog
+    ));
+    assert!(generator.generated_tokens().len() <= 12);
+    assert!(generator.next().is_none(), "generator is fused");
+    drop(generator);
+
+    // Greedy decoding is deterministic: stopping on a substring of the first
+    // run's output must truncate right before it.
+    let Some(stop) = text.split_whitespace().nth(2).map(str::to_owned) else {
+        return;
+    };
+    ctx.clear_kv_cache();
+    let config = GeneratorConfig::new()
+        .with_max_tokens(Some(12))
+        .with_stop_strings([stop.clone()]);
+    let (stopped, reason) = Generator::new(&mut ctx, LlamaSampler::greedy(), &prompt, config)
+        .unwrap()
+        .collect_text()
+        .unwrap();
+    assert_eq!(reason, FinishReason::StopString(stop.clone()));
+    assert!(text.starts_with(&stopped));
+    assert!(!stopped.contains(&stop));
+}
+
+#[test]
+fn integration_generator_reasoning_budget() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+
+    // With a budget of zero the closing tag is forced right away.
+    let reasoning = ReasoningConfig::new().with_starts_in_reasoning(true);
+    let closing = model.str_to_token("\n</think>\n\n", AddBos::Never).unwrap();
+    let mut budget = ReasoningBudget::new(&model, reasoning.clone(), 0).unwrap();
+    let forced: Vec<LlamaToken> = std::iter::from_fn(|| {
+        let token = budget.forced_token()?;
+        budget.push(token).unwrap();
+        Some(token)
+    })
+    .collect();
+    assert_eq!(forced, closing);
+    assert!(!budget.in_reasoning());
+    assert!(budget.forced_token().is_none());
+
+    let ctx_params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(256));
+    let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+    let prompt = model
+        .str_to_token("Once upon a time", AddBos::Always)
+        .unwrap();
+    // Not stopping at EOG makes every step run: four reasoning tokens, then
+    // the injected closing tag.
+    let config = GeneratorConfig::new()
+        .with_max_tokens(Some(4 + closing.len()))
+        .with_stop_on_eog(false)
+        .with_special(Special::Tokenize)
+        .with_reasoning_budget(Some(4))
+        .with_reasoning(reasoning);
+    let mut generator = Generator::new(&mut ctx, LlamaSampler::greedy(), &prompt, config).unwrap();
+    let mut text = String::new();
+    for piece in generator.by_ref() {
+        text.push_str(&piece.unwrap().text);
+    }
+    let generated = generator.generated_tokens();
+    assert_eq!(generated.len(), 4 + closing.len());
+    assert_eq!(generated[4..], closing[..]);
+    assert!(text.contains("</think>"));
+}
+
+#[test]
+fn integration_generator_shifts_context() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+
+    let ctx_params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(64));
+    let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+    if !ctx.memory_can_shift() {
+        return;
+    }
+    let n_ctx = ctx.n_ctx_seq() as usize;
+    let prompt = model
+        .str_to_token("Once upon a time", AddBos::Always)
+        .unwrap();
+
+    // Without shifting, generation stops at the end of the window.
+    let config = GeneratorConfig::new()
+        .with_max_tokens(Some(n_ctx * 2))
+        .with_stop_on_eog(false);
+    let (_, reason) = Generator::new(&mut ctx, LlamaSampler::greedy(), &prompt, config.clone())
+        .unwrap()
+        .collect_text()
+        .unwrap();
+    assert_eq!(reason, FinishReason::ContextFull);
+
+    ctx.clear_kv_cache();
+    let n_keep = prompt.len() as u32;
+    let config = config.with_context_shift(Some(ContextShiftConfig::new(n_keep)));
+    let mut generator = Generator::new(&mut ctx, LlamaSampler::greedy(), &prompt, config).unwrap();
+    let mut history = prompt.clone();
+    let mut shifts = 0;
+    let mut reason = None;
+    for piece in generator.by_ref() {
+        let piece = piece.unwrap();
+        if let Some(shift) = piece.context_shift {
+            assert_eq!(shift.n_keep, n_keep);
+            assert!(shift.n_discard > 0);
+            shift.apply_to(&mut history);
+            assert_eq!(history.len(), shift.n_past as usize);
+            shifts += 1;
+        }
+        history.push(piece.token);
+        reason = piece.finish_reason;
+    }
+    assert_eq!(reason, Some(FinishReason::MaxTokens));
+    assert!(shifts > 0, "generating past n_ctx must shift");
+    assert_eq!(generator.generated_tokens().len(), n_ctx * 2);
+    assert_eq!(&history[..prompt.len()], &prompt[..]);
+}
+
+#[test]
+fn integration_generator_self_extend() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+
+    let self_extend = SelfExtendConfig::new(2, 32).unwrap();
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(128))
+        .with_n_batch(64)
+        .with_self_extend(Some(self_extend));
+    let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+    assert_eq!(ctx.self_extend(), Some(self_extend));
+    if !ctx.memory_can_shift() {
+        return;
+    }
+    let n_ctx = ctx.n_ctx_seq() as i32;
+
+    // The prompt spans more than one window, so prefill already merges.
+    let prompt = model
+        .str_to_token(
+            "Once upon a time there was a little girl who lived in a village near \
+             the forest. Whenever she went out, the little girl wore a red riding \
+             cloak, so everyone in the village called her Little Red Riding Hood.",
+            AddBos::Always,
+        )
+        .unwrap();
+    assert!(prompt.len() > 32);
+
+    let config = GeneratorConfig::new().with_stop_on_eog(false);
+    let mut generator = Generator::new(&mut ctx, LlamaSampler::greedy(), &prompt, config).unwrap();
+    assert!(generator.context().self_extend_compressed(0) > 0);
+    let mut reason = None;
+    for piece in generator.by_ref() {
+        reason = piece.unwrap().finish_reason;
+    }
+    assert_eq!(reason, Some(FinishReason::ContextFull));
+
+    // Every cell is used, but positions stay well below n_ctx.
+    let n_past = generator.n_past();
+    let compressed = generator.context().self_extend_compressed(0);
+    assert_eq!(n_past + compressed, n_ctx);
+    assert!(n_past < n_ctx / 2 + 32);
+    drop(generator);
+
+    // Clearing the sequence resets the merge state.
+    ctx.clear_kv_cache();
+    assert_eq!(ctx.self_extend_compressed(0), 0);
+}
+
+#[test]
+fn integration_batch_scheduler_multiplexes_requests() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+
+    // Two sequences for three requests: the third waits for a free sequence.
+    // n_batch 8 forces chunked prefill interleaved with decode steps.
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(512))
+        .with_n_batch(8)
+        .with_n_ubatch(8)
+        .with_n_seq_max(2);
+    let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+    let mut scheduler = BatchScheduler::new(&mut ctx);
+
+    let prompts = [
+        "Once upon a time there was a little girl",
+        "The sun",
+        "One day a big dog",
+    ];
+    let max_tokens = [6, 10, 4];
+    let mut ids = Vec::new();
+    for (prompt, max) in prompts.iter().zip(max_tokens) {
+        let tokens = model.str_to_token(prompt, AddBos::Always).unwrap();
+        let config = GeneratorConfig::new().with_max_tokens(Some(max));
+        ids.push(
+            scheduler
+                .submit(&tokens, LlamaSampler::greedy(), config)
+                .unwrap(),
+        );
+    }
+    assert_eq!(scheduler.n_queued(), 3);
+
+    let mut generated = vec![0_usize; ids.len()];
+    let mut finished = vec![None; ids.len()];
+    let mut steps = 0;
+    while !scheduler.is_idle() {
+        assert!(scheduler.n_running() <= 2);
+        for event in scheduler.step().unwrap() {
+            let index = ids.iter().position(|id| *id == event.request).unwrap();
+            assert!(finished[index].is_none(), "event after finish");
+            let piece = event.piece.unwrap();
+            generated[index] += 1;
+            finished[index] = piece.finish_reason;
+        }
+        steps += 1;
+        assert!(steps < 200, "scheduler made no progress");
+    }
+
+    for (index, reason) in finished.iter().enumerate() {
+        let reason = reason.as_ref().expect("every request finishes");
+        assert!(matches!(
+            reason,
+            FinishReason::MaxTokens | FinishReason::Eog
+        ));
+        assert!(generated[index] <= max_tokens[index]);
+    }
+    assert!(
+        !scheduler.cancel(ids[0]),
+        "finished requests cannot be cancelled"
+    );
+
+    let tokens = model.str_to_token("Cancelled", AddBos::Always).unwrap();
+    let id = scheduler
+        .submit(&tokens, LlamaSampler::greedy(), GeneratorConfig::new())
+        .unwrap();
+    assert!(scheduler.cancel(id));
+    assert!(scheduler.is_idle());
+    drop(scheduler);
+    assert_eq!(
+        ctx.kv_cache_seq_pos_max(0),
+        -1,
+        "finished sequences are freed"
+    );
+    assert_eq!(ctx.kv_cache_seq_pos_max(1), -1);
+}
+
+#[test]
+fn integration_prompt_cache_reuses_prefix() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+
+    // Sequence 0 is the working sequence; 1 and 2 hold cached prompts.
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(512))
+        .with_n_seq_max(3)
+        .with_kv_unified(true);
+    let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+    let mut cache = PromptCache::new([1, 2], 256);
+
+    let system = model
+        .str_to_token("Once upon a time there was a little girl", AddBos::Always)
+        .unwrap();
+    let mut first = system.clone();
+    first.extend(
+        model
+            .str_to_token(" who liked cats", AddBos::Never)
+            .unwrap(),
+    );
+    let mut second = system.clone();
+    second.extend(
+        model
+            .str_to_token(" who liked dogs", AddBos::Never)
+            .unwrap(),
+    );
+
+    assert_eq!(cache.restore(&mut ctx, &first, 0).unwrap(), 0);
+    let config = GeneratorConfig::new().with_max_tokens(Some(0));
+    Generator::new(&mut ctx, LlamaSampler::greedy(), &first, config.clone()).unwrap();
+    assert!(cache.store(&mut ctx, &first, 0).unwrap());
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.n_cells(), first.len());
+
+    let shared = system
+        .iter()
+        .zip(&second)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let reused = cache.restore(&mut ctx, &second, 0).unwrap();
+    assert!(
+        reused >= shared,
+        "reused {reused} of {shared} shared tokens"
+    );
+    assert_eq!(ctx.kv_cache_seq_pos_max(0), reused as i32 - 1);
+
+    let config = GeneratorConfig::new()
+        .with_max_tokens(Some(4))
+        .with_start_pos(Some(reused as i32));
+    let generator =
+        Generator::new(&mut ctx, LlamaSampler::greedy(), &second[reused..], config).unwrap();
+    generator.collect_text().unwrap();
+    assert!(cache.store(&mut ctx, &second, 0).unwrap());
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.n_cells(), first.len() + second.len() - reused);
+
+    // A repeated prompt reuses everything but its last token.
+    assert_eq!(cache.restore(&mut ctx, &first, 0).unwrap(), first.len() - 1);
+
+    cache.clear(&mut ctx).unwrap();
+    assert!(cache.is_empty());
+    assert_eq!(ctx.kv_cache_seq_pos_max(1), -1);
+    assert_eq!(ctx.kv_cache_seq_pos_max(2), -1);
+}
+
+#[test]
+fn integration_chat_session_reuses_history() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+    let probe = [LlamaChatMessage::new("user".into(), "Hello".into()).unwrap()];
+    if let Err(e) = model.apply_chat_template(None, &probe, true) {
+        eprintln!("SKIP: model has no chat template: {e}");
+        return;
+    }
+
+    let ctx_params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(1024));
+    let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+    let config = GeneratorConfig::new().with_max_tokens(Some(16));
+
+    let mut session = ChatSession::new(&mut ctx);
+    session.push_message("system", "You are terse.").unwrap();
+    session.push_message("user", "Name a colour.").unwrap();
+    let first = session
+        .respond(LlamaSampler::greedy(), config.clone(), |_| {})
+        .unwrap();
+    assert_eq!(first.n_reused, 0);
+    let n_after_first = session.tokens().len();
+
+    session.push_message("user", "Name another one.").unwrap();
+    let second = session
+        .respond(LlamaSampler::greedy(), config.clone(), |_| {})
+        .unwrap();
+    // The first prompt is never decoded again.
+    assert!(second.n_reused >= first.n_decoded);
+    assert!(second.n_reused <= n_after_first);
+    assert_eq!(session.messages().len(), 5);
+    assert_eq!(session.messages()[4].content(), second.text);
+
+    let dir = std::env::temp_dir().join(format!("llama-chat-session-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("chat.session");
+    session.save(&path).unwrap();
+    let tokens = session.tokens().to_vec();
+    let messages = session.messages().to_vec();
+    drop(session);
+
+    let ctx_params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(1024));
+    let mut restored_ctx = model.new_context(backend(), ctx_params).unwrap();
+    let mut restored = ChatSession::new(&mut restored_ctx);
+    restored.load(&path).unwrap();
+    assert_eq!(restored.tokens(), &tokens[..]);
+    assert_eq!(restored.messages(), &messages[..]);
+
+    // The restored cache serves the next turn.
+    restored.push_message("user", "And a third?").unwrap();
+    let third = restored
+        .respond(LlamaSampler::greedy(), config, |_| {})
+        .unwrap();
+    assert!(third.n_reused > 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn integration_infill_fits_budget() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+    let request = InfillRequest::new("fn main() {\n    let x = ".repeat(40), "\n}\n".repeat(40))
+        .with_file_name("src/main.rs");
+    let prompt = match request.build_prompt(&model, 64) {
+        Ok(prompt) => prompt,
+        Err(InfillError::Unsupported) => {
+            eprintln!("SKIP: model has no fill-in-the-middle tokens");
+            return;
+        }
+        Err(e) => panic!("build_prompt: {e}"),
+    };
+    assert!(prompt.tokens.len() <= 64);
+    assert!(prompt.truncated);
+    assert!(prompt.n_prefix >= prompt.n_suffix);
+    assert_eq!(prompt.tokens.last(), Some(&model.token_fim_mid()));
+
+    let ctx_params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(512));
+    let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+    let config = GeneratorConfig::new().with_max_tokens(Some(8));
+    let sampler = llama_cpp_4::infill::infill_sampler(&model);
+    let output = llama_cpp_4::infill::infill(&mut ctx, sampler, &request, config, |_| {}).unwrap();
+    assert!(output.prompt.tokens.len() <= 512 - 8);
+}
+
+#[test]
+fn integration_rerank_requires_rank_pooling() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+    let query = model.str_to_token("query", AddBos::Never).unwrap();
+    let document = model.str_to_token("document", AddBos::Never).unwrap();
+    let pair = format_rerank(&model, &query, &document);
+    assert!(pair.len() >= query.len() + document.len());
+
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(256))
+        .with_embeddings(true)
+        .with_pooling_type(LlamaPoolingType::Mean);
+    let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+    let err = rerank(&mut ctx, "query", &["document"]).unwrap_err();
+    assert!(matches!(
+        err,
+        RerankError::NotRankPooling(LlamaPoolingType::Mean)
+    ));
+}
+
+#[test]
+fn integration_seq2seq_t5() {
+    let _guard = llama_guard();
+    if let Some(model) = load_full_model() {
+        if !model.has_encoder() {
+            let ctx_params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(128));
+            let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+            let input = model.str_to_token("hello", AddBos::Always).unwrap();
+            let err = Generator::seq2seq(
+                &mut ctx,
+                LlamaSampler::greedy(),
+                &input,
+                GeneratorConfig::new(),
+            )
+            .err()
+            .unwrap();
+            assert!(matches!(err, GenerateError::NoEncoder));
+        }
+    }
+
+    let Some(model) = load_t5_model() else {
+        // The integration CI job writes the fixture and sets this.
+        assert!(
+            std::env::var_os("LLAMA_TEST_REQUIRE_T5").is_none(),
+            "no T5 fixture; run scripts/fetch-test-model.sh"
+        );
+        eprintln!("SKIP: no T5 model (set LLAMA_TEST_T5_MODEL or run fetch-test-model.sh)");
+        return;
+    };
+    assert!(model.has_encoder());
+    // T5 starts decoding from <pad>.
+    assert_eq!(model.decode_start_token(), LlamaToken(0));
+    let ctx_params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(512));
+    let mut ctx = model.new_context(backend(), ctx_params).unwrap();
+    let input = model
+        .str_to_token(
+            "translate English to German: The house is wonderful.",
+            AddBos::Always,
+        )
+        .unwrap();
+    // The fixture's random weights may end at once; not stopping at EOG runs
+    // every decoder step.
+    let config = GeneratorConfig::new()
+        .with_max_tokens(Some(8))
+        .with_stop_on_eog(false);
+
+    let mut generator =
+        Generator::seq2seq(&mut ctx, LlamaSampler::greedy(), &input, config.clone()).unwrap();
+    // Only the decoder start token is in the sequence.
+    assert_eq!(generator.n_past(), 1);
+    let mut finish_reason = None;
+    for piece in generator.by_ref() {
+        finish_reason = piece.unwrap().finish_reason;
+    }
+    assert_eq!(finish_reason, Some(FinishReason::MaxTokens));
+    let generated = generator.generated_tokens().to_vec();
+    assert_eq!(generated.len(), 8);
+    drop(generator);
+
+    // A second input in the same context starts from a clean decoder state.
+    let mut again = Generator::seq2seq(&mut ctx, LlamaSampler::greedy(), &input, config).unwrap();
+    assert_eq!(again.n_past(), 1);
+    for piece in again.by_ref() {
+        piece.unwrap();
+    }
+    assert_eq!(again.generated_tokens(), &generated[..]);
+}
+
 #[test]
 fn integration_embeddings() {
     let _guard = llama_guard();
@@ -0,0 +1,85 @@
+//! Golden tests for the Jinja chat template renderer.
+//!
+//! `tests/templates/cases.json` lists the conversations. The expected outputs
+//! next to it were rendered by Jinja2 the way `transformers` renders chat
+//! templates; regenerate them with `tests/templates/generate.py`.
+#![cfg(feature = "jinja")]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use llama_cpp_4::jinja::{ChatTemplate, ChatTemplateInputs, JinjaError};
+use serde_json::{json, Value};
+
+fn templates_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/templates")
+}
+
+fn read(name: &str) -> String {
+    fs::read_to_string(templates_dir().join(name)).unwrap()
+}
+
+fn load(name: &str, bos_token: &str, eos_token: &str) -> ChatTemplate {
+    ChatTemplate::new(&read(name), bos_token, eos_token).unwrap()
+}
+
+#[test]
+fn renders_golden_outputs() {
+    let cases: Vec<Value> = serde_json::from_str(&read("cases.json")).unwrap();
+    let mut failures = Vec::new();
+    for case in &cases {
+        let name = case["name"].as_str().unwrap();
+        let template = load(
+            case["template"].as_str().unwrap(),
+            case["bos_token"].as_str().unwrap(),
+            case["eos_token"].as_str().unwrap(),
+        );
+        let mut inputs = ChatTemplateInputs::new(case["messages"].as_array().unwrap().clone())
+            .with_tools(case["tools"].as_array().cloned().unwrap_or_default())
+            .with_add_generation_prompt(case["add_generation_prompt"].as_bool().unwrap_or(true))
+            .with_enable_thinking(case["enable_thinking"].as_bool().unwrap_or(true));
+        if let Some(extra) = case["extra_context"].as_object() {
+            for (key, value) in extra {
+                inputs = inputs.with_extra_context(key.clone(), value.clone());
+            }
+        }
+
+        let expected = read(&format!("{name}.txt"));
+        match template.render(&inputs) {
+            Ok(actual) if actual == expected => {}
+            Ok(actual) => failures.push(format!(
+                "{name}:\n--- expected\n{expected}\n--- actual\n{actual}"
+            )),
+            Err(err) => failures.push(format!("{name}: {err}")),
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+#[test]
+fn detects_capabilities() {
+    let llama3 = load("llama3.jinja", "<|begin_of_text|>", "<|eot_id|>").caps();
+    assert!(llama3.supports_tools);
+    assert!(!llama3.supports_typed_content);
+    assert!(!llama3.supports_thinking);
+
+    let gemma3 = load("gemma3.jinja", "<bos>", "<eos>").caps();
+    assert!(gemma3.supports_typed_content);
+    assert!(!gemma3.supports_tools);
+
+    let qwen3 = load("qwen3.jinja", "", "<|im_end|>").caps();
+    assert!(qwen3.supports_thinking);
+    assert!(qwen3.supports_tools);
+}
+
+#[test]
+fn template_exceptions_are_errors() {
+    let template = load("gemma3.jinja", "<bos>", "<eos>");
+    let inputs = ChatTemplateInputs::new(vec![
+        json!({"role": "user", "content": "a"}),
+        json!({"role": "user", "content": "b"}),
+    ]);
+    let err = template.render(&inputs).unwrap_err();
+    assert!(matches!(err, JinjaError::Template(_)));
+    assert!(err.to_string().contains("must alternate"), "{err}");
+}
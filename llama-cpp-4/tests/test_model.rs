@@ -11,7 +11,7 @@ use llama_cpp_4::model::{AddBos, LlamaModel, Special};
 use llama_cpp_4::token::LlamaToken;
 use llama_cpp_4::TokenToStringError;
 
-use support::model::{backend, load_model};
+use support::model::{backend, load_model, load_vocab};
 
 fn load_test_model() -> Option<(&'static LlamaBackend, LlamaModel, bool)> {
     let (model, vocab_only) = load_model()?;
@@ -405,6 +405,42 @@ fn test_tokenize_with_bos() {
     );
 }
 
+#[test]
+fn test_tokenize_with_offsets_spm_and_bpe() {
+    let text = "Hello world! naïve 日本語\n  indented";
+    let mut tested = 0;
+    for file in ["ggml-vocab-llama-spm.gguf", "ggml-vocab-llama-bpe.gguf"] {
+        let Some(model) = load_vocab(file) else {
+            // CI builds llama.cpp, so a missing vocab there is a bug.
+            assert!(
+                std::env::var_os("CI").is_none(),
+                "{file} not in the build tree"
+            );
+            eprintln!("SKIP: {file} not in the build tree");
+            continue;
+        };
+        let spans = model
+            .str_to_token_with_offsets(text, AddBos::Always)
+            .unwrap();
+        let tokens: Vec<LlamaToken> = spans.iter().map(|span| span.token).collect();
+        assert_eq!(tokens, model.str_to_token(text, AddBos::Always).unwrap());
+
+        // BOS covers nothing; the other tokens tile the input in order.
+        assert_eq!(spans[0].token, model.token_bos());
+        assert_eq!(spans[0].range, 0..0);
+        let mut end = 0;
+        for span in &spans {
+            assert_eq!(span.range.start, end, "{file}: gap before {span:?}");
+            end = span.range.end;
+        }
+        assert_eq!(end, text.len(), "{file}: input not covered");
+        tested += 1;
+    }
+    if tested == 0 {
+        eprintln!("SKIP: no vocab-only GGUFs available");
+    }
+}
+
 // ============================================================
 // Metadata
 // ============================================================
@@ -1,5 +1,6 @@
 //! Tests for sampler creation and introspection (no model needed for most).
 
+use llama_cpp_4::sampling::custom::{CustomSampler, CustomSamplerCallback, SamplerCandidates};
 use llama_cpp_4::sampling::LlamaSampler;
 use llama_cpp_4::token::data::LlamaTokenData;
 use llama_cpp_4::token::data_array::LlamaTokenDataArray;
@@ -229,3 +230,147 @@ fn test_with_tokens() {
         .with_tokens([LlamaToken(0), LlamaToken(1)]);
     assert_eq!(sampler.name(), "chain");
 }
+
+/// Bans one token and counts how often it saw `accept` / `reset`.
+#[derive(Clone, Default)]
+struct CountingBan {
+    banned: i32,
+    accepted: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    resets: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl CustomSampler for CountingBan {
+    fn name(&self) -> &str {
+        "counting-ban"
+    }
+
+    fn apply(&mut self, candidates: &mut SamplerCandidates<'_>) {
+        for data in candidates.as_mut_slice() {
+            if data.id() == LlamaToken(self.banned) {
+                data.set_logit(f32::NEG_INFINITY);
+            }
+        }
+    }
+
+    fn accept(&mut self, _token: LlamaToken) {
+        self.accepted
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn reset(&mut self) {
+        self.resets
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Keeps the single best candidate and selects it.
+#[derive(Clone)]
+struct KeepBest;
+
+impl CustomSampler for KeepBest {
+    fn apply(&mut self, candidates: &mut SamplerCandidates<'_>) {
+        let data = candidates.as_mut_slice();
+        data.sort_by(|a, b| b.logit().total_cmp(&a.logit()));
+        candidates.set_sorted(true);
+        candidates.truncate(1);
+        candidates.set_selected(Some(0));
+    }
+}
+
+#[derive(Clone)]
+struct PanicOnAccept;
+
+impl CustomSampler for PanicOnAccept {
+    fn apply(&mut self, candidates: &mut SamplerCandidates<'_>) {
+        candidates.truncate(1);
+    }
+
+    fn accept(&mut self, token: LlamaToken) {
+        panic!("rejected token {}", token.0);
+    }
+}
+
+fn three_candidates() -> LlamaTokenDataArray {
+    LlamaTokenDataArray::new(
+        vec![
+            LlamaTokenData::new(LlamaToken(0), 1.0, 0.0),
+            LlamaTokenData::new(LlamaToken(1), 5.0, 0.0),
+            LlamaTokenData::new(LlamaToken(2), 3.0, 0.0),
+        ],
+        false,
+    )
+}
+
+#[test]
+fn test_custom_sampler_name() {
+    let sampler = LlamaSampler::custom(CountingBan::default());
+    assert_eq!(sampler.name(), "counting-ban");
+    assert_eq!(LlamaSampler::custom(KeepBest).name(), "custom");
+}
+
+#[test]
+fn test_custom_sampler_in_chain() {
+    let mut data_array = three_candidates();
+    data_array.apply_sampler(&mut LlamaSampler::chain_simple([
+        LlamaSampler::custom(CountingBan {
+            banned: 1,
+            ..CountingBan::default()
+        }),
+        LlamaSampler::greedy(),
+    ]));
+    assert_eq!(data_array.selected_token(), Some(LlamaToken(2)));
+}
+
+#[test]
+fn test_custom_sampler_truncates_and_selects() {
+    let mut data_array = three_candidates();
+    data_array.apply_sampler(&mut LlamaSampler::custom(KeepBest));
+    assert_eq!(data_array.data.len(), 1);
+    assert!(data_array.sorted);
+    assert_eq!(data_array.selected_token(), Some(LlamaToken(1)));
+}
+
+#[test]
+fn test_custom_sampler_accept_reset_and_clone() {
+    let counting = CountingBan::default();
+    let accepted = counting.accepted.clone();
+    let resets = counting.resets.clone();
+
+    let mut chain =
+        LlamaSampler::chain_simple([LlamaSampler::custom(counting), LlamaSampler::greedy()]);
+    chain.accept_many([LlamaToken(0), LlamaToken(1)]);
+    chain.reset();
+
+    let mut cloned = chain.clone_sampler();
+    assert_eq!(cloned.chain_n(), 2);
+    cloned.accept(LlamaToken(2));
+    drop(chain);
+
+    let mut data_array = three_candidates();
+    data_array.apply_sampler(&mut cloned);
+    assert_eq!(data_array.selected_token(), Some(LlamaToken(2)));
+
+    // The clone shares the counters because `Arc` fields are cloned.
+    assert_eq!(accepted.load(std::sync::atomic::Ordering::Relaxed), 3);
+    assert_eq!(resets.load(std::sync::atomic::Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_custom_sampler_panic_is_contained() {
+    let (mut sampler, monitor) = LlamaSampler::custom_with_monitor(PanicOnAccept);
+    assert!(!monitor.has_failed());
+
+    sampler.accept(LlamaToken(7));
+    let failure = monitor.failure().expect("panic recorded");
+    assert_eq!(failure.callback(), CustomSamplerCallback::Accept);
+    assert_eq!(failure.message(), "rejected token 7");
+
+    // A poisoned sampler passes candidates through untouched.
+    let mut data_array = three_candidates();
+    data_array.apply_sampler(&mut sampler);
+    assert_eq!(data_array.data.len(), 3);
+
+    // Cloning a poisoned sampler still yields a usable native sampler.
+    let cloned = sampler.clone_sampler();
+    assert_eq!(cloned.name(), "custom");
+}
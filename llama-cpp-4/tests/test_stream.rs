@@ -0,0 +1,105 @@
+//! Integration tests for the async [`TokenStream`] (`tokio` feature).
+//!
+//! Like `test_integration`, these skip (pass) when no full model is available
+//! and hold [`support::model::llama_guard`] until the worker has exited.
+
+#![cfg(feature = "tokio")]
+
+mod support;
+
+use std::num::NonZeroU32;
+use std::sync::Arc;
+
+use llama_cpp_4::prelude::*;
+use llama_cpp_4::stream::TokenStream;
+
+use support::model::{backend, llama_guard, load_full_model, skip_no_model};
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+}
+
+fn small_context() -> LlamaContextParams {
+    LlamaContextParams::default().with_n_ctx(NonZeroU32::new(256))
+}
+
+#[test]
+fn stream_matches_blocking_generator() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+    let model = Arc::new(model);
+    let prompt = model
+        .str_to_token("Once upon a time", AddBos::Always)
+        .unwrap();
+    let config = GeneratorConfig::new().with_max_tokens(Some(8));
+
+    let mut ctx = model.new_context(backend(), small_context()).unwrap();
+    let (expected, expected_reason) =
+        Generator::new(&mut ctx, LlamaSampler::greedy(), &prompt, config.clone())
+            .unwrap()
+            .collect_text()
+            .unwrap();
+    drop(ctx);
+
+    let mut stream = TokenStream::builder(Arc::clone(&model), prompt)
+        .with_config(config)
+        .with_context_params(small_context)
+        .with_capacity(1)
+        .spawn(backend())
+        .unwrap();
+
+    let (text, reason) = runtime().block_on(async {
+        let mut text = String::new();
+        let mut reason = None;
+        while let Some(piece) = stream.next_piece().await {
+            let piece = piece.unwrap();
+            text.push_str(&piece.text);
+            reason = piece.finish_reason;
+        }
+        (text, reason)
+    });
+    assert_eq!(text, expected);
+    assert_eq!(reason, Some(expected_reason));
+}
+
+#[test]
+fn cancelled_stream_ends_early() {
+    let _guard = llama_guard();
+    let Some(model) = load_full_model() else {
+        skip_no_model();
+        return;
+    };
+    let model = Arc::new(model);
+    let prompt = model
+        .str_to_token("Once upon a time", AddBos::Always)
+        .unwrap();
+
+    let mut stream = TokenStream::builder(Arc::clone(&model), prompt)
+        .with_config(GeneratorConfig::new().with_max_tokens(Some(200)))
+        .with_context_params(small_context)
+        .with_capacity(1)
+        .spawn(backend())
+        .unwrap();
+
+    let received = runtime().block_on(async {
+        let first = stream.next_piece().await;
+        assert!(first.is_some_and(|piece| piece.is_ok()));
+        stream.cancel();
+        let mut received = 1;
+        while stream.next_piece().await.is_some() {
+            received += 1;
+        }
+        received
+    });
+    assert!(stream.is_cancelled());
+    // Capacity 1: at most the queued piece and the one being sent remain.
+    assert!(received <= 3, "received {received} pieces after cancel");
+    while !stream.is_worker_finished() {
+        std::thread::yield_now();
+    }
+}
@@ -6,6 +6,11 @@
 #
 # The file is cached at target/test-models/stories260K.gguf (same model as
 # llama.cpp server tests: ggml-org/models tinyllamas/stories260K.gguf).
+#
+# The encoder-decoder test (integration_seq2seq_t5) uses
+# target/test-models/t5-small.gguf: a tiny random-weight T5 written by
+# make-t5-test-model.py (needs numpy and llama.cpp's gguf-py), or a real T5
+# GGUF downloaded from LLAMA_TEST_T5_URL when that is set.
 
 set -euo pipefail
 
@@ -18,9 +23,22 @@ mkdir -p "$DEST"
 
 if [[ -f "$FILE" ]]; then
   echo "Test model already present: $FILE"
-  exit 0
+else
+  echo "Downloading stories260K.gguf (~1 MB)…"
+  curl -fsSL "$URL" -o "$FILE"
+  echo "Saved: $FILE"
 fi
 
-echo "Downloading stories260K.gguf (~1 MB)…"
-curl -fsSL "$URL" -o "$FILE"
-echo "Saved: $FILE"
+T5_FILE="$DEST/t5-small.gguf"
+if [[ -f "$T5_FILE" ]]; then
+  echo "T5 test model already present: $T5_FILE"
+elif [[ -n "${LLAMA_TEST_T5_URL:-}" ]]; then
+  echo "Downloading T5 test model…"
+  curl -fsSL "$LLAMA_TEST_T5_URL" -o "$T5_FILE"
+  echo "Saved: $T5_FILE"
+elif python3 "$ROOT/scripts/make-t5-test-model.py" "$T5_FILE"; then
+  :
+else
+  rm -f "$T5_FILE"
+  echo "Skipping T5 test model (install numpy and gguf-py, or set LLAMA_TEST_T5_URL)"
+fi
@@ -0,0 +1,135 @@
+#!/usr/bin/env python3
+"""Write a tiny T5 GGUF with random weights for the seq2seq integration test.
+
+Usage:
+  python3 scripts/make-t5-test-model.py target/test-models/t5-small.gguf
+
+The model is useless for translation but exercises the same code paths as a
+real T5: a UGM ("t5") tokenizer, an encoder with relative attention buckets,
+and a decoder with cross-attention that starts from `<pad>`. Needs numpy and
+llama.cpp's gguf-py (`pip install ./llama-cpp-sys-4/llama.cpp/gguf-py`); the
+copy in the llama.cpp submodule is used when the package is not installed.
+"""
+
+import sys
+from pathlib import Path
+
+import numpy as np
+
+ROOT = Path(__file__).resolve().parent.parent
+sys.path.append(str(ROOT / "llama-cpp-sys-4" / "llama.cpp" / "gguf-py"))
+
+import gguf  # noqa: E402
+
+N_EMBD = 32
+N_HEAD = 4
+HEAD_DIM = N_EMBD // N_HEAD
+N_FF = 64
+N_LAYER = 2
+N_REL_BUCKETS = 32
+N_CTX = 512
+
+# Same special tokens as T5: <pad> (also the decoder start), </s>, <unk>.
+PAD, EOS, UNK = 0, 1, 2
+
+
+def vocab():
+    tokens = [b"<pad>", b"</s>", b"<unk>"]
+    types = [
+        gguf.TokenType.CONTROL,
+        gguf.TokenType.CONTROL,
+        gguf.TokenType.UNKNOWN,
+    ]
+    chars = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789.,:;!?'-"
+    pieces = ["▁", *chars] + ["▁" + c for c in chars]
+    tokens += [p.encode() for p in pieces]
+    types += [gguf.TokenType.NORMAL] * len(pieces)
+    # Longer pieces score higher, so words start with a "▁x" piece.
+    scores = [0.0, 0.0, 0.0] + [-10.0 + len(p) for p in pieces]
+    return tokens, scores, types
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit(f"usage: {sys.argv[0]} OUTPUT.gguf")
+    out = Path(sys.argv[1])
+    out.parent.mkdir(parents=True, exist_ok=True)
+
+    rng = np.random.default_rng(0)
+    tokens, scores, types = vocab()
+    n_vocab = len(tokens)
+
+    writer = gguf.GGUFWriter(str(out), gguf.MODEL_ARCH_NAMES[gguf.MODEL_ARCH.T5])
+    writer.add_name("t5-test-random")
+    writer.add_context_length(N_CTX)
+    writer.add_embedding_length(N_EMBD)
+    writer.add_feed_forward_length(N_FF)
+    writer.add_block_count(N_LAYER)
+    writer.add_head_count(N_HEAD)
+    writer.add_head_count_kv(N_HEAD)
+    writer.add_key_length(HEAD_DIM)
+    writer.add_value_length(HEAD_DIM)
+    writer.add_layer_norm_rms_eps(1e-6)
+    writer.add_relative_attn_buckets_count(N_REL_BUCKETS)
+    writer.add_decoder_start_token_id(PAD)
+    writer.add_file_type(gguf.LlamaFileType.ALL_F32)
+
+    writer.add_tokenizer_model("t5")
+    writer.add_token_list(tokens)
+    writer.add_token_scores(scores)
+    writer.add_token_types(types)
+    writer.add_pad_token_id(PAD)
+    writer.add_eos_token_id(EOS)
+    writer.add_unk_token_id(UNK)
+    writer.add_add_bos_token(False)
+    writer.add_add_eos_token(True)
+
+    # numpy shapes are the reverse of ggml's `ne`.
+    def weight(name, *shape, bid=None):
+        name = gguf.TENSOR_NAMES[name].format(bid=bid) + ".weight"
+        data = rng.normal(0.0, 0.02, size=shape).astype(np.float32)
+        writer.add_tensor(name, data)
+
+    def norm(name, bid=None):
+        name = gguf.TENSOR_NAMES[name].format(bid=bid) + ".weight"
+        writer.add_tensor(name, np.ones(N_EMBD, dtype=np.float32))
+
+    T = gguf.MODEL_TENSOR
+    weight(T.TOKEN_EMBD, n_vocab, N_EMBD)
+    weight(T.OUTPUT, n_vocab, N_EMBD)
+    norm(T.ENC_OUTPUT_NORM)
+    norm(T.DEC_OUTPUT_NORM)
+    for i in range(N_LAYER):
+        norm(T.ENC_ATTN_NORM, i)
+        weight(T.ENC_ATTN_REL_B, N_REL_BUCKETS, N_HEAD, bid=i)
+        for t in (T.ENC_ATTN_Q, T.ENC_ATTN_K, T.ENC_ATTN_V, T.ENC_ATTN_OUT):
+            weight(t, N_EMBD, N_EMBD, bid=i)
+        norm(T.ENC_FFN_NORM, i)
+        weight(T.ENC_FFN_UP, N_FF, N_EMBD, bid=i)
+        weight(T.ENC_FFN_DOWN, N_EMBD, N_FF, bid=i)
+
+        norm(T.DEC_ATTN_NORM, i)
+        weight(T.DEC_ATTN_REL_B, N_REL_BUCKETS, N_HEAD, bid=i)
+        for t in (T.DEC_ATTN_Q, T.DEC_ATTN_K, T.DEC_ATTN_V, T.DEC_ATTN_OUT):
+            weight(t, N_EMBD, N_EMBD, bid=i)
+        norm(T.DEC_CROSS_ATTN_NORM, i)
+        for t in (
+            T.DEC_CROSS_ATTN_Q,
+            T.DEC_CROSS_ATTN_K,
+            T.DEC_CROSS_ATTN_V,
+            T.DEC_CROSS_ATTN_OUT,
+        ):
+            weight(t, N_EMBD, N_EMBD, bid=i)
+        norm(T.DEC_FFN_NORM, i)
+        weight(T.DEC_FFN_UP, N_FF, N_EMBD, bid=i)
+        weight(T.DEC_FFN_DOWN, N_EMBD, N_FF, bid=i)
+
+    writer.write_header_to_file()
+    writer.write_kv_data_to_file()
+    writer.write_tensors_to_file()
+    writer.close()
+    print(f"Saved: {out}")
+
+
+if __name__ == "__main__":
+    main()